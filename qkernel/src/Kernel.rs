@@ -1,7 +1,6 @@
 use spin::Mutex;
 use lazy_static::lazy_static;
 use super::qlib;
-use alloc::string::String;
 
 lazy_static! {
     pub static ref KERNEL: Mutex<Kernel> = Mutex::new(Kernel::Init());
@@ -17,13 +16,25 @@ impl Kernel {
     }
 
     pub fn Print(str: &str) {
-        let mut msg = qlib::Msg::Print (String::from(str));
-        Kernel::Call(&mut msg);
+        //qvisor takes at most MAX_STR_LEN bytes a message, split at char boundaries
+        let mut rest = str;
+        while rest.len() > 0 {
+            let mut len = rest.len().min(qlib::MAX_STR_LEN as usize);
+            while !rest.is_char_boundary(len) {
+                len -= 1;
+            }
+
+            let mut msg = qlib::Msg::Print(&rest[..len]);
+            Kernel::Call(&mut msg);
+            rest = &rest[len..];
+        }
     }
 
     fn Call(event: &mut qlib::Msg) {
-        super::SHARESPACE.lock().SetMsg(event);
-        qlib::Wait();
+        super::SHARESPACE.Lock().SetMsg(event);
+        super::SHARESPACE.Wait();
+        //qvisor may have hotplugged memory while the kernel waited
+        super::ALLOCATOR.TakeHotplugged();
     }
 }
 
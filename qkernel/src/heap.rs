@@ -0,0 +1,223 @@
+use core::alloc::{GlobalAlloc, Layout};
+use core::cmp::max;
+use core::mem::size_of;
+use core::ptr::{self, NonNull};
+use linked_list_allocator::Heap;
+use spin::Mutex;
+
+use super::qlib;
+
+//the memory asked from qvisor when the heap runs out
+const GROW_SIZE: u64 = 64 * 0x100000;
+//the heap memory is given to qvisor with HYPERCALL_BALLOON in chunks of this, aligned for hugetlb memory
+const BALLOON_CHUNK: u64 = 2 * 0x100000;
+const BALLOON_CHUNKS: usize = 64;
+//the header of an extra heap, rounded up so the heap memory stays 16 bytes aligned
+const EXTRA_HEADER_SIZE: u64 = (size_of::<ExtraHeap>() as u64 + 15) & !15;
+
+//a heap on guest RAM added at run time, its header is at the start of the memory
+struct ExtraHeap {
+    heap: Heap,
+    //the allocations not freed yet, the memory goes back to qvisor at 0
+    allocs: u64,
+    next: *mut ExtraHeap,
+}
+
+struct Heaps {
+    main: Heap,
+    extra: *mut ExtraHeap,
+    //the chunks of the main heap in the balloon, allocated so the heap doesn't use them
+    balloon: [u64; BALLOON_CHUNKS],
+    ballooned: usize,
+}
+
+//the extra heaps are only touched under the lock
+unsafe impl Send for Heaps {}
+
+//the fixed heap at HEAP_START, which grows by the guest RAM qvisor hotplugs or gives for HYPERCALL_ALLOCMEM,
+//and gives an extra heap which is empty again back with HYPERCALL_FREEMEM. Free memory of the fixed heap goes
+//to the balloon with Inflate, an allocation which doesn't fit takes it back.
+//qvisor has mapped the new memory in the kernel page tables already
+pub struct KernelHeap(Mutex<Heaps>);
+
+impl KernelHeap {
+    pub const fn Empty() -> Self {
+        return KernelHeap(Mutex::new(Heaps {
+            main: Heap::empty(),
+            extra: ptr::null_mut(),
+            balloon: [0; BALLOON_CHUNKS],
+            ballooned: 0,
+        }))
+    }
+
+    pub unsafe fn Init(&self, start: usize, size: usize) {
+        self.0.lock().main.init(start, size);
+    }
+
+    unsafe fn Add(heaps: &mut Heaps, start: u64, len: u64) {
+        if len <= EXTRA_HEADER_SIZE {
+            return;
+        }
+
+        let extra = start as *mut ExtraHeap;
+        ptr::write(extra, ExtraHeap {
+            heap: Heap::empty(),
+            allocs: 0,
+            next: heaps.extra,
+        });
+        (*extra).heap.init((start + EXTRA_HEADER_SIZE) as usize, (len - EXTRA_HEADER_SIZE) as usize);
+        heaps.extra = extra;
+    }
+
+    //move the memory qvisor hotplugged from the ShareSpace to the heap
+    unsafe fn AddHotplugged(heaps: &mut Heaps) {
+        loop {
+            let added = super::SHARESPACE.Lock().TakeMem();
+            match added {
+                Some((addr, len)) => Self::Add(heaps, addr, len),
+                None => return,
+            }
+        }
+    }
+
+    pub fn TakeHotplugged(&self) {
+        unsafe {
+            Self::AddHotplugged(&mut self.0.lock());
+        }
+    }
+
+    fn ChunkLayout() -> Layout {
+        return Layout::from_size_align(BALLOON_CHUNK as usize, BALLOON_CHUNK as usize).unwrap()
+    }
+
+    //give up to len bytes of the free memory of the main heap to qvisor, return the bytes given
+    pub fn Inflate(&self, len: u64) -> u64 {
+        let mut heaps = self.0.lock();
+        let mut given = 0;
+        while given + BALLOON_CHUNK <= len && heaps.ballooned < BALLOON_CHUNKS {
+            let p = match heaps.main.allocate_first_fit(Self::ChunkLayout()) {
+                Ok(p) => p,
+                Err(_) => break,
+            };
+
+            if qlib::BalloonInflate(p.as_ptr() as u64, BALLOON_CHUNK).is_err() {
+                unsafe {
+                    heaps.main.deallocate(p, Self::ChunkLayout());
+                }
+                break;
+            }
+
+            let idx = heaps.ballooned;
+            heaps.balloon[idx] = p.as_ptr() as u64;
+            heaps.ballooned += 1;
+            given += BALLOON_CHUNK;
+        }
+
+        return given
+    }
+
+    //take the memory of Inflate back to the heap, return the bytes taken
+    fn DeflateLocked(heaps: &mut Heaps) -> u64 {
+        let mut taken = 0;
+        while heaps.ballooned > 0 {
+            let addr = heaps.balloon[heaps.ballooned - 1];
+            //qvisor only refuses a range it doesn't know, which the heap can use as it is
+            let _ = qlib::BalloonDeflate(addr, BALLOON_CHUNK);
+            unsafe {
+                heaps.main.deallocate(NonNull::new_unchecked(addr as *mut u8), Self::ChunkLayout());
+            }
+            heaps.ballooned -= 1;
+            taken += BALLOON_CHUNK;
+        }
+
+        return taken
+    }
+
+    pub fn Deflate(&self) -> u64 {
+        return Self::DeflateLocked(&mut self.0.lock())
+    }
+
+    fn TryAlloc(heaps: &mut Heaps, layout: Layout) -> *mut u8 {
+        if let Ok(p) = heaps.main.allocate_first_fit(layout) {
+            return p.as_ptr()
+        }
+
+        let mut extra = heaps.extra;
+        while !extra.is_null() {
+            unsafe {
+                if let Ok(p) = (*extra).heap.allocate_first_fit(layout) {
+                    (*extra).allocs += 1;
+                    return p.as_ptr()
+                }
+                extra = (*extra).next;
+            }
+        }
+
+        return ptr::null_mut()
+    }
+}
+
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut heaps = self.0.lock();
+        let p = Self::TryAlloc(&mut heaps, layout);
+        if !p.is_null() {
+            return p
+        }
+
+        //the balloon first, its memory is the guest's already
+        if Self::DeflateLocked(&mut heaps) > 0 {
+            let p = Self::TryAlloc(&mut heaps, layout);
+            if !p.is_null() {
+                return p
+            }
+        }
+
+        Self::AddHotplugged(&mut heaps);
+        let p = Self::TryAlloc(&mut heaps, layout);
+        if !p.is_null() {
+            return p
+        }
+
+        //out of memory, ask qvisor for more
+        let need = max(GROW_SIZE, EXTRA_HEADER_SIZE + (layout.size() + layout.align()) as u64);
+        let len = (need + qlib::PAGE_SIZE - 1) & !(qlib::PAGE_SIZE - 1);
+        match qlib::AllocMem(len, false) {
+            Ok(addr) => {
+                Self::Add(&mut heaps, addr, len);
+                return Self::TryAlloc(&mut heaps, layout)
+            }
+            Err(_) => return ptr::null_mut(),
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let mut heaps = self.0.lock();
+        let addr = ptr as usize;
+        if heaps.main.bottom() <= addr && addr < heaps.main.top() {
+            heaps.main.deallocate(NonNull::new_unchecked(ptr), layout);
+            return;
+        }
+
+        let mut prev: *mut ExtraHeap = ptr::null_mut();
+        let mut extra = heaps.extra;
+        while !extra.is_null() {
+            if (*extra).heap.bottom() <= addr && addr < (*extra).heap.top() {
+                (*extra).heap.deallocate(NonNull::new_unchecked(ptr), layout);
+                (*extra).allocs -= 1;
+
+                //give an empty heap back, but the newest one, so an allocation at the edge doesn't map
+                //and free guest memory each time
+                if (*extra).allocs == 0 && !prev.is_null() {
+                    (*prev).next = (*extra).next;
+                    //a refused free only leaves the memory unused
+                    let _ = qlib::FreeMem(extra as u64);
+                }
+                return;
+            }
+
+            prev = extra;
+            extra = (*extra).next;
+        }
+    }
+}
@@ -1,5 +1,10 @@
 use lazy_static::lazy_static;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use x86_64::structures::DescriptorTablePointer;
+use super::qlib;
+use super::timer;
+use super::serial;
+use super::notify;
 
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
@@ -14,7 +19,12 @@ lazy_static! {
         idt.invalid_opcode.set_handler_fn(breakpoint_handler);
         idt.device_not_available.set_handler_fn(breakpoint_handler);
 
-        idt.double_fault.set_handler_fn(double_fault_handler);
+        //qvisor gives each vcpu a dedicated double fault stack in its TSS
+        unsafe {
+            idt.double_fault
+                .set_handler_fn(double_fault_handler)
+                .set_stack_index(qlib::DOUBLE_FAULT_IST_INDEX);
+        }
 
         idt.invalid_tss.set_handler_fn(double_fault_handler);
         idt.segment_not_present.set_handler_fn(double_fault_handler);
@@ -28,6 +38,12 @@ lazy_static! {
         idt.virtualization.set_handler_fn(breakpoint_handler);
         idt.security_exception.set_handler_fn(double_fault_handler);
 
+        idt[timer::PIT_VECTOR as usize].set_handler_fn(timer::pit_handler);
+        idt[timer::APIC_TIMER_VECTOR as usize].set_handler_fn(timer::apic_timer_handler);
+        idt[timer::SPURIOUS_VECTOR as usize].set_handler_fn(timer::spurious_handler);
+        idt[serial::SERIAL_VECTOR as usize].set_handler_fn(serial::serial_handler);
+        idt[notify::NOTIFY_VECTOR as usize].set_handler_fn(notify::notify_handler);
+
         /*
         unsafe {
             idt.breakpoint.set_handler_fn(breakpoint_handler).set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);;
@@ -47,11 +63,17 @@ lazy_static! {
 pub fn init_idt() {
     use core::mem::size_of;
 
-    let idtAddr : u64 = &IDT as *const _ as u64;
+    let idtAddr : u64 = &*IDT as *const _ as u64;
     let limit = (size_of::<InterruptDescriptorTable>() - 1) as u16;
     kprintln!("the idt addr is {:x}, limit={:x}", idtAddr, limit);
 
-    //IDT.load();
+    //qvisor checks the table is mapped and loads it into the vcpu's IDTR
+    let ptr = DescriptorTablePointer {
+        limit: limit,
+        base: idtAddr,
+    };
+
+    qlib::HyperCall(qlib::HYPERCALL_LOADIDT, &ptr as *const _ as u64);
 }
 
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: &mut InterruptStackFrame) {
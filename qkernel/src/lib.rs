@@ -20,41 +20,58 @@ mod print;
 mod qlib;
 mod interrupts;
 mod Kernel;
+mod heap;
+mod timer;
+mod serial;
+mod notify;
 
 use core::panic::PanicInfo;
-use linked_list_allocator::LockedHeap;
-use qlib::{ShareSpace};
-use lazy_static::lazy_static;
-use spin::Mutex;
+use heap::KernelHeap;
+use qlib::ShareSpaceLock;
 
 pub const HEAP_START: usize = 0x70_2000_0000;
 pub const HEAP_SIZE: usize = 0x1000_0000;
 
 #[global_allocator]
-static ALLOCATOR:  LockedHeap = LockedHeap::empty();
+pub static ALLOCATOR: KernelHeap = KernelHeap::Empty();
 
-lazy_static! {
-    pub static ref SHARESPACE: Mutex<ShareSpace> = Mutex::new(ShareSpace::Init());
-}
+pub static SHARESPACE: ShareSpaceLock = ShareSpaceLock::New();
 
 #[no_mangle]
 pub extern fn rust_main() {
+    serial::Init();
+    kprintln!("qkernel serial console");
+
     unsafe {
-        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+        ALLOCATOR.Init(HEAP_START, HEAP_SIZE);
     }
 
-    qlib::HyperCall(qlib::HYPERCALL_INIT, (&(*SHARESPACE) as * const Mutex<ShareSpace>) as u64);
+    qlib::HyperCall(qlib::HYPERCALL_INIT, SHARESPACE.Addr());
 
     interrupts::init_idt();
+    timer::Init();
+    serial::EnableInput();
+    notify::Init();
+
+    //the free heap isn't worth saving, an allocation takes it back from the balloon
+    let ballooned = ALLOCATOR.Inflate(HEAP_SIZE as u64 / 2);
+    kprintln!("{:x} bytes of the heap in the balloon", ballooned);
+
+    //the kernel is up, a restored vm starts from here
+    qlib::Snapshot();
 
     for i in 0..10 {
         println!("in kernel {}", i);
+        notify::Ring();
+        timer::YieldPoint();
     }
 
     //enable to repro the exception issue
     //x86_64::instructions::interrupts::int3();
 
-    println!("in kernel end....");
+    let (rings, answers) = notify::Counts();
+    println!("in kernel end.... {} ticks, {} ns, {} slices, {} rings {} answers", timer::Ticks(), timer::MonotonicNs(), timer::Slices(), rings, answers);
+    qlib::Exit(0);
 }
 #[panic_handler]
 fn panic(_info: &PanicInfo) -> ! {
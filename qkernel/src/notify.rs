@@ -0,0 +1,37 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::port::Port;
+use x86_64::structures::idt::InterruptStackFrame;
+
+use super::qlib;
+use super::timer;
+
+const NOTIFY_IRQ: u8 = qlib::NOTIFY_GSI as u8;
+pub const NOTIFY_VECTOR: u8 = timer::PIC_VECTOR_BASE + NOTIFY_IRQ;
+
+static RINGS: AtomicU64 = AtomicU64::new(0);
+static ANSWERS: AtomicU64 = AtomicU64::new(0);
+
+//take the answers of qvisor, after timer::Init set up the 8259s
+pub fn Init() {
+    unsafe {
+        timer::UnmaskIrq(NOTIFY_IRQ);
+    }
+}
+
+//ring the doorbell, the vcpu goes on without an exit
+pub fn Ring() {
+    RINGS.fetch_add(1, Ordering::SeqCst);
+    unsafe {
+        Port::<u8>::new(qlib::NOTIFY_PORT).write(1);
+    }
+}
+
+//(rings, answers), qvisor may answer several rings with one interrupt
+pub fn Counts() -> (u64, u64) {
+    return (RINGS.load(Ordering::SeqCst), ANSWERS.load(Ordering::SeqCst))
+}
+
+pub extern "x86-interrupt" fn notify_handler(_stack_frame: &mut InterruptStackFrame) {
+    ANSWERS.fetch_add(1, Ordering::SeqCst);
+    timer::PicEoi();
+}
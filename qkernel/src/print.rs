@@ -47,7 +47,8 @@ macro_rules! kprintln {
     ($($arg:tt)*) => ($crate::kprint!("{}\n", format_args!($($arg)*)));
 }
 
+//to the serial console, which works before the heap and the ShareSpace
 #[doc(hidden)]
 pub fn k_print(args: Arguments) {
-    PRINT_STRUCT.lock().write_fmt(args).unwrap();
+    super::serial::SERIAL.lock().write_fmt(args).unwrap();
 }
\ No newline at end of file
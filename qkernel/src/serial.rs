@@ -0,0 +1,143 @@
+use core::fmt;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+use x86_64::structures::idt::InterruptStackFrame;
+
+use super::timer;
+
+//the COM1 16550 of qvisor, on isa irq 4
+const COM1: u16 = 0x3f8;
+const COM1_IRQ: u8 = 4;
+pub const SERIAL_VECTOR: u8 = timer::PIC_VECTOR_BASE + COM1_IRQ;
+
+const UART_DATA: u16 = 0;
+const UART_IER: u16 = 1;
+const UART_FCR: u16 = 2;
+const UART_LCR: u16 = 3;
+const UART_MCR: u16 = 4;
+const UART_LSR: u16 = 5;
+
+const IER_RDI: u8 = 0x01;
+const LSR_DR: u8 = 0x01;
+const LSR_THRE: u8 = 0x20;
+const LCR_DLAB: u8 = 0x80;
+const LCR_8N1: u8 = 0x03;
+//enable and clear the fifos
+const FCR_INIT: u8 = 0x07;
+//DTR, RTS and OUT2, which gates the irq on a pc
+const MCR_INIT: u8 = 0x0b;
+//115200 baud
+const DIVISOR: u16 = 1;
+
+//the input not read yet, in a fixed ring as the console works before the heap
+const INPUT_SIZE: usize = 256;
+
+struct Input {
+    buf: [u8; INPUT_SIZE],
+    head: usize,
+    len: usize,
+}
+
+lazy_static! {
+    static ref INPUT: Mutex<Input> = Mutex::new(Input {
+        buf: [0; INPUT_SIZE],
+        head: 0,
+        len: 0,
+    });
+
+    //the writers take it so their lines don't mix, the irq handler echoes without it
+    pub static ref SERIAL: Mutex<SerialWriter> = Mutex::new(SerialWriter {});
+}
+
+pub struct SerialWriter {
+
+}
+
+impl fmt::Write for SerialWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &b in s.as_bytes() {
+            if b == b'\n' {
+                PutByte(b'\r');
+            }
+
+            PutByte(b);
+        }
+
+        Ok(())
+    }
+}
+
+fn Reg(offset: u16) -> Port<u8> {
+    return Port::new(COM1 + offset)
+}
+
+//8N1 with the fifos on and the irq off, needs neither the heap nor the ShareSpace
+pub fn Init() {
+    unsafe {
+        Reg(UART_IER).write(0);
+        Reg(UART_LCR).write(LCR_DLAB);
+        Reg(UART_DATA).write(DIVISOR as u8);
+        Reg(UART_IER).write((DIVISOR >> 8) as u8);
+        Reg(UART_LCR).write(LCR_8N1);
+        Reg(UART_FCR).write(FCR_INIT);
+        Reg(UART_MCR).write(MCR_INIT);
+    }
+}
+
+//take the input with interrupts, after timer::Init set up the 8259s
+pub fn EnableInput() {
+    unsafe {
+        Reg(UART_IER).write(IER_RDI);
+        timer::UnmaskIrq(COM1_IRQ);
+    }
+}
+
+fn PutByte(b: u8) {
+    unsafe {
+        while Reg(UART_LSR).read() & LSR_THRE == 0 {}
+        Reg(UART_DATA).write(b);
+    }
+}
+
+//the next input byte, None when there is none yet
+pub fn ReadByte() -> Option<u8> {
+    return x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut input = INPUT.lock();
+        if input.len == 0 {
+            return None
+        }
+
+        let b = input.buf[input.head];
+        input.head = (input.head + 1) % INPUT_SIZE;
+        input.len -= 1;
+        Some(b)
+    })
+}
+
+//move the received bytes to INPUT and echo them, the bytes beyond INPUT_SIZE are dropped
+pub extern "x86-interrupt" fn serial_handler(_stack_frame: &mut InterruptStackFrame) {
+    unsafe {
+        while Reg(UART_LSR).read() & LSR_DR != 0 {
+            let b = Reg(UART_DATA).read();
+            let b = if b == b'\r' { b'\n' } else { b };
+
+            {
+                let mut input = INPUT.lock();
+                if input.len < INPUT_SIZE {
+                    let tail = (input.head + input.len) % INPUT_SIZE;
+                    input.buf[tail] = b;
+                    input.len += 1;
+                }
+            }
+
+            if b == b'\n' {
+                PutByte(b'\r');
+            }
+
+            PutByte(b);
+        }
+    }
+
+    timer::PicEoi();
+}
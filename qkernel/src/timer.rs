@@ -0,0 +1,215 @@
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::instructions::port::Port;
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::idt::InterruptStackFrame;
+
+//the pit keeps time at HZ, the lapic timer ends a time slice every TIME_SLICE_MS. Nothing is preempted:
+//the interrupts only count, and a kernel loop polls the end of its slice at a YieldPoint
+pub const HZ: u64 = 100;
+pub const TIME_SLICE_MS: u64 = 10;
+const PIT_FREQ: u64 = 1_193_182;
+
+//the 8259s are remapped above the exceptions, irq 0 is the pit
+pub const PIC_VECTOR_BASE: u8 = 0x20;
+pub const PIT_VECTOR: u8 = PIC_VECTOR_BASE;
+pub const APIC_TIMER_VECTOR: u8 = 0x30;
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+const PIC1_CMD: u16 = 0x20;
+const PIC1_DATA: u16 = 0x21;
+const PIC2_CMD: u16 = 0xa0;
+const PIC2_DATA: u16 = 0xa1;
+const PIC_EOI: u8 = 0x20;
+
+const PIT_CHANNEL0: u16 = 0x40;
+const PIT_CMD: u16 = 0x43;
+//channel 0, lobyte/hibyte, rate generator
+const PIT_MODE_RATE: u8 = 0x34;
+
+const CPUID_1_ECX_X2APIC: u32 = 1 << 21;
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_X2APIC: u64 = 1 << 10;
+
+//the x2apic msrs, 0x800 + the xapic register offset / 16
+const X2APIC_EOI: u32 = 0x80b;
+const X2APIC_SVR: u32 = 0x80f;
+const X2APIC_LVT_TIMER: u32 = 0x832;
+const X2APIC_TIMER_INIT: u32 = 0x838;
+const X2APIC_TIMER_CUR: u32 = 0x839;
+const X2APIC_TIMER_DIV: u32 = 0x83e;
+
+const APIC_SVR_ENABLE: u64 = 1 << 8;
+const APIC_LVT_MASKED: u64 = 1 << 16;
+const APIC_LVT_PERIODIC: u64 = 1 << 17;
+const APIC_TIMER_DIV_16: u64 = 0x3;
+
+//the pit ticks the lapic timer is measured over
+const CALIBRATE_TICKS: u64 = 10;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+static NEED_RESCHED: AtomicBool = AtomicBool::new(false);
+static APIC_TIMER: AtomicBool = AtomicBool::new(false);
+//the time slices which ran out at a YieldPoint
+static SLICES: AtomicU64 = AtomicU64::new(0);
+
+//start the pit and, when the cpu has an x2apic, the lapic timer. Leaves interrupts on
+pub fn Init() {
+    unsafe {
+        InitPic();
+        InitPit();
+    }
+
+    x86_64::instructions::interrupts::enable();
+
+    if !HasX2apic() {
+        kprintln!("no x2apic, the pit ends the time slices");
+        return
+    }
+
+    let countPerMs = unsafe {
+        EnableX2apic();
+        CalibrateApicTimer()
+    };
+
+    if countPerMs == 0 {
+        kprintln!("the lapic timer doesn't count, the pit ends the time slices");
+        return
+    }
+
+    unsafe {
+        Msr::new(X2APIC_LVT_TIMER).write(APIC_TIMER_VECTOR as u64 | APIC_LVT_PERIODIC);
+        Msr::new(X2APIC_TIMER_INIT).write(countPerMs * TIME_SLICE_MS);
+    }
+
+    APIC_TIMER.store(true, Ordering::SeqCst);
+    kprintln!("lapic timer: {} counts a ms", countPerMs);
+}
+
+//the pit ticks since Init
+pub fn Ticks() -> u64 {
+    return TICKS.load(Ordering::SeqCst)
+}
+
+pub fn MonotonicNs() -> u64 {
+    return Ticks() * (1_000_000_000 / HZ)
+}
+
+//whether the time slice ran out since the last call
+pub fn TakeResched() -> bool {
+    return NEED_RESCHED.swap(false, Ordering::SeqCst)
+}
+
+//the hook a long running kernel loop calls to give up the cpu at the end of its slice. There is no
+//scheduler and no other task to switch to, so this only counts the slices which ran out and returns;
+//it is not preemption
+pub fn YieldPoint() {
+    if TakeResched() {
+        SLICES.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+pub fn Slices() -> u64 {
+    return SLICES.load(Ordering::SeqCst)
+}
+
+//let the 8259 pass the isa irq, InitPic masks all but the pit
+pub unsafe fn UnmaskIrq(irq: u8) {
+    let mut data: Port<u8> = Port::new(if irq < 8 { PIC1_DATA } else { PIC2_DATA });
+    let mask = data.read();
+    data.write(mask & !(1 << (irq % 8)));
+}
+
+//the eoi of an irq on the master 8259
+pub fn PicEoi() {
+    unsafe {
+        Port::<u8>::new(PIC1_CMD).write(PIC_EOI);
+    }
+}
+
+//remap the 8259s to PIC_VECTOR_BASE and mask everything but the pit
+unsafe fn InitPic() {
+    let mut cmd1: Port<u8> = Port::new(PIC1_CMD);
+    let mut data1: Port<u8> = Port::new(PIC1_DATA);
+    let mut cmd2: Port<u8> = Port::new(PIC2_CMD);
+    let mut data2: Port<u8> = Port::new(PIC2_DATA);
+
+    //ICW1: init, ICW4 follows
+    cmd1.write(0x11);
+    cmd2.write(0x11);
+    //ICW2: the vector bases
+    data1.write(PIC_VECTOR_BASE);
+    data2.write(PIC_VECTOR_BASE + 8);
+    //ICW3: the slave is on irq 2
+    data1.write(0x04);
+    data2.write(0x02);
+    //ICW4: 8086 mode
+    data1.write(0x01);
+    data2.write(0x01);
+
+    data1.write(!0x01);
+    data2.write(0xff);
+}
+
+unsafe fn InitPit() {
+    let divisor = (PIT_FREQ / HZ) as u16;
+    Port::<u8>::new(PIT_CMD).write(PIT_MODE_RATE);
+    let mut channel0: Port<u8> = Port::new(PIT_CHANNEL0);
+    channel0.write(divisor as u8);
+    channel0.write((divisor >> 8) as u8);
+}
+
+fn HasX2apic() -> bool {
+    let cpuid = unsafe { core::arch::x86_64::__cpuid(1) };
+    return cpuid.ecx & CPUID_1_ECX_X2APIC != 0
+}
+
+unsafe fn EnableX2apic() {
+    let mut base = Msr::new(IA32_APIC_BASE);
+    let value = base.read();
+    base.write(value | APIC_BASE_ENABLE | APIC_BASE_X2APIC);
+    Msr::new(X2APIC_SVR).write(APIC_SVR_ENABLE | SPURIOUS_VECTOR as u64);
+}
+
+//count the masked lapic timer down over CALIBRATE_TICKS pit ticks, return its counts a ms
+unsafe fn CalibrateApicTimer() -> u64 {
+    Msr::new(X2APIC_TIMER_DIV).write(APIC_TIMER_DIV_16);
+    Msr::new(X2APIC_LVT_TIMER).write(APIC_TIMER_VECTOR as u64 | APIC_LVT_MASKED);
+
+    //start on a tick boundary
+    WaitTicks(1);
+    Msr::new(X2APIC_TIMER_INIT).write(u32::max_value() as u64);
+    WaitTicks(CALIBRATE_TICKS);
+    let left = Msr::new(X2APIC_TIMER_CUR).read();
+    Msr::new(X2APIC_TIMER_INIT).write(0);
+
+    let elapsed = u32::max_value() as u64 - left;
+    return elapsed / (CALIBRATE_TICKS * 1000 / HZ)
+}
+
+fn WaitTicks(count: u64) {
+    let end = Ticks() + count;
+    while Ticks() < end {
+        x86_64::instructions::hlt();
+    }
+}
+
+pub extern "x86-interrupt" fn pit_handler(_stack_frame: &mut InterruptStackFrame) {
+    TICKS.fetch_add(1, Ordering::SeqCst);
+    if !APIC_TIMER.load(Ordering::SeqCst) && Ticks() % (TIME_SLICE_MS * HZ / 1000).max(1) == 0 {
+        NEED_RESCHED.store(true, Ordering::SeqCst);
+    }
+
+    PicEoi();
+}
+
+pub extern "x86-interrupt" fn apic_timer_handler(_stack_frame: &mut InterruptStackFrame) {
+    NEED_RESCHED.store(true, Ordering::SeqCst);
+    unsafe {
+        Msr::new(X2APIC_EOI).write(0);
+    }
+}
+
+//a spurious interrupt isn't in service, so it takes no eoi
+pub extern "x86-interrupt" fn spurious_handler(_stack_frame: &mut InterruptStackFrame) {
+}
@@ -39,6 +39,32 @@ impl PageOpts {
             User: true,
         }
     }
+
+    //supervisor only mapping, required by the guest kernel when SMEP/SMAP are on
+    pub fn Kernel() -> Self {
+        return PageOpts {
+            AccessType : AccessType{
+                Read: true,
+                Write: true,
+                Exec: true,
+            },
+            Global: true,
+            User: false,
+        }
+    }
+
+    //Kernel without write, for the guest memory backed by a readonly host file
+    pub fn KernelReadOnly() -> Self {
+        return PageOpts {
+            AccessType : AccessType{
+                Read: true,
+                Write: false,
+                Exec: false,
+            },
+            Global: true,
+            User: false,
+        }
+    }
 }
 
 
@@ -22,6 +22,8 @@ pub enum Error {
     ELFLoadError(&'static str),
     InterpreterFileErr,
     MMampError,
+    //a failed host syscall, carry the errno
+    SysError(i32),
     UnmatchRegion,
     AddressDoesMatch,
     Locked,
@@ -30,6 +32,9 @@ pub enum Error {
     NoData,
     NoneIdx,
     AddressNotMap,
+    CpuProfileIncompatible(String),
+    //the guest stopped abnormally, carry the exit code for qvisor
+    VcpuAbnormalExit(i32),
 }
 
 impl Default for Error {
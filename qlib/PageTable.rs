@@ -109,8 +109,105 @@ impl PageTables {
         return Ok(false);
     }
 
-    fn mapCanonical(&self, start: Addr, end: Addr, phyAddr: Addr, _opts: &PageOpts, pagePool: &mut PagePool) -> Result<bool> {
+    fn LeafFlags(opts: &PageOpts) -> PageTableFlags {
+        let mut flags = PageTableFlags::PRESENT;
+        if opts.AccessType.Write {
+            flags |= PageTableFlags::WRITABLE;
+        }
+
+        if opts.User {
+            flags |= PageTableFlags::USER_ACCESSIBLE;
+        }
+
+        if opts.Global {
+            flags |= PageTableFlags::GLOBAL;
+        }
+
+        if !opts.AccessType.Exec {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+
+        return flags;
+    }
+
+    //call f(vaddr, phyAddr, size, flags) for every present leaf entry. the flags are the effective
+    //permission of the whole walk: USER_ACCESSIBLE and WRITABLE only when all levels set them,
+    //NO_EXECUTE when any level sets it
+    pub fn ForEachMapping<F: FnMut(u64, u64, u64, PageTableFlags)>(&self, mut f: F) {
+        fn effective(parent: PageTableFlags, entry: PageTableFlags) -> PageTableFlags {
+            let mut flags = entry;
+            flags &= !(PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE);
+            flags |= parent & entry & (PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE);
+            flags |= (parent | entry) & PageTableFlags::NO_EXECUTE;
+            return flags;
+        }
+
+        fn canonical(addr: u64) -> u64 {
+            if addr & (1 << 47) != 0 {
+                return addr | 0xffff_0000_0000_0000;
+            }
+
+            return addr;
+        }
+
+        let all = PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE;
+        let pt: *const PageTable = self.root.0 as *const PageTable;
+
+        unsafe {
+            for p4Idx in 0..super::ENTRY_COUNT as usize {
+                let pgdEntry = &(*pt)[p4Idx];
+                if pgdEntry.is_unused() {
+                    continue;
+                }
+
+                let p4Flags = effective(all, pgdEntry.flags());
+                let pudTbl = pgdEntry.addr().as_u64() as *const PageTable;
+                for p3Idx in 0..super::ENTRY_COUNT as usize {
+                    let pudEntry = &(*pudTbl)[p3Idx];
+                    if pudEntry.is_unused() {
+                        continue;
+                    }
+
+                    let p3Addr = canonical(((p4Idx as u64) << 39) | ((p3Idx as u64) << 30));
+                    let p3Flags = effective(p4Flags, pudEntry.flags());
+                    if p3Flags.contains(PageTableFlags::HUGE_PAGE) {
+                        f(p3Addr, pudEntry.addr().as_u64(), super::ONE_GB, p3Flags);
+                        continue;
+                    }
+
+                    let pmdTbl = pudEntry.addr().as_u64() as *const PageTable;
+                    for p2Idx in 0..super::ENTRY_COUNT as usize {
+                        let pmdEntry = &(*pmdTbl)[p2Idx];
+                        if pmdEntry.is_unused() {
+                            continue;
+                        }
+
+                        let p2Addr = p3Addr | ((p2Idx as u64) << 21);
+                        let p2Flags = effective(p3Flags, pmdEntry.flags());
+                        if p2Flags.contains(PageTableFlags::HUGE_PAGE) {
+                            f(p2Addr, pmdEntry.addr().as_u64(), super::PAGE_SIZE_2M, p2Flags);
+                            continue;
+                        }
+
+                        let pteTbl = pmdEntry.addr().as_u64() as *const PageTable;
+                        for p1Idx in 0..super::ENTRY_COUNT as usize {
+                            let pteEntry = &(*pteTbl)[p1Idx];
+                            if pteEntry.is_unused() {
+                                continue;
+                            }
+
+                            let p1Addr = p2Addr | ((p1Idx as u64) << 12);
+                            f(p1Addr, pteEntry.addr().as_u64(), super::PAGE_SIZE_4K, effective(p2Flags, pteEntry.flags()));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn mapCanonical(&self, start: Addr, end: Addr, phyAddr: Addr, opts: &PageOpts, pagePool: &mut PagePool) -> Result<bool> {
         let mut res = false;
+        let leafFlags = Self::LeafFlags(opts);
 
         let mut curAddr = start;
 
@@ -166,7 +263,7 @@ impl PageTables {
                             let pteEntry =  &mut (*pteTbl)[p1Idx];
 
                             if pteEntry.is_unused() {
-                                pteEntry.set_addr(PhysAddr::new(phyAddr.0 + curAddr.0 - start.0), PageTableFlags::PRESENT |PageTableFlags::WRITABLE |PageTableFlags::USER_ACCESSIBLE);
+                                pteEntry.set_addr(PhysAddr::new(phyAddr.0 + curAddr.0 - start.0), leafFlags);
                             } else {
                                 res = true;
                             }
@@ -204,8 +301,61 @@ impl PageTables {
         return Ok(res);
     }
 
-    pub fn Unmap(&self, _start: Addr, _end: Addr) -> Result<bool> {
-        return Ok(true)
+    //clear the 4K leaf entries of [start, end), the tables stay. return true when there was a mapping
+    pub fn Unmap(&self, start: Addr, end: Addr) -> Result<bool> {
+        start.PageAligned()?;
+        if end.0 < start.0 {
+            return Err(Error::AddressNotInRange);
+        }
+
+        //the start of the next range of size, past a table which isn't there
+        fn next(addr: u64, size: u64) -> u64 {
+            return (addr & !(size - 1)).saturating_add(size)
+        }
+
+        let mut res = false;
+        let mut addr = start.0;
+        let pt: *mut PageTable = self.root.0 as *mut PageTable;
+        unsafe {
+            while addr < end.0 {
+                let vaddr = VirtAddr::new(addr);
+                let pgdEntry = &(*pt)[vaddr.p4_index()];
+                if pgdEntry.is_unused() {
+                    addr = next(addr, 1 << 39);
+                    continue;
+                }
+
+                let pudTbl = pgdEntry.addr().as_u64() as *const PageTable;
+                let pudEntry = &(*pudTbl)[vaddr.p3_index()];
+                if pudEntry.is_unused() {
+                    addr = next(addr, super::ONE_GB);
+                    continue;
+                }
+
+                let pmdTbl = pudEntry.addr().as_u64() as *const PageTable;
+                let pmdEntry = &(*pmdTbl)[vaddr.p2_index()];
+                if pmdEntry.is_unused() {
+                    addr = next(addr, super::PAGE_SIZE_2M);
+                    continue;
+                }
+
+                //mapCanonical only makes 4K pages
+                if pudEntry.flags().contains(PageTableFlags::HUGE_PAGE) || pmdEntry.flags().contains(PageTableFlags::HUGE_PAGE) {
+                    return Err(Error::UnmatchRegion);
+                }
+
+                let pteTbl = pmdEntry.addr().as_u64() as *mut PageTable;
+                let pteEntry = &mut (*pteTbl)[vaddr.p1_index()];
+                if !pteEntry.is_unused() {
+                    pteEntry.set_unused();
+                    res = true;
+                }
+
+                addr += super::PAGE_SIZE_4K;
+            }
+        }
+
+        return Ok(res)
     }
 }
 
@@ -268,6 +418,20 @@ impl PagePool {
         }
     }
 
+    //rebuild the pool from the state saved in a snapshot
+    pub fn InitWithFreePool(baseAddr: u64, pageCount: u32, next: u32, freePool: Vec<u32>) -> Self {
+        return PagePool {
+            baseAddr: Addr(baseAddr),
+            next: next,
+            pageCount,
+            freePool: freePool,
+        }
+    }
+
+    pub fn FreePool(&self) -> &Vec<u32> {
+        return &self.freePool
+    }
+
     pub fn Allocate(&mut self) -> Result<Addr> {
         if self.freePool.len() > 0 {
             let idx = self.freePool[self.freePool.len() - 1];
@@ -7,12 +7,26 @@ pub mod Common;
 pub mod Addr;
 pub mod PageTable;
 
-use alloc::string::String;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{spin_loop_hint, AtomicU32, Ordering};
 
 pub const HYPERCALL_INIT : u16 = 1;
 pub const HYPERCALL_PANIC : u16 = 2;
 pub const HYPERCALL_WAIT : u16 = 3;
 pub const HYPERCALL_LOADIDT : u16 = 4;
+pub const HYPERCALL_SNAPSHOT : u16 = 5;
+pub const HYPERCALL_ALLOCMEM : u16 = 6;
+pub const HYPERCALL_BALLOON : u16 = 7;
+pub const HYPERCALL_EXIT : u16 = 8;
+pub const HYPERCALL_FREEMEM : u16 = 9;
+//the hypercalls are the io ports HYPERCALL_INIT to HYPERCALL_MAX, a new one moves HYPERCALL_MAX
+pub const HYPERCALL_MAX : u16 = HYPERCALL_FREEMEM;
+
+//the guest rings the notify doorbell with a byte write to NOTIFY_PORT, which doesn't exit to qvisor. A host
+//thread answers each ring, or several at once, with an edge on NOTIFY_GSI
+pub const NOTIFY_PORT : u16 = 0x510;
+pub const NOTIFY_GSI : u32 = 5;
 
 const MSG_QLEN: u32 = 1024;
 const MSG_INIT_COUNT: u32 = 8;
@@ -38,6 +52,22 @@ pub const LOWER_TOP : u64 = 0x00007fffffffffff;
 pub const UPPER_BOTTOM : u64 = 0xffff800000000000;
 pub const ENTRY_COUNT: u16 = 512 as u16;
 
+//the GDT layout built by qvisor. kernel code/data then user data/code, in the order SYSCALL/SYSRET expects
+pub const KERNEL_CODE_SEL : u16 = 1 << 3;
+pub const KERNEL_DATA_SEL : u16 = 2 << 3;
+pub const USER_DATA_SEL : u16 = (3 << 3) | 3;
+pub const USER_CODE_SEL : u16 = (4 << 3) | 3;
+//each vcpu's TSS descriptor takes 2 GDT entries starting from here
+pub const TSS_SEL_BASE : u16 = 5 << 3;
+pub const MAX_VCPU_COUNT : usize = 15;
+
+//the IST slot qvisor fills in each vcpu's TSS with a dedicated stack for double fault
+pub const DOUBLE_FAULT_IST_INDEX : u16 = 0;
+
+pub fn TssSelector(vcpuId: usize) -> u16 {
+    return TSS_SEL_BASE + (vcpuId as u16) * 16
+}
+
 pub fn HyperCall(type_: u16, para1: u64) {
     unsafe {
         rusty_asm::rusty_asm! {
@@ -78,32 +108,276 @@ pub fn Wait() {
     HyperCall(HYPERCALL_WAIT, 0)
 }
 
-#[derive(Clone, Default, Debug)]
+//the point where "qvisor snapshot" saves the vm, a no-op otherwise
+pub fn Snapshot() {
+    HyperCall(HYPERCALL_SNAPSHOT, 0)
+}
+
+//stop the vm with code, hlt doesn't exit to qvisor with the in-kernel irqchip
+pub fn Exit(code: u64) {
+    HyperCall(HYPERCALL_EXIT, code)
+}
+
+//the request of HYPERCALL_ALLOCMEM, qvisor fills addr and result
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
+pub struct AllocMemReq {
+    pub len: u64,
+    pub hugePage: u64,
+    //the guest physical address of the new memory, qvisor maps it at the same virtual address
+    pub addr: u64,
+    //0 on success
+    pub result: i64,
+}
+
+//ask qvisor for len bytes more of guest RAM, return its address
+pub fn AllocMem(len: u64, hugePage: bool) -> Common::Result<u64> {
+    let mut req = AllocMemReq {
+        len: len,
+        hugePage: hugePage as u64,
+        addr: 0,
+        result: -1,
+    };
+
+    HyperCall(HYPERCALL_ALLOCMEM, &mut req as *mut AllocMemReq as u64);
+    let req = unsafe { core::ptr::read_volatile(&req) };
+    if req.result != 0 {
+        return Err(Common::Error::NoEnoughMemory)
+    }
+
+    return Ok(req.addr)
+}
+
+//the request of HYPERCALL_FREEMEM, qvisor fills result
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
+pub struct FreeMemReq {
+    //the address AllocMem returned, or of the memory qvisor hotplugged
+    pub addr: u64,
+    //0 on success
+    pub result: i64,
+}
+
+//give the memory at addr back to qvisor whole. The guest must not touch it after
+pub fn FreeMem(addr: u64) -> Common::Result<()> {
+    let mut req = FreeMemReq {
+        addr: addr,
+        result: -1,
+    };
+
+    HyperCall(HYPERCALL_FREEMEM, &mut req as *mut FreeMemReq as u64);
+    let req = unsafe { core::ptr::read_volatile(&req) };
+    if req.result != 0 {
+        return Err(Common::Error::UnmatchRegion)
+    }
+
+    return Ok(())
+}
+
+//the request of HYPERCALL_BALLOON, qvisor fills result
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
+pub struct BalloonReq {
+    //a page aligned guest physical range of RAM, 2MB aligned in hugetlb memory
+    pub addr: u64,
+    pub len: u64,
+    //0 to give the range to the host, 1 to take it back
+    pub deflate: u64,
+    //0 on success
+    pub result: i64,
+}
+
+fn Balloon(addr: u64, len: u64, deflate: bool) -> Common::Result<()> {
+    let mut req = BalloonReq {
+        addr: addr,
+        len: len,
+        deflate: deflate as u64,
+        result: -1,
+    };
+
+    HyperCall(HYPERCALL_BALLOON, &mut req as *mut BalloonReq as u64);
+    let req = unsafe { core::ptr::read_volatile(&req) };
+    if req.result != 0 {
+        return Err(Common::Error::AddressNotInRange)
+    }
+
+    return Ok(())
+}
+
+//report free pages to qvisor, which frees their host memory. The guest must not touch them before
+//BalloonDeflate
+pub fn BalloonInflate(addr: u64, len: u64) -> Common::Result<()> {
+    return Balloon(addr, len, false)
+}
+
+//take pages from BalloonInflate back, their content is undefined
+pub fn BalloonDeflate(addr: u64, len: u64) -> Common::Result<()> {
+    return Balloon(addr, len, true)
+}
+
+//the longest Str qvisor reads from guest memory
+pub const MAX_STR_LEN: u32 = 4096;
+
+//bytes in guest memory, at a guest virtual address
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
 pub struct Str {
     pub addr: u64,
     pub len: u32
 }
 
-#[derive(Clone, Debug)]
-pub enum Msg {
-    Print (String),
-    Msg1 { addr: u64, len: u32}
+pub const MSG_PRINT: u32 = 1;
+pub const MSG_MSG1: u32 = 2;
+
+//a message of HYPERCALL_WAIT, plain data so qvisor can copy it out of guest memory and check it
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Msg {
+    pub msgType: u32,
+    pub str: Str,
+}
+
+impl Msg {
+    //s has to stay alive until qvisor has taken the message, and be at most MAX_STR_LEN bytes
+    pub fn Print(s: &str) -> Self {
+        return Msg {
+            msgType: MSG_PRINT,
+            str: Str {
+                addr: s.as_ptr() as u64,
+                len: s.len() as u32,
+            },
+        }
+    }
 }
 
+//the hotplugged memory ranges qvisor can queue before the guest takes them
+pub const MEM_ADDED_SLOTS: usize = 16;
+
+//guest RAM qvisor hotplugged and mapped
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
+pub struct MemRange {
+    pub addr: u64,
+    pub len: u64,
+}
+
+//the memory shared by the guest and qvisor, at the guest address passed with HYPERCALL_INIT. The guest
+//changes it under ShareSpaceLock, qvisor reads and writes it by value while the vcpu is out of KVM_RUN,
+//and leaves it alone when lock is held
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
 pub struct ShareSpace {
-    pub msgAddr : u64
+    //1 while the guest holds it, 0 else
+    pub lock: u32,
+    pub memAddedCount: u32,
+    pub msgAddr : u64,
+    //the guest adds these to its heap
+    pub memAdded: [MemRange; MEM_ADDED_SLOTS],
 }
 
 impl ShareSpace {
-    pub fn Init() -> Self {
-        ShareSpace { msgAddr : 0}
+    pub const fn Init() -> Self {
+        ShareSpace {
+            lock: 0,
+            memAddedCount: 0,
+            msgAddr : 0,
+            memAdded: [MemRange { addr: 0, len: 0 }; MEM_ADDED_SLOTS],
+        }
+    }
+
+    //qvisor side, the guest was stopped holding the lock
+    pub fn Locked(&self) -> bool {
+        return self.lock != 0
+    }
+
+    //qvisor side, false when the queue is full
+    pub fn AddMem(&mut self, addr: u64, len: u64) -> bool {
+        if self.memAddedCount as usize >= MEM_ADDED_SLOTS {
+            return false
+        }
+
+        self.memAdded[self.memAddedCount as usize] = MemRange { addr: addr, len: len };
+        self.memAddedCount += 1;
+        return true
+    }
+
+    //guest side
+    pub fn TakeMem(&mut self) -> Option<(u64, u64)> {
+        if self.memAddedCount == 0 || self.memAddedCount as usize > MEM_ADDED_SLOTS {
+            return None
+        }
+
+        self.memAddedCount -= 1;
+        let range = self.memAdded[self.memAddedCount as usize];
+        return Some((range.addr, range.len))
     }
 
     pub fn SetMsg(&mut self, msg: &mut Msg) {
         self.msgAddr = &(*msg) as *const _ as u64;
     }
 
-    pub fn GetMsg(&mut self) -> u64 {
+    pub fn GetMsg(&self) -> u64 {
         return self.msgAddr
     }
 }
+
+//the guest's ShareSpace, the lock word is taken with atomic ops on the u32
+pub struct ShareSpaceLock(UnsafeCell<ShareSpace>);
+
+//the ShareSpace is only touched under the lock word
+unsafe impl Sync for ShareSpaceLock {}
+
+impl ShareSpaceLock {
+    pub const fn New() -> Self {
+        return ShareSpaceLock(UnsafeCell::new(ShareSpace::Init()))
+    }
+
+    //the address for HYPERCALL_INIT
+    pub fn Addr(&self) -> u64 {
+        return self.0.get() as u64
+    }
+
+    fn LockWord(&self) -> &AtomicU32 {
+        //AtomicU32 has the layout of u32
+        return unsafe { &*(&(*self.0.get()).lock as *const u32 as *const AtomicU32) }
+    }
+
+    pub fn Lock(&self) -> ShareSpaceGuard {
+        while self.LockWord().compare_exchange_weak(0, 1, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            spin_loop_hint();
+        }
+
+        return ShareSpaceGuard { space: self }
+    }
+
+    //HYPERCALL_WAIT. qvisor doesn't read the message of a locked ShareSpace, so the lock must be released
+    //before waiting
+    pub fn Wait(&self) {
+        assert!(self.LockWord().load(Ordering::Acquire) == 0, "HYPERCALL_WAIT with the ShareSpace locked");
+        Wait()
+    }
+}
+
+pub struct ShareSpaceGuard<'a> {
+    space: &'a ShareSpaceLock,
+}
+
+impl<'a> Deref for ShareSpaceGuard<'a> {
+    type Target = ShareSpace;
+
+    fn deref(&self) -> &ShareSpace {
+        return unsafe { &*self.space.0.get() }
+    }
+}
+
+impl<'a> DerefMut for ShareSpaceGuard<'a> {
+    fn deref_mut(&mut self) -> &mut ShareSpace {
+        return unsafe { &mut *self.space.0.get() }
+    }
+}
+
+impl<'a> Drop for ShareSpaceGuard<'a> {
+    fn drop(&mut self) {
+        self.space.LockWord().store(0, Ordering::Release);
+    }
+}
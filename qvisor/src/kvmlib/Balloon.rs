@@ -0,0 +1,136 @@
+use std::collections::BTreeMap;
+
+//the guest memory the guest gave back to the host, as page ranges. The host memory of the ranges is
+//discarded when they are added, a range taken back reads zeros or the template's pages in a clone
+pub struct Balloon {
+    //start -> len, no two ranges overlap or touch
+    ranges: BTreeMap<u64, u64>,
+    reclaimed: u64,
+}
+
+impl Balloon {
+    pub fn New() -> Self {
+        return Balloon {
+            ranges: BTreeMap::new(),
+            reclaimed: 0,
+        }
+    }
+
+    //the bytes in the balloon
+    pub fn Reclaimed(&self) -> u64 {
+        return self.reclaimed
+    }
+
+    //the ranges that overlap or touch [start, end), in address order
+    fn Touching(&self, start: u64, end: u64) -> Vec<(u64, u64)> {
+        let mut res: Vec<(u64, u64)> = self.ranges.range(..=end).rev()
+            .take_while(|&(&rStart, &rLen)| rStart + rLen >= start)
+            .map(|(&rStart, &rLen)| (rStart, rLen))
+            .collect();
+        res.reverse();
+        return res
+    }
+
+    //add [start, start + len), return the bytes which were not in the balloon before
+    pub fn Inflate(&mut self, start: u64, len: u64) -> u64 {
+        let end = start + len;
+        let mut newStart = start;
+        let mut newEnd = end;
+        let mut overlap = 0;
+        for (rStart, rLen) in self.Touching(start, end) {
+            let rEnd = rStart + rLen;
+            if rStart < end && rEnd > start {
+                overlap += rEnd.min(end) - rStart.max(start);
+            }
+
+            newStart = newStart.min(rStart);
+            newEnd = newEnd.max(rEnd);
+            self.ranges.remove(&rStart);
+        }
+
+        self.ranges.insert(newStart, newEnd - newStart);
+        self.reclaimed += len - overlap;
+        return len - overlap
+    }
+
+    //take [start, start + len) out, return the bytes which were in the balloon
+    pub fn Deflate(&mut self, start: u64, len: u64) -> u64 {
+        let end = start + len;
+        let mut removed = 0;
+        for (rStart, rLen) in self.Touching(start, end) {
+            let rEnd = rStart + rLen;
+            if rStart >= end || rEnd <= start {
+                continue;
+            }
+
+            self.ranges.remove(&rStart);
+            if rStart < start {
+                self.ranges.insert(rStart, start - rStart);
+            }
+
+            if rEnd > end {
+                self.ranges.insert(end, rEnd - end);
+            }
+
+            removed += rEnd.min(end) - rStart.max(start);
+        }
+
+        self.reclaimed -= removed;
+        return removed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn Ranges(b: &Balloon) -> Vec<(u64, u64)> {
+        return b.ranges.iter().map(|(&start, &len)| (start, len)).collect()
+    }
+
+    #[test]
+    fn InflateMerge() {
+        let mut b = Balloon::New();
+        assert_eq!(b.Inflate(0x1000, 0x1000), 0x1000);
+        assert_eq!(b.Inflate(0x4000, 0x1000), 0x1000);
+        assert_eq!(Ranges(&b), vec![(0x1000, 0x1000), (0x4000, 0x1000)]);
+
+        //touching on both sides joins the three
+        assert_eq!(b.Inflate(0x2000, 0x2000), 0x2000);
+        assert_eq!(Ranges(&b), vec![(0x1000, 0x4000)]);
+
+        //already in, and half in
+        assert_eq!(b.Inflate(0x2000, 0x1000), 0);
+        assert_eq!(b.Inflate(0x4000, 0x2000), 0x1000);
+        assert_eq!(Ranges(&b), vec![(0x1000, 0x5000)]);
+
+        //covering everything
+        assert_eq!(b.Inflate(0, 0x8000), 0x3000);
+        assert_eq!(Ranges(&b), vec![(0, 0x8000)]);
+        assert_eq!(b.Reclaimed(), 0x8000);
+    }
+
+    #[test]
+    fn DeflateSplit() {
+        let mut b = Balloon::New();
+        b.Inflate(0x1000, 0x8000);
+
+        //the middle splits the range
+        assert_eq!(b.Deflate(0x3000, 0x2000), 0x2000);
+        assert_eq!(Ranges(&b), vec![(0x1000, 0x2000), (0x5000, 0x4000)]);
+
+        //the ends
+        assert_eq!(b.Deflate(0, 0x2000), 0x1000);
+        assert_eq!(b.Deflate(0x8000, 0x2000), 0x1000);
+        assert_eq!(Ranges(&b), vec![(0x2000, 0x1000), (0x5000, 0x3000)]);
+
+        //not in the balloon, or only touching a range
+        assert_eq!(b.Deflate(0x3000, 0x2000), 0);
+        assert_eq!(Ranges(&b), vec![(0x2000, 0x1000), (0x5000, 0x3000)]);
+
+        //across both ranges and the hole between them
+        assert_eq!(b.Deflate(0x2000, 0x6000), 0x4000);
+        assert!(Ranges(&b).is_empty());
+        assert_eq!(b.Reclaimed(), 0);
+    }
+}
@@ -0,0 +1,160 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use spin::Mutex;
+
+use super::qlib::Common::Error;
+use super::qlib::Common::Result;
+
+//a device behind a range of guest physical addresses or io ports. offset is from the start of the
+//range, data is the size of the access
+pub trait BusDevice: Send {
+    fn Read(&mut self, offset: u64, data: &mut [u8]);
+    fn Write(&mut self, offset: u64, data: &[u8]);
+}
+
+struct BusRange {
+    len: u64,
+    device: Arc<Mutex<dyn BusDevice>>,
+}
+
+//the devices of the mmio space or the port io space, by their non overlapping ranges. A device may
+//be at several ranges
+pub struct Bus {
+    //start -> range
+    ranges: BTreeMap<u64, BusRange>,
+}
+
+impl Bus {
+    pub fn New() -> Self {
+        return Bus {
+            ranges: BTreeMap::new(),
+        }
+    }
+
+    pub fn Insert(&mut self, start: u64, len: u64, device: Arc<Mutex<dyn BusDevice>>) -> Result<()> {
+        if len == 0 {
+            return Err(Error::ZeroCount)
+        }
+
+        let end = start.checked_add(len).ok_or(Error::Overflow)?;
+        //the range before which ends after start, or the first range from start which begins before end
+        let before = self.ranges.range(..start).next_back().map_or(false, |(&s, r)| s + r.len > start);
+        let after = self.ranges.range(start..).next().map_or(false, |(&s, _)| s < end);
+        if before || after {
+            return Err(Error::Common(format!("bus range {:x}-{:x} overlaps a device", start, end)));
+        }
+
+        self.ranges.insert(start, BusRange {
+            len: len,
+            device: device,
+        });
+        return Ok(())
+    }
+
+    //drop the range inserted at start
+    pub fn Remove(&mut self, start: u64) -> Result<()> {
+        match self.ranges.remove(&start) {
+            Some(_) => return Ok(()),
+            None => return Err(Error::UnmatchRegion),
+        }
+    }
+
+    pub fn Contains(&self, addr: u64) -> bool {
+        return self.Get(addr, 1).is_some()
+    }
+
+    //the device with all of [addr, addr + len) in one of its ranges and the offset of addr in it
+    fn Get(&self, addr: u64, len: u64) -> Option<(&Arc<Mutex<dyn BusDevice>>, u64)> {
+        let (&start, range) = self.ranges.range(..=addr).next_back()?;
+        let offset = addr - start;
+        if offset >= range.len || len > range.len - offset {
+            return None
+        }
+
+        return Some((&range.device, offset))
+    }
+
+    //return false when no device claims all of the access
+    pub fn Read(&self, addr: u64, data: &mut [u8]) -> bool {
+        match self.Get(addr, data.len() as u64) {
+            Some((device, offset)) => {
+                device.lock().Read(offset, data);
+                return true
+            }
+            None => return false,
+        }
+    }
+
+    //return false when no device claims all of the access
+    pub fn Write(&self, addr: u64, data: &[u8]) -> bool {
+        match self.Get(addr, data.len() as u64) {
+            Some((device, offset)) => {
+                device.lock().Write(offset, data);
+                return true
+            }
+            None => return false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Null;
+
+    impl BusDevice for Null {
+        fn Read(&mut self, _offset: u64, _data: &mut [u8]) {}
+        fn Write(&mut self, _offset: u64, _data: &[u8]) {}
+    }
+
+    fn Device() -> Arc<Mutex<dyn BusDevice>> {
+        return Arc::new(Mutex::new(Null))
+    }
+
+    #[test]
+    fn InsertOverlap() {
+        let mut bus = Bus::New();
+        bus.Insert(0x10, 0x10, Device()).unwrap();
+
+        //over the start, inside, over the end and around the range
+        assert!(bus.Insert(0x8, 0x10, Device()).is_err());
+        assert!(bus.Insert(0x14, 0x4, Device()).is_err());
+        assert!(bus.Insert(0x1f, 0x10, Device()).is_err());
+        assert!(bus.Insert(0x0, 0x40, Device()).is_err());
+
+        //touching on either side
+        bus.Insert(0x0, 0x10, Device()).unwrap();
+        bus.Insert(0x20, 0x10, Device()).unwrap();
+
+        assert!(bus.Insert(0x40, 0, Device()).is_err());
+        assert!(bus.Insert(u64::max_value(), 2, Device()).is_err());
+    }
+
+    #[test]
+    fn GetLookup() {
+        let mut bus = Bus::New();
+        bus.Insert(0x10, 0x8, Device()).unwrap();
+        bus.Insert(0x20, 0x8, Device()).unwrap();
+
+        assert_eq!(bus.Get(0x10, 1).map(|(_, offset)| offset), Some(0));
+        assert_eq!(bus.Get(0x17, 1).map(|(_, offset)| offset), Some(7));
+        assert_eq!(bus.Get(0x24, 4).map(|(_, offset)| offset), Some(4));
+
+        //the gaps and past the end
+        assert!(bus.Get(0xf, 1).is_none());
+        assert!(bus.Get(0x18, 1).is_none());
+        assert!(bus.Get(0x28, 1).is_none());
+
+        //an access running past the end of its range
+        assert!(bus.Get(0x16, 4).is_none());
+        assert!(bus.Get(0x27, 2).is_none());
+        assert!(!bus.Write(0x16, &[0; 4]));
+        assert!(bus.Read(0x14, &mut [0; 4]));
+
+        bus.Remove(0x10).unwrap();
+        assert!(!bus.Contains(0x10));
+        assert!(bus.Remove(0x10).is_err());
+    }
+}
@@ -0,0 +1,199 @@
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixListener;
+use std::ptr;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use super::qlib::Common::Error;
+use super::qlib::Common::Result;
+use super::EventFd::{EventFd, StoppableRead, WaitReadable};
+use super::Gdb::{Conn, InstallKickHandler};
+use super::MemMgr;
+
+//a command from the control socket for the vcpu thread
+pub enum ControlReq {
+    //add len bytes of guest RAM and hand it to the guest
+    Hotplug { len: u64, hugePage: bool },
+    //map len bytes of the host file path from offset as guest memory, see KVMMachine::AllocGuestFile
+    MapFile { path: String, offset: u64, len: u64, shared: bool, readonly: bool },
+    //the guest RAM and the balloon size
+    Info,
+}
+
+pub struct ControlCmd {
+    pub req: ControlReq,
+    reply: Sender<String>,
+}
+
+impl ControlCmd {
+    //the one line answer to the client
+    pub fn Reply(self, res: String) {
+        let _ = self.reply.send(res);
+    }
+}
+
+//the control socket, served by its own thread, one client at a time. The commands are one per line:
+//  hotplug <size>[k|m|g] [huge]
+//  mapfile <path> <offset>[k|m|g] <size>[k|m|g] [shared] [ro]     (unix socket only)
+//  info
+pub struct Control {
+    //None once dropping, so a thread waiting for a reply gets an error
+    cmds: Option<Receiver<ControlCmd>>,
+    stop: Arc<EventFd>,
+    thread: Option<JoinHandle<()>>,
+}
+
+//end the thread and close the socket, fork would leave them dead in the child but the socket open
+impl Drop for Control {
+    fn drop(&mut self) {
+        self.cmds = None;
+        let _ = self.stop.Write(1);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+//"64m" and the like
+fn ParseSize(s: &str) -> Option<u64> {
+    let (num, unit) = match s.chars().last()? {
+        'k' | 'K' => (&s[..s.len() - 1], 1024),
+        'm' | 'M' => (&s[..s.len() - 1], MemMgr::ONE_MB),
+        'g' | 'G' => (&s[..s.len() - 1], MemMgr::ONE_GB),
+        _ => (s, 1),
+    };
+
+    return num.parse::<u64>().ok()?.checked_mul(unit)
+}
+
+fn ParseCmd(line: &str) -> core::result::Result<ControlReq, String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    match words.as_slice() {
+        ["hotplug", size] | ["hotplug", size, "huge"] => {
+            let len = ParseSize(size).ok_or(format!("bad size {}", size))?;
+            return Ok(ControlReq::Hotplug { len: len, hugePage: words.len() == 3 })
+        }
+        _ if words.len() >= 4 && words[0] == "mapfile" => {
+            let (path, offset, size) = (words[1], words[2], words[3]);
+            let offset = ParseSize(offset).ok_or(format!("bad offset {}", offset))?;
+            let len = ParseSize(size).ok_or(format!("bad size {}", size))?;
+            let mut shared = false;
+            let mut readonly = false;
+            for opt in &words[4..] {
+                match *opt {
+                    "shared" => shared = true,
+                    "ro" => readonly = true,
+                    _ => return Err(format!("bad mapfile option {}", opt)),
+                }
+            }
+
+            return Ok(ControlReq::MapFile { path: path.to_string(), offset: offset, len: len, shared: shared, readonly: readonly })
+        }
+        ["info"] => return Ok(ControlReq::Info),
+        _ => return Err(format!("unknown command {}", line.trim())),
+    }
+}
+
+impl Control {
+    //addr is "unix:<path>" or a tcp "host:port" on a loopback address. Nothing authenticates a client,
+    //so a tcp client, which any local user can be, doesn't get mapfile. Must be called on the vcpu
+    //thread, which is kicked for each command, immediateExitAddr is the immediate_exit byte of its kvm_run
+    pub fn Listen(addr: &str, immediateExitAddr: u64) -> Result<Self> {
+        let ioErr = |e: std::io::Error| Error::IOError(format!("io::error is {:?}", e));
+
+        let unixListener = if addr.starts_with("unix:") { Some(UnixListener::bind(&addr[5..]).map_err(ioErr)?) } else { None };
+        let tcpListener = if unixListener.is_none() { Some(TcpListener::bind(addr).map_err(ioErr)?) } else { None };
+        if let Some(l) = &tcpListener {
+            if !l.local_addr().map_err(ioErr)?.ip().is_loopback() {
+                return Err(Error::Common(format!("control socket {} is not on a loopback address", addr)));
+            }
+        }
+        println!("control socket on {}", addr);
+
+        InstallKickHandler()?;
+
+        let (tx, rx) = channel();
+        let stop = Arc::new(EventFd::New()?);
+        let threadStop = stop.clone();
+        let vcpuThread = unsafe { libc::pthread_self() };
+        let thread = thread::spawn(move || {
+            let listenFd = match (&unixListener, &tcpListener) {
+                (Some(l), _) => l.as_raw_fd(),
+                (_, Some(l)) => l.as_raw_fd(),
+                _ => return,
+            };
+
+            loop {
+                if !WaitReadable(listenFd, &threadStop) {
+                    return;
+                }
+
+                let conn = match (&unixListener, &tcpListener) {
+                    (Some(l), _) => l.accept().map(|(s, _)| Conn::Unix(s)),
+                    (_, Some(l)) => l.accept().map(|(s, _)| Conn::Tcp(s)),
+                    _ => return,
+                };
+
+                let conn = match conn {
+                    Ok(conn) => conn,
+                    Err(e) => {
+                        println!("control socket accept fail: {:?}", e);
+                        continue;
+                    }
+                };
+
+                let mut out = match conn.TryClone() {
+                    Ok(out) => out,
+                    Err(_) => continue,
+                };
+
+                let mut input = BufReader::new(StoppableRead::New(conn, threadStop.clone()));
+                let mut line = String::new();
+                loop {
+                    line.clear();
+                    match input.read_line(&mut line) {
+                        Ok(0) | Err(_) => break,
+                        Ok(_) => (),
+                    }
+
+                    let res = match ParseCmd(&line) {
+                        Err(e) => format!("error {}", e),
+                        Ok(ControlReq::MapFile { .. }) if tcpListener.is_some() => String::from("error mapfile needs a unix control socket"),
+                        Ok(req) => {
+                            let (replyTx, replyRx) = channel();
+                            if tx.send(ControlCmd { req: req, reply: replyTx }).is_err() {
+                                return;
+                            }
+
+                            //immediate_exit covers a kick which lands before the vcpu enters KVM_RUN
+                            unsafe {
+                                ptr::write_volatile(immediateExitAddr as *mut u8, 1);
+                                libc::pthread_kill(vcpuThread, libc::SIGUSR1);
+                            }
+
+                            replyRx.recv().unwrap_or(String::from("error the vm is gone"))
+                        }
+                    };
+
+                    if writeln!(out, "{}", res).is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+
+        return Ok(Control {
+            cmds: Some(rx),
+            stop: stop,
+            thread: Some(thread),
+        })
+    }
+
+    //the commands sent since the last call
+    pub fn TakeCmds(&self) -> Vec<ControlCmd> {
+        return self.cmds.as_ref().map_or(Vec::new(), |cmds| cmds.try_iter().collect())
+    }
+}
@@ -0,0 +1,359 @@
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::mem::size_of;
+use std::slice;
+
+use kvm_bindings::{kvm_fpu, kvm_regs, kvm_sregs};
+use kvm_ioctls::VcpuFd;
+
+use super::qlib::Common::Error;
+use super::qlib::Common::Result;
+use super::GuestMemory::GuestMemory;
+
+const PAGE_SIZE: u64 = 0x1000;
+
+const ET_CORE: u16 = 4;
+const EM_X86_64: u16 = 62;
+const EV_CURRENT: u32 = 1;
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+const NT_PRSTATUS: u32 = 1;
+const NT_FPREGSET: u32 = 2;
+const NT_PRPSINFO: u32 = 3;
+
+//the signal gdb shows as the stop reason
+const SIGSEGV: u16 = 11;
+
+#[repr(C)]
+#[derive(Default)]
+struct Elf64Ehdr {
+    e_ident: [u8; 16],
+    e_type: u16,
+    e_machine: u16,
+    e_version: u32,
+    e_entry: u64,
+    e_phoff: u64,
+    e_shoff: u64,
+    e_flags: u32,
+    e_ehsize: u16,
+    e_phentsize: u16,
+    e_phnum: u16,
+    e_shentsize: u16,
+    e_shnum: u16,
+    e_shstrndx: u16,
+}
+
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct Elf64Phdr {
+    p_type: u32,
+    p_flags: u32,
+    p_offset: u64,
+    p_vaddr: u64,
+    p_paddr: u64,
+    p_filesz: u64,
+    p_memsz: u64,
+    p_align: u64,
+}
+
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct Timeval {
+    tv_sec: i64,
+    tv_usec: i64,
+}
+
+//struct elf_prstatus of x86_64 linux
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct ElfPrstatus {
+    si_signo: i32,
+    si_code: i32,
+    si_errno: i32,
+    pr_cursig: u16,
+    pad0: u16,
+    pr_sigpend: u64,
+    pr_sighold: u64,
+    pr_pid: i32,
+    pr_ppid: i32,
+    pr_pgrp: i32,
+    pr_sid: i32,
+    pr_utime: Timeval,
+    pr_stime: Timeval,
+    pr_cutime: Timeval,
+    pr_cstime: Timeval,
+    pr_reg: [u64; 27],
+    pr_fpvalid: i32,
+    pad1: i32,
+}
+
+//struct elf_prpsinfo of x86_64 linux
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct ElfPrpsinfo {
+    pr_state: u8,
+    pr_sname: u8,
+    pr_zomb: u8,
+    pr_nice: u8,
+    pad0: u32,
+    pr_flag: u64,
+    pr_uid: u32,
+    pr_gid: u32,
+    pr_pid: i32,
+    pr_ppid: i32,
+    pr_pgrp: i32,
+    pr_sid: i32,
+    pr_fname: [u8; 16],
+    pr_psargs: [u8; 80],
+}
+
+//struct user_fpregs_struct, the FXSAVE layout
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct UserFpregs {
+    cwd: u16,
+    swd: u16,
+    ftw: u16,
+    fop: u16,
+    rip: u64,
+    rdp: u64,
+    mxcsr: u32,
+    mxcr_mask: u32,
+    st_space: [u8; 128],
+    xmm_space: [u8; 256],
+    padding: [u8; 96],
+}
+
+pub struct VcpuDumpState {
+    pub regs: kvm_regs,
+    pub sregs: kvm_sregs,
+    pub fpu: Option<kvm_fpu>,
+}
+
+impl VcpuDumpState {
+    pub fn Get(vcpu: &VcpuFd) -> Result<Self> {
+        return Ok(VcpuDumpState {
+            regs: vcpu.get_regs().map_err(|e| Error::IOError(format!("io::error is {:?}", e)))?,
+            sregs: vcpu.get_sregs().map_err(|e| Error::IOError(format!("io::error is {:?}", e)))?,
+            fpu: vcpu.get_fpu().ok(),
+        })
+    }
+}
+
+fn AsBytes<T>(obj: &T) -> &[u8] {
+    return unsafe { slice::from_raw_parts(obj as *const T as *const u8, size_of::<T>()) }
+}
+
+fn Align4(len: usize) -> usize {
+    return (len + 3) & !3
+}
+
+fn PushNote(buf: &mut Vec<u8>, noteType: u32, desc: &[u8]) {
+    let name = b"CORE\0";
+    buf.extend_from_slice(&(name.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&(desc.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&noteType.to_le_bytes());
+    buf.extend_from_slice(name);
+    buf.resize(Align4(buf.len()), 0);
+    buf.extend_from_slice(desc);
+    buf.resize(Align4(buf.len()), 0);
+}
+
+fn Prstatus(vcpuId: usize, s: &VcpuDumpState) -> ElfPrstatus {
+    let r = &s.regs;
+    let sr = &s.sregs;
+    let mut st = ElfPrstatus::default();
+    st.si_signo = SIGSEGV as i32;
+    st.pr_cursig = SIGSEGV;
+    st.pr_pid = vcpuId as i32 + 1;
+    st.pr_reg = [
+        r.r15, r.r14, r.r13, r.r12, r.rbp, r.rbx, r.r11, r.r10, r.r9, r.r8,
+        r.rax, r.rcx, r.rdx, r.rsi, r.rdi, r.rax /* orig_rax */, r.rip,
+        sr.cs.selector as u64, r.rflags, r.rsp, sr.ss.selector as u64,
+        sr.fs.base, sr.gs.base,
+        sr.ds.selector as u64, sr.es.selector as u64, sr.fs.selector as u64, sr.gs.selector as u64,
+    ];
+    st.pr_fpvalid = s.fpu.is_some() as i32;
+    return st;
+}
+
+fn Fpregs(fpu: &kvm_fpu) -> UserFpregs {
+    let mut f = UserFpregs {
+        cwd: fpu.fcw,
+        swd: fpu.fsw,
+        ftw: fpu.ftwx as u16,
+        fop: fpu.last_opcode,
+        rip: fpu.last_ip,
+        rdp: fpu.last_dp,
+        mxcsr: fpu.mxcsr,
+        mxcr_mask: 0xffff,
+        st_space: [0; 128],
+        xmm_space: [0; 256],
+        padding: [0; 96],
+    };
+
+    for i in 0..8 {
+        f.st_space[i * 16..i * 16 + 16].copy_from_slice(&fpu.fpr[i]);
+    }
+
+    for i in 0..16 {
+        f.xmm_space[i * 16..i * 16 + 16].copy_from_slice(&fpu.xmm[i]);
+    }
+
+    return f;
+}
+
+struct Segment {
+    vaddr: u64,
+    paddr: u64,
+    len: u64,
+    flags: u32,
+}
+
+//the guest virtual mappings whose backing memory the host can read, merged into the largest
+//runs which are contiguous both virtually and physically with the same permission
+fn CollectSegments(reader: &GuestMemory, cr3: u64) -> Vec<Segment> {
+    let mut segs: Vec<Segment> = Vec::new();
+    reader.ForEachMapping(cr3, &mut |vaddr, phyAddr, size, writable, executable| {
+        if !reader.Contains(phyAddr, size) {
+            return;
+        }
+
+        let mut flags = PF_R;
+        if writable {
+            flags |= PF_W;
+        }
+
+        if executable {
+            flags |= PF_X;
+        }
+
+        if let Some(last) = segs.last_mut() {
+            if last.vaddr + last.len == vaddr && last.paddr + last.len == phyAddr && last.flags == flags {
+                last.len += size;
+                return;
+            }
+        }
+
+        segs.push(Segment {
+            vaddr: vaddr,
+            paddr: phyAddr,
+            len: size,
+            flags: flags,
+        })
+    });
+
+    return segs;
+}
+
+//write an ELF core file of the guest: one PT_NOTE with the state of every vcpu and one PT_LOAD
+//for each run of guest virtual memory mapped by the page tables of vcpu 0
+pub fn Write(path: &str, reader: &GuestMemory, vcpus: &[VcpuDumpState]) -> Result<()> {
+    if vcpus.len() == 0 {
+        return Err(Error::Common(String::from("no vcpu to dump")));
+    }
+
+    let segs = CollectSegments(reader, vcpus[0].sregs.cr3);
+
+    let mut notes = Vec::new();
+    let mut psinfo = ElfPrpsinfo {
+        pr_state: 0,
+        pr_sname: b'R',
+        pr_zomb: 0,
+        pr_nice: 0,
+        pad0: 0,
+        pr_flag: 0,
+        pr_uid: 0,
+        pr_gid: 0,
+        pr_pid: 1,
+        pr_ppid: 0,
+        pr_pgrp: 1,
+        pr_sid: 1,
+        pr_fname: [0; 16],
+        pr_psargs: [0; 80],
+    };
+    psinfo.pr_fname[..7].copy_from_slice(b"qkernel");
+    PushNote(&mut notes, NT_PRPSINFO, AsBytes(&psinfo));
+
+    for (i, vcpu) in vcpus.iter().enumerate() {
+        PushNote(&mut notes, NT_PRSTATUS, AsBytes(&Prstatus(i, vcpu)));
+        if let Some(fpu) = &vcpu.fpu {
+            PushNote(&mut notes, NT_FPREGSET, AsBytes(&Fpregs(fpu)));
+        }
+    }
+
+    let phnum = segs.len() + 1;
+    let ehdrSize = size_of::<Elf64Ehdr>() as u64;
+    let phdrSize = size_of::<Elf64Phdr>() as u64;
+    let noteOffset = ehdrSize + phdrSize * phnum as u64;
+    let mut dataOffset = (noteOffset + notes.len() as u64 + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+
+    let mut ehdr = Elf64Ehdr::default();
+    ehdr.e_ident[..4].copy_from_slice(b"\x7fELF");
+    ehdr.e_ident[4] = 2; //ELFCLASS64
+    ehdr.e_ident[5] = 1; //ELFDATA2LSB
+    ehdr.e_ident[6] = 1; //EV_CURRENT
+    ehdr.e_type = ET_CORE;
+    ehdr.e_machine = EM_X86_64;
+    ehdr.e_version = EV_CURRENT;
+    ehdr.e_phoff = ehdrSize;
+    ehdr.e_ehsize = ehdrSize as u16;
+    ehdr.e_phentsize = phdrSize as u16;
+    if phnum >= 0xffff {
+        return Err(Error::Common(format!("too many segments {} for the core file", phnum)));
+    }
+    ehdr.e_phnum = phnum as u16;
+
+    let mut phdrs = Vec::with_capacity(phnum);
+    phdrs.push(Elf64Phdr {
+        p_type: PT_NOTE,
+        p_offset: noteOffset,
+        p_filesz: notes.len() as u64,
+        p_align: 4,
+        ..Default::default()
+    });
+
+    for seg in &segs {
+        phdrs.push(Elf64Phdr {
+            p_type: PT_LOAD,
+            p_flags: seg.flags,
+            p_offset: dataOffset,
+            p_vaddr: seg.vaddr,
+            p_paddr: seg.paddr,
+            p_filesz: seg.len,
+            p_memsz: seg.len,
+            p_align: PAGE_SIZE,
+        });
+        dataOffset += seg.len;
+    }
+
+    let f = File::create(path).map_err(|e| Error::IOError(format!("io::error is {:?}", e)))?;
+    let mut w = BufWriter::new(f);
+    let ioErr = |e: std::io::Error| Error::IOError(format!("io::error is {:?}", e));
+
+    w.write_all(AsBytes(&ehdr)).map_err(ioErr)?;
+    for p in &phdrs {
+        w.write_all(AsBytes(p)).map_err(ioErr)?;
+    }
+    w.write_all(&notes).map_err(ioErr)?;
+
+    let mut buf = vec![0 as u8; 1 << 20];
+    for (i, seg) in segs.iter().enumerate() {
+        w.seek(SeekFrom::Start(phdrs[i + 1].p_offset)).map_err(ioErr)?;
+        let mut done = 0;
+        while done < seg.len {
+            let len = core::cmp::min(buf.len() as u64, seg.len - done) as usize;
+            reader.ReadSlice(seg.paddr + done, &mut buf[..len])?;
+            w.write_all(&buf[..len]).map_err(ioErr)?;
+            done += len as u64;
+        }
+    }
+
+    w.flush().map_err(ioErr)?;
+    println!("core dump {} written: {} load segments, {} vcpus", path, segs.len(), vcpus.len());
+    return Ok(())
+}
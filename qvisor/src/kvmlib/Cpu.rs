@@ -0,0 +1,512 @@
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use kvm_bindings::{kvm_cpuid_entry2, kvm_fpu, kvm_sregs, kvm_xcrs};
+use kvm_ioctls::{Kvm, VcpuFd};
+
+use super::qlib::Common::Error;
+use super::qlib::Common::Result;
+use super::qlib::Addr::PageOpts;
+use super::qlib::PageTable::PageTables;
+use x86_64::structures::paging::PageTableFlags;
+
+pub const KVM_GET_XCRS: u64 = 0x8188_aea6;
+pub const KVM_SET_XCRS: u64 = 0x4188_aea7;
+
+//the first size tried for KVM_GET_SUPPORTED_CPUID, doubled while the host has more
+pub const MAX_CPUID_ENTRIES: usize = 80;
+//KVM_MAX_CPUID_ENTRIES of the kernel is 256, leave room for a newer one
+const CPUID_ENTRIES_LIMIT: usize = 4096;
+
+// x87 control word after FNINIT and the power up MXCSR value (all SIMD exceptions masked)
+pub const FPU_FCW_DEFAULT: u16 = 0x37f;
+pub const MXCSR_DEFAULT: u32 = 0x1f80;
+
+// XCR0 state components
+pub const XSTATE_X87: u64 = 1 << 0;
+pub const XSTATE_SSE: u64 = 1 << 1;
+pub const XSTATE_AVX: u64 = 1 << 2;
+pub const XSTATE_BNDREGS: u64 = 1 << 3;
+pub const XSTATE_BNDCSR: u64 = 1 << 4;
+pub const XSTATE_OPMASK: u64 = 1 << 5;
+pub const XSTATE_ZMM_HI256: u64 = 1 << 6;
+pub const XSTATE_HI16_ZMM: u64 = 1 << 7;
+pub const XSTATE_PKRU: u64 = 1 << 9;
+
+pub const XSTATE_MPX: u64 = XSTATE_BNDREGS | XSTATE_BNDCSR;
+pub const XSTATE_AVX512: u64 = XSTATE_OPMASK | XSTATE_ZMM_HI256 | XSTATE_HI16_ZMM;
+
+// CPUID.1:ECX
+const CPUID_1_ECX_FMA: u32 = 1 << 12;
+const CPUID_1_ECX_XSAVE: u32 = 1 << 26;
+const CPUID_1_ECX_AVX: u32 = 1 << 28;
+const CPUID_1_ECX_F16C: u32 = 1 << 29;
+
+// CPUID.1:EDX
+const CPUID_1_EDX_PGE: u32 = 1 << 13;
+const CPUID_1_EDX_FXSR: u32 = 1 << 24;
+const CPUID_1_EDX_SSE: u32 = 1 << 25;
+
+// CPUID.(EAX=7,ECX=0):EBX
+const CPUID_7_EBX_AVX2: u32 = 1 << 5;
+const CPUID_7_EBX_SMEP: u32 = 1 << 7;
+const CPUID_7_EBX_MPX: u32 = 1 << 14;
+const CPUID_7_EBX_SMAP: u32 = 1 << 20;
+const CPUID_7_EBX_AVX512_MASK: u32 = (1 << 16) | (1 << 17) | (1 << 21) | (1 << 26) | (1 << 27) | (1 << 28) | (1 << 30) | (1 << 31);
+
+// CPUID.(EAX=7,ECX=0):ECX
+const CPUID_7_ECX_UMIP: u32 = 1 << 2;
+const CPUID_7_ECX_PKU: u32 = 1 << 3;
+const CPUID_7_ECX_OSPKE: u32 = 1 << 4;
+
+// CPUID.80000001H:EDX
+const CPUID_80000001_EDX_NX: u32 = 1 << 20;
+
+//struct kvm_cpuid2 with room for count entries. The entries are all u32, so the buffer is u32 words:
+//nent, padding, then the entries
+struct CpuidBuf {
+    words: Vec<u32>,
+}
+
+const CPUID_ENTRY_WORDS: usize = std::mem::size_of::<kvm_cpuid_entry2>() / 4;
+
+impl CpuidBuf {
+    fn New(count: usize) -> Self {
+        let mut words = vec![0; 2 + count * CPUID_ENTRY_WORDS];
+        words[0] = count as u32;
+        return CpuidBuf { words: words }
+    }
+
+    fn From(entries: &[kvm_cpuid_entry2]) -> Self {
+        let mut buf = CpuidBuf::New(entries.len());
+        buf.Entries().copy_from_slice(entries);
+        return buf
+    }
+
+    fn Entries(&mut self) -> &mut [kvm_cpuid_entry2] {
+        let count = self.words[0] as usize;
+        return unsafe {
+            std::slice::from_raw_parts_mut(self.words[2..].as_mut_ptr() as *mut kvm_cpuid_entry2, count)
+        }
+    }
+
+    fn Ptr(&mut self) -> *mut u32 {
+        return self.words.as_mut_ptr()
+    }
+}
+
+pub fn Ioctl<T>(fd: RawFd, req: u64, arg: *mut T) -> Result<i32> {
+    let ret = unsafe {
+        libc::ioctl(fd, req as _, arg)
+    };
+
+    if ret < 0 {
+        return Err(Error::IOError(format!("ioctl {:x} fail, io::error is {:?}", req, std::io::Error::last_os_error())))
+    }
+
+    return Ok(ret)
+}
+
+pub struct CpuidEntries {
+    pub entries: Vec<kvm_cpuid_entry2>,
+}
+
+impl CpuidEntries {
+    //the cpuid leaves which KVM is able to expose to the guest on this host
+    pub fn Supported(kvm: &Kvm) -> Result<Self> {
+        //E2BIG when the buffer is too small for the host's leaves
+        let mut count = MAX_CPUID_ENTRIES;
+        loop {
+            let mut buf = CpuidBuf::New(count);
+            let ret = unsafe { libc::ioctl(kvm.as_raw_fd(), super::KVM_GET_SUPPORTED_CPUID as _, buf.Ptr()) };
+            if ret >= 0 {
+                return Ok(CpuidEntries {
+                    entries: buf.Entries().to_vec(),
+                })
+            }
+
+            let err = std::io::Error::last_os_error();
+            if err.raw_os_error() != Some(libc::E2BIG) || count >= CPUID_ENTRIES_LIMIT {
+                return Err(Error::IOError(format!("KVM_GET_SUPPORTED_CPUID fail, io::error is {:?}", err)));
+            }
+
+            count *= 2;
+        }
+    }
+
+    pub fn Get(&self, function: u32, index: u32) -> Option<&kvm_cpuid_entry2> {
+        return self.entries.iter().find(|e| e.function == function && e.index == index)
+    }
+
+    fn GetMut(&mut self, function: u32, index: u32) -> Option<&mut kvm_cpuid_entry2> {
+        return self.entries.iter_mut().find(|e| e.function == function && e.index == index)
+    }
+
+    pub fn HasXSave(&self) -> bool {
+        match self.Get(1, 0) {
+            None => false,
+            Some(e) => e.ecx & CPUID_1_ECX_XSAVE != 0,
+        }
+    }
+
+    pub fn HasFxsrSse(&self) -> bool {
+        match self.Get(1, 0) {
+            None => false,
+            Some(e) => e.edx & (CPUID_1_EDX_FXSR | CPUID_1_EDX_SSE) == (CPUID_1_EDX_FXSR | CPUID_1_EDX_SSE),
+        }
+    }
+
+    fn HasBit(&self, function: u32, reg: &dyn Fn(&kvm_cpuid_entry2) -> u32, bit: u32) -> bool {
+        match self.Get(function, 0) {
+            None => false,
+            Some(e) => reg(e) & bit != 0,
+        }
+    }
+
+    pub fn HasNX(&self) -> bool {
+        return self.HasBit(0x8000_0001, &|e| e.edx, CPUID_80000001_EDX_NX)
+    }
+
+    pub fn HasPGE(&self) -> bool {
+        return self.HasBit(1, &|e| e.edx, CPUID_1_EDX_PGE)
+    }
+
+    pub fn HasSMEP(&self) -> bool {
+        return self.HasBit(7, &|e| e.ebx, CPUID_7_EBX_SMEP)
+    }
+
+    pub fn HasSMAP(&self) -> bool {
+        return self.HasBit(7, &|e| e.ebx, CPUID_7_EBX_SMAP)
+    }
+
+    pub fn HasUMIP(&self) -> bool {
+        return self.HasBit(7, &|e| e.ecx, CPUID_7_ECX_UMIP)
+    }
+
+    //the XCR0 bits the host cpu and KVM can context switch
+    pub fn SupportedXcr0(&self) -> u64 {
+        if !self.HasXSave() {
+            return 0;
+        }
+
+        match self.Get(0xd, 0) {
+            None => 0,
+            Some(e) => e.eax as u64 | ((e.edx as u64) << 32),
+        }
+    }
+
+    //hide the cpu features whose state component is not allowed by xcr0
+    pub fn FilterXState(&mut self, xcr0: u64) {
+        if let Some(e) = self.GetMut(1, 0) {
+            if xcr0 & XSTATE_AVX == 0 {
+                e.ecx &= !(CPUID_1_ECX_AVX | CPUID_1_ECX_FMA | CPUID_1_ECX_F16C);
+            }
+
+            if xcr0 == 0 {
+                e.ecx &= !CPUID_1_ECX_XSAVE;
+            }
+        }
+
+        if let Some(e) = self.GetMut(7, 0) {
+            if xcr0 & XSTATE_AVX == 0 {
+                e.ebx &= !CPUID_7_EBX_AVX2;
+            }
+
+            if xcr0 & XSTATE_AVX512 != XSTATE_AVX512 {
+                e.ebx &= !CPUID_7_EBX_AVX512_MASK;
+            }
+
+            if xcr0 & XSTATE_MPX != XSTATE_MPX {
+                e.ebx &= !CPUID_7_EBX_MPX;
+            }
+
+            if xcr0 & XSTATE_PKRU == 0 {
+                e.ecx &= !(CPUID_7_ECX_PKU | CPUID_7_ECX_OSPKE);
+            }
+        }
+
+        if let Some(e) = self.GetMut(0xd, 0) {
+            e.eax &= xcr0 as u32;
+            e.edx &= (xcr0 >> 32) as u32;
+        }
+
+        //the sub leaves 2..63 of leaf 0xd describe the size and offset of each state component
+        self.entries.retain(|e| {
+            !(e.function == 0xd && e.index >= 2 && e.index < 64 && xcr0 & (1 << e.index) == 0)
+        });
+    }
+
+    pub fn SetVcpu(&self, vcpu: &VcpuFd) -> Result<()> {
+        let mut buf = CpuidBuf::From(&self.entries);
+        Ioctl(vcpu.as_raw_fd(), super::KVM_SET_CPUID2, buf.Ptr())?;
+        return Ok(())
+    }
+}
+
+//which XSAVE state components the guest is allowed to enable in XCR0
+#[derive(Debug, Copy, Clone)]
+pub struct XStatePolicy {
+    pub allowed: u64,
+}
+
+impl XStatePolicy {
+    //x87 and SSE only, which is what the compiler may emit for qkernel
+    pub fn Minimal() -> Self {
+        return XStatePolicy {
+            allowed: XSTATE_X87 | XSTATE_SSE,
+        }
+    }
+
+    //Minimal and AVX, what qvisor starts qkernel with
+    pub fn Default() -> Self {
+        return XStatePolicy {
+            allowed: XSTATE_X87 | XSTATE_SSE | XSTATE_AVX,
+        }
+    }
+
+    pub fn AllowAVX(&mut self) -> &mut Self {
+        self.allowed |= XSTATE_AVX;
+        self
+    }
+
+    pub fn AllowAVX512(&mut self) -> &mut Self {
+        self.allowed |= XSTATE_AVX | XSTATE_AVX512;
+        self
+    }
+
+    pub fn AllowMPX(&mut self) -> &mut Self {
+        self.allowed |= XSTATE_MPX;
+        self
+    }
+
+    pub fn AllowPKRU(&mut self) -> &mut Self {
+        self.allowed |= XSTATE_PKRU;
+        self
+    }
+
+    pub fn DisallowAVX(&mut self) -> &mut Self {
+        self.allowed &= !(XSTATE_AVX | XSTATE_AVX512);
+        self
+    }
+
+    //return the XCR0 value for the guest, 0 means XSAVE is not enabled
+    pub fn Xcr0(&self, supported: u64) -> u64 {
+        if supported & XSTATE_X87 == 0 {
+            return 0;
+        }
+
+        let mut xcr0 = (self.allowed & supported) | XSTATE_X87;
+
+        //AVX state requires SSE state, AVX-512 state requires all 3 of its components and AVX
+        if xcr0 & XSTATE_SSE == 0 {
+            xcr0 &= !(XSTATE_AVX | XSTATE_AVX512);
+        }
+
+        if xcr0 & XSTATE_AVX == 0 || xcr0 & XSTATE_AVX512 != XSTATE_AVX512 {
+            xcr0 &= !XSTATE_AVX512;
+        }
+
+        if xcr0 & XSTATE_MPX != XSTATE_MPX {
+            xcr0 &= !XSTATE_MPX;
+        }
+
+        return xcr0;
+    }
+}
+
+//the protection features setup_long_mode turns on, each one only when the cpuid reports it
+#[derive(Debug, Copy, Clone)]
+pub struct CpuProfile {
+    pub nxe: bool,
+    pub smep: bool,
+    pub smap: bool,
+    pub pge: bool,
+    pub umip: bool,
+}
+
+impl CpuProfile {
+    pub fn Hardened() -> Self {
+        return CpuProfile {
+            nxe: true,
+            smep: true,
+            smap: true,
+            pge: true,
+            umip: true,
+        }
+    }
+
+    //the original behavior: PAE paging without any protection feature
+    pub fn Legacy() -> Self {
+        return CpuProfile {
+            nxe: false,
+            smep: false,
+            smap: false,
+            pge: false,
+            umip: false,
+        }
+    }
+
+    pub fn NXE(&mut self, enable: bool) -> &mut Self {
+        self.nxe = enable;
+        self
+    }
+
+    pub fn SMEP(&mut self, enable: bool) -> &mut Self {
+        self.smep = enable;
+        self
+    }
+
+    pub fn SMAP(&mut self, enable: bool) -> &mut Self {
+        self.smap = enable;
+        self
+    }
+
+    pub fn PGE(&mut self, enable: bool) -> &mut Self {
+        self.pge = enable;
+        self
+    }
+
+    pub fn UMIP(&mut self, enable: bool) -> &mut Self {
+        self.umip = enable;
+        self
+    }
+
+    //drop the features the host cpu doesn't support
+    pub fn Effective(&self, cpuid: &CpuidEntries) -> Self {
+        return CpuProfile {
+            nxe: self.nxe && cpuid.HasNX(),
+            smep: self.smep && cpuid.HasSMEP(),
+            smap: self.smap && cpuid.HasSMAP(),
+            pge: self.pge && cpuid.HasPGE(),
+            umip: self.umip && cpuid.HasUMIP(),
+        }
+    }
+
+    //the profile a saved vcpu runs with
+    pub fn FromSregs(sregs: &kvm_sregs) -> Self {
+        return CpuProfile {
+            nxe: sregs.efer & super::EFER_NXE != 0,
+            smep: sregs.cr4 & super::CR4_SMEP != 0,
+            smap: sregs.cr4 & super::CR4_SMAP != 0,
+            pge: sregs.cr4 & super::CR4_PGE != 0,
+            umip: sregs.cr4 & super::CR4_UMIP != 0,
+        }
+    }
+
+    //a new mapping the guest can use with the profile, NO_EXECUTE is a reserved bit without EFER.NXE
+    pub fn CheckPageOpts(&self, opts: &PageOpts) -> Result<()> {
+        if !self.nxe && !opts.AccessType.Exec {
+            return Err(Error::CpuProfileIncompatible(String::from("a NO_EXECUTE mapping but EFER.NXE is off")));
+        }
+
+        return Ok(())
+    }
+
+    pub fn Cr4(&self) -> u64 {
+        let mut cr4 = 0;
+        if self.smep {
+            cr4 |= super::CR4_SMEP;
+        }
+
+        if self.smap {
+            cr4 |= super::CR4_SMAP;
+        }
+
+        if self.pge {
+            cr4 |= super::CR4_PGE;
+        }
+
+        if self.umip {
+            cr4 |= super::CR4_UMIP;
+        }
+
+        return cr4;
+    }
+
+    pub fn Efer(&self) -> u64 {
+        if self.nxe {
+            return super::EFER_NXE;
+        }
+
+        return 0;
+    }
+
+    //check the guest page tables against the profile before the vcpu runs with it, so that an
+    //incompatible mapping fails here instead of as a triple fault at the first instruction
+    pub fn CheckPageTables(&self, pt: &PageTables, entry: u64, stackStart: u64, stackEnd: u64) -> Result<()> {
+        let mut supervisorPages = 0;
+        let mut nxPages = 0;
+        let mut entryFlags = None;
+        let mut userStackPage = None;
+
+        pt.ForEachMapping(|vaddr, _phyAddr, size, flags| {
+            if !flags.contains(PageTableFlags::USER_ACCESSIBLE) {
+                supervisorPages += 1;
+            }
+
+            if flags.contains(PageTableFlags::NO_EXECUTE) {
+                nxPages += 1;
+            }
+
+            if entry >= vaddr && entry < vaddr + size {
+                entryFlags = Some(flags);
+            }
+
+            if userStackPage.is_none() && flags.contains(PageTableFlags::USER_ACCESSIBLE)
+                && vaddr < stackEnd && stackStart < vaddr + size {
+                userStackPage = Some(vaddr);
+            }
+        });
+
+        let entryFlags = match entryFlags {
+            None => return Err(Error::CpuProfileIncompatible(format!("the entry address {:x} is not mapped", entry))),
+            Some(flags) => flags,
+        };
+
+        if !self.nxe && nxPages > 0 {
+            return Err(Error::CpuProfileIncompatible(format!("{} pages are mapped NO_EXECUTE but EFER.NXE is off", nxPages)));
+        }
+
+        if entryFlags.contains(PageTableFlags::NO_EXECUTE) {
+            return Err(Error::CpuProfileIncompatible(format!("the entry address {:x} is mapped NO_EXECUTE", entry)));
+        }
+
+        if self.smep || self.smap {
+            if supervisorPages == 0 {
+                return Err(Error::CpuProfileIncompatible(String::from("all the guest pages are USER_ACCESSIBLE, SMEP/SMAP need supervisor pages for the kernel")));
+            }
+        }
+
+        if self.smep && entryFlags.contains(PageTableFlags::USER_ACCESSIBLE) {
+            return Err(Error::CpuProfileIncompatible(format!("SMEP is on but the entry address {:x} is USER_ACCESSIBLE", entry)));
+        }
+
+        if self.smap {
+            if let Some(vaddr) = userStackPage {
+                return Err(Error::CpuProfileIncompatible(format!("SMAP is on but the kernel stack page {:x} is USER_ACCESSIBLE", vaddr)));
+            }
+        }
+
+        return Ok(())
+    }
+}
+
+pub fn SetFpu(vcpu: &VcpuFd) -> Result<()> {
+    let fpu = kvm_fpu {
+        fcw: FPU_FCW_DEFAULT,
+        mxcsr: MXCSR_DEFAULT,
+        ..Default::default()
+    };
+
+    vcpu.set_fpu(&fpu).map_err(|e| Error::IOError(format!("io::error is {:?}", e)))?;
+    return Ok(())
+}
+
+pub fn SetXcr0(vcpu: &VcpuFd, xcr0: u64) -> Result<()> {
+    let mut xcrs = kvm_xcrs::default();
+    xcrs.nr_xcrs = 1;
+    xcrs.xcrs[0].xcr = 0;
+    xcrs.xcrs[0].value = xcr0;
+
+    Ioctl(vcpu.as_raw_fd(), KVM_SET_XCRS, &mut xcrs as *mut kvm_xcrs)?;
+    return Ok(())
+}
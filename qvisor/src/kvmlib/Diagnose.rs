@@ -0,0 +1,310 @@
+use std::fmt::Write;
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+
+use kvm_bindings::{kvm_regs, kvm_segment, kvm_sregs};
+use kvm_ioctls::{Kvm, VcpuFd};
+
+use super::qlib::Common::Result;
+use super::MemMgr::MapOption;
+use super::MemMgr::MappedRegion;
+use super::Cpu;
+use super::ELFLoader::KernelELF;
+use super::GuestMemory::GuestMemory;
+use super::Unwind;
+
+//qvisor exit status for each kind of abnormal guest exit
+pub const EXIT_CODE_GUEST_PANIC: i32 = 2;
+pub const EXIT_CODE_FAIL_ENTRY: i32 = 3;
+pub const EXIT_CODE_EXCEPTION: i32 = 4;
+pub const EXIT_CODE_TRIPLE_FAULT: i32 = 5;
+pub const EXIT_CODE_INTERNAL_ERROR: i32 = 6;
+pub const EXIT_CODE_UNEXPECTED_EXIT: i32 = 7;
+pub const EXIT_CODE_BAD_HYPERCALL: i32 = 8;
+
+const KVM_EXIT_EXCEPTION: u32 = 1;
+const KVM_EXIT_DEBUG: u32 = 4;
+const KVM_EXIT_FAIL_ENTRY: u32 = 9;
+const KVM_EXIT_INTERNAL_ERROR: u32 = 17;
+
+const INSTRUCTION_DUMP_LEN: usize = 16;
+
+const EXCEPTION_NAMES: [&str; 32] = [
+    "#DE divide error", "#DB debug", "NMI", "#BP breakpoint", "#OF overflow", "#BR bound range exceeded",
+    "#UD invalid opcode", "#NM device not available", "#DF double fault", "coprocessor segment overrun",
+    "#TS invalid TSS", "#NP segment not present", "#SS stack segment fault", "#GP general protection",
+    "#PF page fault", "reserved", "#MF x87 floating point", "#AC alignment check", "#MC machine check",
+    "#XM SIMD floating point", "#VE virtualization", "#CP control protection", "reserved", "reserved",
+    "reserved", "reserved", "reserved", "reserved", "#HV hypervisor injection", "#VC VMM communication",
+    "#SX security", "reserved",
+];
+
+//the head of struct kvm_run, the union after it starts at offset 32
+#[repr(C)]
+struct KvmRunHeader {
+    request_interrupt_window: u8,
+    immediate_exit: u8,
+    padding1: [u8; 6],
+    exit_reason: u32,
+    ready_for_interrupt_injection: u8,
+    if_flag: u8,
+    flags: u16,
+    cr8: u64,
+    apic_base: u64,
+}
+
+const KVM_RUN_UNION_OFFSET: usize = 32;
+
+//a second mapping of the vcpu's kvm_run page, to read the exit details kvm_ioctls doesn't decode
+pub struct KvmRun {
+    mr: MappedRegion,
+}
+
+impl KvmRun {
+    pub fn MmapSize(kvm: &Kvm) -> Result<usize> {
+        let size = Cpu::Ioctl(kvm.as_raw_fd(), super::KVM_GET_VCPU_MMAP_SIZE, ptr::null_mut::<u8>())?;
+        return Ok(size as usize)
+    }
+
+    pub fn New(vcpu: &VcpuFd, mmapSize: usize) -> Result<Self> {
+        let mr = MapOption::New().Len(mmapSize as u64).FileId(vcpu.as_raw_fd()).MapShare().ProtoRead().ProtoWrite().Map()?;
+        return Ok(KvmRun {
+            mr: mr
+        })
+    }
+
+    fn Header(&self) -> &KvmRunHeader {
+        return unsafe { &*(self.mr.ptr as *const KvmRunHeader) }
+    }
+
+    fn UnionU32(&self, idx: usize) -> u32 {
+        return unsafe { ptr::read((self.mr.ptr as usize + KVM_RUN_UNION_OFFSET + idx * 4) as *const u32) }
+    }
+
+    fn UnionU64(&self, offset: usize) -> u64 {
+        return unsafe { ptr::read_unaligned((self.mr.ptr as usize + KVM_RUN_UNION_OFFSET + offset) as *const u64) }
+    }
+
+    pub fn ExitReason(&self) -> u32 {
+        return self.Header().exit_reason
+    }
+
+    pub fn SetImmediateExit(&self, val: bool) {
+        unsafe {
+            ptr::write_volatile(self.ImmediateExitAddr() as *mut u8, val as u8);
+        }
+    }
+
+    //the address of the immediate_exit byte, for another thread to kick the vcpu out of KVM_RUN
+    pub fn ImmediateExitAddr(&self) -> u64 {
+        return &(self.Header().immediate_exit) as *const u8 as u64
+    }
+
+    //(exception, pc, dr6, dr7) of KVM_EXIT_DEBUG
+    pub fn Debug(&self) -> Option<(u32, u64, u64, u64)> {
+        if self.ExitReason() != KVM_EXIT_DEBUG {
+            return None;
+        }
+
+        return Some((self.UnionU32(0), self.UnionU64(8), self.UnionU64(16), self.UnionU64(24)))
+    }
+
+    //(hardware_entry_failure_reason, cpu) of KVM_EXIT_FAIL_ENTRY
+    pub fn FailEntry(&self) -> Option<(u64, u32)> {
+        if self.ExitReason() != KVM_EXIT_FAIL_ENTRY {
+            return None;
+        }
+
+        return Some((self.UnionU64(0), self.UnionU32(2)))
+    }
+
+    //(exception, error_code) of KVM_EXIT_EXCEPTION
+    pub fn Exception(&self) -> Option<(u32, u32)> {
+        if self.ExitReason() != KVM_EXIT_EXCEPTION {
+            return None;
+        }
+
+        return Some((self.UnionU32(0), self.UnionU32(1)))
+    }
+
+    //(suberror, data) of KVM_EXIT_INTERNAL_ERROR
+    pub fn InternalError(&self) -> Option<(u32, Vec<u64>)> {
+        if self.ExitReason() != KVM_EXIT_INTERNAL_ERROR {
+            return None;
+        }
+
+        let ndata = core::cmp::min(self.UnionU32(1) as usize, 16);
+        let mut data = Vec::with_capacity(ndata);
+        for i in 0..ndata {
+            data.push(self.UnionU64(8 + i * 8));
+        }
+
+        return Some((self.UnionU32(0), data))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum AbnormalExit {
+    GuestPanic,
+    FailEntry,
+    Exception,
+    Shutdown,
+    InternalError,
+    Unexpected(String),
+    //a hypercall argument qvisor refused, the guest is not trusted to pass valid ones
+    BadHypercall(String),
+}
+
+impl AbnormalExit {
+    pub fn ExitCode(&self) -> i32 {
+        match self {
+            AbnormalExit::GuestPanic => EXIT_CODE_GUEST_PANIC,
+            AbnormalExit::FailEntry => EXIT_CODE_FAIL_ENTRY,
+            AbnormalExit::Exception => EXIT_CODE_EXCEPTION,
+            AbnormalExit::Shutdown => EXIT_CODE_TRIPLE_FAULT,
+            AbnormalExit::InternalError => EXIT_CODE_INTERNAL_ERROR,
+            AbnormalExit::Unexpected(_) => EXIT_CODE_UNEXPECTED_EXIT,
+            AbnormalExit::BadHypercall(_) => EXIT_CODE_BAD_HYPERCALL,
+        }
+    }
+
+    pub fn Describe(&self) -> String {
+        match self {
+            AbnormalExit::GuestPanic => String::from("guest panic"),
+            AbnormalExit::FailEntry => String::from("vm entry failure"),
+            AbnormalExit::Exception => String::from("unhandled guest exception"),
+            AbnormalExit::Shutdown => String::from("triple fault (shutdown)"),
+            AbnormalExit::InternalError => String::from("kvm internal error"),
+            AbnormalExit::Unexpected(r) => format!("unexpected exit {}", r),
+            AbnormalExit::BadHypercall(r) => format!("bad hypercall {}", r),
+        }
+    }
+}
+
+pub fn DecodeEntryFailure(reason: u64) -> String {
+    //SVM reports VMEXIT_INVALID
+    if reason == 0xffff_ffff_ffff_ffff {
+        return String::from("SVM VMEXIT_INVALID: invalid guest state in VMCB");
+    }
+
+    let basic = reason & 0xffff;
+    let desc = match basic {
+        33 => "VMX entry failure: invalid guest state",
+        34 => "VMX entry failure: MSR loading",
+        41 => "VMX entry failure: machine-check event",
+        _ => "unknown",
+    };
+
+    return format!("{} (basic exit reason {}, raw {:#x})", desc, basic, reason)
+}
+
+pub fn DecodeInternalError(suberror: u32) -> &'static str {
+    match suberror {
+        1 => "instruction emulation failure",
+        2 => "exception during exception delivery",
+        3 => "event delivery failure",
+        4 => "unexpected vm exit",
+        _ => "unknown",
+    }
+}
+
+pub fn ExceptionName(vector: u32) -> &'static str {
+    if (vector as usize) < EXCEPTION_NAMES.len() {
+        return EXCEPTION_NAMES[vector as usize]
+    }
+
+    return "external interrupt"
+}
+
+fn FormatSegment(name: &str, s: &kvm_segment) -> String {
+    return format!("{:<4} sel={:04x} base={:016x} limit={:08x} type={:x} p={} dpl={} db={} s={} l={} g={} unusable={}",
+                   name, s.selector, s.base, s.limit, s.type_, s.present, s.dpl, s.db, s.s, s.l, s.g, s.unusable)
+}
+
+pub fn FormatRegs(regs: &kvm_regs) -> String {
+    let mut s = String::new();
+    writeln!(s, "RIP: {:016x} RSP: {:016x} RFLAGS: {:08x}", regs.rip, regs.rsp, regs.rflags).unwrap();
+    writeln!(s, "RAX: {:016x} RBX: {:016x} RCX: {:016x}", regs.rax, regs.rbx, regs.rcx).unwrap();
+    writeln!(s, "RDX: {:016x} RSI: {:016x} RDI: {:016x}", regs.rdx, regs.rsi, regs.rdi).unwrap();
+    writeln!(s, "RBP: {:016x} R08: {:016x} R09: {:016x}", regs.rbp, regs.r8, regs.r9).unwrap();
+    writeln!(s, "R10: {:016x} R11: {:016x} R12: {:016x}", regs.r10, regs.r11, regs.r12).unwrap();
+    writeln!(s, "R13: {:016x} R14: {:016x} R15: {:016x}", regs.r13, regs.r14, regs.r15).unwrap();
+    return s;
+}
+
+pub fn FormatSregs(sregs: &kvm_sregs) -> String {
+    let mut s = String::new();
+    for (name, seg) in [("CS", &sregs.cs), ("DS", &sregs.ds), ("ES", &sregs.es), ("FS", &sregs.fs),
+                        ("GS", &sregs.gs), ("SS", &sregs.ss), ("TR", &sregs.tr), ("LDT", &sregs.ldt)].iter() {
+        writeln!(s, "{}", FormatSegment(name, seg)).unwrap();
+    }
+
+    writeln!(s, "GDT  base={:016x} limit={:04x}", sregs.gdt.base, sregs.gdt.limit).unwrap();
+    writeln!(s, "IDT  base={:016x} limit={:04x}", sregs.idt.base, sregs.idt.limit).unwrap();
+    writeln!(s, "CR0: {:016x} CR2: {:016x} CR3: {:016x}", sregs.cr0, sregs.cr2, sregs.cr3).unwrap();
+    writeln!(s, "CR4: {:016x} CR8: {:016x} EFER: {:016x}", sregs.cr4, sregs.cr8, sregs.efer).unwrap();
+    writeln!(s, "APIC_BASE: {:016x}", sregs.apic_base).unwrap();
+
+    let pending: Vec<String> = sregs.interrupt_bitmap.iter().enumerate()
+        .flat_map(|(i, &w)| (0..64).filter(move |b| w & (1 << b) != 0).map(move |b| format!("{}", i * 64 + b)))
+        .collect();
+    if pending.len() > 0 {
+        writeln!(s, "pending interrupts: {}", pending.join(" ")).unwrap();
+    }
+
+    return s;
+}
+
+//build the report for an abnormal exit. never fails, the parts which can't be read are reported as such
+pub fn Report(exit: &AbnormalExit, vcpuId: usize, vcpu: &VcpuFd, run: &KvmRun, reader: &GuestMemory, elf: &KernelELF) -> String {
+    let mut s = String::new();
+    writeln!(s, "==================== qvisor: {} on vcpu {} ====================", exit.Describe(), vcpuId).unwrap();
+
+    if let Some((reason, cpu)) = run.FailEntry() {
+        writeln!(s, "hardware entry failure reason: {} on host cpu {}", DecodeEntryFailure(reason), cpu).unwrap();
+    }
+
+    if let Some((suberror, data)) = run.InternalError() {
+        let data: Vec<String> = data.iter().map(|d| format!("{:x}", d)).collect();
+        writeln!(s, "internal error: {} (suberror {}) data: [{}]", DecodeInternalError(suberror), suberror, data.join(", ")).unwrap();
+    }
+
+    if let Some((vector, errorCode)) = run.Exception() {
+        writeln!(s, "exception: {} (vector {}) error code {:#x}", ExceptionName(vector), vector, errorCode).unwrap();
+    }
+
+    let regs = vcpu.get_regs();
+    let sregs = vcpu.get_sregs();
+
+    match &regs {
+        Ok(regs) => {
+            writeln!(s, "RIP is at {}", elf.symbols.Symbolize(regs.rip)).unwrap();
+            s.push_str(&FormatRegs(regs));
+        }
+        Err(e) => writeln!(s, "fail to get regs: {:?}", e).unwrap(),
+    }
+
+    match &sregs {
+        Ok(sregs) => s.push_str(&FormatSregs(sregs)),
+        Err(e) => writeln!(s, "fail to get sregs: {:?}", e).unwrap(),
+    }
+
+    if let (Ok(regs), Ok(sregs)) = (&regs, &sregs) {
+        writeln!(s, "faulting address (CR2): {:016x}", sregs.cr2).unwrap();
+
+        let mut code = [0 as u8; INSTRUCTION_DUMP_LEN];
+        let cnt = reader.ReadVirt(sregs.cr3, regs.rip, &mut code);
+        if cnt == 0 {
+            writeln!(s, "code at RIP {:016x}: <unmapped>", regs.rip).unwrap();
+        } else {
+            let bytes: Vec<String> = code[..cnt].iter().map(|b| format!("{:02x}", b)).collect();
+            writeln!(s, "code at RIP {:016x}: {}", regs.rip, bytes.join(" ")).unwrap();
+        }
+
+        let frames = Unwind::Backtrace(reader, sregs.cr3, regs, elf.ehFrame.as_ref());
+        s.push_str(&Unwind::FormatBacktrace(&frames, &elf.symbols));
+    }
+
+    writeln!(s, "==================== end of report, exit code {} ====================", exit.ExitCode()).unwrap();
+    return s;
+}
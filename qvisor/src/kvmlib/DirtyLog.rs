@@ -0,0 +1,199 @@
+use kvm_bindings::kvm_userspace_memory_region;
+use kvm_bindings::KVM_MEM_LOG_DIRTY_PAGES;
+use kvm_bindings::KVM_MEM_READONLY;
+use std::collections::BTreeSet;
+
+use kvm_ioctls::VmFd;
+
+use super::qlib::Common::Error;
+use super::qlib::Common::Result;
+use super::Cpu;
+use super::MemMgr;
+
+//struct kvm_dirty_log
+#[repr(C)]
+struct DirtyLogArg {
+    slot: u32,
+    padding: u32,
+    bitmap: u64,
+}
+
+//a kvm memory slot as registered with KVM_SET_USER_MEMORY_REGION
+#[derive(Debug, Clone)]
+pub struct MemSlot {
+    pub slot: u32,
+    pub guestPhysAddr: u64,
+    pub memorySize: u64,
+    pub userspaceAddr: u64,
+    pub logDirty: bool,
+    //the guest writes to the slot exit as mmio writes
+    pub readonly: bool,
+}
+
+//[start, start + len) guest physical pages written since the last fetch
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DirtyRange {
+    pub start: u64,
+    pub len: u64,
+}
+
+impl MemSlot {
+    pub fn New(slot: u32, guestPhysAddr: u64, memorySize: u64, userspaceAddr: u64) -> Self {
+        return MemSlot {
+            slot: slot,
+            guestPhysAddr: guestPhysAddr,
+            memorySize: memorySize,
+            userspaceAddr: userspaceAddr,
+            logDirty: false,
+            readonly: false,
+        }
+    }
+
+    pub fn Register(&self, vm_fd: &VmFd) -> Result<()> {
+        let mem_region = kvm_userspace_memory_region {
+            slot: self.slot,
+            guest_phys_addr: self.guestPhysAddr,
+            memory_size: self.memorySize,
+            userspace_addr: self.userspaceAddr,
+            flags: (if self.logDirty { KVM_MEM_LOG_DIRTY_PAGES } else { 0 }) | (if self.readonly { KVM_MEM_READONLY } else { 0 }),
+        };
+
+        vm_fd.set_user_memory_region(mem_region).map_err(|e| Error::IOError(format!("io::error is {:?}", e)))?;
+        return Ok(())
+    }
+
+    //a zero sized region deletes the slot, the host memory stays mapped
+    pub fn Unregister(&self, vm_fd: &VmFd) -> Result<()> {
+        let mem_region = kvm_userspace_memory_region {
+            slot: self.slot,
+            guest_phys_addr: self.guestPhysAddr,
+            memory_size: 0,
+            userspace_addr: self.userspaceAddr,
+            flags: 0,
+        };
+
+        vm_fd.set_user_memory_region(mem_region).map_err(|e| Error::IOError(format!("io::error is {:?}", e)))?;
+        return Ok(())
+    }
+
+    //re-register the slot with only the flags changed. kvm starts a clean bitmap when logging is
+    //turned on, so the pages written before it are not reported
+    pub fn SetLogDirty(&mut self, vm_fd: &VmFd, enable: bool) -> Result<()> {
+        if self.logDirty == enable {
+            return Ok(())
+        }
+
+        self.logDirty = enable;
+        if let Err(e) = self.Register(vm_fd) {
+            self.logDirty = !enable;
+            return Err(e);
+        }
+
+        return Ok(())
+    }
+
+    //fetch and clear the slot's dirty bitmap, one bit per 4KB page from guestPhysAddr
+    pub fn GetDirtyLog(&self, vm_fd: &VmFd) -> Result<Vec<u64>> {
+        if !self.logDirty {
+            return Err(Error::Common(format!("dirty logging is off for slot {}", self.slot)));
+        }
+
+        return vm_fd.get_dirty_log(self.slot, self.memorySize as usize).map_err(|e| Error::IOError(format!("io::error is {:?}", e)))
+    }
+
+    //GetDirtyLog for a thread which only has the raw vm fd
+    pub fn GetDirtyLogRaw(&self, vmFd: i32) -> Result<Vec<u64>> {
+        if !self.logDirty {
+            return Err(Error::Common(format!("dirty logging is off for slot {}", self.slot)));
+        }
+
+        let pageCount = self.memorySize / MemMgr::PAGE_SIZE_4K;
+        let mut bitmap = vec![0 as u64; ((pageCount + 63) / 64) as usize];
+        let mut arg = DirtyLogArg {
+            slot: self.slot,
+            padding: 0,
+            bitmap: bitmap.as_mut_ptr() as u64,
+        };
+
+        Cpu::Ioctl(vmFd, super::KVM_GET_DIRTY_LOG, &mut arg as *mut DirtyLogArg)?;
+        return Ok(bitmap)
+    }
+
+    pub fn GetDirtyRanges(&self, vm_fd: &VmFd) -> Result<Vec<DirtyRange>> {
+        let bitmap = self.GetDirtyLog(vm_fd)?;
+        return Ok(BitmapToRanges(&bitmap, self.guestPhysAddr, self.memorySize))
+    }
+}
+
+//the kvm memory slot ids in use, the lowest free id is given out first
+pub struct SlotIdAllocator {
+    max: u32,
+    used: BTreeSet<u32>,
+}
+
+impl SlotIdAllocator {
+    //max is the slot count of the host, KVM_CAP_NR_MEMSLOTS
+    pub fn New(max: u32) -> Self {
+        return SlotIdAllocator {
+            max: max,
+            used: BTreeSet::new(),
+        }
+    }
+
+    pub fn Alloc(&mut self) -> Result<u32> {
+        for id in 0..self.max {
+            if self.used.insert(id) {
+                return Ok(id)
+            }
+        }
+
+        return Err(Error::Common(format!("all {} kvm memory slots are used", self.max)))
+    }
+
+    //take an id of a slot registered elsewhere, when rebuilding a saved vm
+    pub fn Mark(&mut self, id: u32) -> Result<()> {
+        if id >= self.max || !self.used.insert(id) {
+            return Err(Error::Common(format!("kvm memory slot {} is not available", id)));
+        }
+
+        return Ok(())
+    }
+
+    pub fn Free(&mut self, id: u32) {
+        self.used.remove(&id);
+    }
+}
+
+//merge the set bits of a dirty bitmap into page ranges, pages past memorySize are ignored
+pub fn BitmapToRanges(bitmap: &[u64], guestPhysAddr: u64, memorySize: u64) -> Vec<DirtyRange> {
+    let pageCount = memorySize / MemMgr::PAGE_SIZE_4K;
+    let mut ranges: Vec<DirtyRange> = Vec::new();
+
+    for (i, &word) in bitmap.iter().enumerate() {
+        let mut bits = word;
+        while bits != 0 {
+            let bit = bits.trailing_zeros() as u64;
+            bits &= bits - 1;
+
+            let page = i as u64 * 64 + bit;
+            if page >= pageCount {
+                return ranges;
+            }
+
+            let addr = guestPhysAddr + page * MemMgr::PAGE_SIZE_4K;
+            if let Some(last) = ranges.last_mut() {
+                if last.start + last.len == addr {
+                    last.len += MemMgr::PAGE_SIZE_4K;
+                    continue;
+                }
+            }
+
+            ranges.push(DirtyRange {
+                start: addr,
+                len: MemMgr::PAGE_SIZE_4K,
+            })
+        }
+    }
+
+    return ranges;
+}
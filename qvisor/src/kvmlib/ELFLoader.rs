@@ -28,13 +28,19 @@ use super::qlib::Common::Result;
 use std::fs::File;
 
 use super::MemMgr::{MappedRegion, MapOption};
+use super::Symbol::SymbolTable;
+use super::Unwind::EhFrame;
 
 pub struct KernelELF {
+    pub fileName: String,
     mmap: Mmap,
     startAddr: Addr,
     endAddr: Addr,
     entry: u64,
     mr: Option<MappedRegion>,
+
+    pub symbols: SymbolTable,
+    pub ehFrame: Option<EhFrame>,
 }
 
 impl KernelELF {
@@ -68,12 +74,18 @@ impl KernelELF {
             }
         }
 
+        let symbols = SymbolTable::Init(&elfFile);
+        let ehFrame = EhFrame::Init(&elfFile);
+
         return Ok(KernelELF {
+            fileName: fileName.clone(),
             mmap,
             startAddr,
             endAddr,
             entry,
             mr : None,
+            symbols,
+            ehFrame,
         })
     }
 
@@ -85,7 +97,16 @@ impl KernelELF {
         return self.endAddr;
     }
 
-    pub fn LoadKernel(&mut self) -> Result<u64> {
+    //the [start, end) host range the kernel is loaded at, None before LoadKernel
+    pub fn MappedRange(&self) -> Option<(u64, u64)> {
+        match &self.mr {
+            None => None,
+            Some(mr) => Some((mr.ptr, mr.ptr + mr.sz)),
+        }
+    }
+
+    //map the range of the kernel without loading it, a restored snapshot fills it
+    pub fn MapKernel(&mut self) -> Result<()> {
         let mut option = &mut MapOption::New();
         option = option.Offset(self.startAddr.0).Len(self.endAddr.0 - self.startAddr.0).MapAnan().MapPrivate().ProtoRead().ProtoWrite().ProtoExec();
 
@@ -97,6 +118,12 @@ impl KernelELF {
         }
 
         println!("loadKernel: get address is {:x}", mr.ptr as u64);
+        self.mr = Some(mr);
+        return Ok(())
+    }
+
+    pub fn LoadKernel(&mut self) -> Result<u64> {
+        self.MapKernel()?;
 
         let elfFile = ElfFile::new(&self.mmap).map_err(Error::ELFLoadError)?;
         for p in elfFile.program_iter() {
@@ -112,13 +139,11 @@ impl KernelELF {
 
                     target.clone_from_slice(source);
 
-                    VMS.lock().Map(startMem, endMem, startMem, &PageOpts::Default())?;
+                    VMS.lock().Map(startMem, endMem, startMem, &PageOpts::Kernel())?;
                 }
             }
         }
 
-        self.mr = Some(mr);
-
         return Ok(self.entry)
     }
 }
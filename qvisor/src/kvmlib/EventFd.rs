@@ -0,0 +1,319 @@
+use std::io::Read;
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use kvm_ioctls::VmFd;
+
+use super::qlib::Common::Error;
+use super::qlib::Common::Result;
+use super::Cpu;
+
+const KVM_IOEVENTFD_FLAG_DATAMATCH: u32 = 1 << 0;
+const KVM_IOEVENTFD_FLAG_PIO: u32 = 1 << 1;
+const KVM_IOEVENTFD_FLAG_DEASSIGN: u32 = 1 << 2;
+const KVM_IRQFD_FLAG_DEASSIGN: u32 = 1 << 0;
+
+//struct kvm_ioeventfd
+#[repr(C)]
+#[derive(Default)]
+struct KvmIoEventFd {
+    datamatch: u64,
+    addr: u64,
+    len: u32,
+    fd: i32,
+    flags: u32,
+    pad: [u32; 9],
+}
+
+//struct kvm_irqfd
+#[repr(C)]
+#[derive(Default)]
+struct KvmIrqFd {
+    fd: u32,
+    gsi: u32,
+    flags: u32,
+    resamplefd: u32,
+    pad: [u64; 2],
+}
+
+//an eventfd counter, closed on drop
+pub struct EventFd {
+    fd: i32,
+}
+
+impl Drop for EventFd {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+impl EventFd {
+    pub fn New() -> Result<Self> {
+        let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(Error::IOError(format!("eventfd fail, io::error is {:?}", std::io::Error::last_os_error())));
+        }
+
+        return Ok(EventFd { fd: fd })
+    }
+
+    pub fn Fd(&self) -> i32 {
+        return self.fd
+    }
+
+    //add count to the counter, wakes a reader
+    pub fn Write(&self, count: u64) -> Result<()> {
+        let ret = unsafe { libc::write(self.fd, &count as *const u64 as *const libc::c_void, 8) };
+        if ret != 8 {
+            return Err(Error::IOError(format!("write eventfd fail, io::error is {:?}", std::io::Error::last_os_error())));
+        }
+
+        return Ok(())
+    }
+
+    //wait for the counter to be non zero, return it and reset it to 0
+    pub fn Read(&self) -> Result<u64> {
+        let mut count: u64 = 0;
+        loop {
+            let ret = unsafe { libc::read(self.fd, &mut count as *mut u64 as *mut libc::c_void, 8) };
+            if ret == 8 {
+                return Ok(count)
+            }
+
+            let err = std::io::Error::last_os_error();
+            if ret < 0 && err.raw_os_error() == Some(libc::EINTR) {
+                continue;
+            }
+
+            return Err(Error::IOError(format!("read eventfd fail, io::error is {:?}", err)));
+        }
+    }
+}
+
+//block until fd is readable or stop is signalled. false for stop, stop stays signalled for the
+//other waiters
+pub fn WaitReadable(fd: i32, stop: &EventFd) -> bool {
+    let mut pfds = [
+        libc::pollfd { fd: fd, events: libc::POLLIN, revents: 0 },
+        libc::pollfd { fd: stop.Fd(), events: libc::POLLIN, revents: 0 },
+    ];
+
+    loop {
+        let ret = unsafe { libc::poll(pfds.as_mut_ptr(), 2, -1) };
+        if ret < 0 {
+            if std::io::Error::last_os_error().raw_os_error() == Some(libc::EINTR) {
+                continue;
+            }
+
+            return false
+        }
+
+        if pfds[1].revents != 0 {
+            return false
+        }
+
+        //an error or a hang up is for the read to report
+        if pfds[0].revents != 0 {
+            return true
+        }
+    }
+}
+
+//the input of a host thread which has to end on request: it ends, as at end of file, once stop
+//is signalled
+pub struct StoppableRead<R: Read + AsRawFd> {
+    inner: R,
+    stop: Arc<EventFd>,
+}
+
+impl<R: Read + AsRawFd> StoppableRead<R> {
+    pub fn New(inner: R, stop: Arc<EventFd>) -> Self {
+        return StoppableRead {
+            inner: inner,
+            stop: stop,
+        }
+    }
+}
+
+impl<R: Read + AsRawFd> Read for StoppableRead<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if !WaitReadable(self.inner.as_raw_fd(), &self.stop) {
+            return Ok(0)
+        }
+
+        return self.inner.read(buf)
+    }
+}
+
+//a guest write kvm turns into an eventfd signal without exiting to the vcpu thread. The write is
+//dropped, so it only tells a host io thread there is work, with the data in guest memory
+pub struct IoEventFd {
+    pub addr: u64,
+    pub len: u32,
+    pub pio: bool,
+    //signal only on writes of this value, any write when None
+    pub datamatch: Option<u64>,
+    evt: Arc<EventFd>,
+}
+
+impl IoEventFd {
+    //len is 1, 2, 4 or 8, or 0 to match a write of any size to an mmio addr
+    pub fn Register(vm_fd: &VmFd, addr: u64, len: u32, pio: bool, datamatch: Option<u64>) -> Result<Self> {
+        let ioEventFd = IoEventFd {
+            addr: addr,
+            len: len,
+            pio: pio,
+            datamatch: datamatch,
+            evt: Arc::new(EventFd::New()?),
+        };
+
+        ioEventFd.Ioctl(vm_fd, 0)?;
+        return Ok(ioEventFd)
+    }
+
+    pub fn Unregister(&self, vm_fd: &VmFd) -> Result<()> {
+        return self.Ioctl(vm_fd, KVM_IOEVENTFD_FLAG_DEASSIGN)
+    }
+
+    fn Ioctl(&self, vm_fd: &VmFd, flags: u32) -> Result<()> {
+        let mut req = KvmIoEventFd {
+            datamatch: self.datamatch.unwrap_or(0),
+            addr: self.addr,
+            len: self.len,
+            fd: self.evt.Fd(),
+            flags: flags,
+            ..Default::default()
+        };
+
+        if self.pio {
+            req.flags |= KVM_IOEVENTFD_FLAG_PIO;
+        }
+
+        if self.datamatch.is_some() {
+            req.flags |= KVM_IOEVENTFD_FLAG_DATAMATCH;
+        }
+
+        Cpu::Ioctl(vm_fd.as_raw_fd(), super::KVM_IOEVENTFD, &mut req as *mut KvmIoEventFd)?;
+        return Ok(())
+    }
+
+    pub fn EventFd(&self) -> Arc<EventFd> {
+        return self.evt.clone()
+    }
+
+    //kvm refuses a second ioeventfd for the same writes: at the same addr on the same bus, unless the
+    //lengths differ or both match different values. A len of 0 takes any length
+    pub fn Collides(&self, addr: u64, len: u32, pio: bool, datamatch: Option<u64>) -> bool {
+        if self.pio != pio || self.addr != addr {
+            return false
+        }
+
+        if self.len == 0 || len == 0 {
+            return true
+        }
+
+        return self.len == len && (self.datamatch.is_none() || datamatch.is_none() || self.datamatch == datamatch)
+    }
+
+    pub fn Matches(&self, addr: u64, len: u32, pio: bool, datamatch: Option<u64>) -> bool {
+        return self.addr == addr && self.len == len && self.pio == pio && self.datamatch == datamatch
+    }
+}
+
+//an IoEventFd with a host thread which runs handler for the guest's writes, several writes before
+//the thread wakes up run it once
+pub struct Doorbell {
+    ioEventFd: IoEventFd,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Doorbell {
+    pub fn Start(ioEventFd: IoEventFd, mut handler: Box<dyn FnMut() + Send>) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let evt = ioEventFd.EventFd();
+        let threadStop = stop.clone();
+        let thread = thread::spawn(move || {
+            loop {
+                if let Err(e) = evt.Read() {
+                    println!("doorbell {:?}", e);
+                    return;
+                }
+
+                if threadStop.load(Ordering::SeqCst) {
+                    return;
+                }
+
+                handler();
+            }
+        });
+
+        return Doorbell {
+            ioEventFd: ioEventFd,
+            stop: stop,
+            thread: Some(thread),
+        }
+    }
+
+    pub fn IoEventFd(&self) -> &IoEventFd {
+        return &self.ioEventFd
+    }
+
+    //unregister and end the thread, a handler already running finishes first
+    pub fn Stop(&mut self, vm_fd: &VmFd) -> Result<()> {
+        self.ioEventFd.Unregister(vm_fd)?;
+        self.stop.store(true, Ordering::SeqCst);
+        self.ioEventFd.evt.Write(1)?;
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+
+        return Ok(())
+    }
+}
+
+//raise a gsi of the in-kernel irqchip from any host thread with Trigger, without the vcpu thread.
+//Clones share the eventfd
+#[derive(Clone)]
+pub struct IrqFd {
+    pub gsi: u32,
+    evt: Arc<EventFd>,
+}
+
+impl IrqFd {
+    //needs the irqchip from Irqchip::Create
+    pub fn Register(vm_fd: &VmFd, gsi: u32) -> Result<Self> {
+        let irqFd = IrqFd {
+            gsi: gsi,
+            evt: Arc::new(EventFd::New()?),
+        };
+
+        irqFd.Ioctl(vm_fd, 0)?;
+        return Ok(irqFd)
+    }
+
+    pub fn Unregister(&self, vm_fd: &VmFd) -> Result<()> {
+        return self.Ioctl(vm_fd, KVM_IRQFD_FLAG_DEASSIGN)
+    }
+
+    fn Ioctl(&self, vm_fd: &VmFd, flags: u32) -> Result<()> {
+        let mut req = KvmIrqFd {
+            fd: self.evt.Fd() as u32,
+            gsi: self.gsi,
+            flags: flags,
+            ..Default::default()
+        };
+
+        Cpu::Ioctl(vm_fd.as_raw_fd(), super::KVM_IRQFD, &mut req as *mut KvmIrqFd)?;
+        return Ok(())
+    }
+
+    //an edge on the gsi, the guest gets one interrupt for several triggers before it runs
+    pub fn Trigger(&self) -> Result<()> {
+        return self.evt.Write(1)
+    }
+}
@@ -0,0 +1,842 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as FmtWrite;
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use kvm_bindings::{kvm_fpu, kvm_regs};
+use kvm_ioctls::VcpuFd;
+
+use super::qlib::Common::Error;
+use super::qlib::Common::Result;
+use super::Cpu;
+use super::GuestMemory::GuestMemory;
+
+const KVM_GUESTDBG_ENABLE: u32 = 0x1;
+const KVM_GUESTDBG_SINGLESTEP: u32 = 0x2;
+const KVM_GUESTDBG_USE_SW_BP: u32 = 0x1_0000;
+const KVM_GUESTDBG_USE_HW_BP: u32 = 0x2_0000;
+
+const INT3: u8 = 0xcc;
+const HW_BREAKPOINT_COUNT: usize = 4;
+
+//the packet size announced to gdb, and the largest memory read answered in one packet
+const PACKET_SIZE: usize = 0x4000;
+const MAX_MEM_XFER: usize = 0x1000;
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+const SIGABRT: u8 = 6;
+
+//the register file gdb expects of i386:x86-64 without a target description:
+//16 gp, rip, eflags, 6 segment selectors, st0-7, 8 x87 control, xmm0-15, mxcsr
+const REG_COUNT: usize = 57;
+
+#[repr(C)]
+#[derive(Default)]
+struct KvmGuestDebug {
+    control: u32,
+    pad: u32,
+    debugreg: [u64; 8],
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum HwKind {
+    Exec,
+    Write,
+    Access,
+}
+
+#[derive(Clone, Copy)]
+struct HwBreakpoint {
+    addr: u64,
+    len: u64,
+    kind: HwKind,
+}
+
+impl HwBreakpoint {
+    //the DR7 enable, R/W and LEN bits for debug register idx
+    fn Dr7(&self, idx: usize) -> u64 {
+        let rw = match self.kind {
+            HwKind::Exec => 0b00,
+            HwKind::Write => 0b01,
+            HwKind::Access => 0b11,
+        };
+
+        let len = match self.len {
+            2 => 0b01,
+            4 => 0b11,
+            8 => 0b10,
+            _ => 0b00,
+        };
+
+        return (1 << (idx * 2)) | (rw << (16 + idx * 4)) | (len << (18 + idx * 4))
+    }
+}
+
+pub enum StopReason {
+    //stopped before running the first instruction
+    Attach,
+    //gdb sent ^C
+    Interrupt,
+    //KVM_EXIT_DEBUG with the exception vector and DR6
+    Debug(u32, u64),
+    //the guest called HYPERCALL_PANIC
+    Panic,
+}
+
+pub enum GdbResume {
+    Continue,
+    //gdb went away, the guest keeps running without the stub
+    Detach,
+    Kill,
+}
+
+//a tcp or unix socket stream, also used by the migration channel
+pub enum Conn {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Conn {
+    pub fn TryClone(&self) -> std::io::Result<Conn> {
+        match self {
+            Conn::Tcp(s) => Ok(Conn::Tcp(s.try_clone()?)),
+            Conn::Unix(s) => Ok(Conn::Unix(s.try_clone()?)),
+        }
+    }
+
+    pub fn Shutdown(&self) {
+        let _ = match self {
+            Conn::Tcp(s) => s.shutdown(Shutdown::Both),
+            Conn::Unix(s) => s.shutdown(Shutdown::Both),
+        };
+    }
+}
+
+impl AsRawFd for Conn {
+    fn as_raw_fd(&self) -> i32 {
+        match self {
+            Conn::Tcp(s) => s.as_raw_fd(),
+            Conn::Unix(s) => s.as_raw_fd(),
+        }
+    }
+}
+
+impl Read for Conn {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Conn::Tcp(s) => s.read(buf),
+            Conn::Unix(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Conn {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Conn::Tcp(s) => s.write(buf),
+            Conn::Unix(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Conn::Tcp(s) => s.flush(),
+            Conn::Unix(s) => s.flush(),
+        }
+    }
+}
+
+extern "C" fn KickHandler(_sig: libc::c_int) {}
+
+//SIGUSR1 only has to interrupt KVM_RUN with EINTR, the default action would kill qvisor
+pub fn InstallKickHandler() -> Result<()> {
+    unsafe {
+        let mut act: libc::sigaction = std::mem::zeroed();
+        act.sa_sigaction = KickHandler as usize;
+        libc::sigemptyset(&mut act.sa_mask);
+        if libc::sigaction(libc::SIGUSR1, &act, ptr::null_mut()) < 0 {
+            return Err(Error::IOError(format!("sigaction fail, io::error is {:?}", std::io::Error::last_os_error())))
+        }
+    }
+
+    return Ok(())
+}
+
+//a gdb remote serial protocol server for vcpu 0. The run loop hands the vcpu to HandleStop whenever
+//it stops for the debugger, and gdb's ^C is turned into a kick of the vcpu thread by the reader thread
+pub struct GdbStub {
+    conn: Conn,
+    input: Receiver<u8>,
+    running: Arc<AtomicBool>,
+    interrupted: Arc<AtomicBool>,
+    reader: Option<JoinHandle<()>>,
+
+    swBreakpoints: BTreeMap<u64, u8>,
+    hwBreakpoints: [Option<HwBreakpoint>; HW_BREAKPOINT_COUNT],
+    singleStep: bool,
+    lastStop: String,
+}
+
+impl Drop for GdbStub {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+        //the reader thread sees the end of the input and exits
+        self.conn.Shutdown();
+        if let Some(reader) = self.reader.take() {
+            let _ = reader.join();
+        }
+    }
+}
+
+impl GdbStub {
+    //addr is "unix:<path>" or a tcp "host:port". Must be called on the vcpu thread, which is the
+    //one kicked on ^C, immediateExitAddr is the immediate_exit byte of its kvm_run
+    pub fn Listen(addr: &str, immediateExitAddr: u64) -> Result<Self> {
+        let ioErr = |e: std::io::Error| Error::IOError(format!("io::error is {:?}", e));
+
+        println!("waiting for gdb on {}", addr);
+        let conn = if addr.starts_with("unix:") {
+            let listener = UnixListener::bind(&addr[5..]).map_err(ioErr)?;
+            let (stream, _) = listener.accept().map_err(ioErr)?;
+            Conn::Unix(stream)
+        } else {
+            let listener = TcpListener::bind(addr).map_err(ioErr)?;
+            let (stream, peer) = listener.accept().map_err(ioErr)?;
+            println!("gdb connected from {}", peer);
+            stream.set_nodelay(true).map_err(ioErr)?;
+            Conn::Tcp(stream)
+        };
+
+        InstallKickHandler()?;
+
+        let (tx, rx) = channel();
+        let running = Arc::new(AtomicBool::new(false));
+        let interrupted = Arc::new(AtomicBool::new(false));
+        let vcpuThread = unsafe { libc::pthread_self() };
+
+        let mut input = conn.TryClone().map_err(ioErr)?;
+        let threadRunning = running.clone();
+        let threadInterrupted = interrupted.clone();
+        let reader = thread::spawn(move || {
+            let mut buf = [0; 1024];
+            loop {
+                let n = match input.read(&mut buf) {
+                    Ok(0) | Err(_) => return,
+                    Ok(n) => n,
+                };
+
+                for &b in &buf[..n] {
+                    if b == 0x03 && threadRunning.load(Ordering::SeqCst) {
+                        threadInterrupted.store(true, Ordering::SeqCst);
+                        //immediate_exit covers a kick which lands before the vcpu enters KVM_RUN
+                        unsafe {
+                            ptr::write_volatile(immediateExitAddr as *mut u8, 1);
+                            libc::pthread_kill(vcpuThread, libc::SIGUSR1);
+                        }
+                        continue;
+                    }
+
+                    if tx.send(b).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+
+        return Ok(GdbStub {
+            conn: conn,
+            input: rx,
+            running: running,
+            interrupted: interrupted,
+            reader: Some(reader),
+            swBreakpoints: BTreeMap::new(),
+            hwBreakpoints: [None; HW_BREAKPOINT_COUNT],
+            singleStep: false,
+            lastStop: format!("S{:02x}", SIGTRAP),
+        })
+    }
+
+    //whether the vcpu was kicked for gdb's ^C, clears the request
+    pub fn TakeInterrupt(&self) -> bool {
+        return self.interrupted.swap(false, Ordering::SeqCst)
+    }
+
+    //talk to gdb until it resumes the stopped vcpu
+    pub fn HandleStop(&mut self, vcpu: &VcpuFd, reader: &GuestMemory, reason: StopReason) -> Result<GdbResume> {
+        self.running.store(false, Ordering::SeqCst);
+        self.lastStop = self.StopReply(&reason);
+
+        match reason {
+            StopReason::Attach => (),
+            _ => {
+                let reply = self.lastStop.clone();
+                if self.PutPacket(&reply).is_err() {
+                    return self.Detach(vcpu, reader);
+                }
+            }
+        }
+
+        loop {
+            let packet = match self.GetPacket() {
+                Ok(p) => p,
+                Err(_) => {
+                    println!("gdb connection lost, detach");
+                    return self.Detach(vcpu, reader);
+                }
+            };
+
+            match self.Dispatch(vcpu, reader, &packet) {
+                Ok(Some(resume)) => return Ok(resume),
+                Ok(None) => (),
+                Err(e) => {
+                    println!("gdb connection lost with {:?}, detach", e);
+                    return self.Detach(vcpu, reader);
+                }
+            }
+        }
+    }
+
+    fn StopReply(&self, reason: &StopReason) -> String {
+        match reason {
+            StopReason::Attach => format!("S{:02x}", SIGTRAP),
+            StopReason::Interrupt => format!("S{:02x}", SIGINT),
+            StopReason::Panic => format!("S{:02x}", SIGABRT),
+            //#BP from an int3 gdb inserted
+            StopReason::Debug(3, _) => format!("T{:02x}swbreak:;", SIGTRAP),
+            StopReason::Debug(_, dr6) => {
+                for i in 0..HW_BREAKPOINT_COUNT {
+                    if dr6 & (1 << i) == 0 {
+                        continue;
+                    }
+
+                    if let Some(bp) = self.hwBreakpoints[i] {
+                        return match bp.kind {
+                            HwKind::Exec => format!("T{:02x}hwbreak:;", SIGTRAP),
+                            HwKind::Write => format!("T{:02x}watch:{:x};", SIGTRAP, bp.addr),
+                            HwKind::Access => format!("T{:02x}awatch:{:x};", SIGTRAP, bp.addr),
+                        }
+                    }
+                }
+
+                //single step
+                format!("S{:02x}", SIGTRAP)
+            }
+        }
+    }
+
+    //handle one packet, return the resume action when gdb lets the vcpu go
+    fn Dispatch(&mut self, vcpu: &VcpuFd, reader: &GuestMemory, packet: &str) -> Result<Option<GdbResume>> {
+        let (cmd, args) = match packet.chars().next() {
+            Some(c) => (c, &packet[c.len_utf8()..]),
+            None => {
+                self.PutPacket("")?;
+                return Ok(None)
+            }
+        };
+
+        let reply = match cmd {
+            '?' => self.lastStop.clone(),
+            'g' => match ReadRegisters(vcpu) {
+                Ok(regs) => Hex(&regs),
+                Err(_) => String::from("E01"),
+            },
+            'G' => match UnHex(args) {
+                Some(data) => Reply(WriteRegisters(vcpu, &data)),
+                None => String::from("E01"),
+            },
+            'p' => match (ParseHex(args).and_then(RegSlot), ReadRegisters(vcpu)) {
+                (Some((offset, len)), Ok(regs)) => Hex(&regs[offset..offset + len]),
+                _ => String::from("E01"),
+            },
+            'P' => self.WriteRegister(vcpu, args),
+            'm' => self.ReadMemory(vcpu, reader, args),
+            'M' => self.WriteMemory(vcpu, reader, args),
+            'Z' => self.InsertBreakpoint(vcpu, reader, args),
+            'z' => self.RemoveBreakpoint(vcpu, reader, args),
+            'c' | 's' => {
+                if args.len() > 0 {
+                    match ParseHex(args) {
+                        Some(addr) => SetRip(vcpu, addr)?,
+                        None => {
+                            self.PutPacket("E01")?;
+                            return Ok(None)
+                        }
+                    }
+                }
+
+                self.singleStep = cmd == 's';
+                self.SetGuestDebug(vcpu, true)?;
+                self.running.store(true, Ordering::SeqCst);
+                return Ok(Some(GdbResume::Continue))
+            }
+            'D' => {
+                self.PutPacket("OK")?;
+                return self.Detach(vcpu, reader).map(|r| Some(r))
+            }
+            'k' => return Ok(Some(GdbResume::Kill)),
+            'H' | 'T' => String::from("OK"),
+            'q' => self.Query(args),
+            'v' if args.starts_with("Kill") => {
+                self.PutPacket("OK")?;
+                return Ok(Some(GdbResume::Kill))
+            }
+            _ => String::new(),
+        };
+
+        self.PutPacket(&reply)?;
+        return Ok(None)
+    }
+
+    fn Query(&self, args: &str) -> String {
+        if args.starts_with("Supported") {
+            return format!("PacketSize={:x};swbreak+;hwbreak+", PACKET_SIZE)
+        }
+
+        match args {
+            "Attached" => String::from("1"),
+            "C" => String::from("QC1"),
+            "fThreadInfo" => String::from("m1"),
+            "sThreadInfo" => String::from("l"),
+            _ => String::new(),
+        }
+    }
+
+    fn WriteRegister(&self, vcpu: &VcpuFd, args: &str) -> String {
+        let mut parts = args.splitn(2, '=');
+        let slot = parts.next().and_then(ParseHex).and_then(RegSlot);
+        let val = parts.next().and_then(UnHex);
+        let (offset, len, val) = match (slot, val) {
+            (Some((offset, len)), Some(val)) if val.len() == len => (offset, len, val),
+            _ => return String::from("E01"),
+        };
+
+        let mut regs = match ReadRegisters(vcpu) {
+            Ok(r) => r,
+            Err(_) => return String::from("E01"),
+        };
+
+        regs[offset..offset + len].copy_from_slice(&val);
+        return Reply(WriteRegisters(vcpu, &regs))
+    }
+
+    fn ReadMemory(&self, vcpu: &VcpuFd, reader: &GuestMemory, args: &str) -> String {
+        let (addr, len) = match ParseAddrLen(args) {
+            Some(v) => v,
+            None => return String::from("E01"),
+        };
+
+        let cr3 = match vcpu.get_sregs() {
+            Ok(sregs) => sregs.cr3,
+            Err(_) => return String::from("E01"),
+        };
+
+        let mut buf = vec![0; core::cmp::min(len as usize, MAX_MEM_XFER)];
+        let n = reader.ReadVirt(cr3, addr, &mut buf);
+        if n == 0 && buf.len() > 0 {
+            return String::from("E14");
+        }
+
+        //show the guest's own bytes, not the int3 gdb planted
+        for (&bpAddr, &orig) in self.swBreakpoints.range(addr..addr.saturating_add(n as u64)) {
+            buf[(bpAddr - addr) as usize] = orig;
+        }
+
+        return Hex(&buf[..n])
+    }
+
+    fn WriteMemory(&self, vcpu: &VcpuFd, reader: &GuestMemory, args: &str) -> String {
+        let mut parts = args.splitn(2, ':');
+        let addrLen = parts.next().and_then(ParseAddrLen);
+        let data = parts.next().and_then(UnHex);
+        let (addr, data) = match (addrLen, data) {
+            (Some((addr, len)), Some(data)) if data.len() as u64 == len => (addr, data),
+            _ => return String::from("E01"),
+        };
+
+        let cr3 = match vcpu.get_sregs() {
+            Ok(sregs) => sregs.cr3,
+            Err(_) => return String::from("E01"),
+        };
+
+        return match reader.WriteVirt(cr3, addr, &data) {
+            Ok(()) => String::from("OK"),
+            Err(_) => String::from("E14"),
+        }
+    }
+
+    fn InsertBreakpoint(&mut self, vcpu: &VcpuFd, reader: &GuestMemory, args: &str) -> String {
+        let (ty, addr, len) = match ParseBreakpoint(args) {
+            Some(v) => v,
+            None => return String::from("E01"),
+        };
+
+        if ty == 0 {
+            if self.swBreakpoints.contains_key(&addr) {
+                return String::from("OK");
+            }
+
+            let cr3 = match vcpu.get_sregs() {
+                Ok(sregs) => sregs.cr3,
+                Err(_) => return String::from("E01"),
+            };
+
+            let mut orig = [0; 1];
+            if reader.ReadVirt(cr3, addr, &mut orig) != 1 || reader.WriteVirt(cr3, addr, &[INT3]).is_err() {
+                return String::from("E14");
+            }
+
+            self.swBreakpoints.insert(addr, orig[0]);
+            return String::from("OK");
+        }
+
+        let kind = match ty {
+            1 => HwKind::Exec,
+            2 => HwKind::Write,
+            4 => HwKind::Access,
+            //x86 can't trap on reads only
+            _ => return String::new(),
+        };
+
+        let len = if kind == HwKind::Exec { 1 } else { len };
+        if !(len == 1 || len == 2 || len == 4 || len == 8) || addr & (len - 1) != 0 {
+            return String::from("E22");
+        }
+
+        let bp = HwBreakpoint { addr, len, kind };
+        match self.hwBreakpoints.iter().position(|b| b.is_none()) {
+            Some(idx) => {
+                self.hwBreakpoints[idx] = Some(bp);
+                String::from("OK")
+            }
+            None => String::from("E28"),
+        }
+    }
+
+    fn RemoveBreakpoint(&mut self, vcpu: &VcpuFd, reader: &GuestMemory, args: &str) -> String {
+        let (ty, addr, _len) = match ParseBreakpoint(args) {
+            Some(v) => v,
+            None => return String::from("E01"),
+        };
+
+        if ty == 0 {
+            let orig = match self.swBreakpoints.remove(&addr) {
+                Some(orig) => orig,
+                None => return String::from("OK"),
+            };
+
+            let cr3 = match vcpu.get_sregs() {
+                Ok(sregs) => sregs.cr3,
+                Err(_) => return String::from("E01"),
+            };
+
+            return Reply(reader.WriteVirt(cr3, addr, &[orig]))
+        }
+
+        for slot in self.hwBreakpoints.iter_mut() {
+            if let Some(bp) = slot {
+                if bp.addr == addr {
+                    *slot = None;
+                    return String::from("OK");
+                }
+            }
+        }
+
+        return String::from("OK")
+    }
+
+    //drop every breakpoint and let the guest run without debugging
+    fn Detach(&mut self, vcpu: &VcpuFd, reader: &GuestMemory) -> Result<GdbResume> {
+        if let Ok(sregs) = vcpu.get_sregs() {
+            for (&addr, &orig) in &self.swBreakpoints {
+                let _ = reader.WriteVirt(sregs.cr3, addr, &[orig]);
+            }
+        }
+
+        self.swBreakpoints.clear();
+        self.hwBreakpoints = [None; HW_BREAKPOINT_COUNT];
+        self.singleStep = false;
+        self.SetGuestDebug(vcpu, false)?;
+        return Ok(GdbResume::Detach)
+    }
+
+    fn SetGuestDebug(&self, vcpu: &VcpuFd, enable: bool) -> Result<()> {
+        let mut dbg = KvmGuestDebug::default();
+        if enable {
+            dbg.control = KVM_GUESTDBG_ENABLE | KVM_GUESTDBG_USE_SW_BP;
+            if self.singleStep {
+                dbg.control |= KVM_GUESTDBG_SINGLESTEP;
+            }
+
+            for (i, bp) in self.hwBreakpoints.iter().enumerate() {
+                if let Some(bp) = bp {
+                    dbg.control |= KVM_GUESTDBG_USE_HW_BP;
+                    dbg.debugreg[i] = bp.addr;
+                    dbg.debugreg[7] |= bp.Dr7(i);
+                }
+            }
+        }
+
+        Cpu::Ioctl(vcpu.as_raw_fd(), super::KVM_SET_GUEST_DEBUG, &mut dbg as *mut KvmGuestDebug)?;
+        return Ok(())
+    }
+
+    fn ReadByte(&self) -> Result<u8> {
+        return self.input.recv().map_err(|_| Error::IOError(String::from("gdb connection closed")))
+    }
+
+    fn GetPacket(&mut self) -> Result<String> {
+        loop {
+            //acks and stray ^C between packets
+            while self.ReadByte()? != b'$' {}
+
+            let mut data = Vec::new();
+            let mut sum: u8 = 0;
+            loop {
+                let b = self.ReadByte()?;
+                if b == b'#' {
+                    break;
+                }
+
+                sum = sum.wrapping_add(b);
+                data.push(b);
+                if data.len() > PACKET_SIZE {
+                    return Err(Error::Common(String::from("gdb packet too long")));
+                }
+            }
+
+            let check = [self.ReadByte()?, self.ReadByte()?];
+            let expected = std::str::from_utf8(&check).ok().and_then(|s| u8::from_str_radix(s, 16).ok());
+            if expected != Some(sum) {
+                self.Send(b"-")?;
+                continue;
+            }
+
+            self.Send(b"+")?;
+            return Ok(String::from_utf8_lossy(&data).into_owned())
+        }
+    }
+
+    fn PutPacket(&mut self, data: &str) -> Result<()> {
+        let sum = data.bytes().fold(0u8, |s, b| s.wrapping_add(b));
+        let packet = format!("${}#{:02x}", data, sum);
+        loop {
+            self.Send(packet.as_bytes())?;
+            loop {
+                match self.ReadByte()? {
+                    b'+' => return Ok(()),
+                    b'-' => break,
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    fn Send(&mut self, data: &[u8]) -> Result<()> {
+        self.conn.write_all(data).map_err(|e| Error::IOError(format!("io::error is {:?}", e)))?;
+        return self.conn.flush().map_err(|e| Error::IOError(format!("io::error is {:?}", e)))
+    }
+}
+
+fn Reply(res: Result<()>) -> String {
+    match res {
+        Ok(()) => String::from("OK"),
+        Err(_) => String::from("E01"),
+    }
+}
+
+fn Hex(data: &[u8]) -> String {
+    let mut s = String::with_capacity(data.len() * 2);
+    for b in data {
+        write!(s, "{:02x}", b).unwrap();
+    }
+
+    return s;
+}
+
+fn UnHex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+
+    let mut data = Vec::with_capacity(s.len() / 2);
+    for i in (0..s.len()).step_by(2) {
+        data.push(u8::from_str_radix(s.get(i..i + 2)?, 16).ok()?);
+    }
+
+    return Some(data)
+}
+
+fn ParseHex(s: &str) -> Option<u64> {
+    return u64::from_str_radix(s, 16).ok()
+}
+
+//"addr,len"
+fn ParseAddrLen(s: &str) -> Option<(u64, u64)> {
+    let mut parts = s.splitn(2, ',');
+    let addr = ParseHex(parts.next()?)?;
+    let len = ParseHex(parts.next()?)?;
+    return Some((addr, len))
+}
+
+//"type,addr,kind"
+fn ParseBreakpoint(s: &str) -> Option<(u64, u64, u64)> {
+    let mut parts = s.splitn(3, ',');
+    let ty = ParseHex(parts.next()?)?;
+    let addr = ParseHex(parts.next()?)?;
+    let kind = ParseHex(parts.next()?.split(';').next()?)?;
+    return Some((ty, addr, kind))
+}
+
+fn RegSize(idx: usize) -> usize {
+    match idx {
+        0..=16 => 8,
+        24..=31 => 10,
+        40..=55 => 16,
+        _ => 4,
+    }
+}
+
+//(offset, len) of register idx in the 'g' packet
+fn RegSlot(idx: u64) -> Option<(usize, usize)> {
+    let idx = idx as usize;
+    if idx >= REG_COUNT {
+        return None;
+    }
+
+    let offset = (0..idx).map(RegSize).sum();
+    return Some((offset, RegSize(idx)))
+}
+
+fn GpRegs(regs: &mut kvm_regs) -> [&mut u64; 17] {
+    return [
+        &mut regs.rax, &mut regs.rbx, &mut regs.rcx, &mut regs.rdx, &mut regs.rsi, &mut regs.rdi,
+        &mut regs.rbp, &mut regs.rsp, &mut regs.r8, &mut regs.r9, &mut regs.r10, &mut regs.r11,
+        &mut regs.r12, &mut regs.r13, &mut regs.r14, &mut regs.r15, &mut regs.rip,
+    ]
+}
+
+//kvm has the abridged FXSAVE tag, gdb wants the full x87 tag word: valid(00) or empty(11)
+fn FullTag(ftwx: u8) -> u32 {
+    let mut tag = 0;
+    for i in 0..8 {
+        if ftwx & (1 << i) == 0 {
+            tag |= 0b11 << (i * 2);
+        }
+    }
+
+    return tag;
+}
+
+fn AbridgedTag(tag: u32) -> u8 {
+    let mut ftwx = 0;
+    for i in 0..8 {
+        if (tag >> (i * 2)) & 0b11 != 0b11 {
+            ftwx |= 1 << i;
+        }
+    }
+
+    return ftwx;
+}
+
+fn ReadRegisters(vcpu: &VcpuFd) -> Result<Vec<u8>> {
+    let mut regs = vcpu.get_regs().map_err(|e| Error::IOError(format!("io::error is {:?}", e)))?;
+    let sregs = vcpu.get_sregs().map_err(|e| Error::IOError(format!("io::error is {:?}", e)))?;
+    let fpu = vcpu.get_fpu().map_err(|e| Error::IOError(format!("io::error is {:?}", e)))?;
+
+    let mut buf = Vec::new();
+    let rflags = regs.rflags;
+    for r in GpRegs(&mut regs).iter() {
+        buf.extend_from_slice(&r.to_le_bytes());
+    }
+
+    buf.extend_from_slice(&(rflags as u32).to_le_bytes());
+    for s in &[sregs.cs, sregs.ss, sregs.ds, sregs.es, sregs.fs, sregs.gs] {
+        buf.extend_from_slice(&(s.selector as u32).to_le_bytes());
+    }
+
+    for st in fpu.fpr.iter() {
+        buf.extend_from_slice(&st[..10]);
+    }
+
+    let ctrl = [
+        fpu.fcw as u32, fpu.fsw as u32, FullTag(fpu.ftwx), 0,
+        fpu.last_ip as u32, 0, fpu.last_dp as u32, fpu.last_opcode as u32,
+    ];
+    for c in &ctrl {
+        buf.extend_from_slice(&c.to_le_bytes());
+    }
+
+    for xmm in fpu.xmm.iter() {
+        buf.extend_from_slice(xmm);
+    }
+
+    buf.extend_from_slice(&fpu.mxcsr.to_le_bytes());
+    return Ok(buf)
+}
+
+//the segment selectors are read only, qkernel's descriptors are fixed
+fn WriteRegisters(vcpu: &VcpuFd, buf: &[u8]) -> Result<()> {
+    let total = RegSlot(REG_COUNT as u64 - 1).map(|(o, l)| o + l).unwrap();
+    if buf.len() < total {
+        return Err(Error::Common(format!("short register packet {}", buf.len())));
+    }
+
+    let u64At = |o: usize| {
+        let mut b = [0; 8];
+        b.copy_from_slice(&buf[o..o + 8]);
+        u64::from_le_bytes(b)
+    };
+
+    let u32At = |o: usize| {
+        let mut b = [0; 4];
+        b.copy_from_slice(&buf[o..o + 4]);
+        u32::from_le_bytes(b)
+    };
+
+    let mut regs = vcpu.get_regs().map_err(|e| Error::IOError(format!("io::error is {:?}", e)))?;
+    for (i, r) in GpRegs(&mut regs).iter_mut().enumerate() {
+        **r = u64At(i * 8);
+    }
+
+    regs.rflags = (regs.rflags & !0xffff_ffff) | u32At(RegSlot(17).unwrap().0) as u64;
+
+    let mut fpu: kvm_fpu = vcpu.get_fpu().map_err(|e| Error::IOError(format!("io::error is {:?}", e)))?;
+    let stOffset = RegSlot(24).unwrap().0;
+    for i in 0..8 {
+        fpu.fpr[i][..10].copy_from_slice(&buf[stOffset + i * 10..stOffset + i * 10 + 10]);
+    }
+
+    let ctrlOffset = RegSlot(32).unwrap().0;
+    fpu.fcw = u32At(ctrlOffset) as u16;
+    fpu.fsw = u32At(ctrlOffset + 4) as u16;
+    fpu.ftwx = AbridgedTag(u32At(ctrlOffset + 8));
+    fpu.last_ip = u32At(ctrlOffset + 16) as u64;
+    fpu.last_dp = u32At(ctrlOffset + 24) as u64;
+    fpu.last_opcode = u32At(ctrlOffset + 28) as u16;
+
+    let xmmOffset = RegSlot(40).unwrap().0;
+    for i in 0..16 {
+        fpu.xmm[i].copy_from_slice(&buf[xmmOffset + i * 16..xmmOffset + i * 16 + 16]);
+    }
+
+    fpu.mxcsr = u32At(RegSlot(56).unwrap().0);
+
+    vcpu.set_regs(&regs).map_err(|e| Error::IOError(format!("io::error is {:?}", e)))?;
+    vcpu.set_fpu(&fpu).map_err(|e| Error::IOError(format!("io::error is {:?}", e)))?;
+    return Ok(())
+}
+
+fn SetRip(vcpu: &VcpuFd, rip: u64) -> Result<()> {
+    let mut regs = vcpu.get_regs().map_err(|e| Error::IOError(format!("io::error is {:?}", e)))?;
+    regs.rip = rip;
+    vcpu.set_regs(&regs).map_err(|e| Error::IOError(format!("io::error is {:?}", e)))?;
+    return Ok(())
+}
@@ -0,0 +1,190 @@
+use core::mem::size_of;
+use std::ptr;
+
+use kvm_bindings::{kvm_dtable, kvm_segment};
+
+use super::qlib;
+use super::qlib::Common::Error;
+use super::qlib::Common::Result;
+use super::qlib::Addr::Addr;
+use super::qlib::PageTable::PagePool;
+use super::GuestMemory::GuestMemory;
+
+// long mode descriptors: flat, 4KB granularity
+const KERNEL_CODE_DESC: u64 = 0x00af_9b00_0000_ffff;
+const KERNEL_DATA_DESC: u64 = 0x00cf_9300_0000_ffff;
+const USER_DATA_DESC: u64 = 0x00cf_f300_0000_ffff;
+const USER_CODE_DESC: u64 = 0x00af_fb00_0000_ffff;
+
+// available 64-bit TSS, the cpu marks it busy(11) when TR is loaded
+const TSS_TYPE_AVAILABLE: u64 = 0x9;
+const TSS_TYPE_BUSY: u8 = 0xb;
+
+const IDT_ENTRY_SIZE: u64 = 16;
+const IDT_MAX_SIZE: u64 = 256 * IDT_ENTRY_SIZE;
+
+#[repr(C, packed)]
+#[derive(Default, Copy, Clone)]
+pub struct TaskStateSegment {
+    reserved0: u32,
+    pub rsp: [u64; 3],
+    reserved1: u64,
+    pub ist: [u64; 7],
+    reserved2: u64,
+    reserved3: u16,
+    pub iomapBase: u16,
+}
+
+//the layout of the lidt operand the guest passes for HYPERCALL_LOADIDT
+#[repr(C, packed)]
+#[derive(Default, Copy, Clone)]
+pub struct DescriptorTablePointer {
+    pub limit: u16,
+    pub base: u64,
+}
+
+//GDT, TSSs and a placeholder IDT in guest memory. The pages come from the page pool, which is
+//identity mapped, so the guest physical address is both the host address and the guest virtual address
+pub struct DescTables {
+    pub gdtAddr: u64,
+    pub gdtLimit: u16,
+    pub tssAddr: u64,
+    pub idtAddr: u64,
+    pub vcpuCount: usize,
+}
+
+impl DescTables {
+    //stackTop(i) returns the top of the interrupt stack region of vcpu i: rsp0 is at the top,
+    //the double fault IST stack is the lower half
+    pub fn Init(pagePool: &mut PagePool, vcpuCount: usize, stackTop: &dyn Fn(usize) -> u64, stackSize: u64) -> Result<Self> {
+        if vcpuCount == 0 || vcpuCount > qlib::MAX_VCPU_COUNT {
+            return Err(Error::Common(format!("unsupported vcpu count {}", vcpuCount)));
+        }
+
+        let gdtAddr = Self::ZeroPage(pagePool)?;
+        let tssAddr = Self::ZeroPage(pagePool)?;
+        let idtAddr = Self::ZeroPage(pagePool)?;
+
+        let gdt = gdtAddr as *mut u64;
+        unsafe {
+            *gdt.offset(0) = 0;
+            *gdt.offset((qlib::KERNEL_CODE_SEL >> 3) as isize) = KERNEL_CODE_DESC;
+            *gdt.offset((qlib::KERNEL_DATA_SEL >> 3) as isize) = KERNEL_DATA_DESC;
+            *gdt.offset((qlib::USER_DATA_SEL >> 3) as isize) = USER_DATA_DESC;
+            *gdt.offset((qlib::USER_CODE_SEL >> 3) as isize) = USER_CODE_DESC;
+        }
+
+        for i in 0..vcpuCount {
+            let top = stackTop(i);
+            let mut tss = TaskStateSegment::default();
+            tss.rsp[0] = top;
+            tss.ist[qlib::DOUBLE_FAULT_IST_INDEX as usize] = top - stackSize / 2;
+            tss.iomapBase = size_of::<TaskStateSegment>() as u16;
+
+            let addr = Self::TssAddr(tssAddr, i);
+            unsafe {
+                ptr::write_unaligned(addr as *mut TaskStateSegment, tss);
+            }
+
+            let (low, high) = Self::TssDescriptor(addr);
+            let idx = (qlib::TssSelector(i) >> 3) as isize;
+            unsafe {
+                *gdt.offset(idx) = low;
+                *gdt.offset(idx + 1) = high;
+            }
+        }
+
+        let entries = (qlib::TssSelector(vcpuCount) >> 3) as u64;
+
+        return Ok(DescTables {
+            gdtAddr,
+            gdtLimit: (entries * 8 - 1) as u16,
+            tssAddr,
+            idtAddr,
+            vcpuCount,
+        })
+    }
+
+    fn ZeroPage(pagePool: &mut PagePool) -> Result<u64> {
+        let addr = pagePool.Allocate()?.0;
+        unsafe {
+            ptr::write_bytes(addr as *mut u8, 0, qlib::PAGE_SIZE as usize);
+        }
+
+        return Ok(addr)
+    }
+
+    fn TssAddr(tssAddr: u64, vcpuId: usize) -> u64 {
+        return tssAddr + (vcpuId * size_of::<TaskStateSegment>()) as u64
+    }
+
+    fn TssDescriptor(base: u64) -> (u64, u64) {
+        let limit = (size_of::<TaskStateSegment>() - 1) as u64;
+
+        let mut low = limit & 0xffff;
+        low |= (base & 0xff_ffff) << 16;
+        low |= TSS_TYPE_AVAILABLE << 40;
+        low |= 1 << 47; //present
+        low |= ((limit >> 16) & 0xf) << 48;
+        low |= ((base >> 24) & 0xff) << 56;
+
+        let high = base >> 32;
+        return (low, high)
+    }
+
+    pub fn SetSregs(&self, vcpuId: usize, sregs: &mut kvm_bindings::kvm_sregs) {
+        sregs.gdt = kvm_dtable {
+            base: self.gdtAddr,
+            limit: self.gdtLimit,
+            ..Default::default()
+        };
+
+        //a zero IDT until the guest installs its own one with HYPERCALL_LOADIDT
+        sregs.idt = kvm_dtable {
+            base: self.idtAddr,
+            limit: (IDT_MAX_SIZE - 1) as u16,
+            ..Default::default()
+        };
+
+        sregs.tr = kvm_segment {
+            base: Self::TssAddr(self.tssAddr, vcpuId),
+            limit: (size_of::<TaskStateSegment>() - 1) as u32,
+            selector: qlib::TssSelector(vcpuId),
+            type_: TSS_TYPE_BUSY,
+            present: 1,
+            dpl: 0,
+            db: 0,
+            s: 0,
+            l: 0,
+            g: 0,
+            avl: 0,
+            padding: 0,
+            unusable: 0,
+        };
+    }
+}
+
+//read and check the lidt operand at guest virtual address ptrAddr. return the new IDTR value
+pub fn ReadIdtPointer(mem: &GuestMemory, ptrAddr: u64) -> Result<kvm_dtable> {
+    let ptr: DescriptorTablePointer = mem.ReadObjVirt(ptrAddr)?;
+
+    let limit = ptr.limit as u64;
+    let base = ptr.base;
+    if (limit + 1) % IDT_ENTRY_SIZE != 0 || limit + 1 > IDT_MAX_SIZE {
+        return Err(Error::Common(format!("invalid idt limit {:x}", limit)));
+    }
+
+    //the whole table has to be mapped
+    let end = Addr(base).AddLen(limit)?;
+    let mut page = Addr(base).RoundDown()?;
+    while page.0 <= end.0 {
+        mem.VirtToPhy(page.0)?;
+        page = page.AddLen(qlib::PAGE_SIZE)?;
+    }
+
+    return Ok(kvm_dtable {
+        base: base,
+        limit: limit as u16,
+        ..Default::default()
+    })
+}
@@ -0,0 +1,286 @@
+use core::mem::size_of;
+use std::collections::BTreeSet;
+use std::ptr;
+use std::slice;
+
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+use super::qlib;
+use super::qlib::Common::Error;
+use super::qlib::Common::Result;
+
+const PTE_PRESENT: u64 = 1;
+const PTE_WRITABLE: u64 = 1 << 1;
+const PTE_HUGE: u64 = 1 << 7;
+const PTE_NX: u64 = 1 << 63;
+const PTE_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+lazy_static! {
+    //the guest physical pages written through a GuestMemory while tracking is on. The kvm dirty log
+    //only has the writes of the guest, a migration sends these again with the vcpu paused
+    static ref HOST_WRITES: Mutex<Option<BTreeSet<u64>>> = Mutex::new(None);
+}
+
+//start recording the host writes, dropping what was recorded before
+pub fn TrackHostWrites() {
+    *HOST_WRITES.lock() = Some(BTreeSet::new());
+}
+
+//stop recording and return the pages written since TrackHostWrites
+pub fn TakeHostWrites() -> Vec<u64> {
+    return match HOST_WRITES.lock().take() {
+        Some(pages) => pages.into_iter().collect(),
+        None => Vec::new(),
+    }
+}
+
+fn RecordHostWrite(addr: u64, len: u64) {
+    if let Some(pages) = HOST_WRITES.lock().as_mut() {
+        let mut page = addr & !(qlib::PAGE_SIZE - 1);
+        while page < addr + len {
+            pages.insert(page);
+            page += qlib::PAGE_SIZE;
+        }
+    }
+}
+
+//a guest physical range backed by host memory
+#[derive(Debug, Clone, Copy)]
+pub struct GuestRegion {
+    pub start: u64,
+    pub len: u64,
+    pub hostAddr: u64,
+    //false for the memory of a readonly host file, whose host mapping is PROT_READ
+    pub writable: bool,
+}
+
+//host access to guest memory, for the hypercalls, gdb, the core dump and the diagnostics. A guest
+//physical access has to fall inside one region, and a write inside a writable one. Guest virtual
+//addresses are translated with the page tables at cr3, whose tables have to be inside the regions
+//too, so the guest can't make qvisor touch other host memory. The objects read and written are plain
+//data, valid for any bits the guest leaves in them
+pub struct GuestMemory {
+    regions: Vec<GuestRegion>,
+    //the kernel page tables, for the accesses without an explicit cr3
+    cr3: u64,
+}
+
+impl GuestMemory {
+    pub fn New(regions: Vec<GuestRegion>, cr3: u64) -> Self {
+        return GuestMemory {
+            regions: regions,
+            cr3: cr3,
+        }
+    }
+
+    fn Region(&self, addr: u64, len: u64) -> Result<&GuestRegion> {
+        let end = addr.checked_add(len).ok_or(Error::Overflow)?;
+        for r in &self.regions {
+            if r.start <= addr && end <= r.start + r.len {
+                return Ok(r)
+            }
+        }
+
+        return Err(Error::AddressNotInRange)
+    }
+
+    pub fn Contains(&self, addr: u64, len: u64) -> bool {
+        return self.Region(addr, len).is_ok()
+    }
+
+    //the host address of the guest physical [addr, addr + len), which must be inside one region
+    pub fn HostAddr(&self, addr: u64, len: u64) -> Result<u64> {
+        let r = self.Region(addr, len)?;
+        return Ok(r.hostAddr + (addr - r.start))
+    }
+
+    fn WritableRegion(&self, addr: u64, len: u64) -> Result<&GuestRegion> {
+        let r = self.Region(addr, len)?;
+        if !r.writable {
+            return Err(Error::Common(format!("guest memory {:x} is readonly", addr)));
+        }
+
+        return Ok(r)
+    }
+
+    //HostAddr for a write
+    fn WritableHostAddr(&self, addr: u64, len: u64) -> Result<u64> {
+        let r = self.WritableRegion(addr, len)?;
+        RecordHostWrite(addr, len);
+        return Ok(r.hostAddr + (addr - r.start))
+    }
+
+    pub fn ReadSlice(&self, addr: u64, buf: &mut [u8]) -> Result<()> {
+        let hostAddr = self.HostAddr(addr, buf.len() as u64)?;
+        unsafe {
+            ptr::copy_nonoverlapping(hostAddr as *const u8, buf.as_mut_ptr(), buf.len());
+        }
+
+        return Ok(())
+    }
+
+    pub fn WriteSlice(&self, addr: u64, buf: &[u8]) -> Result<()> {
+        let hostAddr = self.WritableHostAddr(addr, buf.len() as u64)?;
+        unsafe {
+            ptr::copy_nonoverlapping(buf.as_ptr(), hostAddr as *mut u8, buf.len());
+        }
+
+        return Ok(())
+    }
+
+    pub fn ReadObj<T: Copy>(&self, addr: u64) -> Result<T> {
+        let hostAddr = self.HostAddr(addr, size_of::<T>() as u64)?;
+        return Ok(unsafe { ptr::read_unaligned(hostAddr as *const T) })
+    }
+
+    pub fn WriteObj<T: Copy>(&self, addr: u64, obj: T) -> Result<()> {
+        let hostAddr = self.WritableHostAddr(addr, size_of::<T>() as u64)?;
+        unsafe {
+            ptr::write_unaligned(hostAddr as *mut T, obj);
+        }
+
+        return Ok(())
+    }
+
+    //walk the guest page tables at cr3
+    pub fn VirtToPhyAt(&self, cr3: u64, vaddr: u64) -> Result<u64> {
+        let mut table = cr3 & PTE_ADDR_MASK;
+        for level in (0..4).rev() {
+            let shift = 12 + 9 * level;
+            let idx = (vaddr >> shift) & 0x1ff;
+            let entry: u64 = self.ReadObj(table + idx * 8)?;
+            if entry & PTE_PRESENT == 0 {
+                return Err(Error::AddressNotMap);
+            }
+
+            if level == 0 || ((level == 1 || level == 2) && entry & PTE_HUGE != 0) {
+                let pageMask = (1u64 << shift) - 1;
+                return Ok((entry & PTE_ADDR_MASK & !pageMask) | (vaddr & pageMask));
+            }
+
+            table = entry & PTE_ADDR_MASK;
+        }
+
+        return Err(Error::AddressNotMap)
+    }
+
+    //walk the kernel page tables
+    pub fn VirtToPhy(&self, vaddr: u64) -> Result<u64> {
+        return self.VirtToPhyAt(self.cr3, vaddr)
+    }
+
+    //call f(vaddr, phyAddr, size, writable, executable) for every present leaf reachable from cr3,
+    //the tables outside the regions are skipped
+    pub fn ForEachMapping<F: FnMut(u64, u64, u64, bool, bool)>(&self, cr3: u64, f: &mut F) {
+        self.Walk(cr3 & PTE_ADDR_MASK, 3, 0, true, true, f);
+    }
+
+    fn Walk<F: FnMut(u64, u64, u64, bool, bool)>(&self, table: u64, level: u64, base: u64, writable: bool, executable: bool, f: &mut F) {
+        let shift = 12 + 9 * level;
+        for idx in 0..512 {
+            let entry: u64 = match self.ReadObj(table + idx * 8) {
+                Ok(e) => e,
+                Err(_) => return,
+            };
+
+            if entry & PTE_PRESENT == 0 {
+                continue;
+            }
+
+            let mut vaddr = base | (idx << shift);
+            if level == 3 && vaddr & (1 << 47) != 0 {
+                vaddr |= 0xffff_0000_0000_0000;
+            }
+
+            let w = writable && entry & PTE_WRITABLE != 0;
+            let x = executable && entry & PTE_NX == 0;
+            if level == 0 || ((level == 1 || level == 2) && entry & PTE_HUGE != 0) {
+                let size = 1u64 << shift;
+                f(vaddr, entry & PTE_ADDR_MASK & !(size - 1), size, w, x);
+            } else {
+                self.Walk(entry & PTE_ADDR_MASK, level - 1, vaddr, w, x, f);
+            }
+        }
+    }
+
+    //(phyAddr, offset, len) of the len bytes at vaddr, page by page
+    fn VirtPieces(&self, cr3: u64, vaddr: u64, len: usize) -> Result<Vec<(u64, usize, usize)>> {
+        let mut pieces = Vec::new();
+        let mut done = 0;
+        while done < len {
+            let addr = vaddr.checked_add(done as u64).ok_or(Error::Overflow)?;
+            let n = ((qlib::PAGE_SIZE - (addr & (qlib::PAGE_SIZE - 1))) as usize).min(len - done);
+            pieces.push((self.VirtToPhyAt(cr3, addr)?, done, n));
+            done += n;
+        }
+
+        return Ok(pieces)
+    }
+
+    //read guest virtual memory, stops at the first unmapped byte. return the count read
+    pub fn ReadVirt(&self, cr3: u64, vaddr: u64, buf: &mut [u8]) -> usize {
+        let mut done = 0;
+        while done < buf.len() {
+            let addr = vaddr.wrapping_add(done as u64);
+            let n = ((qlib::PAGE_SIZE - (addr & (qlib::PAGE_SIZE - 1))) as usize).min(buf.len() - done);
+            let phyAddr = match self.VirtToPhyAt(cr3, addr) {
+                Ok(phyAddr) => phyAddr,
+                Err(_) => break,
+            };
+
+            if self.ReadSlice(phyAddr, &mut buf[done..done + n]).is_err() {
+                break;
+            }
+
+            done += n;
+        }
+
+        return done;
+    }
+
+    //write guest virtual memory regardless of the guest page protection, all or nothing. The host
+    //protection still holds, a readonly region is refused
+    pub fn WriteVirt(&self, cr3: u64, vaddr: u64, buf: &[u8]) -> Result<()> {
+        let pieces = self.VirtPieces(cr3, vaddr, buf.len())?;
+        for &(phyAddr, _, n) in &pieces {
+            self.WritableRegion(phyAddr, n as u64)?;
+        }
+
+        for (phyAddr, offset, n) in pieces {
+            self.WriteSlice(phyAddr, &buf[offset..offset + n])?;
+        }
+
+        return Ok(())
+    }
+
+    //guest virtual memory with the kernel page tables, all or nothing
+    pub fn ReadSliceVirt(&self, vaddr: u64, buf: &mut [u8]) -> Result<()> {
+        for (phyAddr, offset, n) in self.VirtPieces(self.cr3, vaddr, buf.len())? {
+            self.ReadSlice(phyAddr, &mut buf[offset..offset + n])?;
+        }
+
+        return Ok(())
+    }
+
+    //the objects passed by guest virtual address may cross pages, they are copied piece by piece
+    pub fn ReadObjVirt<T: Copy>(&self, vaddr: u64) -> Result<T> {
+        let mut buf = vec![0u8; size_of::<T>()];
+        self.ReadSliceVirt(vaddr, &mut buf)?;
+        return Ok(unsafe { ptr::read_unaligned(buf.as_ptr() as *const T) })
+    }
+
+    pub fn WriteObjVirt<T: Copy>(&self, vaddr: u64, obj: T) -> Result<()> {
+        let buf = unsafe { slice::from_raw_parts(&obj as *const T as *const u8, size_of::<T>()) };
+        return self.WriteVirt(self.cr3, vaddr, buf)
+    }
+
+    //check the len bytes at vaddr are mapped by the kernel page tables to writable guest memory
+    pub fn CheckWritableVirt(&self, vaddr: u64, len: usize) -> Result<()> {
+        for (phyAddr, _, n) in self.VirtPieces(self.cr3, vaddr, len)? {
+            self.WritableRegion(phyAddr, n as u64)?;
+        }
+
+        return Ok(())
+    }
+}
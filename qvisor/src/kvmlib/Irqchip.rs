@@ -0,0 +1,99 @@
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+
+use kvm_bindings::{kvm_irqchip, kvm_lapic_state, kvm_pit_config, kvm_pit_state2};
+use kvm_ioctls::{VcpuFd, VmFd};
+
+use super::qlib::Common::Result;
+use super::Cpu;
+
+//KVM_GET_IRQCHIP and friends, the structs are sized 0x208 and 0x70
+const KVM_GET_IRQCHIP: u64 = 0xc208_ae62;
+const KVM_SET_IRQCHIP: u64 = 0x8208_ae63;
+const KVM_GET_PIT2: u64 = 0x8070_ae9f;
+const KVM_SET_PIT2: u64 = 0x4070_aea0;
+
+//the speaker port 0x61 is handled in the kernel too instead of exiting to qvisor
+const KVM_PIT_SPEAKER_DUMMY: u32 = 1;
+
+//the 8259 master and slave and the ioapic, KVM_IRQCHIP_PIC_MASTER..KVM_IRQCHIP_IOAPIC
+pub const IRQCHIP_COUNT: usize = 3;
+
+const APIC_LVT0: usize = 0x350;
+const APIC_LVT1: usize = 0x360;
+const APIC_MODE_NMI: u32 = 0x4;
+const APIC_MODE_EXTINT: u32 = 0x7;
+
+//the in-kernel pic, ioapic and pit, which are created with the vm. The lapics are part of VcpuState
+pub struct IrqchipState {
+    pub chips: [kvm_irqchip; IRQCHIP_COUNT],
+    pub pit: kvm_pit_state2,
+}
+
+//create the in-kernel irqchip and pit, before any vcpu
+pub fn Create(vm_fd: &VmFd) -> Result<()> {
+    let fd = vm_fd.as_raw_fd();
+    Cpu::Ioctl(fd, super::KVM_CREATE_IRQCHIP, ptr::null_mut::<u8>())?;
+
+    let mut config: kvm_pit_config = unsafe { std::mem::zeroed() };
+    config.flags = KVM_PIT_SPEAKER_DUMMY;
+    Cpu::Ioctl(fd, super::KVM_CREATE_PIT2, &mut config as *mut kvm_pit_config)?;
+    return Ok(())
+}
+
+fn LapicReg(lapic: &kvm_lapic_state, offset: usize) -> u32 {
+    let mut bytes = [0u8; 4];
+    for i in 0..4 {
+        bytes[i] = lapic.regs[offset + i] as u8;
+    }
+
+    return u32::from_le_bytes(bytes)
+}
+
+fn SetLapicReg(lapic: &mut kvm_lapic_state, offset: usize, value: u32) {
+    for (i, b) in value.to_le_bytes().iter().enumerate() {
+        lapic.regs[offset + i] = *b as _;
+    }
+}
+
+//LINT0 takes the 8259 interrupts as ExtINT and LINT1 the NMIs, as the bios leaves them on a pc
+pub fn SetupLapic(vcpu: &VcpuFd) -> Result<()> {
+    let fd = vcpu.as_raw_fd();
+    let mut lapic: kvm_lapic_state = unsafe { std::mem::zeroed() };
+    Cpu::Ioctl(fd, super::KVM_GET_LAPIC, &mut lapic as *mut kvm_lapic_state)?;
+
+    let lvt0 = LapicReg(&lapic, APIC_LVT0);
+    SetLapicReg(&mut lapic, APIC_LVT0, (lvt0 & !0x700) | (APIC_MODE_EXTINT << 8));
+    let lvt1 = LapicReg(&lapic, APIC_LVT1);
+    SetLapicReg(&mut lapic, APIC_LVT1, (lvt1 & !0x700) | (APIC_MODE_NMI << 8));
+
+    Cpu::Ioctl(fd, super::KVM_SET_LAPIC, &mut lapic as *mut kvm_lapic_state)?;
+    return Ok(())
+}
+
+impl IrqchipState {
+    pub fn Get(vm_fd: &VmFd) -> Result<Self> {
+        let fd = vm_fd.as_raw_fd();
+        let mut state: IrqchipState = unsafe { std::mem::zeroed() };
+        for (id, chip) in state.chips.iter_mut().enumerate() {
+            chip.chip_id = id as u32;
+            Cpu::Ioctl(fd, KVM_GET_IRQCHIP, chip as *mut kvm_irqchip)?;
+        }
+
+        Cpu::Ioctl(fd, KVM_GET_PIT2, &mut state.pit as *mut kvm_pit_state2)?;
+        return Ok(state)
+    }
+
+    //the irqchip and pit have to be created with Create
+    pub fn Set(&self, vm_fd: &VmFd) -> Result<()> {
+        let fd = vm_fd.as_raw_fd();
+        for chip in &self.chips {
+            let mut chip = *chip;
+            Cpu::Ioctl(fd, KVM_SET_IRQCHIP, &mut chip as *mut kvm_irqchip)?;
+        }
+
+        let mut pit = self.pit;
+        Cpu::Ioctl(fd, KVM_SET_PIT2, &mut pit as *mut kvm_pit_state2)?;
+        return Ok(())
+    }
+}
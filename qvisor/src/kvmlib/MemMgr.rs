@@ -2,6 +2,8 @@
 #![allow(dead_code)]
 
 use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::os::unix::io::AsRawFd;
 
 use super::Addr::{Addr};
 use super::qlib::Common::Error;
@@ -16,6 +18,8 @@ pub const STACK_SIZE : u64 = 6 * ONE_MB;
 pub const STACK_GUARDPAGE_SIZE : u64 = 2 * ONE_MB;
 pub const BLOCK_SIZE:  u64 = 64 * ONE_GB;
 pub const PHY_UPPER_ADDR: u64 = 7 * BLOCK_SIZE;
+//the guest RAM PhyAddrMgr adds at runtime starts this far above PHY_UPPER_ADDR, past the kernel memory and ELF
+pub const DYNAMIC_MEM_OFFSET: u64 = 16 * ONE_GB;
 pub const KERNEL_ADDR_SIZE: u64 = 128 * BLOCK_SIZE;
 pub const PHY_MEM_SPACE : u64 = 8*BLOCK_SIZE;
 pub const LOWER_TOP : u64 = 0x00007fffffffffff;
@@ -42,31 +46,120 @@ pub struct Range {
 pub struct FileInfo {
     offset: Addr,
     hostFileName: String,
+    //MAP_SHARED, the guest writes go to the file and the page cache is shared with the host
+    shared: bool,
+    readonly: bool,
+}
+
+//whether guest RAM is mapped with hugetlb pages
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HugePagePolicy {
+    //fail when the host has no hugetlb pages left
+    Require,
+    //use hugetlb pages when the host has them, else normal pages with madvise(MADV_HUGEPAGE)
+    Prefer,
+    Never,
+}
+
+impl Default for HugePagePolicy {
+    fn default() -> Self { HugePagePolicy::Prefer }
+}
+
+//how guest RAM is backed, chosen before the vm is created
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GuestMemOption {
+    //memfd_create instead of anonymous memory, so the memory can be shared by fd with other processes
+    pub memfd: bool,
+    pub hugePage: HugePagePolicy,
 }
 
 pub struct PhyRegion {
     fileInfo : Option<FileInfo>,
+    hugePage: bool,
+    //the memfd backing the region and the offset of the region in it, -1 for anonymous memory
+    fd: i32,
+    fileOffset: u64,
 
     hostBaseAddr: Addr,
     mr: Box<MappedRegion>,
 }
 
 impl PhyRegion {
-    pub fn InitAnan(hostBaseAddr: Addr, hostAddrLimit:Addr, len: u64, hugePage: bool) -> Result<PhyRegion> {
+    //map the region at exactly hostAddr, which is its guest physical address too
+    pub fn InitAnanAt(hostBaseAddr: Addr, hostAddrLimit:Addr, hostAddr: Addr, len: u64, hugePage: bool, memOption: &GuestMemOption) -> Result<PhyRegion> {
+        if hostAddr.0 < hostBaseAddr.0 || hostAddr.AddLen(len)?.0 > hostAddrLimit.0 {
+            return Err(Error::AddressNotInRange);
+        }
+
         let mut option = &mut MapOption::New();
-        option = option.Offset(hostBaseAddr.0).Len(len).MapAnan().MapPrivate().ProtoRead().ProtoWrite().ProtoExec();
+        option = option.Offset(hostAddr.0).Len(len).MapAnan().MapPrivate().MapFixedNoReplace().ProtoRead().ProtoWrite().ProtoExec();
         if hugePage {
-            option = option.MapHugeTLB();
+            option = option.HugePage(memOption.hugePage);
+        }
+
+        if memOption.memfd {
+            option = option.Memfd("qvisor-phy-region");
         }
 
         let mr = Box::new(option.Map()?);
-        //let mr = Box::new(MappedRegion::Init(hostBaseAddr, len, hugePage, libc::PROT_READ |  libc::PROT_WRITE |  libc::PROT_EXEC)?);
-        if mr.End()?.0 >= hostAddrLimit.0 {
-            return Err(Error::AddressNotInRange);
+        if mr.Start().0 != hostAddr.0 {
+            return Err(Error::AddressDoesMatch);
         }
 
         return Ok(PhyRegion{
             fileInfo: None,
+            hugePage: mr.pageSize == PAGE_SIZE_2M,
+            fd: mr.fd,
+            fileOffset: mr.fileOffset,
+            hostBaseAddr: hostBaseAddr,
+            mr: mr,
+        })
+    }
+
+    //map len bytes of the host file path from offset at exactly hostAddr. A private mapping keeps the
+    //guest writes in copy-on-write pages, a shared one writes them to the file
+    pub fn InitFileAt(hostBaseAddr: Addr, hostAddrLimit:Addr, hostAddr: Addr, path: &str, offset: u64, len: u64, shared: bool, readonly: bool) -> Result<PhyRegion> {
+        if hostAddr.0 < hostBaseAddr.0 || hostAddr.AddLen(len)?.0 > hostAddrLimit.0 {
+            return Err(Error::AddressNotInRange);
+        }
+
+        if offset % PAGE_SIZE_4K != 0 || len % PAGE_SIZE_4K != 0 {
+            return Err(Error::UnallignedSize);
+        }
+
+        let file = OpenOptions::new().read(true).write(shared && !readonly).open(path)
+            .map_err(|e| Error::IOError(format!("open {} fail, io::error is {:?}", path, e)))?;
+        let fileSize = file.metadata().map_err(|e| Error::IOError(format!("io::error is {:?}", e)))?.len();
+
+        //the guest would fault on the pages past the end of the file, the last partial page reads zeros
+        let end = offset.checked_add(len).ok_or(Error::Overflow)?;
+        if end > fileSize.saturating_add(PAGE_SIZE_4K - 1) & !(PAGE_SIZE_4K - 1) {
+            return Err(Error::Common(format!("{:x} bytes at {:x} are past the end of {}, its size is {:x}", len, offset, path, fileSize)));
+        }
+
+        let mut option = &mut MapOption::New();
+        option = option.Offset(hostAddr.0).Len(len).FileId(file.as_raw_fd()).FileOffset(offset).MapFixedNoReplace().ProtoRead();
+        option = if shared { option.MapShare() } else { option.MapPrivate() };
+        if !readonly {
+            option = option.ProtoWrite();
+        }
+
+        //the mapping keeps the file open
+        let mr = Box::new(option.Map()?);
+        if mr.Start().0 != hostAddr.0 {
+            return Err(Error::AddressDoesMatch);
+        }
+
+        return Ok(PhyRegion{
+            fileInfo: Some(FileInfo {
+                offset: Addr(offset),
+                hostFileName: path.to_string(),
+                shared: shared,
+                readonly: readonly,
+            }),
+            hugePage: false,
+            fd: -1,
+            fileOffset: 0,
             hostBaseAddr: hostBaseAddr,
             mr: mr,
         })
@@ -76,46 +169,169 @@ impl PhyRegion {
         return self.mr.Start();
     }
 
+    //guest physical memory is identity mapped to the host like the kernel memory
     fn PhyStartAddr(&self) -> Addr {
-        return self.mr.Start().Offset(self.hostBaseAddr).unwrap();
+        return self.mr.Start();
     }
 
     fn Len(&self) -> u64 {
         return self.mr.Len()
     }
 
+    //(memfd, offset) of the region when it is memfd backed
+    fn Memfd(&self) -> Option<(i32, u64)> {
+        if self.fd < 0 {
+            return None
+        }
+
+        return Some((self.fd, self.fileOffset))
+    }
+
     fn IsAnan(&self) -> bool {
         match self.fileInfo {
             None => true,
             _ => false
         }
     }
+
+    fn IsReadOnly(&self) -> bool {
+        return self.fileInfo.as_ref().map_or(false, |f| f.readonly)
+    }
 }
 
 pub struct PhyAddrMgr {
     hostBaseAddr: Addr,
     hostAddrLimit: Addr,
+    memOption: GuestMemOption,
     regions: HashMap<u64, Box<PhyRegion>>,
 }
 
 impl PhyAddrMgr {
-    pub fn Init(hostBaseAddr: Addr, len: u64) -> Result<Self> {
+    pub fn Init(hostBaseAddr: Addr, len: u64, memOption: GuestMemOption) -> Result<Self> {
         return Ok(PhyAddrMgr {
             hostBaseAddr: hostBaseAddr,
             hostAddrLimit: hostBaseAddr.AddLen(len)?,
+            memOption: memOption,
             regions: HashMap::new(),
         })
     }
 
-    //rely on host OS to manage the range
-    //return guest phyical start address
+    //the lowest range of len bytes aligned to align in [hostBaseAddr, hostAddrLimit) which no region overlaps
+    fn FindRange(&self, len: u64, align: u64) -> Result<Addr> {
+        if len > self.hostAddrLimit.0 - self.hostBaseAddr.0 {
+            return Err(Error::NoEnoughSpace);
+        }
+
+        let mut used: Vec<(u64, u64)> = self.regions.values().map(|r| (r.HostStartAddr().0, r.HostStartAddr().0 + r.Len())).collect();
+        used.sort();
+
+        let alignUp = |addr: u64| (addr + align - 1) & !(align - 1);
+        let mut start = alignUp(self.hostBaseAddr.0);
+        for (rStart, rEnd) in used {
+            if start + len <= rStart {
+                break;
+            }
+
+            if rEnd > start {
+                start = alignUp(rEnd);
+            }
+        }
+
+        if start + len > self.hostAddrLimit.0 {
+            return Err(Error::NoEnoughSpace);
+        }
+
+        return Ok(Addr(start))
+    }
+
+    //map len bytes of anonymous memory, rounded up to the page size, at a free guest physical range.
+    //return guest phyical start address, the host address is the same
     pub fn AllocAnan(&mut self, len: u64, hugePage: bool) -> Result<Addr> {
-        let region = Box::new(PhyRegion::InitAnan(self.hostBaseAddr, self.hostAddrLimit, len, hugePage)?);
+        let align = if hugePage { PAGE_SIZE_2M } else { PAGE_SIZE_4K };
+        let len = len.checked_add(align - 1).ok_or(Error::Overflow)? & !(align - 1);
+        if len == 0 {
+            return Err(Error::UnallignedSize);
+        }
+
+        let addr = self.FindRange(len, align)?;
+        let region = Box::new(PhyRegion::InitAnanAt(self.hostBaseAddr, self.hostAddrLimit, addr, len, hugePage, &self.memOption)?);
+        let ret = region.PhyStartAddr();
+        self.regions.insert(ret.0, region);
+        Ok(ret)
+    }
+
+    //map len bytes of the host file path from offset, rounded up to the page size, at a free guest physical
+    //range. return guest phyical start address, the host address is the same
+    pub fn AllocFile(&mut self, path: &str, offset: u64, len: u64, shared: bool, readonly: bool) -> Result<Addr> {
+        let len = len.checked_add(PAGE_SIZE_4K - 1).ok_or(Error::Overflow)? & !(PAGE_SIZE_4K - 1);
+        if len == 0 {
+            return Err(Error::UnallignedSize);
+        }
+
+        let addr = self.FindRange(len, PAGE_SIZE_4K)?;
+        let region = Box::new(PhyRegion::InitFileAt(self.hostBaseAddr, self.hostAddrLimit, addr, path, offset, len, shared, readonly)?);
         let ret = region.PhyStartAddr();
+        println!("map {} from {:x} len {:x} at {:x}, shared {}, readonly {}", path, offset, len, ret.0, shared, readonly);
         self.regions.insert(ret.0, region);
         Ok(ret)
     }
 
+    //recreate an anonymous region saved in a snapshot at its old host address. A hugetlb region comes
+    //back on normal pages when the host has no hugetlb pages left, as the kernel memory does
+    pub fn RestoreAnan(&mut self, hostAddr: Addr, len: u64, hugePage: bool) -> Result<Addr> {
+        let memOption = GuestMemOption {
+            memfd: false,
+            hugePage: HugePagePolicy::Prefer,
+        };
+
+        let region = Box::new(PhyRegion::InitAnanAt(self.hostBaseAddr, self.hostAddrLimit, hostAddr, len, hugePage, &memOption)?);
+        let ret = region.PhyStartAddr();
+        self.regions.insert(ret.0, region);
+        Ok(ret)
+    }
+
+    //the length of the region at the guest physical address start
+    pub fn RegionLen(&self, start: u64) -> Result<u64> {
+        return self.regions.get(&start).map(|r| r.Len()).ok_or(Error::UnmatchRegion)
+    }
+
+    //(guest physical address, host address, len, hugePage) of every anonymous region
+    pub fn Regions(&self) -> Vec<(u64, u64, u64, bool)> {
+        let mut res: Vec<(u64, u64, u64, bool)> = self.regions.values().filter(|r| r.IsAnan()).map(|r| (r.PhyStartAddr().0, r.HostStartAddr().0, r.Len(), r.hugePage)).collect();
+        res.sort();
+        return res;
+    }
+
+    //discard the host memory of [addr, addr + len) in an anonymous region
+    pub fn Discard(&self, addr: u64, len: u64) -> Result<()> {
+        for r in self.regions.values() {
+            if r.IsAnan() && r.mr.Start().0 <= addr && addr + len <= r.mr.Start().0 + r.Len() {
+                return r.mr.Discard(addr, len)
+            }
+        }
+
+        return Err(Error::AddressNotInRange)
+    }
+
+    //(guest physical address, len, readonly) of every file backed region
+    pub fn FileRegions(&self) -> Vec<(u64, u64, bool)> {
+        let mut res: Vec<(u64, u64, bool)> = self.regions.values().filter(|r| !r.IsAnan()).map(|r| (r.PhyStartAddr().0, r.Len(), r.IsReadOnly())).collect();
+        res.sort();
+        return res;
+    }
+
+    //the regions are mapped MAP_PRIVATE now, see Template::MapPrivate
+    pub fn SetPrivate(&mut self) {
+        for r in self.regions.values_mut() {
+            r.mr.shared = false;
+        }
+    }
+
+    //(host address, memfd, offset) of the memfd backed regions
+    pub fn Memfds(&self) -> Vec<(u64, i32, u64)> {
+        return self.regions.values().filter_map(|r| r.Memfd().map(|(fd, offset)| (r.HostStartAddr().0, fd, offset))).collect()
+    }
+
     pub fn PhyToHostAddr(&mut self, phyStartAddr: Addr) -> Result<Addr> {
         if let Some(region) = self.regions.get(&phyStartAddr.0) {
             return Ok (region.HostStartAddr());
@@ -139,6 +355,10 @@ impl PhyAddrMgr {
     }
 }
 
+fn Errno() -> i32 {
+    return std::io::Error::last_os_error().raw_os_error().unwrap_or(0)
+}
+
 #[derive(Debug)]
 pub struct MapOption {
     offset: u64,
@@ -147,8 +367,17 @@ pub struct MapOption {
     proto: libc::c_int,
     fd: libc::c_int,
     fileOffset: libc::off_t,
+    memfd: Option<String>,
+    hugePage: HugePagePolicy,
 }
 
+const MFD_CLOEXEC: libc::c_uint = 1;
+const MFD_ALLOW_SEALING: libc::c_uint = 2;
+const MFD_HUGETLB: libc::c_uint = 4;
+const F_ADD_SEALS: libc::c_int = 1033;
+const F_SEAL_SHRINK: libc::c_int = 2;
+const F_SEAL_GROW: libc::c_int = 4;
+
 impl MapOption {
     pub fn New() -> Self {
         return MapOption {
@@ -157,7 +386,9 @@ impl MapOption {
             flags: 0,
             proto: libc::PROT_NONE,
             fd: -1,
-            fileOffset: 0
+            fileOffset: 0,
+            memfd: None,
+            hugePage: HugePagePolicy::Never,
         }
     }
 
@@ -228,6 +459,17 @@ impl MapOption {
 
     pub fn MapHugeTLB(&mut self) -> &mut Self {
         self.flags |= libc::MAP_HUGETLB;
+        self.hugePage = HugePagePolicy::Require;
+        self
+    }
+
+    //MapHugeTLB for Require, MapHugeTLB with the fallback to normal pages for Prefer
+    pub fn HugePage(&mut self, policy: HugePagePolicy) -> &mut Self {
+        match policy {
+            HugePagePolicy::Never => self.flags &= !libc::MAP_HUGETLB,
+            _ => self.flags |= libc::MAP_HUGETLB,
+        }
+        self.hugePage = policy;
         self
     }
 
@@ -241,15 +483,104 @@ impl MapOption {
         self
     }
 
+    //back the mapping with a new memfd of the given name instead of anonymous memory. The memfd is
+    //hugetlb when MapHugeTLB or HugePage asks for it, its size is sealed
+    pub fn Memfd(&mut self, name: &str) -> &mut Self {
+        self.memfd = Some(name.to_string());
+        self
+    }
+
+    fn MapMemfd(&self, name: &str, hugePage: bool) -> Result<MappedRegion> {
+        let cname = std::ffi::CString::new(name).map_err(|_| Error::Common(format!("bad memfd name {}", name)))?;
+        let mut mfdFlags = MFD_CLOEXEC | MFD_ALLOW_SEALING;
+        if hugePage {
+            mfdFlags |= MFD_HUGETLB;
+        }
+
+        let fd = unsafe { libc::syscall(libc::SYS_memfd_create, cname.as_ptr(), mfdFlags) } as libc::c_int;
+        if fd < 0 {
+            return Err(Error::IOError(format!("memfd_create {} fail, io::error is {:?}", name, std::io::Error::last_os_error())));
+        }
+
+        let size = self.fileOffset as u64 + self.len;
+        let ret = unsafe {
+            if libc::ftruncate(fd, size as libc::off_t) < 0 || libc::fcntl(fd, F_ADD_SEALS, F_SEAL_SHRINK | F_SEAL_GROW) < 0 {
+                -1
+            } else {
+                0
+            }
+        };
+
+        if ret < 0 {
+            let err = std::io::Error::last_os_error();
+            unsafe {
+                libc::close(fd);
+            }
+            return Err(Error::IOError(format!("size memfd {} fail, io::error is {:?}", name, err)));
+        }
+
+        //the memfd is the memory now, shared by every mapping of it
+        let flags = (self.flags & !(libc::MAP_ANONYMOUS | libc::MAP_PRIVATE | libc::MAP_HUGETLB)) | libc::MAP_SHARED;
+        match MappedRegion::New(self.offset as *mut libc::c_void, self.len as libc::size_t, self.proto, flags, fd, self.fileOffset) {
+            Ok(mut mr) => {
+                mr.fd = fd;
+                mr.fileOffset = self.fileOffset as u64;
+                if hugePage {
+                    mr.pageSize = PAGE_SIZE_2M;
+                }
+                return Ok(mr)
+            }
+            Err(e) => {
+                unsafe {
+                    libc::close(fd);
+                }
+                return Err(e)
+            }
+        }
+    }
+
     pub fn Map(&self) -> Result<MappedRegion> {
-        MappedRegion::New(
-            self.offset as *mut libc::c_void,
-            self.len as libc::size_t,
-            self.proto as libc::c_int,
-            self.flags as libc::c_int,
-            self.fd as libc::c_int,
-            self.fileOffset as libc::off_t
-        )
+        let hugeTLB = self.flags & libc::MAP_HUGETLB != 0;
+        let res = match &self.memfd {
+            Some(name) => self.MapMemfd(name, hugeTLB),
+            None => MappedRegion::New(
+                self.offset as *mut libc::c_void,
+                self.len as libc::size_t,
+                self.proto as libc::c_int,
+                self.flags as libc::c_int,
+                self.fd as libc::c_int,
+                self.fileOffset as libc::off_t
+            ),
+        };
+
+        if !hugeTLB || self.hugePage != HugePagePolicy::Prefer {
+            return res
+        }
+
+        let err = match res {
+            Ok(mr) => return Ok(mr),
+            Err(e) => e,
+        };
+
+        println!("hugetlb pages for {:x} len {:x} are not available: {:?}, fall back to normal pages", self.offset, self.len, err);
+        let option = MapOption {
+            offset: self.offset,
+            len: self.len,
+            flags: self.flags & !libc::MAP_HUGETLB,
+            proto: self.proto,
+            fd: self.fd,
+            fileOffset: self.fileOffset,
+            memfd: self.memfd.clone(),
+            hugePage: HugePagePolicy::Never,
+        };
+
+        let mr = option.Map()?;
+        //transparent huge pages where the host allows them, the pages are still 4KB to qvisor
+        unsafe {
+            libc::madvise(mr.ptr as *mut libc::c_void, mr.sz as usize, libc::MADV_HUGEPAGE);
+        }
+
+        return Ok(mr)
     }
 }
 
@@ -257,6 +588,13 @@ impl MapOption {
 pub struct MappedRegion {
     pub sz: u64,
     pub ptr: u64,
+    //the memfd owned by the mapping and the offset of the mapping in it, -1 when there is none
+    pub fd: i32,
+    pub fileOffset: u64,
+    //the host page size backing the mapping, PAGE_SIZE_2M for hugetlb
+    pub pageSize: u64,
+    //MAP_SHARED, a template clone maps its memory MAP_PRIVATE again
+    pub shared: bool,
 }
 
 impl MappedRegion {
@@ -269,25 +607,16 @@ impl MappedRegion {
                                   fd,
                                   offset);
 
-            if (addr as i64) < 0 {
-                Err(Error::MMampError)
+            if addr == libc::MAP_FAILED {
+                Err(Error::SysError(Errno()))
             } else {
-                /*println!("mmap address is {:x} len is {:x} offset is {:x}, fd is {}, proto is {:x}, flags is {:x}",
-                         addr as u64, len as u64, offset as u64, fd as u32, prot as u64, flags as u64);
-
-                if fd != -1 {
-                    println!("mmap value is:");
-
-                    for i in 0..len {
-                        print!("{:x} ", *((addr as u64 +i as u64) as *const u8))
-                    }
-
-                    println!("");
-                }*/
-
                 Ok( MappedRegion {
                     ptr: addr as u64,
                     sz: len as u64,
+                    fd: -1,
+                    fileOffset: 0,
+                    pageSize: if flags & libc::MAP_HUGETLB != 0 { PAGE_SIZE_2M } else { PAGE_SIZE_4K },
+                    shared: flags & libc::MAP_SHARED != 0,
                 })
             }
         }
@@ -307,6 +636,26 @@ impl MappedRegion {
     pub fn Len(&self) -> u64 {
         return self.sz
     }
+
+    //give the host memory of [addr, addr + len) back, aligned to the page size. A shared memfd gets a hole
+    //punched and reads zeros after, a private mapping drops its own copies of the pages
+    pub fn Discard(&self, addr: u64, len: u64) -> Result<()> {
+        if addr < self.ptr || addr + len > self.ptr + self.sz {
+            return Err(Error::AddressNotInRange);
+        }
+
+        if addr % self.pageSize != 0 || len % self.pageSize != 0 {
+            return Err(Error::UnallignedAddress);
+        }
+
+        //MADV_REMOVE fails on a private mapping, MADV_DONTNEED leaves the pages in a shared memfd
+        let advice = if self.fd >= 0 && self.shared { libc::MADV_REMOVE } else { libc::MADV_DONTNEED };
+        if unsafe { libc::madvise(addr as *mut libc::c_void, len as usize, advice) } < 0 {
+            return Err(Error::SysError(Errno()));
+        }
+
+        return Ok(())
+    }
 }
 
 impl Drop for MappedRegion {
@@ -317,6 +666,10 @@ impl Drop for MappedRegion {
             if libc::munmap(self.ptr as *mut libc::c_void, self.sz as usize) != 0 {
                 panic!("munmap: {}", std::io::Error::last_os_error());
             }
+
+            if self.fd >= 0 {
+                libc::close(self.fd);
+            }
         }
     }
 }
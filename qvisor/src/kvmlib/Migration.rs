@@ -0,0 +1,459 @@
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::ptr;
+use std::slice;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
+use std::thread;
+
+use super::qlib::Common::Error;
+use super::qlib::Common::Result;
+use super::DirtyLog::{BitmapToRanges, MemSlot};
+use super::Gdb::{Conn, InstallKickHandler};
+use super::MemMgr;
+use super::Snapshot::{self, MemRegion, PutBytes, PutU32, PutU64, GetBytes};
+use super::Symbol::Reader;
+
+//both sides send the hello first: magic and protocol version. Version 2 carries the snapshot version 5
+//metadata and the repr(C) ShareSpace in the guest pages
+const MIGRATION_MAGIC: &[u8; 8] = b"QVMIGR\0\0";
+pub const MIGRATION_VERSION: u32 = 2;
+
+//the frames after the hello: type u32, payload length u64, payload
+//source -> destination: the elf path and the guest memory regions, sent once before any page
+const MSG_LAYOUT: u32 = 1;
+//source -> destination: region u32, first page u32, page count u32, then the page data
+const MSG_PAGES: u32 = 2;
+//source -> destination: the snapshot metadata of the paused vm, the last frame of the source
+const MSG_STATE: u32 = 3;
+//destination -> source: the vm is rebuilt and about to run, the source can go away
+const MSG_RESUMED: u32 = 4;
+
+const PAGE_SIZE: u64 = MemMgr::PAGE_SIZE_4K;
+const FRAME_HEADER_SIZE: usize = 4 + 8;
+const MAX_FRAME_SIZE: u64 = 256 * MemMgr::ONE_MB;
+const PAGES_PER_MSG: u64 = 256;
+
+//pre-copy stops when a round leaves fewer dirty pages than this, or after MAX_ROUNDS rounds
+const CONVERGED_PAGES: u64 = 256;
+const MAX_ROUNDS: usize = 30;
+
+fn IoErr(e: std::io::Error) -> Error {
+    return Error::IOError(format!("io::error is {:?}", e))
+}
+
+fn Truncated() -> Error {
+    return Error::Common(String::from("truncated migration frame"))
+}
+
+fn Connect(addr: &str) -> Result<Conn> {
+    if addr.starts_with("unix:") {
+        return Ok(Conn::Unix(UnixStream::connect(&addr[5..]).map_err(IoErr)?))
+    }
+
+    let stream = TcpStream::connect(addr).map_err(IoErr)?;
+    stream.set_nodelay(true).map_err(IoErr)?;
+    return Ok(Conn::Tcp(stream))
+}
+
+fn Accept(addr: &str) -> Result<Conn> {
+    if addr.starts_with("unix:") {
+        let listener = UnixListener::bind(&addr[5..]).map_err(IoErr)?;
+        let (stream, _) = listener.accept().map_err(IoErr)?;
+        return Ok(Conn::Unix(stream))
+    }
+
+    let listener = TcpListener::bind(addr).map_err(IoErr)?;
+    let (stream, peer) = listener.accept().map_err(IoErr)?;
+    println!("migration source connected from {}", peer);
+    stream.set_nodelay(true).map_err(IoErr)?;
+    return Ok(Conn::Tcp(stream))
+}
+
+fn Hello(conn: &mut Conn) -> Result<()> {
+    let mut hello = Vec::with_capacity(12);
+    hello.extend_from_slice(MIGRATION_MAGIC);
+    PutU32(&mut hello, MIGRATION_VERSION);
+    conn.write_all(&hello).map_err(IoErr)?;
+
+    let mut peer = [0; 12];
+    conn.read_exact(&mut peer).map_err(IoErr)?;
+    if &peer[..8] != &MIGRATION_MAGIC[..] {
+        return Err(Error::Common(String::from("the peer is not a qvisor migration")));
+    }
+
+    let version = u32::from_le_bytes([peer[8], peer[9], peer[10], peer[11]]);
+    if version != MIGRATION_VERSION {
+        return Err(Error::Common(format!("migration version {} is not supported, expect {}", version, MIGRATION_VERSION)));
+    }
+
+    return Ok(())
+}
+
+fn WriteFrame(conn: &mut Conn, msgType: u32, parts: &[&[u8]]) -> Result<()> {
+    let len: usize = parts.iter().map(|p| p.len()).sum();
+    let mut header = Vec::with_capacity(FRAME_HEADER_SIZE);
+    PutU32(&mut header, msgType);
+    PutU64(&mut header, len as u64);
+    conn.write_all(&header).map_err(IoErr)?;
+    for part in parts {
+        conn.write_all(part).map_err(IoErr)?;
+    }
+
+    return Ok(())
+}
+
+fn ReadFrameHeader(conn: &mut Conn) -> Result<(u32, u64)> {
+    let mut header = [0; FRAME_HEADER_SIZE];
+    conn.read_exact(&mut header).map_err(IoErr)?;
+
+    let mut r = Reader::New(&header);
+    let msgType = r.U32().ok_or_else(Truncated)?;
+    let len = r.U64().ok_or_else(Truncated)?;
+    if len > MAX_FRAME_SIZE {
+        return Err(Error::Common(format!("migration frame {} is too large: {:x}", msgType, len)));
+    }
+
+    return Ok((msgType, len))
+}
+
+fn ReadPayload(conn: &mut Conn, len: u64) -> Result<Vec<u8>> {
+    let mut payload = vec![0; len as usize];
+    conn.read_exact(&mut payload).map_err(IoErr)?;
+    return Ok(payload)
+}
+
+fn EncodeLayout(elfPath: &str, regions: &[MemRegion]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    PutBytes(&mut buf, elfPath.as_bytes());
+    PutU32(&mut buf, regions.len() as u32);
+    for r in regions {
+        PutU32(&mut buf, r.kind);
+        PutU64(&mut buf, r.hostAddr);
+        PutU64(&mut buf, r.phyAddr);
+        PutU64(&mut buf, r.len);
+        buf.push(r.hugePage as u8);
+    }
+
+    return buf
+}
+
+fn DecodeLayout(data: &[u8]) -> Result<(String, Vec<MemRegion>)> {
+    let mut r = Reader::New(data);
+    let elfPath = String::from_utf8(GetBytes(&mut r)?.to_vec()).map_err(|_| Error::Common(String::from("bad kernel path in migration layout")))?;
+
+    let count = r.U32().ok_or_else(Truncated)? as usize;
+    let mut regions = Vec::new();
+    for _ in 0..count {
+        let region = MemRegion::New(r.U32().ok_or_else(Truncated)?, r.U64().ok_or_else(Truncated)?,
+                                    r.U64().ok_or_else(Truncated)?, r.U64().ok_or_else(Truncated)?,
+                                    r.U8().ok_or_else(Truncated)? != 0);
+        if region.len % PAGE_SIZE != 0 || region.hostAddr % PAGE_SIZE != 0 {
+            return Err(Error::Common(format!("bad migration region {:x} len {:x}", region.hostAddr, region.len)));
+        }
+        regions.push(region);
+    }
+
+    return Ok((elfPath, regions))
+}
+
+//the 4KB pages of [hostAddr, hostAddr + len) which are resident in the host
+fn ResidentPages(hostAddr: u64, len: u64) -> Result<Vec<u32>> {
+    let pageCount = (len / PAGE_SIZE) as usize;
+    let mut resident = vec![0 as u8; pageCount];
+    let ret = unsafe {
+        libc::mincore(hostAddr as *mut libc::c_void, len as usize, resident.as_mut_ptr())
+    };
+    if ret < 0 {
+        return Err(Error::IOError(format!("mincore fail, io::error is {:?}", std::io::Error::last_os_error())));
+    }
+
+    return Ok((0..pageCount).filter(|&i| resident[i] & 1 != 0).map(|i| i as u32).collect())
+}
+
+//the vcpu thread's side of an outgoing migration. The guest keeps running while the sender thread
+//copies memory, until the sender asks for the pause with PauseRequested
+pub struct Outgoing {
+    pauseRequested: Arc<AtomicBool>,
+    failed: Arc<AtomicBool>,
+    stateTx: Sender<(Vec<u8>, Vec<(u64, u64)>)>,
+    result: Receiver<Result<()>>,
+}
+
+impl Outgoing {
+    //connect to the destination and start the pre-copy. regions is the guest memory to send, slots the
+    //memory slots with dirty logging already on. Must be called on the vcpu thread, which is the one
+    //kicked for the pause, immediateExitAddr is the immediate_exit byte of its kvm_run
+    pub fn Start(addr: &str, elfPath: &str, regions: Vec<MemRegion>, slots: Vec<MemSlot>, vmFd: i32, immediateExitAddr: u64) -> Result<Self> {
+        let mut conn = Connect(addr)?;
+        Hello(&mut conn)?;
+        WriteFrame(&mut conn, MSG_LAYOUT, &[&EncodeLayout(elfPath, &regions)])?;
+
+        InstallKickHandler()?;
+
+        let pauseRequested = Arc::new(AtomicBool::new(false));
+        let failed = Arc::new(AtomicBool::new(false));
+        let (stateTx, stateRx) = channel();
+        let (resultTx, result) = channel();
+
+        let mut sender = PageSender {
+            conn: conn,
+            regions: regions,
+            slots: slots,
+            vmFd: vmFd,
+            pages: 0,
+        };
+
+        let vcpuThread = unsafe { libc::pthread_self() };
+        let threadPauseRequested = pauseRequested.clone();
+        let threadFailed = failed.clone();
+        thread::spawn(move || {
+            if let Err(e) = sender.PreCopy() {
+                eprintln!("migration fail, the guest keeps running here: {:?}", e);
+                threadFailed.store(true, Ordering::SeqCst);
+                return;
+            }
+
+            threadPauseRequested.store(true, Ordering::SeqCst);
+            unsafe {
+                ptr::write_volatile(immediateExitAddr as *mut u8, 1);
+                libc::pthread_kill(vcpuThread, libc::SIGUSR1);
+            }
+
+            let res = match stateRx.recv() {
+                Ok((state, hostWrites)) => sender.StopAndCopy(&state, &hostWrites),
+                Err(_) => Err(Error::Common(String::from("the vcpu thread is gone"))),
+            };
+            let _ = resultTx.send(res);
+        });
+
+        return Ok(Outgoing {
+            pauseRequested: pauseRequested,
+            failed: failed,
+            stateTx: stateTx,
+            result: result,
+        })
+    }
+
+    //the pre-copy has converged, the vcpu has to stop and hand over its state with Complete
+    pub fn PauseRequested(&self) -> bool {
+        return self.pauseRequested.swap(false, Ordering::SeqCst)
+    }
+
+    //the pre-copy failed, the guest stays here
+    pub fn Failed(&self) -> bool {
+        return self.failed.load(Ordering::SeqCst)
+    }
+
+    //send the final round and state while the vcpu is paused. hostWrites are the (host address, len)
+    //qvisor wrote to since Start, which are not in the dirty log. Ok means the destination runs the
+    //guest now, on error the guest can resume here
+    pub fn Complete(&self, state: Vec<u8>, hostWrites: Vec<(u64, u64)>) -> Result<()> {
+        self.stateTx.send((state, hostWrites)).map_err(|_| Error::Common(String::from("the migration thread is gone")))?;
+        return match self.result.recv() {
+            Ok(res) => res,
+            Err(_) => Err(Error::Common(String::from("the migration thread is gone"))),
+        }
+    }
+}
+
+struct PageSender {
+    conn: Conn,
+    regions: Vec<MemRegion>,
+    slots: Vec<MemSlot>,
+    vmFd: i32,
+    pages: u64,
+}
+
+impl PageSender {
+    //the first full copy and the dirty rounds which follow it, with the guest running
+    fn PreCopy(&mut self) -> Result<()> {
+        for i in 0..self.regions.len() {
+            self.regions[i].ScanPages()?;
+            for (start, count) in self.regions[i].Runs() {
+                self.SendPages(i, start as u64, count as u64)?;
+            }
+        }
+        println!("migration round 0: {} pages", self.pages);
+
+        for round in 1..MAX_ROUNDS + 1 {
+            let dirty = self.SendDirty()?;
+            println!("migration round {}: {} dirty pages", round, dirty);
+            if dirty < CONVERGED_PAGES {
+                break;
+            }
+        }
+
+        return Ok(())
+    }
+
+    //with the vcpu paused: the last dirty pages, the pages qvisor wrote, the regions without dirty
+    //logging, then the state. returns once the destination has taken over
+    fn StopAndCopy(&mut self, state: &[u8], hostWrites: &[(u64, u64)]) -> Result<()> {
+        let dirty = self.SendDirty()?;
+        let hostWritten = self.SendRanges(hostWrites)?;
+
+        //memory outside of the kvm slots has no dirty log, send all of it again
+        let mut unlogged = 0;
+        for i in 0..self.regions.len() {
+            if self.Logged(i) {
+                continue;
+            }
+
+            let r = &self.regions[i];
+            for page in ResidentPages(r.hostAddr, r.len)? {
+                self.SendPages(i, page as u64, 1)?;
+                unlogged += 1;
+            }
+        }
+
+        WriteFrame(&mut self.conn, MSG_STATE, &[state])?;
+        println!("migration final round: {} dirty pages, {} host written pages, {} unlogged pages, {} pages in total",
+                 dirty, hostWritten, unlogged, self.pages);
+
+        let (msgType, len) = ReadFrameHeader(&mut self.conn)?;
+        if msgType != MSG_RESUMED || len != 0 {
+            return Err(Error::Common(format!("unexpected migration frame {} from the destination", msgType)));
+        }
+
+        return Ok(())
+    }
+
+    fn Logged(&self, region: usize) -> bool {
+        let r = &self.regions[region];
+        return self.slots.iter().any(|s| s.userspaceAddr <= r.hostAddr && r.hostAddr + r.len <= s.userspaceAddr + s.memorySize)
+    }
+
+    //fetch and clear the dirty logs, then send the pages they name. A page written after the fetch
+    //is in the next log
+    fn SendDirty(&mut self) -> Result<u64> {
+        let mut ranges = Vec::new();
+        for slot in &self.slots {
+            let bitmap = slot.GetDirtyLogRaw(self.vmFd)?;
+            for range in BitmapToRanges(&bitmap, slot.guestPhysAddr, slot.memorySize) {
+                ranges.push((slot.userspaceAddr + (range.start - slot.guestPhysAddr), range.len));
+            }
+        }
+
+        return self.SendRanges(&ranges)
+    }
+
+    //send the pages of the regions in the (host address, len) ranges, return the page count
+    fn SendRanges(&mut self, ranges: &[(u64, u64)]) -> Result<u64> {
+        let mut sent = 0;
+        for &(start, len) in ranges {
+            for i in 0..self.regions.len() {
+                let (rStart, rEnd) = (self.regions[i].hostAddr, self.regions[i].hostAddr + self.regions[i].len);
+                let from = core::cmp::max(start, rStart);
+                let to = core::cmp::min(start + len, rEnd);
+                if from >= to {
+                    continue;
+                }
+
+                let count = (to - from) / PAGE_SIZE;
+                self.SendPages(i, (from - rStart) / PAGE_SIZE, count)?;
+                sent += count;
+            }
+        }
+
+        return Ok(sent)
+    }
+
+    fn SendPages(&mut self, region: usize, first: u64, count: u64) -> Result<()> {
+        let hostAddr = self.regions[region].hostAddr;
+        let mut done = 0;
+        while done < count {
+            let n = core::cmp::min(PAGES_PER_MSG, count - done);
+            let page = first + done;
+
+            let mut header = Vec::with_capacity(12);
+            PutU32(&mut header, region as u32);
+            PutU32(&mut header, page as u32);
+            PutU32(&mut header, n as u32);
+            let data = unsafe {
+                slice::from_raw_parts((hostAddr + page * PAGE_SIZE) as *const u8, (n * PAGE_SIZE) as usize)
+            };
+            WriteFrame(&mut self.conn, MSG_PAGES, &[&header, data])?;
+
+            done += n;
+        }
+
+        self.pages += count;
+        return Ok(())
+    }
+}
+
+//the destination's side: the memory layout first, then pages into the mapped guest memory until the state
+pub struct Incoming {
+    conn: Conn,
+}
+
+impl Incoming {
+    //wait for the source on addr, "unix:<path>" or a tcp "host:port"
+    pub fn Accept(addr: &str) -> Result<Self> {
+        println!("waiting for the migration source on {}", addr);
+        let mut conn = Accept(addr)?;
+        Hello(&mut conn)?;
+        return Ok(Incoming {
+            conn: conn,
+        })
+    }
+
+    //the kernel path and the guest memory to map at the same host addresses before ReadMemory
+    pub fn ReadLayout(&mut self) -> Result<(String, Vec<MemRegion>)> {
+        let (msgType, len) = ReadFrameHeader(&mut self.conn)?;
+        if msgType != MSG_LAYOUT {
+            return Err(Error::Common(format!("expect the migration layout, get frame {}", msgType)));
+        }
+
+        let payload = ReadPayload(&mut self.conn, len)?;
+        return DecodeLayout(&payload)
+    }
+
+    //copy the pages into regions until the final state of the vm comes. The layout is from the peer,
+    //mapped are the mappings made for it, see CheckLayout
+    pub fn ReadMemory(&mut self, regions: &[MemRegion], mapped: &[(u64, u64)]) -> Result<Snapshot::Snapshot> {
+        Snapshot::CheckLayout(regions, mapped)?;
+
+        let mut pages = 0;
+        loop {
+            let (msgType, len) = ReadFrameHeader(&mut self.conn)?;
+            match msgType {
+                MSG_PAGES => {
+                    let mut header = [0; 12];
+                    if len < 12 {
+                        return Err(Truncated());
+                    }
+                    self.conn.read_exact(&mut header).map_err(IoErr)?;
+
+                    let mut r = Reader::New(&header);
+                    let region = r.U32().ok_or_else(Truncated)? as usize;
+                    let first = r.U32().ok_or_else(Truncated)? as u64;
+                    let count = r.U32().ok_or_else(Truncated)? as u64;
+                    if region >= regions.len() || first + count > regions[region].len / PAGE_SIZE || len != 12 + count * PAGE_SIZE {
+                        return Err(Error::Common(format!("bad migration pages {}:{}+{}", region, first, count)));
+                    }
+
+                    let data = unsafe {
+                        slice::from_raw_parts_mut((regions[region].hostAddr + first * PAGE_SIZE) as *mut u8, (count * PAGE_SIZE) as usize)
+                    };
+                    self.conn.read_exact(data).map_err(IoErr)?;
+                    pages += count;
+                }
+                MSG_STATE => {
+                    let payload = ReadPayload(&mut self.conn, len)?;
+                    println!("migration received {} pages", pages);
+                    return Snapshot::Snapshot::DecodeMeta(&payload)
+                }
+                _ => return Err(Error::Common(format!("unexpected migration frame {} from the source", msgType))),
+            }
+        }
+    }
+
+    //tell the source the vm runs here now
+    pub fn Resumed(&mut self) -> Result<()> {
+        return WriteFrame(&mut self.conn, MSG_RESUMED, &[])
+    }
+}
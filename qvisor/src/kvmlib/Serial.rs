@@ -0,0 +1,381 @@
+use std::collections::VecDeque;
+use std::fs::OpenOptions;
+use std::io::{Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixListener;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use spin::Mutex;
+
+use super::qlib::Common::Error;
+use super::qlib::Common::Result;
+use super::Bus::BusDevice;
+use super::EventFd::{EventFd, IrqFd, StoppableRead, WaitReadable};
+
+//COM1, its 8 registers and its isa irq
+pub const COM1_PORT: u16 = 0x3f8;
+pub const COM1_GSI: u32 = 4;
+pub const SERIAL_PORT_COUNT: u16 = 8;
+
+//the register offsets, DLL and DLM replace RBR/THR and IER when LCR_DLAB is set
+const UART_RBR_THR: u64 = 0;
+const UART_IER: u64 = 1;
+const UART_IIR_FCR: u64 = 2;
+const UART_LCR: u64 = 3;
+const UART_MCR: u64 = 4;
+const UART_LSR: u64 = 5;
+const UART_MSR: u64 = 6;
+const UART_SCR: u64 = 7;
+
+const IER_RDI: u8 = 0x01;
+const IER_THRI: u8 = 0x02;
+const IER_MASK: u8 = 0x0f;
+
+const IIR_NONE: u8 = 0x01;
+const IIR_THRI: u8 = 0x02;
+const IIR_RDI: u8 = 0x04;
+const IIR_FIFO_ENABLED: u8 = 0xc0;
+
+const FCR_FIFO_ENABLE: u8 = 0x01;
+const FCR_CLEAR_RX: u8 = 0x02;
+
+const LCR_DLAB: u8 = 0x80;
+const MCR_LOOP: u8 = 0x10;
+
+const LSR_DR: u8 = 0x01;
+const LSR_OE: u8 = 0x02;
+const LSR_THRE: u8 = 0x20;
+const LSR_TEMT: u8 = 0x40;
+
+//DCD, DSR and CTS, the other side is always there
+const MSR_DEFAULT: u8 = 0xb0;
+//115200 baud
+const DEFAULT_DIVISOR: u16 = 1;
+
+//the input not read by the guest yet, more is dropped with LSR_OE
+const RX_LIMIT: usize = 4096;
+
+//the thread feeding the input of a Serial, ended and its socket closed on drop
+pub struct SerialInput {
+    stop: Arc<EventFd>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for SerialInput {
+    fn drop(&mut self) {
+        let _ = self.stop.Write(1);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+//the registers of a Serial and the input the guest hasn't read, for a snapshot
+#[derive(Clone, Default, Debug)]
+pub struct SerialState {
+    pub ier: u8,
+    pub lcr: u8,
+    pub mcr: u8,
+    pub lsr: u8,
+    pub scr: u8,
+    pub divisor: u16,
+    pub fifo: bool,
+    pub thrInterrupt: bool,
+    pub rx: Vec<u8>,
+}
+
+//the registers in front of rx in the encoding
+const SERIAL_STATE_REGS: usize = 9;
+
+impl SerialState {
+    pub fn Encode(&self) -> Vec<u8> {
+        let mut buf = vec![self.ier, self.lcr, self.mcr, self.lsr, self.scr, self.divisor as u8, (self.divisor >> 8) as u8,
+                           self.fifo as u8, self.thrInterrupt as u8];
+        buf.extend_from_slice(&self.rx);
+        return buf
+    }
+
+    pub fn Decode(data: &[u8]) -> Result<Self> {
+        if data.len() < SERIAL_STATE_REGS || data.len() - SERIAL_STATE_REGS > RX_LIMIT {
+            return Err(Error::Common(format!("bad serial state of {} bytes", data.len())));
+        }
+
+        return Ok(SerialState {
+            ier: data[0] & IER_MASK,
+            lcr: data[1],
+            mcr: data[2],
+            lsr: data[3],
+            scr: data[4],
+            divisor: data[5] as u16 | (data[6] as u16) << 8,
+            fifo: data[7] != 0,
+            thrInterrupt: data[8] != 0,
+            rx: data[SERIAL_STATE_REGS..].to_vec(),
+        })
+    }
+}
+
+//a 16550 uart. The transmitter is never busy, a byte written to THR goes out right away. The input
+//comes from another thread with Input, so the uart is shared behind a lock
+pub struct Serial {
+    ier: u8,
+    lcr: u8,
+    mcr: u8,
+    lsr: u8,
+    scr: u8,
+    divisor: u16,
+    fifo: bool,
+    //the THR empty interrupt, until the guest reads IIR or writes THR
+    thrInterrupt: bool,
+    //the irq line as the guest last saw it, the irqfd gives an edge on each rise
+    irqRaised: bool,
+    rx: VecDeque<u8>,
+    out: Box<dyn Write + Send>,
+    irq: Option<IrqFd>,
+}
+
+impl Serial {
+    pub fn New(out: Box<dyn Write + Send>, irq: Option<IrqFd>) -> Self {
+        return Serial {
+            ier: 0,
+            lcr: 0,
+            mcr: 0,
+            lsr: LSR_THRE | LSR_TEMT,
+            scr: 0,
+            divisor: DEFAULT_DIVISOR,
+            fifo: false,
+            thrInterrupt: false,
+            irqRaised: false,
+            rx: VecDeque::new(),
+            out: out,
+            irq: irq,
+        }
+    }
+
+    //the output to path, appended, or to stdout when None
+    pub fn Output(path: &Option<String>) -> Result<Box<dyn Write + Send>> {
+        match path {
+            None => return Ok(Box::new(std::io::stdout())),
+            Some(path) => {
+                let file = OpenOptions::new().create(true).append(true).open(path)
+                    .map_err(|e| Error::IOError(format!("open {} fail, io::error is {:?}", path, e)))?;
+                return Ok(Box::new(file))
+            }
+        }
+    }
+
+    //feed the input of addr to serial from its own thread, addr is "stdin" or "unix:<path>" with one
+    //client at a time
+    pub fn StartInput(serial: Arc<Mutex<Serial>>, addr: &str) -> Result<SerialInput> {
+        let stop = Arc::new(EventFd::New()?);
+        let threadStop = stop.clone();
+
+        if addr == "stdin" {
+            let thread = thread::spawn(move || {
+                Serial::Feed(&serial, StoppableRead::New(std::io::stdin(), threadStop));
+            });
+
+            return Ok(SerialInput {
+                stop: stop,
+                thread: Some(thread),
+            })
+        }
+
+        if !addr.starts_with("unix:") {
+            return Err(Error::Common(format!("bad serial input {}, stdin or unix:<path>", addr)));
+        }
+
+        let listener = UnixListener::bind(&addr[5..]).map_err(|e| Error::IOError(format!("io::error is {:?}", e)))?;
+        println!("serial input on {}", addr);
+        let thread = thread::spawn(move || {
+            while WaitReadable(listener.as_raw_fd(), &threadStop) {
+                match listener.accept() {
+                    Ok((conn, _)) => Serial::Feed(&serial, StoppableRead::New(conn, threadStop.clone())),
+                    Err(e) => println!("serial input accept fail: {:?}", e),
+                }
+            }
+        });
+
+        return Ok(SerialInput {
+            stop: stop,
+            thread: Some(thread),
+        })
+    }
+
+    fn Feed<R: Read>(serial: &Arc<Mutex<Serial>>, mut input: R) {
+        let mut buf = [0u8; 256];
+        loop {
+            match input.read(&mut buf) {
+                Ok(0) => return,
+                Ok(n) => serial.lock().Input(&buf[..n]),
+                Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => (),
+                Err(_) => return,
+            }
+        }
+    }
+
+    pub fn State(&self) -> SerialState {
+        return SerialState {
+            ier: self.ier,
+            lcr: self.lcr,
+            mcr: self.mcr,
+            lsr: self.lsr,
+            scr: self.scr,
+            divisor: self.divisor,
+            fifo: self.fifo,
+            thrInterrupt: self.thrInterrupt,
+            rx: self.rx.iter().cloned().collect(),
+        }
+    }
+
+    //the irqchip restored with the state has the line as it was, so no new edge
+    pub fn SetState(&mut self, state: &SerialState) {
+        self.ier = state.ier;
+        self.lcr = state.lcr;
+        self.mcr = state.mcr;
+        self.lsr = state.lsr;
+        self.scr = state.scr;
+        self.divisor = state.divisor;
+        self.fifo = state.fifo;
+        self.thrInterrupt = state.thrInterrupt;
+        self.rx = state.rx.iter().cloned().collect();
+        self.irqRaised = self.InterruptId() != IIR_NONE;
+    }
+
+    //a byte for the guest to read from RBR, false when it is dropped with LSR_OE
+    fn Receive(&mut self, b: u8) -> bool {
+        if self.rx.len() == RX_LIMIT {
+            self.lsr |= LSR_OE;
+            return false
+        }
+
+        self.rx.push_back(b);
+        return true
+    }
+
+    //bytes for the guest to read from RBR
+    pub fn Input(&mut self, data: &[u8]) {
+        for &b in data {
+            if !self.Receive(b) {
+                break;
+            }
+        }
+
+        self.Update();
+    }
+
+    fn ReadReg(&mut self, offset: u64) -> u8 {
+        let dlab = self.lcr & LCR_DLAB != 0;
+        let res = match offset {
+            UART_RBR_THR if dlab => self.divisor as u8,
+            UART_RBR_THR => self.rx.pop_front().unwrap_or(0),
+            UART_IER if dlab => (self.divisor >> 8) as u8,
+            UART_IER => self.ier,
+            UART_IIR_FCR => {
+                let id = self.InterruptId();
+                //reading IIR acknowledges the THR empty interrupt
+                if id == IIR_THRI {
+                    self.thrInterrupt = false;
+                }
+
+                id | if self.fifo { IIR_FIFO_ENABLED } else { 0 }
+            }
+            UART_LCR => self.lcr,
+            UART_MCR => self.mcr,
+            UART_LSR => {
+                let lsr = self.lsr | if self.rx.len() > 0 { LSR_DR } else { 0 };
+                //the overrun is reported once
+                self.lsr &= !LSR_OE;
+                lsr
+            }
+            UART_MSR => MSR_DEFAULT,
+            UART_SCR => self.scr,
+            _ => 0xff,
+        };
+
+        self.Update();
+        return res
+    }
+
+    fn WriteReg(&mut self, offset: u64, value: u8) {
+        let dlab = self.lcr & LCR_DLAB != 0;
+        match offset {
+            UART_RBR_THR if dlab => self.divisor = (self.divisor & 0xff00) | value as u16,
+            UART_RBR_THR => {
+                if self.mcr & MCR_LOOP != 0 {
+                    self.Receive(value);
+                } else {
+                    let _ = self.out.write_all(&[value]);
+                    if value == b'\n' {
+                        let _ = self.out.flush();
+                    }
+                }
+
+                self.thrInterrupt = true;
+            }
+            UART_IER if dlab => self.divisor = (self.divisor & 0xff) | (value as u16) << 8,
+            UART_IER => {
+                //enabling it with THR empty raises the THR interrupt
+                if value & IER_THRI != 0 && self.ier & IER_THRI == 0 {
+                    self.thrInterrupt = true;
+                }
+
+                self.ier = value & IER_MASK;
+            }
+            UART_IIR_FCR => {
+                self.fifo = value & FCR_FIFO_ENABLE != 0;
+                if value & FCR_CLEAR_RX != 0 {
+                    self.rx.clear();
+                }
+            }
+            UART_LCR => self.lcr = value,
+            UART_MCR => self.mcr = value,
+            UART_SCR => self.scr = value,
+            //LSR and MSR are read only
+            _ => (),
+        }
+
+        self.Update();
+    }
+
+    //the pending interrupt of the highest priority as in IIR
+    fn InterruptId(&self) -> u8 {
+        if self.ier & IER_RDI != 0 && self.rx.len() > 0 {
+            return IIR_RDI
+        }
+
+        if self.ier & IER_THRI != 0 && self.thrInterrupt {
+            return IIR_THRI
+        }
+
+        return IIR_NONE
+    }
+
+    fn Update(&mut self) {
+        let pending = self.InterruptId() != IIR_NONE;
+        if pending && !self.irqRaised {
+            if let Some(irq) = &self.irq {
+                if let Err(e) = irq.Trigger() {
+                    println!("serial irq fail: {:?}", e);
+                }
+            }
+        }
+
+        self.irqRaised = pending;
+    }
+}
+
+//the registers are a byte each, a wider access goes to the registers after offset
+impl BusDevice for Serial {
+    fn Read(&mut self, offset: u64, data: &mut [u8]) {
+        for (i, b) in data.iter_mut().enumerate() {
+            *b = self.ReadReg(offset + i as u64);
+        }
+    }
+
+    fn Write(&mut self, offset: u64, data: &[u8]) {
+        for (i, &b) in data.iter().enumerate() {
+            self.WriteReg(offset + i as u64, b);
+        }
+    }
+}
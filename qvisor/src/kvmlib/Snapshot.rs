@@ -0,0 +1,860 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::mem::size_of;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+use std::slice;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use kvm_bindings::{kvm_fpu, kvm_lapic_state, kvm_regs, kvm_sregs, kvm_xcrs, kvm_xsave};
+use kvm_ioctls::VcpuFd;
+
+use super::qlib::Common::Error;
+use super::qlib::Common::Result;
+use super::qlib::ShareSpace;
+use super::Cpu;
+use super::Gdt::TaskStateSegment;
+use super::GuestMemory::GuestMemory;
+use super::Irqchip::{IrqchipState, IRQCHIP_COUNT};
+use super::MemMgr;
+use super::Serial::SerialState;
+use super::Symbol::Reader;
+
+const SNAPSHOT_MAGIC: &[u8; 8] = b"QVSNAP\0\0";
+//version 2 adds the prefetch order, version 3 the in-kernel irqchip and pit, version 4 the COM1 uart,
+//version 5 the repr(C) ShareSpace with the u32 lock word
+pub const SNAPSHOT_VERSION: u32 = 5;
+//the guest memory of an older snapshot holds the old ShareSpace layout, which qvisor can't read
+const SNAPSHOT_MIN_VERSION: u32 = 5;
+
+const PAGE_SIZE: u64 = MemMgr::PAGE_SIZE_4K;
+//the fixed part in front of the metadata: magic, version, metadata length, page data offset
+const HEADER_SIZE: usize = 8 + 4 + 8 + 8;
+const MAX_META_SIZE: u64 = 256 * MemMgr::ONE_MB;
+//the stack pages from rsp up put at the front of the prefetch order
+const HOT_STACK_PAGES: u64 = 16;
+
+//the guest memory kinds in a snapshot
+pub const REGION_KERNEL_MEM: u32 = 0;
+pub const REGION_KERNEL_ELF: u32 = 1;
+pub const REGION_PHY: u32 = 2;
+
+//the msrs not covered by kvm_sregs which qkernel may have programmed. KVM_GET_MSRS stops at the
+//first one the host doesn't know, so the architectural ones go first
+const SNAPSHOT_MSR_COUNT: usize = 10;
+const SNAPSHOT_MSRS: [u32; SNAPSHOT_MSR_COUNT] = [
+    0x174,          //IA32_SYSENTER_CS
+    0x175,          //IA32_SYSENTER_ESP
+    0x176,          //IA32_SYSENTER_EIP
+    0x277,          //IA32_PAT
+    0xc000_0081,    //STAR
+    0xc000_0082,    //LSTAR
+    0xc000_0083,    //CSTAR
+    0xc000_0084,    //SFMASK
+    0xc000_0102,    //KERNEL_GS_BASE
+    0x10,           //IA32_TSC
+];
+
+static SNAPSHOT_REQUESTED: AtomicBool = AtomicBool::new(false);
+static IMMEDIATE_EXIT_ADDR: AtomicU64 = AtomicU64::new(0);
+
+extern "C" fn RequestHandler(_sig: libc::c_int) {
+    SNAPSHOT_REQUESTED.store(true, Ordering::SeqCst);
+    let addr = IMMEDIATE_EXIT_ADDR.load(Ordering::SeqCst);
+    if addr != 0 {
+        unsafe {
+            ptr::write_volatile(addr as *mut u8, 1);
+        }
+    }
+}
+
+//SIGUSR2 asks for a snapshot, a migration or a template at whatever point the guest is. immediateExitAddr is the
+//immediate_exit byte of the vcpu, set in case the signal lands on another thread
+pub fn InstallRequestHandler(immediateExitAddr: u64) -> Result<()> {
+    IMMEDIATE_EXIT_ADDR.store(immediateExitAddr, Ordering::SeqCst);
+    unsafe {
+        let mut act: libc::sigaction = std::mem::zeroed();
+        act.sa_sigaction = RequestHandler as usize;
+        libc::sigemptyset(&mut act.sa_mask);
+        if libc::sigaction(libc::SIGUSR2, &act, ptr::null_mut()) < 0 {
+            return Err(Error::IOError(format!("sigaction fail, io::error is {:?}", std::io::Error::last_os_error())))
+        }
+    }
+
+    return Ok(())
+}
+
+pub fn TakeRequest() -> bool {
+    return SNAPSHOT_REQUESTED.swap(false, Ordering::SeqCst)
+}
+
+fn AsBytes<T>(obj: &T) -> &[u8] {
+    return unsafe { slice::from_raw_parts(obj as *const T as *const u8, size_of::<T>()) }
+}
+
+fn FromBytes<T: Copy>(data: &[u8]) -> Result<T> {
+    if data.len() != size_of::<T>() {
+        return Err(Error::Common(format!("snapshot record size {} doesn't match {}", data.len(), size_of::<T>())));
+    }
+
+    return Ok(unsafe { ptr::read_unaligned(data.as_ptr() as *const T) })
+}
+
+fn IoErr(e: std::io::Error) -> Error {
+    return Error::IOError(format!("io::error is {:?}", e))
+}
+
+fn Truncated() -> Error {
+    return Error::Common(String::from("truncated snapshot"))
+}
+
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct MsrEntry {
+    index: u32,
+    reserved: u32,
+    data: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct MsrBuf {
+    nmsrs: u32,
+    pad: u32,
+    entries: [MsrEntry; SNAPSHOT_MSR_COUNT],
+}
+
+//the architectural state of one vcpu
+pub struct VcpuState {
+    pub regs: kvm_regs,
+    pub sregs: kvm_sregs,
+    pub fpu: kvm_fpu,
+    pub xsave: Option<kvm_xsave>,
+    pub xcrs: Option<kvm_xcrs>,
+    pub msrs: Vec<(u32, u64)>,
+    //only with an in kernel irqchip
+    pub lapic: Option<kvm_lapic_state>,
+}
+
+impl VcpuState {
+    pub fn Get(vcpu: &VcpuFd) -> Result<Self> {
+        let fd = vcpu.as_raw_fd();
+
+        let mut xsave = kvm_xsave::default();
+        let xsave = Cpu::Ioctl(fd, super::KVM_GET_XSAVE, &mut xsave as *mut kvm_xsave).ok().map(|_| xsave);
+
+        let mut xcrs = kvm_xcrs::default();
+        let xcrs = Cpu::Ioctl(fd, Cpu::KVM_GET_XCRS, &mut xcrs as *mut kvm_xcrs).ok().map(|_| xcrs);
+
+        let mut lapic = kvm_lapic_state::default();
+        let lapic = Cpu::Ioctl(fd, super::KVM_GET_LAPIC, &mut lapic as *mut kvm_lapic_state).ok().map(|_| lapic);
+
+        let mut buf = MsrBuf::default();
+        buf.nmsrs = SNAPSHOT_MSRS.len() as u32;
+        for (i, &index) in SNAPSHOT_MSRS.iter().enumerate() {
+            buf.entries[i].index = index;
+        }
+
+        let n = Cpu::Ioctl(fd, super::KVM_GET_MSRS, &mut buf as *mut MsrBuf)? as usize;
+        let msrs = buf.entries[..n].iter().map(|e| (e.index, e.data)).collect();
+
+        return Ok(VcpuState {
+            regs: vcpu.get_regs().map_err(IoErr)?,
+            sregs: vcpu.get_sregs().map_err(IoErr)?,
+            fpu: vcpu.get_fpu().map_err(IoErr)?,
+            xsave: xsave,
+            xcrs: xcrs,
+            msrs: msrs,
+            lapic: lapic,
+        })
+    }
+
+    //the vcpu's cpuid has to be set before, it decides which xsave components are accepted
+    pub fn Set(&self, vcpu: &VcpuFd) -> Result<()> {
+        let fd = vcpu.as_raw_fd();
+
+        vcpu.set_sregs(&self.sregs).map_err(IoErr)?;
+        vcpu.set_regs(&self.regs).map_err(IoErr)?;
+
+        //XCR0 before the xsave area, which may hold components only XCR0 enables
+        if let Some(xcrs) = &self.xcrs {
+            let mut xcrs = *xcrs;
+            Cpu::Ioctl(fd, Cpu::KVM_SET_XCRS, &mut xcrs as *mut kvm_xcrs)?;
+        }
+
+        match &self.xsave {
+            Some(xsave) => {
+                let mut xsave = *xsave;
+                Cpu::Ioctl(fd, super::KVM_SET_XSAVE, &mut xsave as *mut kvm_xsave)?;
+            }
+            None => vcpu.set_fpu(&self.fpu).map_err(IoErr)?,
+        }
+
+        let mut buf = MsrBuf::default();
+        buf.nmsrs = self.msrs.len() as u32;
+        for (i, &(index, data)) in self.msrs.iter().enumerate() {
+            buf.entries[i].index = index;
+            buf.entries[i].data = data;
+        }
+
+        let n = Cpu::Ioctl(fd, super::KVM_SET_MSRS, &mut buf as *mut MsrBuf)? as usize;
+        if n != self.msrs.len() {
+            return Err(Error::Common(format!("only {} of {} msrs restored, msr {:x} is rejected", n, self.msrs.len(), self.msrs[n].0)));
+        }
+
+        if let Some(lapic) = &self.lapic {
+            let mut lapic = *lapic;
+            Cpu::Ioctl(fd, super::KVM_SET_LAPIC, &mut lapic as *mut kvm_lapic_state)?;
+        }
+
+        return Ok(())
+    }
+
+    fn Encode(&self, buf: &mut Vec<u8>) {
+        PutBytes(buf, AsBytes(&self.regs));
+        PutBytes(buf, AsBytes(&self.sregs));
+        PutBytes(buf, AsBytes(&self.fpu));
+        PutOption(buf, self.xsave.as_ref().map(AsBytes));
+        PutOption(buf, self.xcrs.as_ref().map(AsBytes));
+        PutU32(buf, self.msrs.len() as u32);
+        for &(index, data) in &self.msrs {
+            PutU32(buf, index);
+            PutU64(buf, data);
+        }
+        PutOption(buf, self.lapic.as_ref().map(AsBytes));
+    }
+
+    fn Decode(r: &mut Reader) -> Result<Self> {
+        let regs = FromBytes(GetBytes(r)?)?;
+        let sregs = FromBytes(GetBytes(r)?)?;
+        let fpu = FromBytes(GetBytes(r)?)?;
+        let xsave = match GetOption(r)? {
+            Some(data) => Some(FromBytes(data)?),
+            None => None,
+        };
+        let xcrs = match GetOption(r)? {
+            Some(data) => Some(FromBytes(data)?),
+            None => None,
+        };
+
+        let count = r.U32().ok_or_else(Truncated)? as usize;
+        if count > SNAPSHOT_MSR_COUNT {
+            return Err(Error::Common(format!("too many msrs {} in snapshot", count)));
+        }
+
+        let mut msrs = Vec::with_capacity(count);
+        for _ in 0..count {
+            msrs.push((r.U32().ok_or_else(Truncated)?, r.U64().ok_or_else(Truncated)?));
+        }
+
+        let lapic = match GetOption(r)? {
+            Some(data) => Some(FromBytes(data)?),
+            None => None,
+        };
+
+        return Ok(VcpuState {
+            regs, sregs, fpu, xsave, xcrs, msrs, lapic,
+        })
+    }
+}
+
+pub fn PutU32(buf: &mut Vec<u8>, v: u32) {
+    buf.extend_from_slice(&v.to_le_bytes());
+}
+
+pub fn PutU64(buf: &mut Vec<u8>, v: u64) {
+    buf.extend_from_slice(&v.to_le_bytes());
+}
+
+pub fn PutBytes(buf: &mut Vec<u8>, data: &[u8]) {
+    PutU64(buf, data.len() as u64);
+    buf.extend_from_slice(data);
+}
+
+fn PutOption(buf: &mut Vec<u8>, data: Option<&[u8]>) {
+    match data {
+        Some(data) => {
+            buf.push(1);
+            PutBytes(buf, data);
+        }
+        None => buf.push(0),
+    }
+}
+
+pub fn GetBytes<'a>(r: &mut Reader<'a>) -> Result<&'a [u8]> {
+    let len = r.U64().ok_or_else(Truncated)?;
+    if len > r.Remain() as u64 {
+        return Err(Truncated());
+    }
+
+    return r.Bytes(len as usize).ok_or_else(Truncated)
+}
+
+fn GetOption<'a>(r: &mut Reader<'a>) -> Result<Option<&'a [u8]>> {
+    match r.U8().ok_or_else(Truncated)? {
+        0 => Ok(None),
+        _ => Ok(Some(GetBytes(r)?)),
+    }
+}
+
+//a guest memory range and the pages of it saved in the snapshot
+pub struct MemRegion {
+    pub kind: u32,
+    pub hostAddr: u64,
+    pub phyAddr: u64,
+    pub len: u64,
+    pub hugePage: bool,
+    //indexes of the 4KB pages with data, the others are zero
+    pub pages: Vec<u32>,
+    //file offset of the first saved page, the saved pages follow each other
+    pub dataOffset: u64,
+}
+
+impl MemRegion {
+    pub fn New(kind: u32, hostAddr: u64, phyAddr: u64, len: u64, hugePage: bool) -> Self {
+        return MemRegion {
+            kind: kind,
+            hostAddr: hostAddr,
+            phyAddr: phyAddr,
+            len: len,
+            hugePage: hugePage,
+            pages: Vec::new(),
+            dataOffset: 0,
+        }
+    }
+
+    //find the pages worth saving: resident in the host and not all zero. mincore keeps the scan
+    //from faulting in the untouched part of the region
+    pub fn ScanPages(&mut self) -> Result<()> {
+        let pageCount = (self.len / PAGE_SIZE) as usize;
+        let mut resident = vec![0 as u8; pageCount];
+        let ret = unsafe {
+            libc::mincore(self.hostAddr as *mut libc::c_void, self.len as usize, resident.as_mut_ptr())
+        };
+        if ret < 0 {
+            return Err(Error::IOError(format!("mincore fail, io::error is {:?}", std::io::Error::last_os_error())));
+        }
+
+        self.pages.clear();
+        for i in 0..pageCount {
+            if resident[i] & 1 == 0 {
+                continue;
+            }
+
+            let page = unsafe { slice::from_raw_parts((self.hostAddr + i as u64 * PAGE_SIZE) as *const u64, (PAGE_SIZE / 8) as usize) };
+            if page.iter().any(|&w| w != 0) {
+                self.pages.push(i as u32);
+            }
+        }
+
+        return Ok(())
+    }
+
+    //the saved pages as runs of (first page index, page count)
+    pub fn Runs(&self) -> Vec<(u32, u32)> {
+        let mut runs: Vec<(u32, u32)> = Vec::new();
+        for &idx in &self.pages {
+            if let Some(last) = runs.last_mut() {
+                if last.0 + last.1 == idx {
+                    last.1 += 1;
+                    continue;
+                }
+            }
+
+            runs.push((idx, 1));
+        }
+
+        return runs;
+    }
+
+    //the file offset of the page at index pos of pages
+    pub fn PageOffset(&self, pos: usize) -> u64 {
+        return self.dataOffset + pos as u64 * PAGE_SIZE
+    }
+}
+
+//the regions of a saved vm come from a file or a peer and their pages are copied to hostAddr, so every
+//region has to be exactly one of the mappings (host address, len) made for them, no two on the same one
+pub fn CheckLayout(regions: &[MemRegion], mapped: &[(u64, u64)]) -> Result<()> {
+    let mut used = vec![false; mapped.len()];
+    for r in regions {
+        if r.kind != REGION_KERNEL_MEM && r.kind != REGION_KERNEL_ELF && r.kind != REGION_PHY {
+            return Err(Error::Common(format!("unknown region kind {} in the saved vm", r.kind)));
+        }
+
+        match mapped.iter().position(|&m| m == (r.hostAddr, r.len)) {
+            Some(idx) if !used[idx] => used[idx] = true,
+            Some(_) => return Err(Error::Common(format!("duplicate region {:x} len {:x} in the saved vm", r.hostAddr, r.len))),
+            None => return Err(Error::Common(format!("region {:x} len {:x} of the saved vm is not mapped", r.hostAddr, r.len))),
+        }
+    }
+
+    return Ok(())
+}
+
+//everything needed to rebuild the vm in a new qvisor process. guest memory is at fixed host
+//addresses, so the restored vm finds it at the same place
+pub struct Snapshot {
+    pub elfPath: String,
+    pub entry: u64,
+    pub topStackAddr: u64,
+    pub defaultStackAddr: u64,
+    pub shareSpace: u64,
+
+    pub gdtAddr: u64,
+    pub gdtLimit: u16,
+    pub tssAddr: u64,
+    pub idtAddr: u64,
+
+    pub pageTableRoot: u64,
+    pub pagePoolBase: u64,
+    pub pagePoolCount: u32,
+    pub pagePoolNext: u32,
+    pub pagePoolFree: Vec<u32>,
+
+    //(slot, guest physical address, size, host address)
+    pub memSlots: Vec<(u32, u64, u64, u64)>,
+    pub vcpus: Vec<VcpuState>,
+    pub regions: Vec<MemRegion>,
+    //(region index, page index) of every saved page, in the order a lazy restore prefetches them
+    pub prefetch: Vec<(u32, u32)>,
+    //None for the snapshots of version 2 and before, taken without an irqchip
+    pub irqchip: Option<IrqchipState>,
+    //None for the snapshots of version 3 and before, and for a vm saved before it started the uart
+    pub serial: Option<SerialState>,
+
+    //the snapshot file the page data is read from, None when writing
+    pub file: Option<File>,
+}
+
+//the host addresses of the pages vcpu 0 needs first after a restore: its page table root, code,
+//stack, descriptor tables and the ShareSpace
+pub fn HotPages(reader: &GuestMemory, vcpu: &VcpuState, shareSpace: u64) -> Vec<u64> {
+    let cr3 = vcpu.sregs.cr3;
+    let mut hot = vec![cr3 & !(PAGE_SIZE - 1)];
+
+    let mut vaddrs = vec![vcpu.regs.rip, vcpu.sregs.gdt.base, vcpu.sregs.idt.base, vcpu.sregs.tr.base];
+    if shareSpace != 0 {
+        let end = shareSpace + size_of::<ShareSpace>() as u64;
+        let mut vaddr = shareSpace & !(PAGE_SIZE - 1);
+        while vaddr < end {
+            vaddrs.push(vaddr);
+            vaddr += PAGE_SIZE;
+        }
+    }
+
+    for i in 0..HOT_STACK_PAGES {
+        vaddrs.push(vcpu.regs.rsp.wrapping_add(i * PAGE_SIZE));
+    }
+
+    for vaddr in vaddrs {
+        if let Ok(phy) = reader.VirtToPhyAt(cr3, vaddr) {
+            hot.push(phy & !(PAGE_SIZE - 1));
+        }
+    }
+
+    return hot;
+}
+
+impl Snapshot {
+    //hot are host addresses of pages to prefetch before the others, see HotPages
+    pub fn Write(&mut self, path: &str, hot: &[u64]) -> Result<()> {
+        for r in self.regions.iter_mut() {
+            r.ScanPages()?;
+        }
+
+        self.prefetch = self.PrefetchOrder(hot);
+
+        let mut meta = Vec::new();
+        self.Encode(&mut meta);
+
+        let metaEnd = (HEADER_SIZE + meta.len()) as u64;
+        let dataStart = (metaEnd + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+
+        //the region records hold offsets relative to the page data, which starts on a page boundary
+        let mut header = Vec::with_capacity(HEADER_SIZE);
+        header.extend_from_slice(SNAPSHOT_MAGIC);
+        PutU32(&mut header, SNAPSHOT_VERSION);
+        PutU64(&mut header, meta.len() as u64);
+        PutU64(&mut header, dataStart);
+
+        let f = File::create(path).map_err(IoErr)?;
+        let mut w = BufWriter::with_capacity(1 << 20, f);
+        w.write_all(&header).map_err(IoErr)?;
+        w.write_all(&meta).map_err(IoErr)?;
+        w.write_all(&vec![0; (dataStart - metaEnd) as usize]).map_err(IoErr)?;
+
+        let mut pages = 0;
+        for r in &self.regions {
+            for (start, count) in r.Runs() {
+                let data = unsafe {
+                    slice::from_raw_parts((r.hostAddr + start as u64 * PAGE_SIZE) as *const u8, (count as u64 * PAGE_SIZE) as usize)
+                };
+                w.write_all(data).map_err(IoErr)?;
+            }
+            pages += r.pages.len();
+        }
+
+        w.flush().map_err(IoErr)?;
+        println!("snapshot {} written: {} vcpus, {} regions, {} pages", path, self.vcpus.len(), self.regions.len(), pages);
+        return Ok(())
+    }
+
+    fn PrefetchOrder(&self, hot: &[u64]) -> Vec<(u32, u32)> {
+        let mut order = Vec::new();
+        let mut seen = HashSet::new();
+
+        for &addr in hot {
+            for (i, r) in self.regions.iter().enumerate() {
+                if addr < r.hostAddr || addr >= r.hostAddr + r.len {
+                    continue;
+                }
+
+                let page = ((addr - r.hostAddr) / PAGE_SIZE) as u32;
+                if r.pages.binary_search(&page).is_ok() && seen.insert((i as u32, page)) {
+                    order.push((i as u32, page));
+                }
+            }
+        }
+
+        for (i, r) in self.regions.iter().enumerate() {
+            for &page in &r.pages {
+                if !seen.contains(&(i as u32, page)) {
+                    order.push((i as u32, page));
+                }
+            }
+        }
+
+        return order;
+    }
+
+    fn Encode(&self, buf: &mut Vec<u8>) {
+        PutBytes(buf, self.elfPath.as_bytes());
+        PutU64(buf, self.entry);
+        PutU64(buf, self.topStackAddr);
+        PutU64(buf, self.defaultStackAddr);
+        PutU64(buf, self.shareSpace);
+
+        PutU64(buf, self.gdtAddr);
+        PutU32(buf, self.gdtLimit as u32);
+        PutU64(buf, self.tssAddr);
+        PutU64(buf, self.idtAddr);
+
+        PutU64(buf, self.pageTableRoot);
+        PutU64(buf, self.pagePoolBase);
+        PutU32(buf, self.pagePoolCount);
+        PutU32(buf, self.pagePoolNext);
+        PutU32(buf, self.pagePoolFree.len() as u32);
+        for &idx in &self.pagePoolFree {
+            PutU32(buf, idx);
+        }
+
+        PutU32(buf, self.memSlots.len() as u32);
+        for &(slot, gpa, size, hostAddr) in &self.memSlots {
+            PutU32(buf, slot);
+            PutU64(buf, gpa);
+            PutU64(buf, size);
+            PutU64(buf, hostAddr);
+        }
+
+        PutU32(buf, self.vcpus.len() as u32);
+        for vcpu in &self.vcpus {
+            vcpu.Encode(buf);
+        }
+
+        let mut dataOffset = 0;
+        PutU32(buf, self.regions.len() as u32);
+        for r in &self.regions {
+            PutU32(buf, r.kind);
+            PutU64(buf, r.hostAddr);
+            PutU64(buf, r.phyAddr);
+            PutU64(buf, r.len);
+            buf.push(r.hugePage as u8);
+            PutU64(buf, dataOffset);
+            PutU32(buf, r.pages.len() as u32);
+            for &idx in &r.pages {
+                PutU32(buf, idx);
+            }
+            dataOffset += r.pages.len() as u64 * PAGE_SIZE;
+        }
+
+        PutU32(buf, self.prefetch.len() as u32);
+        for &(region, page) in &self.prefetch {
+            PutU32(buf, region);
+            PutU32(buf, page);
+        }
+
+        match &self.irqchip {
+            Some(irqchip) => {
+                PutU32(buf, IRQCHIP_COUNT as u32);
+                for chip in &irqchip.chips {
+                    PutBytes(buf, AsBytes(chip));
+                }
+                PutBytes(buf, AsBytes(&irqchip.pit));
+            }
+            None => PutU32(buf, 0),
+        }
+
+        match &self.serial {
+            Some(serial) => PutOption(buf, Some(&serial.Encode())),
+            None => PutOption(buf, None),
+        }
+    }
+
+    //the metadata alone, for a vm state sent without a snapshot file
+    pub fn EncodeMeta(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.Encode(&mut buf);
+        return buf
+    }
+
+    pub fn DecodeMeta(data: &[u8]) -> Result<Self> {
+        return Self::Decode(&mut Reader::New(data), SNAPSHOT_VERSION)
+    }
+
+    //read the metadata, the page data stays in the file until LoadRegions
+    pub fn Open(path: &str) -> Result<Self> {
+        let mut f = File::open(path).map_err(IoErr)?;
+        let mut header = [0; HEADER_SIZE];
+        f.read_exact(&mut header).map_err(IoErr)?;
+
+        let mut r = Reader::New(&header);
+        if r.Bytes(8) != Some(&SNAPSHOT_MAGIC[..]) {
+            return Err(Error::Common(format!("{} is not a qvisor snapshot", path)));
+        }
+
+        let version = r.U32().ok_or_else(Truncated)?;
+        if version < SNAPSHOT_MIN_VERSION || version > SNAPSHOT_VERSION {
+            return Err(Error::Common(format!("snapshot version {} is not supported, expect {}", version, SNAPSHOT_VERSION)));
+        }
+
+        let metaLen = r.U64().ok_or_else(Truncated)?;
+        let dataStart = r.U64().ok_or_else(Truncated)?;
+        if metaLen > MAX_META_SIZE || dataStart < HEADER_SIZE as u64 + metaLen || dataStart % PAGE_SIZE != 0 {
+            return Err(Error::Common(format!("bad snapshot header: meta len {:x}, data start {:x}", metaLen, dataStart)));
+        }
+
+        let mut meta = vec![0; metaLen as usize];
+        f.read_exact(&mut meta).map_err(IoErr)?;
+
+        let mut snapshot = Self::Decode(&mut Reader::New(&meta), version)?;
+        let fileLen = f.metadata().map_err(IoErr)?.len();
+        for region in snapshot.regions.iter_mut() {
+            region.dataOffset += dataStart;
+            let end = region.PageOffset(region.pages.len());
+            if end > fileLen {
+                return Err(Truncated());
+            }
+        }
+
+        snapshot.file = Some(f);
+        return Ok(snapshot)
+    }
+
+    fn Decode(r: &mut Reader, version: u32) -> Result<Self> {
+        let elfPath = String::from_utf8(GetBytes(r)?.to_vec()).map_err(|_| Error::Common(String::from("bad kernel path in snapshot")))?;
+        let entry = r.U64().ok_or_else(Truncated)?;
+        let topStackAddr = r.U64().ok_or_else(Truncated)?;
+        let defaultStackAddr = r.U64().ok_or_else(Truncated)?;
+        let shareSpace = r.U64().ok_or_else(Truncated)?;
+
+        let gdtAddr = r.U64().ok_or_else(Truncated)?;
+        let gdtLimit = r.U32().ok_or_else(Truncated)? as u16;
+        let tssAddr = r.U64().ok_or_else(Truncated)?;
+        let idtAddr = r.U64().ok_or_else(Truncated)?;
+
+        let pageTableRoot = r.U64().ok_or_else(Truncated)?;
+        let pagePoolBase = r.U64().ok_or_else(Truncated)?;
+        let pagePoolCount = r.U32().ok_or_else(Truncated)?;
+        let pagePoolNext = r.U32().ok_or_else(Truncated)?;
+        let freeCount = r.U32().ok_or_else(Truncated)? as usize;
+        if freeCount > r.Remain() / 4 {
+            return Err(Truncated());
+        }
+
+        let mut pagePoolFree = Vec::with_capacity(freeCount);
+        for _ in 0..freeCount {
+            pagePoolFree.push(r.U32().ok_or_else(Truncated)?);
+        }
+
+        let slotCount = r.U32().ok_or_else(Truncated)? as usize;
+        let mut memSlots = Vec::new();
+        for _ in 0..slotCount {
+            memSlots.push((r.U32().ok_or_else(Truncated)?, r.U64().ok_or_else(Truncated)?,
+                           r.U64().ok_or_else(Truncated)?, r.U64().ok_or_else(Truncated)?));
+        }
+
+        let vcpuCount = r.U32().ok_or_else(Truncated)? as usize;
+        if vcpuCount == 0 || vcpuCount > super::qlib::MAX_VCPU_COUNT {
+            return Err(Error::Common(format!("bad vcpu count {} in snapshot", vcpuCount)));
+        }
+
+        let mut vcpus = Vec::with_capacity(vcpuCount);
+        for _ in 0..vcpuCount {
+            vcpus.push(VcpuState::Decode(r)?);
+        }
+
+        let regionCount = r.U32().ok_or_else(Truncated)? as usize;
+        let mut regions = Vec::new();
+        for _ in 0..regionCount {
+            let mut region = MemRegion::New(r.U32().ok_or_else(Truncated)?, r.U64().ok_or_else(Truncated)?,
+                                            r.U64().ok_or_else(Truncated)?, r.U64().ok_or_else(Truncated)?,
+                                            r.U8().ok_or_else(Truncated)? != 0);
+            region.dataOffset = r.U64().ok_or_else(Truncated)?;
+
+            let pageCount = r.U32().ok_or_else(Truncated)? as usize;
+            if pageCount > r.Remain() / 4 {
+                return Err(Truncated());
+            }
+
+            for _ in 0..pageCount {
+                let idx = r.U32().ok_or_else(Truncated)?;
+                if idx as u64 >= region.len / PAGE_SIZE || region.pages.last().map_or(false, |&last| last >= idx) {
+                    return Err(Error::Common(format!("bad page index {} in snapshot region {:x}", idx, region.hostAddr)));
+                }
+                region.pages.push(idx);
+            }
+
+            regions.push(region);
+        }
+
+        let mut prefetch = Vec::new();
+        if version >= 2 {
+            let count = r.U32().ok_or_else(Truncated)? as usize;
+            if count > r.Remain() / 8 {
+                return Err(Truncated());
+            }
+
+            for _ in 0..count {
+                let region = r.U32().ok_or_else(Truncated)?;
+                let page = r.U32().ok_or_else(Truncated)?;
+                if region as usize >= regions.len() || regions[region as usize].pages.binary_search(&page).is_err() {
+                    return Err(Error::Common(format!("bad prefetch page {}:{} in snapshot", region, page)));
+                }
+                prefetch.push((region, page));
+            }
+        } else {
+            //address order for snapshots without one
+            for (i, region) in regions.iter().enumerate() {
+                for &page in &region.pages {
+                    prefetch.push((i as u32, page));
+                }
+            }
+        }
+
+        let mut irqchip = None;
+        if version >= 3 {
+            match r.U32().ok_or_else(Truncated)? as usize {
+                0 => (),
+                IRQCHIP_COUNT => {
+                    let mut state: IrqchipState = unsafe { std::mem::zeroed() };
+                    for chip in state.chips.iter_mut() {
+                        *chip = FromBytes(GetBytes(r)?)?;
+                    }
+                    state.pit = FromBytes(GetBytes(r)?)?;
+                    irqchip = Some(state);
+                }
+                count => return Err(Error::Common(format!("bad irqchip count {} in snapshot", count))),
+            }
+        }
+
+        let mut serial = None;
+        if version >= 4 {
+            if let Some(data) = GetOption(r)? {
+                serial = Some(SerialState::Decode(data)?);
+            }
+        }
+
+        return Ok(Snapshot {
+            elfPath, entry, topStackAddr, defaultStackAddr, shareSpace,
+            gdtAddr, gdtLimit, tssAddr, idtAddr,
+            pageTableRoot, pagePoolBase, pagePoolCount, pagePoolNext, pagePoolFree,
+            memSlots, vcpus, regions, prefetch, irqchip, serial,
+            file: None,
+        })
+    }
+
+    //the memory slots, the page pool and the tables in it come from a file or a peer too and qvisor maps
+    //and writes through them. Every slot has to be inside one of the mappings made for the regions, and
+    //the pool with the page table root and the descriptor tables inside the kernel memory kernelMem,
+    //(host address, len)
+    pub fn CheckState(&self, mapped: &[(u64, u64)], kernelMem: (u64, u64)) -> Result<()> {
+        let mut slots = HashSet::new();
+        for &(slot, gpa, size, hostAddr) in &self.memSlots {
+            let inside = match gpa.checked_add(size) {
+                Some(end) => mapped.iter().any(|&(start, len)| start <= gpa && end <= start + len),
+                None => false,
+            };
+
+            if size == 0 || hostAddr != gpa || !inside || !slots.insert(slot) {
+                return Err(Error::Common(format!("bad memory slot {} at {:x} len {:x} host {:x} in the saved vm", slot, gpa, size, hostAddr)));
+            }
+        }
+
+        let (kernelStart, kernelLen) = kernelMem;
+        let poolEnd = (self.pagePoolCount as u64).checked_mul(PAGE_SIZE).and_then(|len| self.pagePoolBase.checked_add(len));
+        let poolInside = match poolEnd {
+            Some(end) => kernelStart <= self.pagePoolBase && end <= kernelStart + kernelLen,
+            None => false,
+        };
+
+        if !poolInside || self.pagePoolBase % PAGE_SIZE != 0 || self.pagePoolNext > self.pagePoolCount {
+            return Err(Error::Common(format!("bad page pool at {:x} of {} pages in the saved vm", self.pagePoolBase, self.pagePoolCount)));
+        }
+
+        let mut free = HashSet::new();
+        for &idx in &self.pagePoolFree {
+            if idx >= self.pagePoolNext || !free.insert(idx) {
+                return Err(Error::Common(format!("bad free page {} of the page pool in the saved vm", idx)));
+            }
+        }
+
+        //a page the pool handed out
+        let allocated = |addr: u64| {
+            addr % PAGE_SIZE == 0 && addr >= self.pagePoolBase && addr < self.pagePoolBase + self.pagePoolNext as u64 * PAGE_SIZE
+        };
+
+        if !allocated(self.pageTableRoot) {
+            return Err(Error::Common(format!("bad page table root {:x} in the saved vm", self.pageTableRoot)));
+        }
+
+        if !allocated(self.gdtAddr) || self.gdtLimit as u64 >= PAGE_SIZE || !allocated(self.tssAddr)
+            || (self.vcpus.len() * size_of::<TaskStateSegment>()) as u64 > PAGE_SIZE || !allocated(self.idtAddr) {
+            return Err(Error::Common(format!("bad descriptor tables gdt {:x} tss {:x} idt {:x} in the saved vm", self.gdtAddr, self.tssAddr, self.idtAddr)));
+        }
+
+        return Ok(())
+    }
+
+    pub fn Region(&self, kind: u32) -> Result<&MemRegion> {
+        return self.regions.iter().find(|r| r.kind == kind).ok_or(Error::Common(format!("no region of kind {} in snapshot", kind)))
+    }
+
+    //copy the saved pages of every region into its host mapping, the mappings must be fresh so the rest
+    //is zero. mapped are the mappings made for the regions, see CheckLayout
+    pub fn LoadRegions(&self, mapped: &[(u64, u64)]) -> Result<()> {
+        CheckLayout(&self.regions, mapped)?;
+        for region in &self.regions {
+            self.LoadRegion(region)?;
+        }
+
+        return Ok(())
+    }
+
+    fn LoadRegion(&self, region: &MemRegion) -> Result<()> {
+        let f = self.file.as_ref().ok_or(Error::Common(String::from("snapshot is not opened")))?;
+
+        let mut pos = 0;
+        for (start, count) in region.Runs() {
+            let data = unsafe {
+                slice::from_raw_parts_mut((region.hostAddr + start as u64 * PAGE_SIZE) as *mut u8, (count as u64 * PAGE_SIZE) as usize)
+            };
+            f.read_exact_at(data, region.PageOffset(pos)).map_err(IoErr)?;
+            pos += count as usize;
+        }
+
+        return Ok(())
+    }
+}
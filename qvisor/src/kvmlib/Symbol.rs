@@ -0,0 +1,734 @@
+use std::collections::HashMap;
+
+use xmas_elf::ElfFile;
+use xmas_elf::sections::SectionData;
+use xmas_elf::symbol_table::{Entry, Type};
+
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    pub addr: u64,
+    pub size: u64,
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct LineRow {
+    addr: u64,
+    file: u32,
+    line: u32,
+    //the first address after a sequence, not a real row
+    endSequence: bool,
+}
+
+//function symbols from .symtab/.strtab and the address to line mapping from .debug_line
+#[derive(Default)]
+pub struct SymbolTable {
+    symbols: Vec<Symbol>,
+    rows: Vec<LineRow>,
+    files: Vec<String>,
+}
+
+impl SymbolTable {
+    pub fn Init(elf: &ElfFile) -> Self {
+        let mut table = SymbolTable::default();
+        table.LoadSymbols(elf);
+
+        if let Some(section) = elf.find_section_by_name(".debug_line") {
+            let debugStr = elf.find_section_by_name(".debug_str").map(|s| s.raw_data(elf));
+            let debugLineStr = elf.find_section_by_name(".debug_line_str").map(|s| s.raw_data(elf));
+            table.LoadLines(section.raw_data(elf), debugStr, debugLineStr);
+        }
+
+        println!("kernel symbols: {} functions, {} line rows", table.symbols.len(), table.rows.len());
+        return table;
+    }
+
+    fn LoadSymbols(&mut self, elf: &ElfFile) {
+        let section = match elf.find_section_by_name(".symtab") {
+            None => return,
+            Some(s) => s,
+        };
+
+        let entries = match section.get_data(elf) {
+            Ok(SectionData::SymbolTable64(entries)) => entries,
+            _ => return,
+        };
+
+        for entry in entries {
+            match entry.get_type() {
+                Ok(Type::Func) | Ok(Type::NoType) => (),
+                _ => continue,
+            }
+
+            if entry.value() == 0 {
+                continue;
+            }
+
+            let name = match entry.get_name(elf) {
+                Ok(name) if name.len() > 0 => name,
+                _ => continue,
+            };
+
+            self.symbols.push(Symbol {
+                addr: entry.value(),
+                size: entry.size(),
+                name: Demangle(name),
+            })
+        }
+
+        //prefer the sized symbol when several symbols share the address
+        self.symbols.sort_by_key(|s| (s.addr, core::cmp::Reverse(s.size)));
+        self.symbols.dedup_by_key(|s| s.addr);
+    }
+
+    fn LoadLines(&mut self, debugLine: &[u8], debugStr: Option<&[u8]>, debugLineStr: Option<&[u8]>) {
+        let mut parser = LineParser {
+            table: self,
+            fileIdx: HashMap::new(),
+            debugStr: debugStr,
+            debugLineStr: debugLineStr,
+        };
+        parser.Parse(debugLine);
+        self.rows.sort_by_key(|r| (r.addr, !r.endSequence));
+    }
+
+    pub fn IsEmpty(&self) -> bool {
+        return self.symbols.len() == 0
+    }
+
+    //the symbol containing addr and the offset in it
+    pub fn Lookup(&self, addr: u64) -> Option<(&Symbol, u64)> {
+        let idx = match self.symbols.binary_search_by_key(&addr, |s| s.addr) {
+            Ok(idx) => idx,
+            Err(0) => return None,
+            Err(idx) => idx - 1,
+        };
+
+        let sym = &self.symbols[idx];
+        let offset = addr - sym.addr;
+
+        //a symbol without size covers the range up to the next symbol
+        if sym.size != 0 && offset >= sym.size {
+            return None;
+        }
+
+        return Some((sym, offset))
+    }
+
+    pub fn LineOf(&self, addr: u64) -> Option<(&str, u32)> {
+        let idx = match self.rows.binary_search_by_key(&addr, |r| r.addr) {
+            Ok(idx) => {
+                //several rows may start at addr, the last real one wins
+                let mut idx = idx;
+                while idx + 1 < self.rows.len() && self.rows[idx + 1].addr == addr {
+                    idx += 1;
+                }
+                idx
+            }
+            Err(0) => return None,
+            Err(idx) => idx - 1,
+        };
+
+        let row = &self.rows[idx];
+        if row.endSequence {
+            return None;
+        }
+
+        return Some((&self.files[row.file as usize], row.line))
+    }
+
+    //format addr as symbol+offset (file:line)
+    pub fn Symbolize(&self, addr: u64) -> String {
+        let mut res = match self.Lookup(addr) {
+            None => format!("{:#x}", addr),
+            Some((sym, offset)) => format!("{}+{:#x}", sym.name, offset),
+        };
+
+        if let Some((file, line)) = self.LineOf(addr) {
+            res = format!("{} ({}:{})", res, file, line);
+        }
+
+        return res;
+    }
+}
+
+//turn a legacy rust mangled name (_ZN...E) into a readable path, other names are returned as is
+pub fn Demangle(name: &str) -> String {
+    let bytes = name.as_bytes();
+    if !name.starts_with("_ZN") || !name.ends_with('E') {
+        return String::from(name);
+    }
+
+    let mut parts: Vec<String> = Vec::new();
+    let mut pos = 3;
+    while pos < bytes.len() - 1 {
+        let start = pos;
+        while pos < bytes.len() && bytes[pos].is_ascii_digit() {
+            pos += 1;
+        }
+
+        let len: usize = match name[start..pos].parse() {
+            Ok(len) => len,
+            Err(_) => return String::from(name),
+        };
+
+        //the last byte is the 'E', a part can't reach it or end inside a multibyte char
+        let end = match pos.checked_add(len) {
+            Some(end) if end <= bytes.len() - 1 => end,
+            _ => return String::from(name),
+        };
+
+        let part = match name.get(pos..end) {
+            Some(part) => part,
+            None => return String::from(name),
+        };
+        pos = end;
+
+        //the trailing hash element
+        if pos == bytes.len() - 1 && part.len() == 17 && part.starts_with('h') && part[1..].chars().all(|c| c.is_ascii_hexdigit()) {
+            break;
+        }
+
+        parts.push(DemangleElement(part));
+    }
+
+    return parts.join("::")
+}
+
+fn DemangleElement(part: &str) -> String {
+    let part = if part.starts_with("_$") { &part[1..] } else { part };
+    let mut res = String::new();
+    let mut rest = part;
+    while rest.len() > 0 {
+        if rest.starts_with("..") {
+            res.push_str("::");
+            rest = &rest[2..];
+            continue;
+        }
+
+        if rest.starts_with('$') {
+            if let Some(end) = rest[1..].find('$') {
+                let esc = &rest[1..end + 1];
+                let c = match esc {
+                    "SP" => Some("@".to_string()),
+                    "BP" => Some("*".to_string()),
+                    "RF" => Some("&".to_string()),
+                    "LT" => Some("<".to_string()),
+                    "GT" => Some(">".to_string()),
+                    "LP" => Some("(".to_string()),
+                    "RP" => Some(")".to_string()),
+                    "C" => Some(",".to_string()),
+                    _ if esc.starts_with('u') => u32::from_str_radix(&esc[1..], 16).ok()
+                        .and_then(core::char::from_u32).map(|c| c.to_string()),
+                    _ => None,
+                };
+
+                if let Some(c) = c {
+                    res.push_str(&c);
+                    rest = &rest[end + 2..];
+                    continue;
+                }
+            }
+        }
+
+        let c = rest.chars().next().unwrap();
+        res.push(c);
+        rest = &rest[c.len_utf8()..];
+    }
+
+    return res;
+}
+
+//a bounds checked little endian reader for the DWARF style sections
+pub struct Reader<'a> {
+    pub data: &'a [u8],
+    pub pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn New(data: &'a [u8]) -> Self {
+        return Reader {
+            data: data,
+            pos: 0,
+        }
+    }
+
+    pub fn Remain(&self) -> usize {
+        return self.data.len() - self.pos
+    }
+
+    pub fn Bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.Remain() < len {
+            return None;
+        }
+
+        let res = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        return Some(res)
+    }
+
+    pub fn U8(&mut self) -> Option<u8> {
+        return self.Bytes(1).map(|b| b[0])
+    }
+
+    pub fn U16(&mut self) -> Option<u16> {
+        return self.Bytes(2).map(|b| u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    pub fn U32(&mut self) -> Option<u32> {
+        return self.Bytes(4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    pub fn U64(&mut self) -> Option<u64> {
+        let lo = self.U32()? as u64;
+        let hi = self.U32()? as u64;
+        return Some(lo | (hi << 32))
+    }
+
+    pub fn UintN(&mut self, size: usize) -> Option<u64> {
+        match size {
+            1 => self.U8().map(|v| v as u64),
+            2 => self.U16().map(|v| v as u64),
+            4 => self.U32().map(|v| v as u64),
+            8 => self.U64(),
+            _ => None,
+        }
+    }
+
+    pub fn Uleb(&mut self) -> Option<u64> {
+        let mut res: u64 = 0;
+        let mut shift = 0;
+        loop {
+            let b = self.U8()?;
+            if shift < 64 {
+                res |= ((b & 0x7f) as u64) << shift;
+            }
+            shift += 7;
+            if b & 0x80 == 0 {
+                return Some(res)
+            }
+        }
+    }
+
+    pub fn Sleb(&mut self) -> Option<i64> {
+        let mut res: i64 = 0;
+        let mut shift = 0;
+        loop {
+            let b = self.U8()?;
+            if shift < 64 {
+                res |= ((b & 0x7f) as i64) << shift;
+            }
+            shift += 7;
+            if b & 0x80 == 0 {
+                if shift < 64 && b & 0x40 != 0 {
+                    res |= -1i64 << shift;
+                }
+                return Some(res)
+            }
+        }
+    }
+
+    pub fn CStr(&mut self) -> Option<&'a str> {
+        let rest = &self.data[self.pos..];
+        let len = rest.iter().position(|&b| b == 0)?;
+        self.pos += len + 1;
+        return core::str::from_utf8(&rest[..len]).ok()
+    }
+}
+
+fn StrAt(section: Option<&[u8]>, offset: u64) -> Option<String> {
+    let data = section?;
+    if offset as usize >= data.len() {
+        return None;
+    }
+
+    let mut r = Reader::New(&data[offset as usize..]);
+    return r.CStr().map(String::from)
+}
+
+const DW_LNS_COPY: u8 = 1;
+const DW_LNS_ADVANCE_PC: u8 = 2;
+const DW_LNS_ADVANCE_LINE: u8 = 3;
+const DW_LNS_SET_FILE: u8 = 4;
+const DW_LNS_CONST_ADD_PC: u8 = 8;
+const DW_LNS_FIXED_ADVANCE_PC: u8 = 9;
+
+const DW_LNE_END_SEQUENCE: u8 = 1;
+const DW_LNE_SET_ADDRESS: u8 = 2;
+
+const DW_LNCT_PATH: u64 = 1;
+const DW_LNCT_DIRECTORY_INDEX: u64 = 2;
+
+const DW_FORM_BLOCK: u64 = 0x09;
+const DW_FORM_BLOCK1: u64 = 0x0a;
+const DW_FORM_DATA1: u64 = 0x0b;
+const DW_FORM_DATA2: u64 = 0x05;
+const DW_FORM_DATA4: u64 = 0x06;
+const DW_FORM_DATA8: u64 = 0x07;
+const DW_FORM_DATA16: u64 = 0x1e;
+const DW_FORM_STRING: u64 = 0x08;
+const DW_FORM_STRP: u64 = 0x0e;
+const DW_FORM_LINE_STRP: u64 = 0x1f;
+const DW_FORM_UDATA: u64 = 0x0f;
+
+//a .debug_line program parser for DWARF version 2 to 5
+struct LineParser<'a, 'b> {
+    table: &'a mut SymbolTable,
+    fileIdx: HashMap<String, u32>,
+    debugStr: Option<&'b [u8]>,
+    debugLineStr: Option<&'b [u8]>,
+}
+
+impl<'a, 'b> LineParser<'a, 'b> {
+    fn Parse(&mut self, data: &[u8]) {
+        let mut r = Reader::New(data);
+        while r.Remain() > 0 {
+            let mut unitLen = match r.U32() {
+                None => return,
+                Some(l) => l as u64,
+            };
+
+            let mut offsetSize = 4;
+            if unitLen == 0xffff_ffff {
+                unitLen = match r.U64() {
+                    None => return,
+                    Some(l) => l,
+                };
+                offsetSize = 8;
+            }
+
+            let unit = match r.Bytes(unitLen as usize) {
+                None => return,
+                Some(unit) => unit,
+            };
+
+            //a malformed unit is skipped, the following ones may still be good
+            self.ParseUnit(unit, offsetSize);
+        }
+    }
+
+    fn InternFile(&mut self, name: String) -> u32 {
+        if let Some(&idx) = self.fileIdx.get(&name) {
+            return idx;
+        }
+
+        let idx = self.table.files.len() as u32;
+        self.table.files.push(name.clone());
+        self.fileIdx.insert(name, idx);
+        return idx;
+    }
+
+    fn FormValue(&self, r: &mut Reader, form: u64, offsetSize: usize) -> Option<(Option<String>, u64)> {
+        match form {
+            DW_FORM_STRING => Some((Some(String::from(r.CStr()?)), 0)),
+            DW_FORM_STRP => {
+                let off = r.UintN(offsetSize)?;
+                Some((StrAt(self.debugStr, off), 0))
+            }
+            DW_FORM_LINE_STRP => {
+                let off = r.UintN(offsetSize)?;
+                Some((StrAt(self.debugLineStr, off), 0))
+            }
+            DW_FORM_UDATA => Some((None, r.Uleb()?)),
+            DW_FORM_DATA1 => Some((None, r.U8()? as u64)),
+            DW_FORM_DATA2 => Some((None, r.U16()? as u64)),
+            DW_FORM_DATA4 => Some((None, r.U32()? as u64)),
+            DW_FORM_DATA8 => Some((None, r.U64()?)),
+            DW_FORM_DATA16 => {
+                r.Bytes(16)?;
+                Some((None, 0))
+            }
+            DW_FORM_BLOCK => {
+                let len = r.Uleb()?;
+                r.Bytes(len as usize)?;
+                Some((None, 0))
+            }
+            DW_FORM_BLOCK1 => {
+                let len = r.U8()?;
+                r.Bytes(len as usize)?;
+                Some((None, 0))
+            }
+            _ => None,
+        }
+    }
+
+    //v5 directory and file tables: a list of (content type, form) then the entries
+    fn EntryTable(&self, r: &mut Reader, offsetSize: usize) -> Option<Vec<(Option<String>, u64)>> {
+        let formatCount = r.U8()?;
+        let mut formats = Vec::new();
+        for _ in 0..formatCount {
+            formats.push((r.Uleb()?, r.Uleb()?));
+        }
+
+        let count = r.Uleb()?;
+        let mut res = Vec::new();
+        for _ in 0..count {
+            let mut path = None;
+            let mut dir = 0;
+            for &(contentType, form) in &formats {
+                let (s, v) = self.FormValue(r, form, offsetSize)?;
+                match contentType {
+                    DW_LNCT_PATH => path = s,
+                    DW_LNCT_DIRECTORY_INDEX => dir = v,
+                    _ => (),
+                }
+            }
+
+            res.push((path, dir));
+        }
+
+        return Some(res)
+    }
+
+    fn ParseUnit(&mut self, unit: &[u8], offsetSize: usize) -> Option<()> {
+        let mut r = Reader::New(unit);
+        let version = r.U16()?;
+        if version < 2 || version > 5 {
+            return None;
+        }
+
+        let mut addrSize = 8;
+        if version >= 5 {
+            addrSize = r.U8()? as usize;
+            let _segSelSize = r.U8()?;
+        }
+
+        let headerLen = r.UintN(offsetSize)? as usize;
+        let programStart = r.pos + headerLen;
+
+        let minInstLen = r.U8()? as u64;
+        if version >= 4 {
+            let _maxOpsPerInst = r.U8()?;
+        }
+
+        let _defaultIsStmt = r.U8()?;
+        let lineBase = r.U8()? as i8 as i64;
+        let lineRange = r.U8()? as u64;
+        let opcodeBase = r.U8()?;
+        if lineRange == 0 || opcodeBase == 0 {
+            return None;
+        }
+
+        let stdLens = r.Bytes(opcodeBase as usize - 1)?.to_vec();
+
+        //file index in the unit -> index in table.files
+        let mut files: Vec<u32> = Vec::new();
+        if version >= 5 {
+            let dirs = self.EntryTable(&mut r, offsetSize)?;
+            let entries = self.EntryTable(&mut r, offsetSize)?;
+            for (path, dir) in entries {
+                let path = path.unwrap_or(String::from("<unknown>"));
+                let full = match dirs.get(dir as usize) {
+                    Some((Some(d), _)) if !path.starts_with('/') => format!("{}/{}", d, path),
+                    _ => path,
+                };
+                files.push(self.InternFile(full));
+            }
+        } else {
+            let mut dirs: Vec<String> = Vec::new();
+            loop {
+                let d = r.CStr()?;
+                if d.len() == 0 {
+                    break;
+                }
+                dirs.push(String::from(d));
+            }
+
+            //file numbers start from 1 before v5
+            files.push(self.InternFile(String::from("<unknown>")));
+            loop {
+                let f = r.CStr()?;
+                if f.len() == 0 {
+                    break;
+                }
+
+                let dir = r.Uleb()?;
+                let _mtime = r.Uleb()?;
+                let _len = r.Uleb()?;
+
+                let full = if dir > 0 && (dir as usize) <= dirs.len() && !f.starts_with('/') {
+                    format!("{}/{}", dirs[dir as usize - 1], f)
+                } else {
+                    String::from(f)
+                };
+                files.push(self.InternFile(full));
+            }
+        }
+
+        if programStart > unit.len() {
+            return None;
+        }
+        r.pos = programStart;
+
+        let fileOf = |idx: u64| -> u32 {
+            match files.get(idx as usize) {
+                Some(&f) => f,
+                None => files[0],
+            }
+        };
+
+        if files.len() == 0 {
+            return None;
+        }
+
+        let mut addr: u64 = 0;
+        let mut file: u64 = 1;
+        let mut line: i64 = 1;
+
+        while r.Remain() > 0 {
+            let op = r.U8()?;
+            if op >= opcodeBase {
+                let adj = (op - opcodeBase) as u64;
+                addr = addr.wrapping_add((adj / lineRange) * minInstLen);
+                line += lineBase + (adj % lineRange) as i64;
+                self.table.rows.push(LineRow { addr, file: fileOf(file), line: line as u32, endSequence: false });
+                continue;
+            }
+
+            match op {
+                0 => {
+                    let len = r.Uleb()? as usize;
+                    let ext = r.Bytes(len)?;
+                    if ext.len() == 0 {
+                        continue;
+                    }
+
+                    match ext[0] {
+                        DW_LNE_END_SEQUENCE => {
+                            self.table.rows.push(LineRow { addr, file: fileOf(file), line: line as u32, endSequence: true });
+                            addr = 0;
+                            file = 1;
+                            line = 1;
+                        }
+                        DW_LNE_SET_ADDRESS => {
+                            let mut er = Reader::New(&ext[1..]);
+                            addr = er.UintN(core::cmp::min(addrSize, ext.len() - 1))?;
+                        }
+                        _ => (),
+                    }
+                }
+                DW_LNS_COPY => {
+                    self.table.rows.push(LineRow { addr, file: fileOf(file), line: line as u32, endSequence: false });
+                }
+                DW_LNS_ADVANCE_PC => {
+                    addr = addr.wrapping_add(r.Uleb()? * minInstLen);
+                }
+                DW_LNS_ADVANCE_LINE => {
+                    line += r.Sleb()?;
+                }
+                DW_LNS_SET_FILE => {
+                    file = r.Uleb()?;
+                }
+                DW_LNS_CONST_ADD_PC => {
+                    addr = addr.wrapping_add(((255 - opcodeBase) as u64 / lineRange) * minInstLen);
+                }
+                DW_LNS_FIXED_ADVANCE_PC => {
+                    addr = addr.wrapping_add(r.U16()? as u64);
+                }
+                _ => {
+                    //standard opcodes without special handling: skip their uleb operands
+                    for _ in 0..stdLens[op as usize - 1] {
+                        r.Uleb()?;
+                    }
+                }
+            }
+        }
+
+        return Some(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn DemangleLegacy() {
+        assert_eq!(Demangle("_ZN7qkernel4main17h0123456789abcdefE"), "qkernel::main");
+        assert_eq!(Demangle("_ZN4core3ptr13drop_in_place17h0123456789abcdefE"), "core::ptr::drop_in_place");
+        assert_eq!(Demangle("_ZN47_$LT$qlib..Addr$u20$as$u20$core..fmt..Debug$GT$3fmt17h0123456789abcdefE"),
+                   "<qlib::Addr as core::fmt::Debug>::fmt");
+        //no hash element
+        assert_eq!(Demangle("_ZN3foo3barE"), "foo::bar");
+        assert_eq!(Demangle("memcpy"), "memcpy");
+    }
+
+    #[test]
+    fn DemangleMalformed() {
+        //a length past the end, or one which overflows
+        assert_eq!(Demangle("_ZN9fooE"), "_ZN9fooE");
+        assert_eq!(Demangle("_ZN18446744073709551615fooE"), "_ZN18446744073709551615fooE");
+        assert_eq!(Demangle("_ZN99999999999999999999999fooE"), "_ZN99999999999999999999999fooE");
+        //a part ending inside a multibyte char
+        assert_eq!(Demangle("_ZN3\u{e9}\u{e9}E"), "_ZN3\u{e9}\u{e9}E");
+        assert_eq!(Demangle("_ZN1\u{e9}E"), "_ZN1\u{e9}E");
+        assert_eq!(Demangle("_ZNfooE"), "_ZNfooE");
+        assert_eq!(Demangle("_ZNE"), "");
+    }
+
+    //a DWARF 2 line program of src/main.rs: line 10 at 0x1000, line 11 at 0x1004, ending at 0x1008
+    fn DebugLineV2() -> Vec<u8> {
+        let mut header = vec![
+            1,                                      //minimum_instruction_length
+            1,                                      //default_is_stmt
+            0xfb,                                   //line_base -5
+            14,                                     //line_range
+            13,                                     //opcode_base
+            0, 1, 1, 1, 1, 0, 0, 0, 1, 0, 0, 1,     //standard_opcode_lengths
+        ];
+        header.extend_from_slice(b"src\0\0");
+        header.extend_from_slice(b"main.rs\0");
+        header.extend_from_slice(&[1, 0, 0, 0]);
+
+        let mut program = vec![0, 9, DW_LNE_SET_ADDRESS];
+        program.extend_from_slice(&0x1000u64.to_le_bytes());
+        program.extend_from_slice(&[
+            DW_LNS_ADVANCE_LINE, 9,
+            DW_LNS_COPY,
+            //address +4, line +1: 13 + (1 - -5) + 14 * 4
+            75,
+            DW_LNS_ADVANCE_PC, 4,
+            0, 1, DW_LNE_END_SEQUENCE,
+        ]);
+
+        let mut unit = Vec::new();
+        unit.extend_from_slice(&2u16.to_le_bytes());
+        unit.extend_from_slice(&(header.len() as u32).to_le_bytes());
+        unit.extend_from_slice(&header);
+        unit.extend_from_slice(&program);
+
+        let mut data = Vec::new();
+        data.extend_from_slice(&(unit.len() as u32).to_le_bytes());
+        data.extend_from_slice(&unit);
+        return data;
+    }
+
+    #[test]
+    fn DebugLine() {
+        let mut table = SymbolTable::default();
+        table.LoadLines(&DebugLineV2(), None, None);
+
+        assert_eq!(table.LineOf(0xfff), None);
+        assert_eq!(table.LineOf(0x1000), Some(("src/main.rs", 10)));
+        assert_eq!(table.LineOf(0x1003), Some(("src/main.rs", 10)));
+        assert_eq!(table.LineOf(0x1004), Some(("src/main.rs", 11)));
+        assert_eq!(table.LineOf(0x1007), Some(("src/main.rs", 11)));
+        assert_eq!(table.LineOf(0x1008), None);
+    }
+
+    #[test]
+    fn DebugLineTruncated() {
+        let data = DebugLineV2();
+        //every prefix parses without a panic and gives no row past the real ones
+        for len in 0..data.len() {
+            let mut table = SymbolTable::default();
+            table.LoadLines(&data[..len], None, None);
+            assert!(table.rows.iter().all(|r| r.addr <= 0x1008));
+        }
+
+        //a unit length beyond the section
+        let mut table = SymbolTable::default();
+        table.LoadLines(&[0xff, 0xff, 0xff, 0x7f, 2, 0], None, None);
+        assert_eq!(table.rows.len(), 0);
+    }
+}
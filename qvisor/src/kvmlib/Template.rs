@@ -0,0 +1,139 @@
+use std::ffi::CString;
+use std::slice;
+
+use super::qlib::Common::Error;
+use super::qlib::Common::Result;
+use super::MemMgr;
+use super::Snapshot::Snapshot;
+
+const MFD_CLOEXEC: u32 = 1;
+const MFD_ALLOW_SEALING: u32 = 2;
+const F_ADD_SEALS: i32 = 1033;
+const F_SEAL_SHRINK: i32 = 2;
+const F_SEAL_GROW: i32 = 4;
+const F_SEAL_WRITE: i32 = 8;
+
+const PAGE_SIZE: u64 = MemMgr::PAGE_SIZE_4K;
+
+fn OsErr(what: &str) -> Error {
+    return Error::IOError(format!("{} fail, io::error is {:?}", what, std::io::Error::last_os_error()))
+}
+
+//a guest memory region in a memfd: a sealed copy, or the memfd the vm already ran on
+struct TemplateRegion {
+    hostAddr: u64,
+    len: u64,
+    fd: i32,
+    offset: u64,
+}
+
+//a vm frozen at a ready state. Its memory is in sealed memfds, which every clone maps MAP_PRIVATE at the
+//same host addresses, so clones share the template's pages until they write them
+pub struct Template {
+    pub state: Snapshot,
+    regions: Vec<TemplateRegion>,
+}
+
+impl Drop for Template {
+    fn drop(&mut self) {
+        for r in &self.regions {
+            unsafe {
+                libc::close(r.fd);
+            }
+        }
+    }
+}
+
+impl Template {
+    //copy the pages of state.regions with data into memfds, the regions already backed by one of memfds,
+    //(host address, memfd, offset), are used in place. The vcpu must stay stopped from SaveState on, the
+    //template's own memory is not written again
+    pub fn Freeze(state: Snapshot, memfds: &[(u64, i32, u64)]) -> Result<Self> {
+        let mut template = Template {
+            state: state,
+            regions: Vec::new(),
+        };
+
+        let mut pages = 0;
+        for i in 0..template.state.regions.len() {
+            let r = &mut template.state.regions[i];
+            if let Some(&(_, fd, offset)) = memfds.iter().find(|m| m.0 == r.hostAddr) {
+                let fd = unsafe { libc::dup(fd) };
+                if fd < 0 {
+                    return Err(OsErr("dup memfd"));
+                }
+
+                template.regions.push(TemplateRegion {
+                    hostAddr: r.hostAddr,
+                    len: r.len,
+                    fd: fd,
+                    offset: offset,
+                });
+                continue;
+            }
+
+            let name = CString::new(format!("qvisor-template-{:x}", r.hostAddr)).unwrap();
+            let fd = unsafe { libc::syscall(libc::SYS_memfd_create, name.as_ptr(), MFD_CLOEXEC | MFD_ALLOW_SEALING) } as i32;
+            if fd < 0 {
+                return Err(OsErr("memfd_create"));
+            }
+
+            //owned by the template from here, so it is closed on the error paths
+            template.regions.push(TemplateRegion {
+                hostAddr: r.hostAddr,
+                len: r.len,
+                fd: fd,
+                offset: 0,
+            });
+
+            if unsafe { libc::ftruncate(fd, r.len as libc::off_t) } < 0 {
+                return Err(OsErr("ftruncate"));
+            }
+
+            //the untouched and zero pages stay holes of the memfd
+            r.ScanPages()?;
+            for (start, count) in r.Runs() {
+                let data = unsafe {
+                    slice::from_raw_parts((r.hostAddr + start as u64 * PAGE_SIZE) as *const u8, (count as u64 * PAGE_SIZE) as usize)
+                };
+
+                let mut done = 0;
+                while done < data.len() {
+                    let n = unsafe {
+                        libc::pwrite(fd, data[done..].as_ptr() as *const libc::c_void, data.len() - done,
+                                     (start as u64 * PAGE_SIZE + done as u64) as libc::off_t)
+                    };
+                    if n < 0 {
+                        return Err(OsErr("pwrite"));
+                    }
+                    done += n as usize;
+                }
+            }
+            pages += r.pages.len();
+
+            if unsafe { libc::fcntl(fd, F_ADD_SEALS, F_SEAL_SHRINK | F_SEAL_GROW | F_SEAL_WRITE) } < 0 {
+                return Err(OsErr("seal memfd"));
+            }
+        }
+
+        println!("template frozen: {} regions, {} pages, rip is {:x}", template.regions.len(), pages, template.state.vcpus[0].regs.rip);
+        return Ok(template)
+    }
+
+    //in a clone: replace the inherited guest memory with copy-on-write views of the template's memfds.
+    //The MappedRegions of the vm still own the ranges
+    pub fn MapPrivate(&self) -> Result<()> {
+        for r in &self.regions {
+            let addr = unsafe {
+                libc::mmap(r.hostAddr as *mut libc::c_void, r.len as usize, libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC,
+                           libc::MAP_PRIVATE | libc::MAP_FIXED, r.fd, r.offset as libc::off_t)
+            };
+
+            if addr == libc::MAP_FAILED || addr as u64 != r.hostAddr {
+                return Err(OsErr("map template memory"));
+            }
+        }
+
+        return Ok(())
+    }
+}
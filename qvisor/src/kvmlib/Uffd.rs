@@ -0,0 +1,347 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::thread;
+
+use super::qlib::Common::Error;
+use super::qlib::Common::Result;
+use super::Cpu;
+use super::MemMgr;
+use super::Snapshot::{CheckLayout, Snapshot};
+
+const UFFD_API: u64 = 0xaa;
+const UFFDIO_API: u64 = 0xc018_aa3f;
+const UFFDIO_REGISTER: u64 = 0xc020_aa00;
+const UFFDIO_UNREGISTER: u64 = 0x8010_aa01;
+const UFFDIO_COPY: u64 = 0xc028_aa03;
+const UFFDIO_ZEROPAGE: u64 = 0xc020_aa04;
+const UFFDIO_REGISTER_MODE_MISSING: u64 = 1;
+const UFFD_EVENT_PAGEFAULT: u8 = 0x12;
+
+const PAGE_SIZE: u64 = MemMgr::PAGE_SIZE_4K;
+//the saved pages read between two polls of the fault queue while prefetching
+const PREFETCH_BATCH: usize = 64;
+
+#[repr(C)]
+#[derive(Default)]
+struct UffdioApi {
+    api: u64,
+    features: u64,
+    ioctls: u64,
+}
+
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct UffdioRange {
+    start: u64,
+    len: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct UffdioRegister {
+    range: UffdioRange,
+    mode: u64,
+    ioctls: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct UffdioCopy {
+    dst: u64,
+    src: u64,
+    len: u64,
+    mode: u64,
+    copy: i64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct UffdioZeropage {
+    range: UffdioRange,
+    mode: u64,
+    zeropage: i64,
+}
+
+//struct uffd_msg, only the pagefault member of the union is used
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct UffdMsg {
+    event: u8,
+    reserved1: u8,
+    reserved2: u16,
+    reserved3: u32,
+    flags: u64,
+    address: u64,
+    ptid: u32,
+    pad: u32,
+}
+
+//a registered guest memory range filled from the snapshot in units of its page size
+struct LazyRegion {
+    hostAddr: u64,
+    len: u64,
+    unit: u64,
+    //4KB page index in the region -> file offset of its data
+    offsets: HashMap<u32, u64>,
+    filled: Vec<bool>,
+}
+
+impl LazyRegion {
+    fn Contains(&self, addr: u64) -> bool {
+        return addr >= self.hostAddr && addr < self.hostAddr + self.len
+    }
+}
+
+//serves the page faults on the guest memory of a restored snapshot from the snapshot file, and
+//fills the rest in the background in the snapshot's prefetch order. Once every saved page is in,
+//the memory is unregistered and the remaining pages are ordinary zero filled anonymous memory
+pub struct LazyLoader {
+    uffd: i32,
+    file: File,
+    regions: Vec<LazyRegion>,
+    prefetch: Vec<(u32, u32)>,
+    next: usize,
+    zero: Vec<u8>,
+}
+
+impl LazyLoader {
+    //register the regions of the snapshot, their mappings must be fresh and untouched, and start the
+    //handler thread. The guest memory can't be touched before this returns. mapped are the mappings
+    //made for the regions, see CheckLayout
+    pub fn Start(snapshot: &Snapshot, mapped: &[(u64, u64)]) -> Result<()> {
+        CheckLayout(&snapshot.regions, mapped)?;
+
+        let file = match &snapshot.file {
+            Some(f) => f.try_clone().map_err(|e| Error::IOError(format!("io::error is {:?}", e)))?,
+            None => return Err(Error::Common(String::from("snapshot is not opened"))),
+        };
+
+        let uffd = unsafe { libc::syscall(libc::SYS_userfaultfd, libc::O_CLOEXEC | libc::O_NONBLOCK) } as i32;
+        if uffd < 0 {
+            return Err(Error::IOError(format!("userfaultfd fail, io::error is {:?}", std::io::Error::last_os_error())));
+        }
+
+        let mut loader = LazyLoader {
+            uffd: uffd,
+            file: file,
+            regions: Vec::new(),
+            prefetch: snapshot.prefetch.clone(),
+            next: 0,
+            zero: Vec::new(),
+        };
+
+        let mut api = UffdioApi {
+            api: UFFD_API,
+            ..Default::default()
+        };
+        Cpu::Ioctl(uffd, UFFDIO_API, &mut api as *mut UffdioApi)?;
+
+        let mut maxUnit = PAGE_SIZE;
+        for r in &snapshot.regions {
+            let unit = if r.hugePage { MemMgr::PAGE_SIZE_2M } else { PAGE_SIZE };
+            maxUnit = core::cmp::max(maxUnit, unit);
+
+            let mut reg = UffdioRegister {
+                range: UffdioRange {
+                    start: r.hostAddr,
+                    len: r.len,
+                },
+                mode: UFFDIO_REGISTER_MODE_MISSING,
+                ..Default::default()
+            };
+            Cpu::Ioctl(uffd, UFFDIO_REGISTER, &mut reg as *mut UffdioRegister)?;
+
+            let mut offsets = HashMap::with_capacity(r.pages.len());
+            for (pos, &page) in r.pages.iter().enumerate() {
+                offsets.insert(page, r.PageOffset(pos));
+            }
+
+            loader.regions.push(LazyRegion {
+                hostAddr: r.hostAddr,
+                len: r.len,
+                unit: unit,
+                offsets: offsets,
+                filled: vec![false; (r.len / unit) as usize],
+            });
+        }
+
+        loader.zero = vec![0; maxUnit as usize];
+
+        thread::spawn(move || {
+            if let Err(e) = loader.Process() {
+                //the guest would hang on the next missing page
+                eprintln!("lazy restore fail: {:?}", e);
+                std::process::exit(1);
+            }
+        });
+
+        return Ok(())
+    }
+
+    fn Process(&mut self) -> Result<()> {
+        let mut buf = vec![0; self.zero.len()];
+        loop {
+            //block only when there is nothing left to prefetch
+            let timeout = if self.next < self.prefetch.len() { 0 } else { -1 };
+            let mut pfd = libc::pollfd {
+                fd: self.uffd,
+                events: libc::POLLIN,
+                revents: 0,
+            };
+
+            let ret = unsafe { libc::poll(&mut pfd, 1, timeout) };
+            if ret < 0 {
+                let err = std::io::Error::last_os_error();
+                if err.raw_os_error() == Some(libc::EINTR) {
+                    continue;
+                }
+
+                return Err(Error::IOError(format!("poll fail, io::error is {:?}", err)));
+            }
+
+            if ret > 0 {
+                self.HandleFaults(&mut buf)?;
+                continue;
+            }
+
+            for _ in 0..PREFETCH_BATCH {
+                if self.next == self.prefetch.len() {
+                    break;
+                }
+
+                let (region, page) = self.prefetch[self.next];
+                self.next += 1;
+
+                let r = &self.regions[region as usize];
+                let unit = (page as u64 * PAGE_SIZE / r.unit) as usize;
+                if !r.filled[unit] {
+                    self.Fill(region as usize, unit, &mut buf)?;
+                }
+            }
+
+            if self.next == self.prefetch.len() {
+                return self.Finish();
+            }
+        }
+    }
+
+    fn HandleFaults(&mut self, buf: &mut Vec<u8>) -> Result<()> {
+        loop {
+            let mut msg = UffdMsg::default();
+            let n = unsafe {
+                libc::read(self.uffd, &mut msg as *mut UffdMsg as *mut libc::c_void, std::mem::size_of::<UffdMsg>())
+            };
+
+            if n < 0 {
+                let err = std::io::Error::last_os_error();
+                if err.raw_os_error() == Some(libc::EAGAIN) {
+                    return Ok(());
+                }
+
+                return Err(Error::IOError(format!("read userfaultfd fail, io::error is {:?}", err)));
+            }
+
+            if msg.event != UFFD_EVENT_PAGEFAULT {
+                continue;
+            }
+
+            let region = match self.regions.iter().position(|r| r.Contains(msg.address)) {
+                Some(idx) => idx,
+                None => return Err(Error::Common(format!("page fault at {:x} outside of the guest memory", msg.address))),
+            };
+
+            let r = &self.regions[region];
+            let unit = ((msg.address - r.hostAddr) / r.unit) as usize;
+            self.Fill(region, unit, buf)?;
+        }
+    }
+
+    //populate one unit of a region with the saved pages in it, zero for the others. A unit the
+    //faulting thread or an earlier prefetch got in first is skipped by the kernel with EEXIST
+    fn Fill(&mut self, region: usize, unit: usize, buf: &mut Vec<u8>) -> Result<()> {
+        let r = &self.regions[region];
+        let dst = r.hostAddr + unit as u64 * r.unit;
+        let firstPage = (unit as u64 * r.unit / PAGE_SIZE) as u32;
+        let pageCount = (r.unit / PAGE_SIZE) as u32;
+
+        let mut hasData = false;
+        for i in 0..pageCount {
+            let data = &mut buf[(i as u64 * PAGE_SIZE) as usize..((i + 1) as u64 * PAGE_SIZE) as usize];
+            match r.offsets.get(&(firstPage + i)) {
+                Some(&offset) => {
+                    self.file.read_exact_at(data, offset).map_err(|e| Error::IOError(format!("io::error is {:?}", e)))?;
+                    hasData = true;
+                }
+                None => {
+                    for b in data.iter_mut() {
+                        *b = 0;
+                    }
+                }
+            }
+        }
+
+        let ret = if !hasData && r.unit == PAGE_SIZE {
+            let mut zp = UffdioZeropage {
+                range: UffdioRange {
+                    start: dst,
+                    len: r.unit,
+                },
+                ..Default::default()
+            };
+            self.Ioctl(UFFDIO_ZEROPAGE, &mut zp as *mut UffdioZeropage)
+        } else {
+            //hugetlb has no zeropage, copy the zero buffer
+            let src = if hasData { buf.as_ptr() } else { self.zero.as_ptr() };
+            let mut copy = UffdioCopy {
+                dst: dst,
+                src: src as u64,
+                len: r.unit,
+                ..Default::default()
+            };
+            self.Ioctl(UFFDIO_COPY, &mut copy as *mut UffdioCopy)
+        };
+
+        match ret {
+            Ok(()) => (),
+            Err(errno) if errno == libc::EEXIST => (),
+            Err(errno) => return Err(Error::IOError(format!("fill {:x} fail, errno is {}", dst, errno))),
+        }
+
+        self.regions[region].filled[unit] = true;
+        return Ok(())
+    }
+
+    //retry on EAGAIN, which is returned while the mm of qvisor is changing
+    fn Ioctl<T>(&self, req: u64, arg: *mut T) -> core::result::Result<(), i32> {
+        loop {
+            let ret = unsafe { libc::ioctl(self.uffd, req as _, arg) };
+            if ret >= 0 {
+                return Ok(());
+            }
+
+            let errno = std::io::Error::last_os_error().raw_os_error().unwrap_or(0);
+            if errno != libc::EAGAIN {
+                return Err(errno);
+            }
+        }
+    }
+
+    //every saved page is in, the kernel can zero fill the rest without us
+    fn Finish(&mut self) -> Result<()> {
+        for r in &self.regions {
+            let mut range = UffdioRange {
+                start: r.hostAddr,
+                len: r.len,
+            };
+            Cpu::Ioctl(self.uffd, UFFDIO_UNREGISTER, &mut range as *mut UffdioRange)?;
+        }
+
+        unsafe {
+            libc::close(self.uffd);
+        }
+
+        println!("lazy restore: all {} saved pages are loaded", self.prefetch.len());
+        return Ok(())
+    }
+}
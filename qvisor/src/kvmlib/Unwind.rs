@@ -0,0 +1,550 @@
+use std::fmt::Write;
+
+use kvm_bindings::kvm_regs;
+use xmas_elf::ElfFile;
+
+use super::GuestMemory::GuestMemory;
+use super::Symbol::{Reader, SymbolTable};
+
+const MAX_FRAMES: usize = 64;
+
+// DWARF register numbers of x86_64
+const REG_RBP: usize = 6;
+const REG_RSP: usize = 7;
+const REG_RA: usize = 16;
+const REG_COUNT: usize = 17;
+const CALLEE_SAVED: [usize; 6] = [3, REG_RBP, 12, 13, 14, 15];
+
+const DW_EH_PE_OMIT: u8 = 0xff;
+const DW_EH_PE_ABSPTR: u8 = 0x00;
+const DW_EH_PE_ULEB128: u8 = 0x01;
+const DW_EH_PE_UDATA2: u8 = 0x02;
+const DW_EH_PE_UDATA4: u8 = 0x03;
+const DW_EH_PE_UDATA8: u8 = 0x04;
+const DW_EH_PE_SLEB128: u8 = 0x09;
+const DW_EH_PE_SDATA2: u8 = 0x0a;
+const DW_EH_PE_SDATA4: u8 = 0x0b;
+const DW_EH_PE_SDATA8: u8 = 0x0c;
+const DW_EH_PE_PCREL: u8 = 0x10;
+
+//the register rules the CFI program can produce
+#[derive(Debug, Clone, Copy)]
+enum RegRule {
+    Undefined,
+    SameValue,
+    Offset(i64),
+    ValOffset(i64),
+    Register(usize),
+}
+
+#[derive(Debug, Clone)]
+struct CfiRow {
+    cfaReg: usize,
+    cfaOffset: i64,
+    regs: [RegRule; REG_COUNT],
+}
+
+struct Cie {
+    codeAlign: u64,
+    dataAlign: i64,
+    raReg: usize,
+    fdeEncoding: u8,
+    hasAugData: bool,
+    instructions: (usize, usize),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct FdeIdx {
+    pcBegin: u64,
+    pcEnd: u64,
+    //offset of the FDE record and its CIE in the section
+    fde: usize,
+    cie: usize,
+}
+
+//the kernel's .eh_frame, indexed by pc range
+pub struct EhFrame {
+    data: Vec<u8>,
+    vaddr: u64,
+    fdes: Vec<FdeIdx>,
+}
+
+impl EhFrame {
+    pub fn Init(elf: &ElfFile) -> Option<Self> {
+        let section = elf.find_section_by_name(".eh_frame")?;
+        let mut eh = EhFrame {
+            data: section.raw_data(elf).to_vec(),
+            vaddr: section.address(),
+            fdes: Vec::new(),
+        };
+
+        eh.Index();
+        if eh.fdes.len() == 0 {
+            return None;
+        }
+
+        println!("kernel .eh_frame: {} FDEs", eh.fdes.len());
+        return Some(eh)
+    }
+
+    fn Index(&mut self) {
+        let mut pos = 0;
+        while pos + 4 <= self.data.len() {
+            let mut r = Reader::New(&self.data);
+            r.pos = pos;
+            let len = match r.U32() {
+                None | Some(0) => break,
+                Some(0xffff_ffff) => break, //64 bit eh_frame is not generated for x86_64
+                Some(len) => len as usize,
+            };
+
+            let start = r.pos;
+            let next = start + len;
+            if next > self.data.len() {
+                break;
+            }
+
+            let id = match r.U32() {
+                None => break,
+                Some(id) => id as usize,
+            };
+
+            if id != 0 && id <= start {
+                let ciePos = start - id;
+                if let Some(cie) = self.ParseCie(ciePos) {
+                    if let Some((pcBegin, pcRange)) = self.ReadFdeRange(&mut r, &cie) {
+                        self.fdes.push(FdeIdx {
+                            pcBegin: pcBegin,
+                            pcEnd: pcBegin.wrapping_add(pcRange),
+                            fde: pos,
+                            cie: ciePos,
+                        });
+                    }
+                }
+            }
+
+            pos = next;
+        }
+
+        self.fdes.sort_by_key(|f| f.pcBegin);
+    }
+
+    fn ReadEncoded(&self, r: &mut Reader, encoding: u8) -> Option<u64> {
+        if encoding == DW_EH_PE_OMIT {
+            return None;
+        }
+
+        let fieldAddr = self.vaddr + r.pos as u64;
+        let val = match encoding & 0x0f {
+            DW_EH_PE_ABSPTR => r.U64()?,
+            DW_EH_PE_ULEB128 => r.Uleb()?,
+            DW_EH_PE_UDATA2 => r.U16()? as u64,
+            DW_EH_PE_UDATA4 => r.U32()? as u64,
+            DW_EH_PE_UDATA8 => r.U64()?,
+            DW_EH_PE_SLEB128 => r.Sleb()? as u64,
+            DW_EH_PE_SDATA2 => r.U16()? as i16 as i64 as u64,
+            DW_EH_PE_SDATA4 => r.U32()? as i32 as i64 as u64,
+            DW_EH_PE_SDATA8 => r.U64()?,
+            _ => return None,
+        };
+
+        match encoding & 0x70 {
+            0 => Some(val),
+            DW_EH_PE_PCREL => Some(fieldAddr.wrapping_add(val)),
+            _ => None,
+        }
+    }
+
+    fn ParseCie(&self, pos: usize) -> Option<Cie> {
+        let mut r = Reader::New(&self.data);
+        r.pos = pos;
+        let len = r.U32()? as usize;
+        let end = r.pos + len;
+        if r.U32()? != 0 {
+            return None;
+        }
+
+        let version = r.U8()?;
+        let aug = r.CStr()?;
+        if aug.contains("eh") {
+            r.U64()?;
+        }
+
+        let codeAlign = r.Uleb()?;
+        let dataAlign = r.Sleb()?;
+        let raReg = if version == 1 { r.U8()? as usize } else { r.Uleb()? as usize };
+
+        let mut fdeEncoding = DW_EH_PE_ABSPTR;
+        let hasAugData = aug.starts_with('z');
+        if hasAugData {
+            let augLen = r.Uleb()? as usize;
+            let augEnd = r.pos + augLen;
+            for c in aug[1..].chars() {
+                match c {
+                    'L' => {
+                        r.U8()?;
+                    }
+                    'R' => fdeEncoding = r.U8()?,
+                    'P' => {
+                        let enc = r.U8()?;
+                        self.ReadEncoded(&mut r, enc & 0x7f)?;
+                    }
+                    'S' => (),
+                    _ => break,
+                }
+            }
+            r.pos = augEnd;
+        }
+
+        if r.pos > end || end > self.data.len() {
+            return None;
+        }
+
+        return Some(Cie {
+            codeAlign,
+            dataAlign,
+            raReg,
+            fdeEncoding,
+            hasAugData,
+            instructions: (r.pos, end),
+        })
+    }
+
+    fn ReadFdeRange(&self, r: &mut Reader, cie: &Cie) -> Option<(u64, u64)> {
+        let pcBegin = self.ReadEncoded(r, cie.fdeEncoding)?;
+        let pcRange = self.ReadEncoded(r, cie.fdeEncoding & 0x0f)?;
+        return Some((pcBegin, pcRange))
+    }
+
+    fn Find(&self, pc: u64) -> Option<&FdeIdx> {
+        let idx = match self.fdes.binary_search_by_key(&pc, |f| f.pcBegin) {
+            Ok(idx) => idx,
+            Err(0) => return None,
+            Err(idx) => idx - 1,
+        };
+
+        let fde = &self.fdes[idx];
+        if pc >= fde.pcBegin && pc < fde.pcEnd {
+            return Some(fde)
+        }
+
+        return None
+    }
+
+    //run the CIE and FDE programs up to pc, return the row describing the frame at pc
+    fn RowAt(&self, pc: u64) -> Option<(CfiRow, usize)> {
+        let idx = self.Find(pc)?;
+        let cie = self.ParseCie(idx.cie)?;
+
+        let mut r = Reader::New(&self.data);
+        r.pos = idx.fde;
+        let len = r.U32()? as usize;
+        let end = r.pos + len;
+        r.U32()?;
+        self.ReadFdeRange(&mut r, &cie)?;
+        if cie.hasAugData {
+            let augLen = r.Uleb()? as usize;
+            r.pos += augLen;
+        }
+
+        let initial = CfiRow {
+            cfaReg: REG_RSP,
+            cfaOffset: 8,
+            regs: [RegRule::Undefined; REG_COUNT],
+        };
+
+        let cieRow = self.Execute(&cie, cie.instructions.0, cie.instructions.1, initial.clone(), &initial, u64::max_value(), 0)?;
+        let row = self.Execute(&cie, r.pos, end, cieRow.clone(), &cieRow, pc, idx.pcBegin)?;
+        return Some((row, cie.raReg))
+    }
+
+    fn Execute(&self, cie: &Cie, start: usize, end: usize, mut row: CfiRow, initial: &CfiRow, pc: u64, mut loc: u64) -> Option<CfiRow> {
+        if end > self.data.len() || start > end {
+            return None;
+        }
+
+        let mut r = Reader::New(&self.data[..end]);
+        r.pos = start;
+        let mut stack: Vec<CfiRow> = Vec::new();
+
+        let setReg = |row: &mut CfiRow, reg: u64, rule: RegRule| {
+            if (reg as usize) < REG_COUNT {
+                row.regs[reg as usize] = rule;
+            }
+        };
+
+        while r.Remain() > 0 {
+            let op = r.U8()?;
+            let high = op & 0xc0;
+            let low = (op & 0x3f) as u64;
+
+            let advance = match high {
+                0x40 => Some(low * cie.codeAlign),
+                0x80 => {
+                    let off = r.Uleb()? as i64 * cie.dataAlign;
+                    setReg(&mut row, low, RegRule::Offset(off));
+                    None
+                }
+                0xc0 => {
+                    if (low as usize) < REG_COUNT {
+                        row.regs[low as usize] = initial.regs[low as usize];
+                    }
+                    None
+                }
+                _ => match op {
+                    0x00 => None,
+                    0x01 => {
+                        loc = self.ReadEncoded(&mut r, cie.fdeEncoding)?;
+                        None
+                    }
+                    0x02 => Some(r.U8()? as u64 * cie.codeAlign),
+                    0x03 => Some(r.U16()? as u64 * cie.codeAlign),
+                    0x04 => Some(r.U32()? as u64 * cie.codeAlign),
+                    0x05 => {
+                        let reg = r.Uleb()?;
+                        let off = r.Uleb()? as i64 * cie.dataAlign;
+                        setReg(&mut row, reg, RegRule::Offset(off));
+                        None
+                    }
+                    0x06 => {
+                        let reg = r.Uleb()? as usize;
+                        if reg < REG_COUNT {
+                            row.regs[reg] = initial.regs[reg];
+                        }
+                        None
+                    }
+                    0x07 => {
+                        let reg = r.Uleb()?;
+                        setReg(&mut row, reg, RegRule::Undefined);
+                        None
+                    }
+                    0x08 => {
+                        let reg = r.Uleb()?;
+                        setReg(&mut row, reg, RegRule::SameValue);
+                        None
+                    }
+                    0x09 => {
+                        let reg = r.Uleb()?;
+                        let reg2 = r.Uleb()? as usize;
+                        setReg(&mut row, reg, RegRule::Register(reg2));
+                        None
+                    }
+                    0x0a => {
+                        stack.push(row.clone());
+                        None
+                    }
+                    0x0b => {
+                        //restore_state brings back the whole row remember_state pushed, the CFA rule too
+                        row = stack.pop()?;
+                        None
+                    }
+                    0x0c => {
+                        row.cfaReg = r.Uleb()? as usize;
+                        row.cfaOffset = r.Uleb()? as i64;
+                        None
+                    }
+                    0x0d => {
+                        row.cfaReg = r.Uleb()? as usize;
+                        None
+                    }
+                    0x0e => {
+                        row.cfaOffset = r.Uleb()? as i64;
+                        None
+                    }
+                    //the CFA defined by an expression is not supported, the caller falls back to rbp
+                    0x0f => return None,
+                    0x10 | 0x16 => {
+                        let reg = r.Uleb()?;
+                        let len = r.Uleb()? as usize;
+                        r.Bytes(len)?;
+                        setReg(&mut row, reg, RegRule::Undefined);
+                        None
+                    }
+                    0x11 => {
+                        let reg = r.Uleb()?;
+                        let off = r.Sleb()? * cie.dataAlign;
+                        setReg(&mut row, reg, RegRule::Offset(off));
+                        None
+                    }
+                    0x12 => {
+                        row.cfaReg = r.Uleb()? as usize;
+                        row.cfaOffset = r.Sleb()? * cie.dataAlign;
+                        None
+                    }
+                    0x13 => {
+                        row.cfaOffset = r.Sleb()? * cie.dataAlign;
+                        None
+                    }
+                    0x14 => {
+                        let reg = r.Uleb()?;
+                        let off = r.Uleb()? as i64 * cie.dataAlign;
+                        setReg(&mut row, reg, RegRule::ValOffset(off));
+                        None
+                    }
+                    0x15 => {
+                        let reg = r.Uleb()?;
+                        let off = r.Sleb()? * cie.dataAlign;
+                        setReg(&mut row, reg, RegRule::ValOffset(off));
+                        None
+                    }
+                    0x2e => {
+                        r.Uleb()?;
+                        None
+                    }
+                    0x2f => {
+                        let reg = r.Uleb()?;
+                        let off = -(r.Uleb()? as i64) * cie.dataAlign;
+                        setReg(&mut row, reg, RegRule::Offset(off));
+                        None
+                    }
+                    _ => return None,
+                }
+            };
+
+            if let Some(delta) = advance {
+                loc = loc.wrapping_add(delta);
+                if loc > pc {
+                    break;
+                }
+            }
+        }
+
+        return Some(row)
+    }
+}
+
+pub struct Frame {
+    pub pc: u64,
+    pub sp: u64,
+}
+
+struct Unwinder<'a> {
+    reader: &'a GuestMemory,
+    cr3: u64,
+    ehFrame: Option<&'a EhFrame>,
+}
+
+impl<'a> Unwinder<'a> {
+    fn ReadU64(&self, addr: u64) -> Option<u64> {
+        let mut buf = [0 as u8; 8];
+        if self.reader.ReadVirt(self.cr3, addr, &mut buf) != 8 {
+            return None;
+        }
+
+        return Some(u64::from_le_bytes(buf))
+    }
+
+    //unwind one frame with the CFI
+    fn StepCfi(&self, regs: &[Option<u64>; REG_COUNT], pc: u64) -> Option<[Option<u64>; REG_COUNT]> {
+        let (row, raReg) = self.ehFrame?.RowAt(pc)?;
+        if raReg >= REG_COUNT || row.cfaReg >= REG_COUNT {
+            return None;
+        }
+
+        let cfa = (regs[row.cfaReg]? as i64).wrapping_add(row.cfaOffset) as u64;
+        let mut next = [None; REG_COUNT];
+        for i in 0..REG_COUNT {
+            next[i] = match row.regs[i] {
+                //the callee saved registers without a rule are not touched by the callee
+                RegRule::Undefined => if CALLEE_SAVED.contains(&i) { regs[i] } else { None },
+                RegRule::SameValue => regs[i],
+                RegRule::Offset(off) => self.ReadU64((cfa as i64).wrapping_add(off) as u64),
+                RegRule::ValOffset(off) => Some((cfa as i64).wrapping_add(off) as u64),
+                RegRule::Register(reg) => if reg < REG_COUNT { regs[reg] } else { None },
+            };
+        }
+
+        next[REG_RSP] = Some(cfa);
+        next[REG_RA] = match row.regs[raReg] {
+            RegRule::Offset(off) => self.ReadU64((cfa as i64).wrapping_add(off) as u64),
+            _ => return None,
+        };
+
+        return Some(next)
+    }
+
+    //unwind one frame following the saved rbp chain
+    fn StepRbp(&self, regs: &[Option<u64>; REG_COUNT]) -> Option<[Option<u64>; REG_COUNT]> {
+        let rbp = regs[REG_RBP]?;
+        if rbp == 0 || rbp & 7 != 0 {
+            return None;
+        }
+
+        let mut next = [None; REG_COUNT];
+        next[REG_RBP] = Some(self.ReadU64(rbp)?);
+        next[REG_RA] = Some(self.ReadU64(rbp.checked_add(8)?)?);
+        next[REG_RSP] = Some(rbp.checked_add(16)?);
+        return Some(next)
+    }
+
+    fn Walk(&self, gprs: &kvm_regs) -> Vec<Frame> {
+        let mut regs: [Option<u64>; REG_COUNT] = [None; REG_COUNT];
+        regs[0] = Some(gprs.rax);
+        regs[1] = Some(gprs.rdx);
+        regs[2] = Some(gprs.rcx);
+        regs[3] = Some(gprs.rbx);
+        regs[4] = Some(gprs.rsi);
+        regs[5] = Some(gprs.rdi);
+        regs[REG_RBP] = Some(gprs.rbp);
+        regs[REG_RSP] = Some(gprs.rsp);
+        regs[8] = Some(gprs.r8);
+        regs[9] = Some(gprs.r9);
+        regs[10] = Some(gprs.r10);
+        regs[11] = Some(gprs.r11);
+        regs[12] = Some(gprs.r12);
+        regs[13] = Some(gprs.r13);
+        regs[14] = Some(gprs.r14);
+        regs[15] = Some(gprs.r15);
+
+        let mut frames = vec![Frame { pc: gprs.rip, sp: gprs.rsp }];
+        let mut pc = gprs.rip;
+
+        while frames.len() < MAX_FRAMES {
+            //the return address points after the call, look up the call itself for the caller frames
+            let lookupPc = if frames.len() == 1 { pc } else { pc - 1 };
+            let next = match self.StepCfi(&regs, lookupPc) {
+                Some(next) => next,
+                None => match self.StepRbp(&regs) {
+                    Some(next) => next,
+                    None => break,
+                }
+            };
+
+            let (ra, sp) = match (next[REG_RA], next[REG_RSP]) {
+                (Some(ra), Some(sp)) => (ra, sp),
+                _ => break,
+            };
+
+            //the stack grows down, a caller frame must be above the callee
+            if ra == 0 || sp <= regs[REG_RSP].unwrap_or(0) {
+                break;
+            }
+
+            frames.push(Frame { pc: ra, sp: sp });
+            pc = ra;
+            regs = next;
+        }
+
+        return frames;
+    }
+}
+
+pub fn Backtrace(reader: &GuestMemory, cr3: u64, regs: &kvm_regs, ehFrame: Option<&EhFrame>) -> Vec<Frame> {
+    let unwinder = Unwinder {
+        reader: reader,
+        cr3: cr3,
+        ehFrame: ehFrame,
+    };
+
+    return unwinder.Walk(regs)
+}
+
+pub fn FormatBacktrace(frames: &[Frame], symbols: &SymbolTable) -> String {
+    let mut s = String::new();
+    writeln!(s, "backtrace:").unwrap();
+    for (i, f) in frames.iter().enumerate() {
+        writeln!(s, "  #{:<2} {:016x} sp={:016x} in {}", i, f.pc, f.sp, symbols.Symbolize(f.pc)).unwrap();
+    }
+
+    return s;
+}
@@ -6,13 +6,34 @@ extern crate alloc;
 mod qlib;
 mod MemMgr;
 mod vmspace;
+mod Cpu;
+mod Gdt;
+mod Diagnose;
+mod Symbol;
+mod Unwind;
+mod CoreDump;
+mod Gdb;
+mod DirtyLog;
+mod Snapshot;
+mod Uffd;
+mod Migration;
+mod Template;
+mod Balloon;
+mod Control;
+mod GuestMemory;
+mod Irqchip;
+mod EventFd;
+mod Serial;
+mod Bus;
 
 pub mod ELFLoader;
 
 use std::sync::Arc;
+use std::os::unix::io::AsRawFd;
 use std::cell::RefCell;
+use std::mem::size_of;
 
-use qlib::Common::Error;
+pub use qlib::Common::Error;
 use qlib::Common::Result;
 
 use qlib::{ShareSpace, Addr};
@@ -21,12 +42,10 @@ use spin::Mutex;
 use std::boxed::Box;
 
 //use kvm_bindings::KVM_MEM_LOG_DIRTY_PAGES;
-use kvm_bindings::kvm_userspace_memory_region;
 use kvm_bindings::kvm_sregs;
 use kvm_bindings::kvm_segment;
 use kvm_bindings::kvm_regs;
 //use kvm_bindings::{kvm_fpu, kvm_msr_entry, kvm_msrs, kvm_regs, kvm_sregs};
-use kvm_bindings::KVM_MEM_LOG_DIRTY_PAGES;
 //use MemMgr::MemSpaceMgr;
 
 //use kvm_ioctls::{Kvm, VmFd, VcpuFd};
@@ -37,13 +56,21 @@ use qlib::PageTable::{PageTables,PagePool};
 use MemMgr::MappedRegion;
 use MemMgr::PhyAddrMgr;
 use MemMgr::MapOption;
+pub use MemMgr::{GuestMemOption, HugePagePolicy};
 use ELFLoader::KernelELF;
+use Cpu::{CpuidEntries, CpuProfile, XStatePolicy};
+use Gdt::DescTables;
+use Diagnose::{AbnormalExit, KvmRun};
+use Gdb::{GdbResume, GdbStub, StopReason};
+use DirtyLog::{DirtyRange, MemSlot, SlotIdAllocator};
+use Snapshot::{MemRegion, VcpuState};
 use lazy_static::lazy_static;
 
 // copy include/uapi/linux/if_tun.h from the kernel code.
 const KVM_GET_API_VERSION: u64 = 0xae00;
 const KVM_CREATE_VM: u64 = 0xae01;
 const KVM_CHECK_EXTENSION: u64 = 0xae03;
+const KVM_CAP_NR_MEMSLOTS: u64 = 10;
 const KVM_GET_VCPU_MMAP_SIZE: u64 = 0xae04;
 const KVM_CREATE_VCPU: u64 = 0xae41;
 const KVM_GET_DIRTY_LOG: u64 = 0x4010_ae42;
@@ -63,6 +90,10 @@ const KVM_SET_LAPIC: u64 = 0x4400_ae8f;
 const KVM_GET_SREGS: u64 = 0x8138_ae83;
 const KVM_GET_LAPIC: u64 = 0x8400_ae8e;
 const KVM_GET_SUPPORTED_CPUID: u64 = 0xc008_ae05;
+const KVM_GET_MSRS: u64 = 0xc008_ae88;
+const KVM_GET_XSAVE: u64 = 0x9000_aea4;
+const KVM_SET_XSAVE: u64 = 0x5000_aea5;
+const KVM_SET_GUEST_DEBUG: u64 = 0x4048_ae9b;
 
 const CR0_PE: u64 =  1;
 const CR0_MP: u64 =  (1 << 1);
@@ -85,7 +116,7 @@ const CR4_PAE: u64 =  (1 << 5);
 const CR4_MCE: u64 =  (1 << 6);
 const CR4_PGE: u64 =  (1 << 7);
 const CR4_PCE: u64 =  (1 << 8);
-const CR4_OSFXSR: u64 =  (1 << 8);
+const CR4_OSFXSR: u64 =  (1 << 9);
 const CR4_OSXMMEXCPT: u64 =  (1 << 10);
 const CR4_UMIP: u64 =  (1 << 11);
 const CR4_VMXE: u64 =  (1 << 13);
@@ -123,6 +154,10 @@ const PDE64_G : u64 = (1 << 8);
 const LOWER_TOP : u64 = 0x00007fffffffffff;
 const UPPER_BOTTOM : u64 = 0xffff800000000000;
 
+const VCPU_COUNT : usize = 1;
+//the guest RAM the guest may grow to with HYPERCALL_ALLOCMEM, hotplugged RAM included
+const DEFAULT_ALLOC_MEM_LIMIT: u64 = 16 * MemMgr::ONE_GB;
+
 lazy_static! {
     pub static ref VMS: Mutex<vmspace::VMSpace> = Mutex::new(vmspace::VMSpace::default());
 }
@@ -131,22 +166,79 @@ pub struct KVMMachine {
     pub kvm : kvm_ioctls::Kvm,
     pub vm_fd: kvm_ioctls::VmFd,
     pub vcpu_fds :  Vec<kvm_ioctls::VcpuFd>,
+    pub vcpuRuns : Vec<KvmRun>,
+    pub vcpuMmapSize: usize,
 
     pub pageMmap: Box<MappedRegion>,
     pub phyAddrMgr : Arc<RefCell<PhyAddrMgr>>,
+    pub memSlots: Vec<MemSlot>,
+    //the ids of memSlots, the guest RAM added at run time takes and returns them
+    pub slotIds: SlotIdAllocator,
+    //the guest RAM the guest gave back with HYPERCALL_BALLOON
+    pub balloon: Balloon::Balloon,
+    //the guest RAM regions of phyAddrMgr HYPERCALL_ALLOCMEM can't grow past, see DEFAULT_ALLOC_MEM_LIMIT
+    pub allocMemLimit: u64,
 
     pub topStackAddr: u64,
     pub defaultStackAddr: u64,
     pub entry: u64,
 
     pub elf: KernelELF,
+    pub descTables: DescTables,
+
+    pub supportedCpuid: CpuidEntries,
+    //the extended state components the guest may enable, applied when the vcpu is created
+    pub xstatePolicy: XStatePolicy,
+    //the protection features to enable in CR4/EFER, dropped one by one when the host doesn't support them
+    pub cpuProfile: CpuProfile,
+
+    //where to write an ELF core file of the guest on a fatal exit, none to skip it
+    pub coreDumpPath: Option<String>,
+    //serve the gdb remote protocol on "host:port" or "unix:<path>", the guest waits for gdb before it starts
+    pub gdbAddr: Option<String>,
+    //"qvisor snapshot": where to save the vm when the guest calls HYPERCALL_SNAPSHOT or qvisor gets SIGUSR2
+    pub snapshotPath: Option<String>,
+    //"qvisor migrate-to": where to migrate the vm at the same points, with "qvisor migrate-from" listening there
+    pub migrateAddr: Option<String>,
+    //"qvisor template": freeze the vm at the same points and start this many copy-on-write clones of it, 0 for none
+    pub templateClones: usize,
+    template: Option<Template::Template>,
+    //serve the control socket on "host:port" or "unix:<path>"
+    pub controlAddr: Option<String>,
+    //the log file for the output of the COM1 uart, stdout when None
+    pub serialOut: Option<String>,
+    //the input of the COM1 uart, "stdin" or "unix:<path>", none when None
+    pub serialIn: Option<String>,
+    serialInput: Option<Serial::SerialInput>,
+    //the COM1 uart once StartSerial added it, and the state it starts with in a restored vm
+    serial: Option<Arc<Mutex<Serial::Serial>>>,
+    serialState: Option<Serial::SerialState>,
+    //the emulated devices by their io ports and their guest physical ranges. Unclaimed reads get all
+    //ones and unclaimed writes are dropped, as on a pc
+    pioBus: Bus::Bus,
+    mmioBus: Bus::Bus,
+    //hotplugged guest RAM not in the ShareSpace yet, the guest hasn't started or its queue is full
+    memAddedPending: Vec<(u64, u64)>,
+    //the guest writes served by host threads through ioeventfd, and the gsis host threads raise through
+    //irqfd. Neither is in a snapshot, the devices register them again
+    doorbells: Vec<EventFd::Doorbell>,
+    irqFds: Vec<EventFd::IrqFd>,
+
+    //the guest address of the ShareSpace, 0 before HYPERCALL_INIT
+    pub shareSpace: u64,
 }
 
 impl KVMMachine {
-    fn initKernelMem(_vm_fd : &VmFd, phyUpperAddr:u64, pageMmapsize: u64) -> Result<Box<MappedRegion>> {
+    fn initKernelMem(_vm_fd : &VmFd, phyUpperAddr:u64, pageMmapsize: u64, memOption: &GuestMemOption) -> Result<Box<MappedRegion>> {
         //let vmSpace : vmspace::VMSpace;
 
-        let mr = MapOption::New().Offset(phyUpperAddr).Len(pageMmapsize).MapHugeTLB().MapAnan().MapPrivate().ProtoRead().ProtoWrite().ProtoExec().Map()?;
+        let mut option = &mut MapOption::New();
+        option = option.Offset(phyUpperAddr).Len(pageMmapsize).HugePage(memOption.hugePage).MapAnan().MapPrivate().ProtoRead().ProtoWrite().ProtoExec();
+        if memOption.memfd {
+            option = option.Memfd("qvisor-kernel-mem");
+        }
+
+        let mr = option.Map()?;
         let pageMmap = Box::new(mr);
 
         //let pageMmap = Box::new(MappedRegion::Init(Addr::Addr(phyUpperAddr), pageMmapsize, true, libc::PROT_READ |  libc::PROT_WRITE |  libc::PROT_EXEC)?);
@@ -159,24 +251,225 @@ impl KVMMachine {
         return Ok(pageMmap)
     }
 
-    pub fn SetMemRegion(slotId: u32, vm_fd : &VmFd, phyUpperAddr:u64, pageMmapsize: u64) -> Result<()> {
+    //register a slot with dirty logging off, turn it on with SetDirtyLogging when needed
+    pub fn SetMemRegion(slotId: u32, vm_fd : &VmFd, phyUpperAddr:u64, pageMmapsize: u64) -> Result<MemSlot> {
         println!("SetMemRegion phyUpperAddr = {:x}, pageMmapsize = {:x}", phyUpperAddr, pageMmapsize);
 
-        let mem_region = kvm_userspace_memory_region {
-            slot: slotId,
-            guest_phys_addr: phyUpperAddr,
-            memory_size: pageMmapsize,
-            userspace_addr: phyUpperAddr,
-            flags: KVM_MEM_LOG_DIRTY_PAGES,
+        let slot = MemSlot::New(slotId, phyUpperAddr, pageMmapsize, phyUpperAddr);
+        slot.Register(vm_fd)?;
+        return Ok(slot)
+    }
+
+    //the memory slot count of the host, the old kernels without KVM_CAP_NR_MEMSLOTS have 32
+    fn MaxMemSlots(kvm: &Kvm) -> u32 {
+        let ret = unsafe { libc::ioctl(kvm.as_raw_fd(), KVM_CHECK_EXTENSION as _, KVM_CAP_NR_MEMSLOTS) };
+        if ret <= 0 {
+            return 32
+        }
+
+        return ret as u32
+    }
+
+    //map len bytes more of guest RAM from PhyAddrMgr in its own slot, at the same guest virtual address.
+    //return the guest physical address
+    pub fn AllocGuestMem(&mut self, len: u64, hugePage: bool) -> Result<u64> {
+        let addr = self.phyAddrMgr.borrow_mut().AllocAnan(len, hugePage)?.0;
+        return self.AddGuestRegion(addr, false)
+    }
+
+    //map len bytes of the host file path from offset as guest memory, see PhyAddrMgr::AllocFile. A readonly
+    //file is readonly to the guest too. return the guest physical address
+    pub fn AllocGuestFile(&mut self, path: &str, offset: u64, len: u64, shared: bool, readonly: bool) -> Result<u64> {
+        let addr = self.phyAddrMgr.borrow_mut().AllocFile(path, offset, len, shared, readonly)?.0;
+        return self.AddGuestRegion(addr, readonly)
+    }
+
+    //register the PhyAddrMgr region at addr in a new slot and map it in the guest page tables
+    fn AddGuestRegion(&mut self, addr: u64, readonly: bool) -> Result<u64> {
+        let len = self.phyAddrMgr.borrow().RegionLen(addr)?;
+
+        let slot = match self.slotIds.Alloc() {
+            Ok(slot) => slot,
+            Err(e) => {
+                self.phyAddrMgr.borrow_mut().Free(addr, len)?;
+                return Err(e)
+            }
         };
-        vm_fd.set_user_memory_region(mem_region).map_err(|e| Error::IOError(format!("io::error is {:?}", e)))?;
+
+        let mut memSlot = MemSlot::New(slot, addr, len, addr);
+        memSlot.readonly = readonly;
+        match memSlot.Register(&self.vm_fd) {
+            Ok(()) => self.memSlots.push(memSlot),
+            Err(e) => {
+                self.slotIds.Free(slot);
+                self.phyAddrMgr.borrow_mut().Free(addr, len)?;
+                return Err(e)
+            }
+        }
+
+        let mut opts = if readonly { Addr::PageOpts::KernelReadOnly() } else { Addr::PageOpts::Kernel() };
+        let res = {
+            let mut vms = VMS.lock();
+            //NO_EXECUTE is a reserved bit without EFER.NXE, the readonly memory stays executable then
+            if !vms.profile.as_ref().map_or(true, |p| p.nxe) {
+                opts.AccessType.Exec = true;
+            }
+
+            vms.Map(Addr::Addr(addr), Addr::Addr(addr + len), Addr::Addr(addr), &opts)
+        };
+
+        //Map keeps an entry it finds, which would have the flags of other memory. FreeGuestMem clears
+        //the entries of the memory it frees, so there is none
+        let err = match res {
+            Ok(false) => None,
+            Ok(true) => Some(Error::Common(format!("guest memory {:x} to {:x} is mapped already", addr, addr + len))),
+            Err(e) => Some(e),
+        };
+
+        if let Some(e) = err {
+            self.FreeGuestMem(addr)?;
+            return Err(e)
+        }
+
+        println!("guest memory {:x} to {:x} added in slot {}", addr, addr + len, slot);
+        return Ok(addr)
+    }
+
+    //drop the guest memory from AllocGuestMem or AllocGuestFile at the guest physical address addr
+    pub fn FreeGuestMem(&mut self, addr: u64) -> Result<()> {
+        let len = self.phyAddrMgr.borrow().RegionLen(addr)?;
+        let idx = self.memSlots.iter().position(|s| s.guestPhysAddr == addr).ok_or(Error::UnmatchRegion)?;
+
+        self.memSlots[idx].Unregister(&self.vm_fd)?;
+        //a range FindRange hands out again gets the entries of the new memory
+        VMS.lock().Unmap(Addr::Addr(addr), Addr::Addr(addr + len))?;
+        let memSlot = self.memSlots.remove(idx);
+        self.slotIds.Free(memSlot.slot);
+        self.balloon.Deflate(addr, len);
+        return self.phyAddrMgr.borrow_mut().Free(addr, len)
+    }
+
+    //run handler on a host thread when the guest writes to addr, a port when pio, with len bytes and
+    //datamatch if any, see EventFd::IoEventFd. The vcpu doesn't exit to qvisor for the write
+    pub fn AddDoorbell(&mut self, addr: u64, len: u32, pio: bool, datamatch: Option<u64>, handler: Box<dyn FnMut() + Send>) -> Result<()> {
+        if self.doorbells.iter().any(|d| d.IoEventFd().Collides(addr, len, pio, datamatch)) {
+            return Err(Error::Common(format!("doorbell at {:x} len {} datamatch {:?} already", addr, len, datamatch)));
+        }
+
+        let ioEventFd = EventFd::IoEventFd::Register(&self.vm_fd, addr, len, pio, datamatch)?;
+        self.doorbells.push(EventFd::Doorbell::Start(ioEventFd, handler));
+        return Ok(())
+    }
+
+    //the doorbell AddDoorbell added with the same addr, len, pio and datamatch
+    pub fn RemoveDoorbell(&mut self, addr: u64, len: u32, pio: bool, datamatch: Option<u64>) -> Result<()> {
+        let idx = self.doorbells.iter().position(|d| d.IoEventFd().Matches(addr, len, pio, datamatch)).ok_or(Error::UnmatchRegion)?;
+        self.doorbells[idx].Stop(&self.vm_fd)?;
+        self.doorbells.remove(idx);
+        return Ok(())
+    }
+
+    //an IrqFd for gsi to hand to a host thread, the gsis 0-15 are the isa irqs of the pic and ioapic
+    pub fn AddIrqFd(&mut self, gsi: u32) -> Result<EventFd::IrqFd> {
+        if let Some(irqFd) = self.irqFds.iter().find(|i| i.gsi == gsi) {
+            return Ok(irqFd.clone())
+        }
+
+        let irqFd = EventFd::IrqFd::Register(&self.vm_fd, gsi)?;
+        self.irqFds.push(irqFd.clone());
+        return Ok(irqFd)
+    }
+
+    //the clones of the IrqFd don't raise gsi anymore
+    pub fn RemoveIrqFd(&mut self, gsi: u32) -> Result<()> {
+        let idx = self.irqFds.iter().position(|i| i.gsi == gsi).ok_or(Error::UnmatchRegion)?;
+        self.irqFds[idx].Unregister(&self.vm_fd)?;
+        self.irqFds.remove(idx);
         return Ok(())
     }
 
-    pub fn init(_mem_size:usize) -> Result<Self> {
+    //serve the io ports [port, port + len) with device
+    pub fn AddPioDevice(&mut self, port: u16, len: u16, device: Arc<Mutex<dyn Bus::BusDevice>>) -> Result<()> {
+        if port as u64 + len as u64 > 0x1_0000 {
+            return Err(Error::AddressNotInRange)
+        }
+
+        //the vcpu loop serves the hypercall ports before the bus
+        if port <= qlib::HYPERCALL_MAX && port as u64 + len as u64 > qlib::HYPERCALL_INIT as u64 {
+            return Err(Error::Common(format!("io ports {:x}-{:x} take hypercall ports", port, port as u64 + len as u64)));
+        }
+
+        return self.pioBus.Insert(port as u64, len as u64, device)
+    }
+
+    //serve the guest physical range [addr, addr + len) with device, it can't be guest memory as the
+    //guest would access the memory without an exit
+    pub fn AddMmioDevice(&mut self, addr: u64, len: u64, device: Arc<Mutex<dyn Bus::BusDevice>>) -> Result<()> {
+        let end = addr.checked_add(len).ok_or(Error::Overflow)?;
+        if self.GuestRanges().iter().any(|&(start, rEnd)| addr < rEnd && start < end) {
+            return Err(Error::Common(format!("mmio range {:x}-{:x} is in guest memory", addr, end)));
+        }
+
+        return self.mmioBus.Insert(addr, len, device)
+    }
+
+    //the COM1 uart, with its irq through an irqfd when the vm has the irqchip. A restored vm gets the
+    //registers and the unread input of the snapshot back
+    fn StartSerial(&mut self) -> Result<()> {
+        if self.pioBus.Contains(Serial::COM1_PORT as u64) {
+            return Ok(())
+        }
+
+        let irq = match self.AddIrqFd(Serial::COM1_GSI) {
+            Ok(irq) => Some(irq),
+            Err(e) => {
+                println!("serial without irq: {:?}", e);
+                None
+            }
+        };
+
+        let serial = Arc::new(Mutex::new(Serial::Serial::New(Serial::Serial::Output(&self.serialOut)?, irq)));
+        if let Some(state) = self.serialState.take() {
+            serial.lock().SetState(&state);
+        }
+
+        self.serial = Some(serial.clone());
+        if let Some(addr) = &self.serialIn {
+            self.serialInput = Some(Serial::Serial::StartInput(serial.clone(), addr)?);
+        }
+
+        return self.AddPioDevice(Serial::COM1_PORT, Serial::SERIAL_PORT_COUNT, serial)
+    }
+
+    //the notify doorbell, see qlib::NOTIFY_PORT
+    fn StartNotify(&mut self) -> Result<()> {
+        let port = qlib::NOTIFY_PORT as u64;
+        if self.doorbells.iter().any(|d| d.IoEventFd().Matches(port, 1, true, None)) {
+            return Ok(())
+        }
+
+        //without the answer the doorbell is no use
+        let irq = match self.AddIrqFd(qlib::NOTIFY_GSI) {
+            Ok(irq) => irq,
+            Err(e) => {
+                println!("no notify doorbell: {:?}", e);
+                return Ok(())
+            }
+        };
+
+        return self.AddDoorbell(port, 1, true, None, Box::new(move || {
+            if let Err(e) = irq.Trigger() {
+                println!("notify irq {:?}", e);
+            }
+        }))
+    }
+
+    pub fn init(_mem_size:usize, memOption: GuestMemOption) -> Result<Self> {
         let kvm = Kvm::new().map_err(|e| Error::IOError(format!("io::error is {:?}", e)))?;
         let vm_fd = kvm.create_vm().map_err(|e| Error::IOError(format!("io::error is {:?}", e)))?;
-
+        Irqchip::Create(&vm_fd)?;
+        let supportedCpuid = CpuidEntries::Supported(&kvm)?;
+        let vcpuMmapSize = KvmRun::MmapSize(&kvm)?;
 
         let mut elf = KernelELF::Init(&String::from("/home/brad/rust/rustkvm/qkernel/build/kernel-x86_64.bin"))?;
 
@@ -188,17 +481,22 @@ impl KVMMachine {
             return Err(Error::AddressDoesMatch)
         }
 
-        let pageMmap = KVMMachine::initKernelMem(&vm_fd, MemMgr::PHY_UPPER_ADDR, kernelMemSize)?;
+        let pageMmap = KVMMachine::initKernelMem(&vm_fd, MemMgr::PHY_UPPER_ADDR, kernelMemSize, &memOption)?;
 
         //KVMMachine::SetMemRegion(&vm_fd, MemMgr::PHY_UPPER_ADDR, elf.EndAddr().0 - MemMgr::PHY_UPPER_ADDR)?;
         //KVMMachine::SetMemRegion(0, &vm_fd, MemMgr::PHY_UPPER_ADDR, 16 * MemMgr::ONE_GB)?;
 
         println!("the end address is {:x}", elf.EndAddr().0);
-        for i in 0..1 {
-            KVMMachine::SetMemRegion(i, &vm_fd, MemMgr::PHY_UPPER_ADDR + i as u64 * 16 * MemMgr::ONE_GB, 16 * MemMgr::ONE_GB)?;
+        //one slot for the kernel memory and the ELF after it, the guest RAM added later gets its own slots
+        let mut slotIds = SlotIdAllocator::New(KVMMachine::MaxMemSlots(&kvm));
+        let kernelSlotSize = (elf.EndAddr().0 - MemMgr::PHY_UPPER_ADDR + MemMgr::PAGE_SIZE_2M - 1) & !(MemMgr::PAGE_SIZE_2M - 1);
+        if kernelSlotSize > MemMgr::DYNAMIC_MEM_OFFSET {
+            return Err(Error::AddressNotInRange)
         }
 
-        println!("set map ragion start={:x}, end={:x}", MemMgr::PHY_UPPER_ADDR, MemMgr::PHY_UPPER_ADDR + 16 * MemMgr::ONE_GB);
+        let memSlots = vec![KVMMachine::SetMemRegion(slotIds.Alloc()?, &vm_fd, MemMgr::PHY_UPPER_ADDR, kernelSlotSize)?];
+
+        println!("set map ragion start={:x}, end={:x}", MemMgr::PHY_UPPER_ADDR, MemMgr::PHY_UPPER_ADDR + kernelSlotSize);
 
         let mut topStackAddr = pageMmap.as_ptr() as u64 + 1 * MemMgr::ONE_GB;
         let defaultStackAddr = topStackAddr + MemMgr::STACK_GUARDPAGE_SIZE + MemMgr::STACK_SIZE;
@@ -208,15 +506,29 @@ impl KVMMachine {
             vms.pagePool = Some(PagePool::Init(Addr::Addr(pageMmap.as_ptr() as u64), 256*1024)?);
             vms.pageTables = Some(PageTables::New(vms.pagePool.as_mut().unwrap())?);
             vms.Map(Addr::Addr(pageMmap.as_ptr() as u64), Addr::Addr(pageMmap.as_ptr() as u64 + 1 * MemMgr::ONE_GB), Addr::Addr(pageMmap.as_ptr() as u64),
-                                             &Addr::PageOpts::Default())?;
-            vms.Map(Addr::Addr(topStackAddr), Addr::Addr(defaultStackAddr), Addr::Addr(topStackAddr), &Addr::PageOpts::Default())?;
+                                             &Addr::PageOpts::Kernel())?;
+            vms.Map(Addr::Addr(topStackAddr), Addr::Addr(defaultStackAddr), Addr::Addr(topStackAddr), &Addr::PageOpts::Kernel())?;
          }
 
+        //the stack slots after the boot stack are the interrupt stacks of the vcpus
+        let interruptStackTop = |vcpuId: usize| -> u64 {
+            defaultStackAddr + (vcpuId as u64 + 1) * (MemMgr::STACK_GUARDPAGE_SIZE + MemMgr::STACK_SIZE)
+        };
+
+        let descTables = {
+            let vms =  &mut VMS.lock();
+            for i in 0..VCPU_COUNT {
+                let top = interruptStackTop(i);
+                vms.Map(Addr::Addr(top - MemMgr::STACK_SIZE), Addr::Addr(top), Addr::Addr(top - MemMgr::STACK_SIZE), &Addr::PageOpts::Kernel())?;
+            }
+
+            DescTables::Init(vms.pagePool.as_mut().unwrap(), VCPU_COUNT, &interruptStackTop, MemMgr::STACK_SIZE)?
+        };
+
 
         topStackAddr += MemMgr::STACK_GUARDPAGE_SIZE + MemMgr::STACK_SIZE;
 
          //todo: allocate stack with guard page one by one
-        let hostMemOffset = Addr::Addr(pageMmap.as_ptr() as u64).AddLen(kernelMemSize)?;
 
         /*let len = 7 * MemMgr::BLOCK_SIZE;
         let mem_region = kvm_userspace_memory_region {
@@ -230,7 +542,7 @@ impl KVMMachine {
 
         println!("extra memory ragion start from {:x} to {:x}", hostMemOffset.0, hostMemOffset.0 +  len);*/
 
-        let phyAddrMgr = Arc::new(RefCell::new(PhyAddrMgr::Init(hostMemOffset,  7 * MemMgr::BLOCK_SIZE)?));
+        let phyAddrMgr = Arc::new(RefCell::new(PhyAddrMgr::Init(Addr::Addr(MemMgr::PHY_UPPER_ADDR + MemMgr::DYNAMIC_MEM_OFFSET),  7 * MemMgr::BLOCK_SIZE, memOption)?));
 
         let entry = elf.LoadKernel()?;
 
@@ -241,25 +553,800 @@ impl KVMMachine {
             kvm: kvm,
             vm_fd : vm_fd,
             vcpu_fds: Vec::new(),
+            vcpuRuns: Vec::new(),
+            vcpuMmapSize,
             pageMmap,
             topStackAddr,
             defaultStackAddr,
             entry: entry,
             phyAddrMgr,
+            memSlots,
+            slotIds,
+            balloon: Balloon::Balloon::New(),
+            allocMemLimit: DEFAULT_ALLOC_MEM_LIMIT,
             elf,
+            descTables,
+            supportedCpuid,
+            xstatePolicy: XStatePolicy::Default(),
+            cpuProfile: CpuProfile::Hardened(),
+            coreDumpPath: None,
+            gdbAddr: None,
+            snapshotPath: None,
+            migrateAddr: None,
+            templateClones: 0,
+            template: None,
+            controlAddr: None,
+            serialOut: None,
+            serialIn: None,
+            serialInput: None,
+            serial: None,
+            serialState: None,
+            pioBus: Bus::Bus::New(),
+            mmioBus: Bus::Bus::New(),
+            memAddedPending: Vec::new(),
+            doorbells: Vec::new(),
+            irqFds: Vec::new(),
+            shareSpace: 0,
         })
     }
 
+    //rebuild the vm saved by "qvisor snapshot" with the vcpu stopped where it was saved, start it with Resume.
+    //lazy leaves guest memory empty and fills it on demand with userfaultfd, so restore time doesn't grow with it
+    pub fn Restore(path: &str, lazy: bool) -> Result<Self> {
+        let kvm = Kvm::new().map_err(|e| Error::IOError(format!("io::error is {:?}", e)))?;
+        let vm_fd = kvm.create_vm().map_err(|e| Error::IOError(format!("io::error is {:?}", e)))?;
+
+        let snapshot = Snapshot::Snapshot::Open(path)?;
+        let (pageMmap, elf, phyAddrMgr, mapped) = KVMMachine::MapSavedMemory(&vm_fd, &snapshot.elfPath, &snapshot.regions)?;
+
+        snapshot.CheckState(&mapped, (pageMmap.Start().0, pageMmap.Len()))?;
+        if lazy {
+            Uffd::LazyLoader::Start(&snapshot, &mapped)?;
+        } else {
+            snapshot.LoadRegions(&mapped)?;
+        }
+
+        let vm = KVMMachine::FromSnapshot(kvm, vm_fd, &snapshot, pageMmap, elf, phyAddrMgr)?;
+        println!("restored {}: rip is {:x}", path, snapshot.vcpus[0].regs.rip);
+        return Ok(vm)
+    }
+
+    //"qvisor migrate-from": wait on addr for a qvisor migrating its vm with --migrate-to, and rebuild the
+    //vm here like Restore, start it with Resume. The kernel binary must be at the same path
+    pub fn MigrateFrom(addr: &str) -> Result<Self> {
+        let kvm = Kvm::new().map_err(|e| Error::IOError(format!("io::error is {:?}", e)))?;
+        let vm_fd = kvm.create_vm().map_err(|e| Error::IOError(format!("io::error is {:?}", e)))?;
+
+        let mut incoming = Migration::Incoming::Accept(addr)?;
+        let (elfPath, regions) = incoming.ReadLayout()?;
+        let (pageMmap, elf, phyAddrMgr, mapped) = KVMMachine::MapSavedMemory(&vm_fd, &elfPath, &regions)?;
+
+        let snapshot = incoming.ReadMemory(&regions, &mapped)?;
+        //the state is from an unauthenticated peer as much as the layout is
+        snapshot.CheckState(&mapped, (pageMmap.Start().0, pageMmap.Len()))?;
+        let vm = KVMMachine::FromSnapshot(kvm, vm_fd, &snapshot, pageMmap, elf, phyAddrMgr)?;
+        incoming.Resumed()?;
+
+        println!("migrated in from {}: rip is {:x}", addr, snapshot.vcpus[0].regs.rip);
+        return Ok(vm)
+    }
+
+    //map the guest memory of a saved vm, empty, with the same layout as init so every region comes back
+    //at its old address. Also return the (host address, len) of the mappings made, for CheckLayout
+    fn MapSavedMemory(vm_fd: &VmFd, elfPath: &str, regions: &[MemRegion]) -> Result<(Box<MappedRegion>, KernelELF, Arc<RefCell<PhyAddrMgr>>, Vec<(u64, u64)>)> {
+        let mut elf = KernelELF::Init(&elfPath.to_string())?;
+
+        let mut kernelMems = regions.iter().filter(|r| r.kind == Snapshot::REGION_KERNEL_MEM);
+        let kernelMem = match (kernelMems.next(), kernelMems.next()) {
+            (Some(r), None) => r,
+            (None, _) => return Err(Error::Common(String::from("no kernel memory in the saved vm"))),
+            (Some(_), Some(_)) => return Err(Error::Common(String::from("more than one kernel memory in the saved vm"))),
+        };
+
+        if kernelMem.hostAddr != MemMgr::PHY_UPPER_ADDR || elf.StartAddr().0 != kernelMem.hostAddr + kernelMem.len {
+            return Err(Error::AddressDoesMatch)
+        }
+
+        let pageMmap = KVMMachine::initKernelMem(vm_fd, kernelMem.hostAddr, kernelMem.len, &GuestMemOption {
+            memfd: false,
+            //a saved hugetlb region may come back on normal pages, not the other way around
+            hugePage: if kernelMem.hugePage { HugePagePolicy::Prefer } else { HugePagePolicy::Never },
+        })?;
+        elf.MapKernel()?;
+
+        let phyAddrMgr = Arc::new(RefCell::new(PhyAddrMgr::Init(Addr::Addr(MemMgr::PHY_UPPER_ADDR + MemMgr::DYNAMIC_MEM_OFFSET),  7 * MemMgr::BLOCK_SIZE, GuestMemOption::default())?));
+
+        let mut mapped = vec![(pageMmap.Start().0, pageMmap.Len())];
+        if let Some((start, end)) = elf.MappedRange() {
+            mapped.push((start, end - start));
+        }
+
+        for region in regions {
+            if region.kind == Snapshot::REGION_PHY {
+                let addr = phyAddrMgr.borrow_mut().RestoreAnan(Addr::Addr(region.hostAddr), region.len, region.hugePage)?;
+                mapped.push((addr.0, phyAddrMgr.borrow().RegionLen(addr.0)?));
+            } else if region.kind == Snapshot::REGION_KERNEL_ELF && Some((region.hostAddr, region.hostAddr + region.len)) != elf.MappedRange() {
+                return Err(Error::AddressDoesMatch)
+            }
+        }
+
+        return Ok((pageMmap, elf, phyAddrMgr, mapped))
+    }
+
+    //the vm around the memory from MapSavedMemory, with vcpu 0 in the saved state
+    fn FromSnapshot(kvm: Kvm, vm_fd: VmFd, snapshot: &Snapshot::Snapshot, pageMmap: Box<MappedRegion>, elf: KernelELF,
+                    phyAddrMgr: Arc<RefCell<PhyAddrMgr>>) -> Result<Self> {
+        let supportedCpuid = CpuidEntries::Supported(&kvm)?;
+        let vcpuMmapSize = KvmRun::MmapSize(&kvm)?;
+
+        let mut memSlots = Vec::new();
+        let mut slotIds = SlotIdAllocator::New(KVMMachine::MaxMemSlots(&kvm));
+        for &(slot, guestPhysAddr, size, hostAddr) in &snapshot.memSlots {
+            slotIds.Mark(slot)?;
+            let memSlot = MemSlot::New(slot, guestPhysAddr, size, hostAddr);
+            memSlot.Register(&vm_fd)?;
+            memSlots.push(memSlot);
+        }
+
+        {
+            let vms =  &mut VMS.lock();
+            vms.pagePool = Some(PagePool::InitWithFreePool(snapshot.pagePoolBase, snapshot.pagePoolCount, snapshot.pagePoolNext, snapshot.pagePoolFree.clone()));
+            vms.pageTables = Some(PageTables { root: Addr::Addr(snapshot.pageTableRoot) });
+            vms.profile = Some(CpuProfile::FromSregs(&snapshot.vcpus[0].sregs));
+        }
+
+        let descTables = DescTables {
+            gdtAddr: snapshot.gdtAddr,
+            gdtLimit: snapshot.gdtLimit,
+            tssAddr: snapshot.tssAddr,
+            idtAddr: snapshot.idtAddr,
+            vcpuCount: snapshot.vcpus.len(),
+        };
+
+        let mut vm = KVMMachine {
+            kvm: kvm,
+            vm_fd : vm_fd,
+            vcpu_fds: Vec::new(),
+            vcpuRuns: Vec::new(),
+            vcpuMmapSize,
+            pageMmap,
+            topStackAddr: snapshot.topStackAddr,
+            defaultStackAddr: snapshot.defaultStackAddr,
+            entry: snapshot.entry,
+            phyAddrMgr,
+            memSlots,
+            slotIds,
+            balloon: Balloon::Balloon::New(),
+            allocMemLimit: DEFAULT_ALLOC_MEM_LIMIT,
+            elf,
+            descTables,
+            supportedCpuid,
+            xstatePolicy: XStatePolicy::Default(),
+            cpuProfile: CpuProfile::Hardened(),
+            coreDumpPath: None,
+            gdbAddr: None,
+            snapshotPath: None,
+            migrateAddr: None,
+            templateClones: 0,
+            template: None,
+            controlAddr: None,
+            serialOut: None,
+            serialIn: None,
+            serialInput: None,
+            serial: None,
+            serialState: snapshot.serial.clone(),
+            pioBus: Bus::Bus::New(),
+            mmioBus: Bus::Bus::New(),
+            memAddedPending: Vec::new(),
+            doorbells: Vec::new(),
+            irqFds: Vec::new(),
+            shareSpace: snapshot.shareSpace,
+        };
+
+        //a vm saved before the irqchip keeps running without it, with its lapic state left out of VcpuState
+        if let Some(irqchip) = &snapshot.irqchip {
+            Irqchip::Create(&vm.vm_fd)?;
+            irqchip.Set(&vm.vm_fd)?;
+        }
+
+        vm.CreateVCPU()?;
+        vm.SetCpuid()?;
+        snapshot.vcpus[0].Set(&vm.vcpu_fds[0])?;
+        return Ok(vm)
+    }
+
+    //finish the pending io instruction, so the saved rip is after the out of the hypercall. vcpu 0 must
+    //be stopped out of KVM_RUN
+    fn CompletePendingIo(&self) -> Result<()> {
+        self.vcpuRuns[0].SetImmediateExit(true);
+        let res = self.vcpu_fds[0].run().map(|_| ());
+        self.vcpuRuns[0].SetImmediateExit(false);
+        match res {
+            Err(ref e) if e.raw_os_error() == Some(libc::EINTR) => return Ok(()),
+            Ok(()) => return Err(Error::Common(String::from("vcpu exits while completing the pending io"))),
+            Err(e) => return Err(Error::IOError(format!("io::error is {:?}", e))),
+        }
+    }
+
+    //(host address, memfd, offset) of the guest memory backed by memfds
+    pub fn Memfds(&self) -> Vec<(u64, i32, u64)> {
+        let mut memfds = self.phyAddrMgr.borrow().Memfds();
+        if self.pageMmap.fd >= 0 {
+            memfds.push((self.pageMmap.Start().0, self.pageMmap.fd, self.pageMmap.fileOffset));
+        }
+
+        return memfds
+    }
+
+    //the guest memory as snapshot regions, with no page scanned yet
+    fn SavedRegions(&self) -> Vec<MemRegion> {
+        let mut regions = vec![MemRegion::New(Snapshot::REGION_KERNEL_MEM, self.pageMmap.Start().0, self.pageMmap.Start().0, self.pageMmap.Len(), self.pageMmap.pageSize == MemMgr::PAGE_SIZE_2M)];
+        if let Some((start, end)) = self.elf.MappedRange() {
+            regions.push(MemRegion::New(Snapshot::REGION_KERNEL_ELF, start, start, end - start, false));
+        }
+
+        for (phyAddr, hostAddr, len, hugePage) in self.phyAddrMgr.borrow().Regions() {
+            regions.push(MemRegion::New(Snapshot::REGION_PHY, hostAddr, phyAddr, len, hugePage));
+        }
+
+        return regions
+    }
+
+    //the host files mapped in the guest are not part of a snapshot, a vm with them can't be saved or moved
+    fn CheckSavable(&self) -> Result<()> {
+        let files = self.phyAddrMgr.borrow().FileRegions();
+        if files.len() > 0 {
+            return Err(Error::Common(format!("{} host files are mapped in the guest", files.len())));
+        }
+
+        return Ok(())
+    }
+
+    //everything but the guest memory, after CompletePendingIo
+    fn SaveState(&self) -> Result<Snapshot::Snapshot> {
+        self.CheckSavable()?;
+
+        let mut vcpus = Vec::with_capacity(self.vcpu_fds.len());
+        for vcpu in &self.vcpu_fds {
+            vcpus.push(VcpuState::Get(vcpu)?);
+        }
+
+        let vms = VMS.lock();
+        let pagePool = vms.pagePool.as_ref().unwrap();
+
+        return Ok(Snapshot::Snapshot {
+            elfPath: self.elf.fileName.clone(),
+            entry: self.entry,
+            topStackAddr: self.topStackAddr,
+            defaultStackAddr: self.defaultStackAddr,
+            shareSpace: self.shareSpace,
+            gdtAddr: self.descTables.gdtAddr,
+            gdtLimit: self.descTables.gdtLimit,
+            tssAddr: self.descTables.tssAddr,
+            idtAddr: self.descTables.idtAddr,
+            pageTableRoot: vms.pageTables.as_ref().unwrap().root.0,
+            pagePoolBase: pagePool.baseAddr.0,
+            pagePoolCount: pagePool.pageCount,
+            pagePoolNext: pagePool.next,
+            pagePoolFree: pagePool.FreePool().clone(),
+            memSlots: self.memSlots.iter().map(|s| (s.slot, s.guestPhysAddr, s.memorySize, s.userspaceAddr)).collect(),
+            vcpus: vcpus,
+            regions: self.SavedRegions(),
+            prefetch: Vec::new(),
+            irqchip: Some(Irqchip::IrqchipState::Get(&self.vm_fd)?),
+            serial: self.serial.as_ref().map(|s| s.lock().State()),
+            file: None,
+        })
+    }
+
+    //save the whole vm to snapshotPath, vcpu 0 must be stopped out of KVM_RUN
+    fn TakeSnapshot(&self) -> Result<()> {
+        let path = match &self.snapshotPath {
+            Some(path) => path,
+            None => return Err(Error::Common(String::from("no snapshot path"))),
+        };
+
+        self.CompletePendingIo()?;
+        let mut snapshot = self.SaveState()?;
+
+        let reader = self.GuestMemory();
+        let hot = Snapshot::HotPages(&reader, &snapshot.vcpus[0], self.shareSpace);
+        return snapshot.Write(path, &hot)
+    }
+
+    //turn on dirty logging and start copying the guest memory to migrateAddr while the guest keeps running
+    fn StartMigration(&mut self) -> Result<Migration::Outgoing> {
+        let addr = match &self.migrateAddr {
+            Some(addr) => addr.clone(),
+            None => return Err(Error::Common(String::from("no migration address"))),
+        };
+
+        self.CheckSavable()?;
+
+        let slots: Vec<u32> = self.memSlots.iter().map(|s| s.slot).collect();
+        for &slot in &slots {
+            self.SetDirtyLogging(slot, true)?;
+        }
+        GuestMemory::TrackHostWrites();
+
+        match Migration::Outgoing::Start(&addr, &self.elf.fileName, self.SavedRegions(), self.memSlots.clone(),
+                                         self.vm_fd.as_raw_fd(), self.vcpuRuns[0].ImmediateExitAddr()) {
+            Ok(migration) => {
+                println!("migrating to {}", addr);
+                return Ok(migration)
+            }
+            Err(e) => {
+                self.StopMigration();
+                return Err(e)
+            }
+        }
+    }
+
+    fn StopMigration(&mut self) {
+        GuestMemory::TakeHostWrites();
+        let slots: Vec<u32> = self.memSlots.iter().map(|s| s.slot).collect();
+        for slot in slots {
+            if let Err(e) = self.SetDirtyLogging(slot, false) {
+                println!("turn off dirty logging of slot {} fail: {:?}", slot, e);
+            }
+        }
+    }
+
+    //the guest memory qvisor wrote during the migration, which the dirty log misses: the pages written
+    //through a GuestMemory, the ShareSpace among them, and the page tables VMS.Map builds in the page pool
+    fn HostWrites(&self) -> Vec<(u64, u64)> {
+        let mut ranges: Vec<(u64, u64)> = GuestMemory::TakeHostWrites().into_iter().map(|page| (page, MemMgr::PAGE_SIZE_4K)).collect();
+        if let Some(pool) = VMS.lock().pagePool.as_ref() {
+            ranges.push((pool.baseAddr.0, pool.next as u64 * MemMgr::PAGE_SIZE_4K));
+        }
+
+        return ranges
+    }
+
+    //the migration asked for the pause: hand the state of the stopped vcpu 0 over. Return true when the
+    //destination runs the guest now, false when it stays here
+    fn CompleteMigration(&mut self, migration: &Migration::Outgoing) -> Result<bool> {
+        self.CompletePendingIo()?;
+        let state = self.SaveState()?.EncodeMeta();
+        match migration.Complete(state, self.HostWrites()) {
+            Ok(()) => {
+                println!("migration done, the guest runs on the destination");
+                return Ok(true)
+            }
+            Err(e) => {
+                println!("migration fail, the guest keeps running here: {:?}", e);
+                self.StopMigration();
+                return Ok(false)
+            }
+        }
+    }
+
+    //fork templateClones clones of the vm frozen by Resume, each runs the guest from the template's state in
+    //its own process with a new kvm vm. Return when all of them exit
+    pub fn SpawnClones(mut self) -> Result<()> {
+        let template = match self.template.take() {
+            Some(t) => t,
+            None => return Err(Error::Common(String::from("the vm is not frozen as a template"))),
+        };
+
+        self.StopHostThreads()?;
+
+        let mut children = Vec::with_capacity(self.templateClones);
+        for i in 0..self.templateClones {
+            let pid = unsafe { libc::fork() };
+            if pid < 0 {
+                return Err(Error::IOError(format!("fork fail, io::error is {:?}", std::io::Error::last_os_error())));
+            }
+
+            if pid == 0 {
+                let code = match self.IntoClone(&template) {
+                    Ok(mut vm) => match vm.Resume() {
+                        Ok(()) => 0,
+                        Err(Error::VcpuAbnormalExit(code)) => code,
+                        Err(e) => {
+                            println!("clone {} run error is {:?}", i, e);
+                            1
+                        }
+                    },
+                    Err(e) => {
+                        println!("clone {} error is {:?}", i, e);
+                        1
+                    }
+                };
+                std::process::exit(code)
+            }
+
+            println!("clone {} started as pid {}", i, pid);
+            children.push(pid);
+        }
+
+        for pid in children {
+            let mut status = 0;
+            if unsafe { libc::waitpid(pid, &mut status, 0) } < 0 {
+                return Err(Error::IOError(format!("waitpid fail, io::error is {:?}", std::io::Error::last_os_error())));
+            }
+            println!("clone pid {} exits with status {:x}", pid, status);
+        }
+
+        return Ok(())
+    }
+
+    //fork copies only the calling thread, a lock another thread holds stays locked in the child forever.
+    //End the host threads and close the sockets they serve, which the children would keep open. The
+    //gdb and control threads ended with Resume
+    fn StopHostThreads(&mut self) -> Result<()> {
+        self.serialInput = None;
+        for mut doorbell in self.doorbells.drain(..) {
+            doorbell.Stop(&self.vm_fd)?;
+        }
+
+        return Ok(())
+    }
+
+    //in a forked child: a new kvm vm over copy-on-write views of the template's memory. The inherited kvm
+    //fds belong to the template's mm and can't run in the child
+    fn IntoClone(self, template: &Template::Template) -> Result<Self> {
+        template.MapPrivate()?;
+
+        let kvm = Kvm::new().map_err(|e| Error::IOError(format!("io::error is {:?}", e)))?;
+        let vm_fd = kvm.create_vm().map_err(|e| Error::IOError(format!("io::error is {:?}", e)))?;
+
+        let KVMMachine { mut pageMmap, elf, phyAddrMgr, coreDumpPath, .. } = self;
+        pageMmap.shared = false;
+        phyAddrMgr.borrow_mut().SetPrivate();
+        let mut vm = KVMMachine::FromSnapshot(kvm, vm_fd, &template.state, pageMmap, elf, phyAddrMgr)?;
+        vm.coreDumpPath = coreDumpPath;
+        return Ok(vm)
+    }
+
+    //the guest memory as the guest passes it in hypercalls, with the kernel page tables
+    pub fn GuestMemory(&self) -> GuestMemory::GuestMemory {
+        //before setup_long_mode there are no kernel page tables, the virtual accesses find nothing at 0
+        let cr3 = VMS.lock().pageTables.as_ref().map_or(0, |p| p.root.0);
+        return GuestMemory::GuestMemory::New(self.GuestRegions(), cr3)
+    }
+
+    //stop the vm for a hypercall argument a well behaved guest never passes
+    fn BadHypercall(&self, call: u16, e: Error) -> Error {
+        return self.AbnormalExit(AbnormalExit::BadHypercall(format!("{}: {:?}", call, e)))
+    }
+
+    //the message the guest passed with HYPERCALL_WAIT and the bytes of its Str
+    fn ReadMsg(&self) -> Result<(qlib::Msg, Vec<u8>)> {
+        if self.shareSpace == 0 {
+            return Err(Error::Common(String::from("no ShareSpace before HYPERCALL_INIT")));
+        }
+
+        let mem = self.GuestMemory();
+        let share: ShareSpace = mem.ReadObjVirt(self.shareSpace)?;
+        //the guest has to release the lock before it waits (ShareSpaceLock::Wait), qvisor never spins on it
+        if share.Locked() {
+            return Err(Error::Locked);
+        }
+
+        let msgAddr = share.GetMsg();
+
+        let msg: qlib::Msg = mem.ReadObjVirt(msgAddr)?;
+        if msg.msgType != qlib::MSG_PRINT && msg.msgType != qlib::MSG_MSG1 {
+            return Err(Error::Common(format!("unknown message type {}", msg.msgType)));
+        }
+
+        if msg.str.len > qlib::MAX_STR_LEN {
+            return Err(Error::Common(format!("message of {:x} bytes", msg.str.len)));
+        }
+
+        let mut data = vec![0; msg.str.len as usize];
+        mem.ReadSliceVirt(msg.str.addr, &mut data)?;
+        return Ok((msg, data))
+    }
+
+    //serve the qlib::AllocMemReq at the guest virtual address reqAddr. A failed allocation is returned
+    //to the guest, a bad request stops the vm
+    fn AllocMemCall(&mut self, reqAddr: u64) -> Result<()> {
+        let mem = self.GuestMemory();
+        let mut req: qlib::AllocMemReq = mem.ReadObjVirt(reqAddr)?;
+        if req.hugePage > 1 {
+            return Err(Error::Common(format!("AllocMemReq hugePage {}", req.hugePage)));
+        }
+
+        //counted as hugetlb, which AllocAnan rounds up the most
+        let used: u64 = self.phyAddrMgr.borrow().Regions().iter().map(|r| r.2).sum();
+        let len = req.len.checked_add(MemMgr::PAGE_SIZE_2M - 1).map(|l| l & !(MemMgr::PAGE_SIZE_2M - 1));
+        let res = match len {
+            Some(len) if used + len <= self.allocMemLimit => self.AllocGuestMem(req.len, req.hugePage != 0),
+            _ => Err(Error::NoEnoughMemory),
+        };
+
+        match res {
+            Ok(addr) => {
+                req.addr = addr;
+                req.result = 0;
+            }
+            Err(e) => {
+                println!("HYPERCALL_ALLOCMEM of {:x} bytes fail: {:?}", req.len, e);
+                req.result = -1;
+            }
+        }
+
+        return mem.WriteObjVirt(reqAddr, req)
+    }
+
+    //serve the qlib::FreeMemReq at the guest virtual address reqAddr. The memory still queued for the
+    //guest is not its to free
+    fn FreeMemCall(&mut self, reqAddr: u64) -> Result<()> {
+        let mut req: qlib::FreeMemReq = self.GuestMemory().ReadObjVirt(reqAddr)?;
+        let res = if self.memAddedPending.iter().any(|&(addr, _)| addr == req.addr) {
+            Err(Error::Common(String::from("the memory is not handed to the guest yet")))
+        } else {
+            self.FreeGuestMem(req.addr)
+        };
+
+        req.result = match res {
+            Ok(()) => 0,
+            Err(e) => {
+                println!("HYPERCALL_FREEMEM of {:x} fail: {:?}", req.addr, e);
+                -1
+            }
+        };
+
+        //the freed memory is gone from a GuestMemory made now, a request inside it stops the vm
+        return self.GuestMemory().WriteObjVirt(reqAddr, req)
+    }
+
+    //add len bytes of guest RAM while the guest runs and queue it for the guest heap. return (addr, len)
+    pub fn HotplugMem(&mut self, len: u64, hugePage: bool) -> Result<(u64, u64)> {
+        let addr = self.AllocGuestMem(len, hugePage)?;
+        let len = self.phyAddrMgr.borrow().RegionLen(addr)?;
+        self.memAddedPending.push((addr, len));
+        self.FlushMemAdded()?;
+        return Ok((addr, len))
+    }
+
+    //move memAddedPending to the ShareSpace queue as far as it fits. The vcpu is out of KVM_RUN, but
+    //may have been stopped with the ShareSpace locked, then it is tried again at a later exit
+    fn FlushMemAdded(&mut self) -> Result<()> {
+        if self.memAddedPending.len() == 0 || self.shareSpace == 0 {
+            return Ok(())
+        }
+
+        let mem = self.GuestMemory();
+        let mut share: ShareSpace = mem.ReadObjVirt(self.shareSpace)?;
+        if share.Locked() {
+            return Ok(())
+        }
+
+        let count = self.memAddedPending.len();
+        while let Some(&(addr, len)) = self.memAddedPending.first() {
+            if !share.AddMem(addr, len) {
+                break;
+            }
+
+            println!("guest memory {:x} len {:x} handed to the guest", addr, len);
+            self.memAddedPending.remove(0);
+        }
+
+        if self.memAddedPending.len() == count {
+            return Ok(())
+        }
+
+        return mem.WriteObjVirt(self.shareSpace, share)
+    }
+
+    //the guest RAM regions, hotplugged or not, and the balloon
+    fn MemInfo(&self) -> String {
+        let mut info = format!("kernel {:x} len {:x}", self.pageMmap.Start().0, self.pageMmap.Len());
+        for (phyAddr, _hostAddr, len, hugePage) in self.phyAddrMgr.borrow().Regions() {
+            info += &format!(", ram {:x} len {:x}{}", phyAddr, len, if hugePage { " huge" } else { "" });
+        }
+
+        for (phyAddr, len, readonly) in self.phyAddrMgr.borrow().FileRegions() {
+            info += &format!(", file {:x} len {:x}{}", phyAddr, len, if readonly { " readonly" } else { "" });
+        }
+
+        info += &format!(", balloon {:x}", self.balloon.Reclaimed());
+        return info
+    }
+
+    fn ControlCmd(&mut self, cmd: Control::ControlCmd) {
+        let res = match cmd.req {
+            Control::ControlReq::Hotplug { len, hugePage } => match self.HotplugMem(len, hugePage) {
+                Ok((addr, len)) => format!("ok {:x} len {:x}", addr, len),
+                Err(e) => format!("error {:?}", e),
+            },
+            Control::ControlReq::MapFile { path, offset, len, shared, readonly } => {
+                match self.AllocGuestFile(&path, offset, len, shared, readonly) {
+                    //the region length is rounded up to the page size
+                    Ok(addr) => format!("ok {:x} len {:x}", addr, self.phyAddrMgr.borrow().RegionLen(addr).unwrap_or(len)),
+                    Err(e) => format!("error {:?}", e),
+                }
+            }
+            Control::ControlReq::Info => format!("ok {}", self.MemInfo()),
+        };
+
+        cmd.Reply(res);
+    }
+
+    //give the guest RAM [addr, addr + len) to the host, or take it back with deflate
+    pub fn Balloon(&mut self, addr: u64, len: u64, deflate: bool) -> Result<()> {
+        if len == 0 || addr.checked_add(len).is_none() {
+            return Err(Error::UnallignedSize);
+        }
+
+        if deflate {
+            let bytes = self.balloon.Deflate(addr, len);
+            println!("balloon deflated by {:x} bytes, {:x} bytes reclaimed", bytes, self.balloon.Reclaimed());
+            return Ok(())
+        }
+
+        //only RAM, not the ELF or host files
+        if self.pageMmap.Start().0 <= addr && addr + len <= self.pageMmap.Start().0 + self.pageMmap.Len() {
+            self.pageMmap.Discard(addr, len)?;
+        } else {
+            self.phyAddrMgr.borrow().Discard(addr, len)?;
+        }
+
+        let bytes = self.balloon.Inflate(addr, len);
+        println!("balloon inflated by {:x} bytes, {:x} bytes reclaimed", bytes, self.balloon.Reclaimed());
+        return Ok(())
+    }
+
+    //serve the qlib::BalloonReq at the guest virtual address reqAddr
+    fn BalloonCall(&mut self, reqAddr: u64) -> Result<()> {
+        let mem = self.GuestMemory();
+        let mut req: qlib::BalloonReq = mem.ReadObjVirt(reqAddr)?;
+        if req.deflate > 1 {
+            return Err(Error::Common(format!("BalloonReq deflate {}", req.deflate)));
+        }
+        req.result = match self.Balloon(req.addr, req.len, req.deflate != 0) {
+            Ok(()) => 0,
+            Err(e) => {
+                println!("HYPERCALL_BALLOON of {:x} len {:x} fail: {:?}", req.addr, req.len, e);
+                -1
+            }
+        };
+
+        return mem.WriteObjVirt(reqAddr, req)
+    }
+
+    //the guest called HYPERCALL_SNAPSHOT or qvisor got SIGUSR2. Return true when the vm is done in this process
+    fn SavePoint(&mut self, migration: &mut Option<Migration::Outgoing>) -> Result<bool> {
+        if self.snapshotPath.is_some() {
+            self.TakeSnapshot()?;
+            return Ok(true)
+        }
+
+        if self.templateClones > 0 {
+            self.CompletePendingIo()?;
+            self.template = Some(Template::Template::Freeze(self.SaveState()?, &self.Memfds())?);
+            return Ok(true)
+        }
+
+        if self.migrateAddr.is_some() && migration.is_none() {
+            match self.StartMigration() {
+                Ok(m) => *migration = Some(m),
+                Err(e) => println!("migration to {:?} fail: {:?}", self.migrateAddr, e),
+            }
+        }
+
+        return Ok(false)
+    }
+
     fn CreateVCPU(&mut self) -> Result<()> {
-        self.vcpu_fds.push(self.vm_fd.create_vcpu(0).map_err(|e| Error::IOError(format!("io::error is {:?}", e)))?);
+        let vcpu = self.vm_fd.create_vcpu(0).map_err(|e| Error::IOError(format!("io::error is {:?}", e)))?;
+        self.vcpuRuns.push(KvmRun::New(&vcpu, self.vcpuMmapSize)?);
+        self.vcpu_fds.push(vcpu);
         Ok(())
     }
 
+    //the guest physical ranges backed by host memory at the same address
+    pub fn GuestRanges(&self) -> Vec<(u64, u64)> {
+        return self.GuestRegions().iter().map(|r| (r.start, r.start + r.len)).collect()
+    }
+
+    //GuestRanges with the host protection, the memory of a readonly file can't be written
+    fn GuestRegions(&self) -> Vec<GuestMemory::GuestRegion> {
+        let region = |start: u64, end: u64, writable: bool| GuestMemory::GuestRegion {
+            start: start,
+            len: end - start,
+            hostAddr: start,
+            writable: writable,
+        };
+
+        let mut regions = vec![region(self.pageMmap.Start().0, self.pageMmap.End().unwrap().0, true)];
+        if let Some((start, end)) = self.elf.MappedRange() {
+            regions.push(region(start, end, true));
+        }
+
+        for (phyAddr, _hostAddr, len, _hugePage) in self.phyAddrMgr.borrow().Regions() {
+            regions.push(region(phyAddr, phyAddr + len, true));
+        }
+
+        for (phyAddr, len, readonly) in self.phyAddrMgr.borrow().FileRegions() {
+            regions.push(region(phyAddr, phyAddr + len, !readonly));
+        }
+
+        return regions;
+    }
+
+    //print the diagnostic report of vcpu 0, write the core file if asked and return the error
+    //carrying the qvisor exit code
+    fn AbnormalExit(&self, exit: AbnormalExit) -> Error {
+        let reader = self.GuestMemory();
+
+        let report = Diagnose::Report(&exit, 0, &self.vcpu_fds[0], &self.vcpuRuns[0], &reader, &self.elf);
+        eprint!("{}", report);
+
+        if let Some(path) = &self.coreDumpPath {
+            if let Err(e) = self.WriteCoreDump(path, &reader) {
+                eprintln!("fail to write core dump {}: {:?}", path, e);
+            }
+        }
+
+        return Error::VcpuAbnormalExit(exit.ExitCode())
+    }
+
+    pub fn WriteCoreDump(&self, path: &str, reader: &GuestMemory::GuestMemory) -> Result<()> {
+        let mut vcpus = Vec::with_capacity(self.vcpu_fds.len());
+        for vcpu in &self.vcpu_fds {
+            vcpus.push(CoreDump::VcpuDumpState::Get(vcpu)?);
+        }
+
+        return CoreDump::Write(path, reader, &vcpus)
+    }
+
+    fn MemSlot(&self, slot: u32) -> Result<&MemSlot> {
+        return self.memSlots.iter().find(|s| s.slot == slot).ok_or(Error::Common(format!("no memory slot {}", slot)))
+    }
+
+    pub fn SetDirtyLogging(&mut self, slot: u32, enable: bool) -> Result<()> {
+        let vm_fd = &self.vm_fd;
+        let memSlot = self.memSlots.iter_mut().find(|s| s.slot == slot).ok_or(Error::Common(format!("no memory slot {}", slot)))?;
+        return memSlot.SetLogDirty(vm_fd, enable)
+    }
+
+    //fetch and clear the dirty bitmap of the slot, bit n is the 4KB page n of the slot
+    pub fn GetDirtyLog(&self, slot: u32) -> Result<Vec<u64>> {
+        return self.MemSlot(slot)?.GetDirtyLog(&self.vm_fd)
+    }
+
+    //fetch and clear the dirty pages of the slot as guest physical ranges
+    pub fn GetDirtyRanges(&self, slot: u32) -> Result<Vec<DirtyRange>> {
+        return self.MemSlot(slot)?.GetDirtyRanges(&self.vm_fd)
+    }
+
+    //the dirty ranges of every slot with logging on
+    pub fn GetAllDirtyRanges(&self) -> Result<Vec<DirtyRange>> {
+        let mut ranges = Vec::new();
+        for slot in self.memSlots.iter().filter(|s| s.logDirty) {
+            ranges.append(&mut slot.GetDirtyRanges(&self.vm_fd)?);
+        }
+
+        return Ok(ranges)
+    }
+
+    //hand the stopped vcpu 0 to gdb. return false when gdb kills the guest
+    fn GdbStop(&self, gdb: &mut Option<GdbStub>, reason: StopReason) -> Result<bool> {
+        let reader = self.GuestMemory();
+
+        let resume = match gdb {
+            Some(stub) => stub.HandleStop(&self.vcpu_fds[0], &reader, reason)?,
+            None => return Ok(true),
+        };
+
+        match resume {
+            GdbResume::Continue => (),
+            GdbResume::Detach => *gdb = None,
+            GdbResume::Kill => return Ok(false),
+        }
+
+        return Ok(true)
+    }
+
     fn setup_64bit_code_segment(sregs : &mut kvm_sregs) {
         let cs_seg = kvm_segment {
             base : 0,
             limit : 0xffffffff,
-            selector : 1 << 3,
+            selector : qlib::KERNEL_CODE_SEL,
             present : 1,
             type_ : 11, /* Code: execute, read, accessed */
             dpl : 0,
@@ -274,7 +1361,7 @@ impl KVMMachine {
 
         let ds_seg = kvm_segment{
             type_ : 3,
-            selector : 2<<3,
+            selector : qlib::KERNEL_DATA_SEL,
             ..cs_seg
         };
 
@@ -290,23 +1377,73 @@ impl KVMMachine {
 
         let mut vcpu_sregs = self.vcpu_fds[0].get_sregs().map_err(|e| Error::IOError(format!("io::error is {:?}", e)))?;
 
-        vcpu_sregs.cr3 = {VMS.lock().pageTables.as_ref().unwrap().root.0};
-        vcpu_sregs.cr4 = CR4_PAE;
+        let profile = self.cpuProfile.Effective(&self.supportedCpuid);
+        {
+            let mut vms = VMS.lock();
+            let pageTables = vms.pageTables.as_ref().unwrap();
+            profile.CheckPageTables(pageTables, self.entry, self.topStackAddr - MemMgr::STACK_SIZE, self.topStackAddr)?;
+            vcpu_sregs.cr3 = pageTables.root.0;
+            vms.profile = Some(profile);
+        }
+
+        vcpu_sregs.cr4 = CR4_PAE | profile.Cr4();
         vcpu_sregs.cr0 = CR0_PE | CR0_MP | CR0_ET | CR0_NE | CR0_WP | CR0_AM | CR0_PG;
 
-        vcpu_sregs.efer = EFER_LME | EFER_LMA | EFER_SCE;
+        vcpu_sregs.efer = EFER_LME | EFER_LMA | EFER_SCE | profile.Efer();
+
+        let xcr0 = self.setup_fpu(&mut vcpu_sregs)?;
 
         KVMMachine::setup_64bit_code_segment(&mut vcpu_sregs);
+        self.descTables.SetSregs(0, &mut vcpu_sregs);
 
         self.vcpu_fds[0].set_sregs(&vcpu_sregs).map_err(|e| Error::IOError(format!("io::error is {:?}", e)))?;
+
+        //XCR0 can only be loaded after CR4.OSXSAVE is set
+        if xcr0 != 0 {
+            Cpu::SetXcr0(&self.vcpu_fds[0], xcr0)?;
+        }
+
         Ok(())
     }
 
+    //give the vcpu the host's cpuid with the xsave components the policy doesn't allow hidden. return the XCR0 to use
+    fn SetCpuid(&self) -> Result<u64> {
+        let xcr0 = self.xstatePolicy.Xcr0(self.supportedCpuid.SupportedXcr0());
+
+        let mut cpuid = CpuidEntries {
+            entries: self.supportedCpuid.entries.clone(),
+        };
+        cpuid.FilterXState(xcr0);
+        cpuid.SetVcpu(&self.vcpu_fds[0])?;
+        return Ok(xcr0)
+    }
+
+    //enable x87/SSE and, when the host supports it, XSAVE for the vcpu. return the XCR0 to load, 0 if XSAVE is off
+    fn setup_fpu(&self, vcpu_sregs: &mut kvm_sregs) -> Result<u64> {
+        if !self.supportedCpuid.HasFxsrSse() {
+            return Err(Error::Common(String::from("the host cpu doesn't support FXSR/SSE")));
+        }
+
+        let xcr0 = self.SetCpuid()?;
+
+        vcpu_sregs.cr0 &= !(CR0_EM | CR0_TS);
+        vcpu_sregs.cr0 |= CR0_MP | CR0_NE;
+        vcpu_sregs.cr4 |= CR4_OSFXSR | CR4_OSXMMEXCPT;
+        if xcr0 != 0 {
+            vcpu_sregs.cr4 |= CR4_OSXSAVE;
+        }
+
+        Cpu::SetFpu(&self.vcpu_fds[0])?;
+
+        return Ok(xcr0)
+    }
+
     pub fn run(&mut self) -> Result<()> {
 
 
 
         self.CreateVCPU()?;
+        Irqchip::SetupLapic(&self.vcpu_fds[0])?;
         self.setup_long_mode()?;
 
         //println!("***the first byte of code is {}", self.mmap[0xb0]);
@@ -325,66 +1462,192 @@ impl KVMMachine {
 
         self.vcpu_fds[0].set_regs(&regs).map_err(|e| Error::IOError(format!("io::error is {:?}", e)))?;
 
-        let mut shareSpace : * mut Mutex<ShareSpace> = 0 as * mut Mutex<ShareSpace>; //give a default to work around compile uninitialized error
+        return self.Resume()
+    }
+
+    //run vcpu 0 from its current state, after run set it up or Restore loaded it
+    pub fn Resume(&mut self) -> Result<()> {
+        if self.snapshotPath.is_some() || self.migrateAddr.is_some() || self.templateClones > 0 {
+            Snapshot::InstallRequestHandler(self.vcpuRuns[0].ImmediateExitAddr())?;
+        }
+
+        let mut gdb = match &self.gdbAddr {
+            Some(addr) => Some(GdbStub::Listen(addr, self.vcpuRuns[0].ImmediateExitAddr())?),
+            None => None,
+        };
+
+        if !self.GdbStop(&mut gdb, StopReason::Attach)? {
+            return Ok(())
+        }
+
+        let control = match &self.controlAddr {
+            Some(addr) => Some(Control::Control::Listen(addr, self.vcpuRuns[0].ImmediateExitAddr())?),
+            None => None,
+        };
+
+        self.StartSerial()?;
+        self.StartNotify()?;
 
+        let mut migration: Option<Migration::Outgoing> = None;
         loop {
-            match self.vcpu_fds[0].run().expect("run failed") {
+            if migration.as_ref().map_or(false, |m| m.Failed()) {
+                migration = None;
+                self.StopMigration();
+            }
+
+            let mut savePoint = false;
+            //the hypercalls which change the vm, served after the exit is released
+            let mut memCall = None;
+            let exit = match self.vcpu_fds[0].run() {
+                Ok(exit) => exit,
+                Err(e) => {
+                    if e.raw_os_error() != Some(libc::EINTR) {
+                        return Err(Error::IOError(format!("io::error is {:?}", e)));
+                    }
+
+                    if Snapshot::TakeRequest() {
+                        self.vcpuRuns[0].SetImmediateExit(false);
+                        if self.SavePoint(&mut migration)? {
+                            return Ok(())
+                        }
+                    }
+
+                    //kicked for the stop and copy of the migration
+                    if migration.as_ref().map_or(false, |m| m.PauseRequested()) {
+                        self.vcpuRuns[0].SetImmediateExit(false);
+                        let m = migration.take().unwrap();
+                        if self.CompleteMigration(&m)? {
+                            return Ok(())
+                        }
+                    }
+
+                    //kicked for a control socket command
+                    if let Some(control) = &control {
+                        let cmds = control.TakeCmds();
+                        if cmds.len() > 0 {
+                            self.vcpuRuns[0].SetImmediateExit(false);
+                        }
+
+                        for cmd in cmds {
+                            self.ControlCmd(cmd);
+                        }
+                    }
+
+                    //kicked for gdb's ^C
+                    if gdb.as_ref().map_or(false, |g| g.TakeInterrupt()) {
+                        self.vcpuRuns[0].SetImmediateExit(false);
+                        if !self.GdbStop(&mut gdb, StopReason::Interrupt)? {
+                            return Ok(())
+                        }
+                    }
+
+                    continue;
+                }
+            };
+
+            match exit {
                 VcpuExit::IoIn(addr, data) => {
-                    println!(
-                        "Received an I/O in exit. Address: {:#x}. Data: {:#x}",
-                        addr,
-                        data[0],
-                    );
+                    if !self.pioBus.Read(addr as u64, data) {
+                        for b in data.iter_mut() {
+                            *b = 0xff;
+                        }
+                    }
                 }
-                VcpuExit::IoOut(addr, _data) => {
+                VcpuExit::IoOut(addr, data) => {
 
                     match addr {
+
                         qlib::HYPERCALL_INIT => {
                             println!("get io out: HYPERCALL_INIT");
 
                             let regs = self.vcpu_fds[0].get_regs().map_err(|e| Error::IOError(format!("io::error is {:?}", e)))?;
-                            shareSpace = regs.rcx as * mut Mutex<ShareSpace>;
+                            if self.shareSpace != 0 {
+                                return Err(self.BadHypercall(addr, Error::Common(format!("ShareSpace is at {:x} already", self.shareSpace))));
+                            }
+
+                            self.GuestMemory().CheckWritableVirt(regs.rcx, size_of::<ShareSpace>()).map_err(|e| self.BadHypercall(addr, e))?;
+                            self.shareSpace = regs.rcx;
                         },
 
 
                         qlib::HYPERCALL_WAIT => {
-                             unsafe {
-                                let mut k = (*shareSpace).lock();
-                                let addr = {(*k).GetMsg()};
-
-                                let msg = addr as *mut qlib::Msg;
-                                match &(*msg) {
-                                    qlib::Msg::Msg1{addr:_, len:_} => {
-                                        println!("get message$3")
+                            match self.ReadMsg() {
+                                Ok((msg, data)) => match msg.msgType {
+                                    qlib::MSG_PRINT => {
+                                        print!("{}", String::from_utf8_lossy(&data));
                                     }
-                                    qlib::Msg::Print(str) => {
-                                        print!("{}", str);
+                                    _ => {
+                                        println!("get message$3")
                                     }
+                                },
+                                //a guest bug, not a broken guest: the message is dropped and the guest goes on
+                                Err(Error::Locked) => {
+                                    println!("HYPERCALL_WAIT with the ShareSpace locked, the message is dropped");
                                 }
+                                Err(e) => return Err(self.BadHypercall(addr, e)),
+                            }
+                        },
+
+                        qlib::HYPERCALL_LOADIDT => {
+                            let regs = self.vcpu_fds[0].get_regs().map_err(|e| Error::IOError(format!("io::error is {:?}", e)))?;
+                            let idt = Gdt::ReadIdtPointer(&self.GuestMemory(), regs.rcx).map_err(|e| self.BadHypercall(addr, e))?;
+
+                            let mut sregs = self.vcpu_fds[0].get_sregs().map_err(|e| Error::IOError(format!("io::error is {:?}", e)))?;
+                            sregs.idt = idt;
+                            self.vcpu_fds[0].set_sregs(&sregs).map_err(|e| Error::IOError(format!("io::error is {:?}", e)))?;
+                            println!("get io out: HYPERCALL_LOADIDT base is {:x}, limit is {:x}", idt.base, idt.limit);
+                        },
+
+                        qlib::HYPERCALL_SNAPSHOT => {
+                            savePoint = true;
+                        },
+
+                        qlib::HYPERCALL_ALLOCMEM => {
+                            let regs = self.vcpu_fds[0].get_regs().map_err(|e| Error::IOError(format!("io::error is {:?}", e)))?;
+                            memCall = Some((addr, regs.rcx));
+                        },
+
+                        qlib::HYPERCALL_BALLOON => {
+                            let regs = self.vcpu_fds[0].get_regs().map_err(|e| Error::IOError(format!("io::error is {:?}", e)))?;
+                            memCall = Some((addr, regs.rcx));
+                        },
+
+                        qlib::HYPERCALL_FREEMEM => {
+                            let regs = self.vcpu_fds[0].get_regs().map_err(|e| Error::IOError(format!("io::error is {:?}", e)))?;
+                            memCall = Some((addr, regs.rcx));
+                        },
 
+                        qlib::HYPERCALL_EXIT => {
+                            let regs = self.vcpu_fds[0].get_regs().map_err(|e| Error::IOError(format!("io::error is {:?}", e)))?;
+                            println!("get io out: HYPERCALL_EXIT code is {}", regs.rcx);
+                            if regs.rcx != 0 {
+                                return Err(Error::VcpuAbnormalExit(regs.rcx as i32));
                             }
 
+                            return Ok(())
                         },
 
                         qlib::HYPERCALL_PANIC => {
-                            println!("get pannic");
+                            //let gdb look at the guest before it is torn down
+                            self.GdbStop(&mut gdb, StopReason::Panic)?;
+                            return Err(self.AbnormalExit(AbnormalExit::GuestPanic));
                         },
 
-                        _ => println!("asdfsadfdasasdfasdf!!!!! address is {}", addr)
+                        _ => {
+                            self.pioBus.Write(addr as u64, data);
+                        }
                     }
 
                 }
-                VcpuExit::MmioRead(addr, _data) => {
-                    println!(
-                        "Received an MMIO Read Request for the address {:#x}.",
-                        addr,
-                    );
+                VcpuExit::MmioRead(addr, data) => {
+                    if !self.mmioBus.Read(addr, data) {
+                        for b in data.iter_mut() {
+                            *b = 0xff;
+                        }
+                    }
                 }
-                VcpuExit::MmioWrite(addr, _data) => {
-                    println!(
-                        "Received an MMIO Write Request to the address {:#x}.",
-                        addr,
-                    );
+                VcpuExit::MmioWrite(addr, data) => {
+                    self.mmioBus.Write(addr, data);
                 }
                 VcpuExit::Hlt => {
                     //self.vcpu_fds[0].get_regs(&regs).map_err(Error::IOError)?;
@@ -401,14 +1664,41 @@ impl KVMMachine {
                     assert_eq!(dirty_pages, 5);*/
                     break;
                 }
+                VcpuExit::Debug if gdb.is_some() => {
+                    let (exception, _pc, dr6, _dr7) = self.vcpuRuns[0].Debug().unwrap_or((0, 0, 0, 0));
+                    if !self.GdbStop(&mut gdb, StopReason::Debug(exception, dr6))? {
+                        return Ok(())
+                    }
+                }
                 VcpuExit::FailEntry => {
-                    println!("get fail entry***********************************");
-                    break
+                    return Err(self.AbnormalExit(AbnormalExit::FailEntry));
                 }
                 VcpuExit::Exception => {
-                    println!("get exception");
+                    return Err(self.AbnormalExit(AbnormalExit::Exception));
+                }
+                VcpuExit::Shutdown => {
+                    return Err(self.AbnormalExit(AbnormalExit::Shutdown));
+                }
+                VcpuExit::InternalError => {
+                    return Err(self.AbnormalExit(AbnormalExit::InternalError));
                 }
-                r => panic!("Unexpected exit reason: {:?}", r),
+                r => {
+                    let reason = format!("{:?}", r);
+                    return Err(self.AbnormalExit(AbnormalExit::Unexpected(reason)));
+                }
+            }
+
+            match memCall {
+                Some((qlib::HYPERCALL_ALLOCMEM, reqAddr)) => self.AllocMemCall(reqAddr).map_err(|e| self.BadHypercall(qlib::HYPERCALL_ALLOCMEM, e))?,
+                Some((qlib::HYPERCALL_FREEMEM, reqAddr)) => self.FreeMemCall(reqAddr).map_err(|e| self.BadHypercall(qlib::HYPERCALL_FREEMEM, e))?,
+                Some((call, reqAddr)) => self.BalloonCall(reqAddr).map_err(|e| self.BadHypercall(call, e))?,
+                None => (),
+            }
+
+            self.FlushMemAdded()?;
+
+            if savePoint && self.SavePoint(&mut migration)? {
+                return Ok(())
             }
         }
 
@@ -1,17 +1,29 @@
 use super::qlib::Common::{Result};
 use super::qlib::PageTable::{PagePool, PageTables};
 use super::qlib::Addr::{Addr, PageOpts};
+use super::Cpu::CpuProfile;
 
 pub struct VMSpace {
     pub pagePool: Option<PagePool>,
     pub pageTables : Option<PageTables>,
+    //the profile the vcpu runs with, the mappings added after it starts are checked against it.
+    //None before setup_long_mode, CheckPageTables checks the mappings of the setup
+    pub profile: Option<CpuProfile>,
 }
 
 impl VMSpace {
     pub fn Map(&mut self, start: Addr, end: Addr, physical: Addr, opts: &PageOpts) -> Result<bool> {
+        if let Some(profile) = &self.profile {
+            profile.CheckPageOpts(opts)?;
+        }
+
         return self.pageTables.as_mut().unwrap().Map(start, end, physical, opts, self.pagePool.as_mut().unwrap());
     }
 
+    pub fn Unmap(&mut self, start: Addr, end: Addr) -> Result<bool> {
+        return self.pageTables.as_mut().unwrap().Unmap(start, end);
+    }
+
 }
 
 impl Default for VMSpace {
@@ -19,6 +31,7 @@ impl Default for VMSpace {
         return VMSpace {
             pagePool: None,
             pageTables: None,
+            profile: None,
         }
     }
 }
\ No newline at end of file
@@ -17,13 +17,150 @@ fn main() {
 
     //kvmlib::ELFLoader::elftest();
 
-    match KVMMachine::init(0x200000) {
+    //qvisor [snapshot <file> | restore <file> [--lazy] | migrate-to <addr> | migrate-from <addr> | template <clones>] [--memfd] [--hugepage require|prefer|never] [--core-dump <path>] [--gdb <addr>] [--control <addr>] [--serial-out <path>] [--serial-in stdin|unix:<path>]
+    let mut coreDumpPath = None;
+    let mut gdbAddr = None;
+    let mut controlAddr = None;
+    let mut serialOut = None;
+    let mut serialIn = None;
+    let mut snapshotPath = None;
+    let mut restorePath = None;
+    let mut migrateAddr = None;
+    let mut migrateFrom = None;
+    let mut templateClones = 0;
+    let mut lazy = false;
+    let mut memOption = kvmlib::GuestMemOption::default();
+    let args: Vec<String> = std::env::args().collect();
+    let mut i = 1;
+    while i < args.len() {
+        match args[i].as_str() {
+            "snapshot" if i == 1 && i + 1 < args.len() => {
+                snapshotPath = Some(args[i + 1].clone());
+                i += 1;
+            }
+            "restore" if i == 1 && i + 1 < args.len() => {
+                restorePath = Some(args[i + 1].clone());
+                i += 1;
+            }
+            "migrate-to" if i == 1 && i + 1 < args.len() => {
+                migrateAddr = Some(args[i + 1].clone());
+                i += 1;
+            }
+            "migrate-from" if i == 1 && i + 1 < args.len() => {
+                migrateFrom = Some(args[i + 1].clone());
+                i += 1;
+            }
+            "template" if i == 1 && i + 1 < args.len() => {
+                templateClones = match args[i + 1].parse::<usize>() {
+                    Ok(n) if n > 0 => n,
+                    _ => {
+                        eprintln!("bad clone count {}", args[i + 1]);
+                        std::process::exit(1)
+                    }
+                };
+                i += 1;
+            }
+            "--lazy" => lazy = true,
+            "--memfd" => memOption.memfd = true,
+            "--hugepage" if i + 1 < args.len() => {
+                memOption.hugePage = match args[i + 1].as_str() {
+                    "require" => kvmlib::HugePagePolicy::Require,
+                    "prefer" => kvmlib::HugePagePolicy::Prefer,
+                    "never" => kvmlib::HugePagePolicy::Never,
+                    policy => {
+                        eprintln!("unknown hugepage policy {}", policy);
+                        std::process::exit(1)
+                    }
+                };
+                i += 1;
+            }
+            "--core-dump" if i + 1 < args.len() => {
+                coreDumpPath = Some(args[i + 1].clone());
+                i += 1;
+            }
+            "--gdb" if i + 1 < args.len() => {
+                gdbAddr = Some(args[i + 1].clone());
+                i += 1;
+            }
+            "--control" if i + 1 < args.len() => {
+                controlAddr = Some(args[i + 1].clone());
+                i += 1;
+            }
+            "--serial-out" if i + 1 < args.len() => {
+                serialOut = Some(args[i + 1].clone());
+                i += 1;
+            }
+            "--serial-in" if i + 1 < args.len() => {
+                serialIn = Some(args[i + 1].clone());
+                i += 1;
+            }
+            arg => {
+                eprintln!("unknown argument {}", arg);
+                std::process::exit(1)
+            }
+        }
+        i += 1;
+    }
+
+    let saved = match (&restorePath, &migrateFrom) {
+        (Some(path), _) => Some((path.clone(), KVMMachine::Restore(path, lazy))),
+        (None, Some(addr)) => Some((addr.clone(), KVMMachine::MigrateFrom(addr))),
+        (None, None) => None,
+    };
+
+    if let Some((path, res)) = saved {
+        match res {
+            Ok(mut vm) => {
+                vm.coreDumpPath = coreDumpPath;
+                vm.gdbAddr = gdbAddr;
+                vm.controlAddr = controlAddr;
+                vm.serialOut = serialOut;
+                vm.serialIn = serialIn;
+                match vm.Resume() {
+                    Ok(()) => (),
+                    Err(kvmlib::Error::VcpuAbnormalExit(code)) => std::process::exit(code),
+                    Err(e) => {
+                        println!("run error is {:?}", e);
+                        std::process::exit(1)
+                    }
+                }
+            },
+            Err(e) => {
+                println!("restore from {} error is {:?}", path, e);
+                std::process::exit(1)
+            }
+        }
+        return;
+    }
+
+    match KVMMachine::init(0x200000, memOption) {
         Ok(mut vm) => {
             println!("test....");
+            vm.coreDumpPath = coreDumpPath;
+            vm.gdbAddr = gdbAddr;
+            vm.controlAddr = controlAddr;
+            vm.serialOut = serialOut;
+            vm.serialIn = serialIn;
+            vm.snapshotPath = snapshotPath;
+            vm.migrateAddr = migrateAddr;
+            vm.templateClones = templateClones;
 
             //vm.MapMemRange(kvmlib::Addr::Addr(vm.mem as u64), 4096, kvmlib::Addr::Addr(0)).expect("asdf");
             //println!("start to run*************");
-            vm.run().expect("asdf1111")
+            match vm.run() {
+                Ok(()) if templateClones > 0 => {
+                    if let Err(e) = vm.SpawnClones() {
+                        println!("template error is {:?}", e);
+                        std::process::exit(1)
+                    }
+                }
+                Ok(()) => (),
+                Err(kvmlib::Error::VcpuAbnormalExit(code)) => std::process::exit(code),
+                Err(e) => {
+                    println!("run error is {:?}", e);
+                    std::process::exit(1)
+                }
+            }
         },
         Err(e) => println!("error is {:?}", e)
     }